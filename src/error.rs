@@ -5,10 +5,17 @@ use std::fmt;
 // dune: failed to setup APIC
 // dune: unable to setup memory management
 // dune: failed to setup safe stack
+#[derive(Debug)]
 pub enum VmplError {
     Io(std::io::Error),
     Sys(i32),
     ApicSetupFailed(i32),
+    ApicInvalidCpu(usize),
+    ApicNotRouted(usize),
+    PerCpuNotActive,
+    PerCpuNoSpace(usize),
+    GdtInvalidSelector(u16),
+    GdtOverlap(u16),
     #[cfg(feature = "seimi")]
     SeimiSetupFailed(i32),
     SyscallSetupFailed(i32),
@@ -36,6 +43,12 @@ impl fmt::Display for VmplError {
             VmplError::Io(e) => write!(f, "{}", e),
             VmplError::Sys(e) => write!(f, "{}", e),
             VmplError::ApicSetupFailed(e) => write!(f, "failed to setup APIC"),
+            VmplError::ApicInvalidCpu(cpu) => write!(f, "invalid CPU {}", cpu),
+            VmplError::ApicNotRouted(cpu) => write!(f, "no APIC ID for CPU {}", cpu),
+            VmplError::PerCpuNotActive => write!(f, "no per-CPU area on this CPU"),
+            VmplError::GdtInvalidSelector(sel) => write!(f, "invalid GDT selector {:#x}", sel),
+            VmplError::GdtOverlap(sel) => write!(f, "GDT descriptor at {:#x} overlaps another", sel),
+            VmplError::PerCpuNoSpace(size) => write!(f, "no room for a {}-byte per-CPU variable", size),
             #[cfg(feature = "seimi")]
             VmplError::SeimiSetupFailed(e) => write!(f, "failed to setup SEIMI"),
             VmplError::SyscallSetupFailed(e) => write!(f, "failed to setup syscall"),
@@ -14,6 +14,22 @@ use x86_64::VirtAddr;
 
 use crate::mm::pgtable_va_to_pa;
 use crate::BIT;
+#[cfg(feature = "ghcb")]
+use crate::ghcb::vc::{vc_register_ghcb, vc_terminate_svsm_page_err};
+#[cfg(feature = "ghcb")]
+use crate::mm::PageOwner;
+#[cfg(feature = "ghcb")]
+use crate::mm::PageRef;
+#[cfg(feature = "ghcb")]
+use crate::mm::{pgtable_make_ghcb_shared, PAGE_SIZE};
+#[cfg(feature = "ghcb")]
+use crate::sys::percpu::this_cpu;
+#[cfg(feature = "ghcb")]
+use crate::util::memset;
+#[cfg(feature = "ghcb")]
+use crate::STATIC_ASSERT;
+#[cfg(feature = "ghcb")]
+use std::mem::size_of;
 
 /// 1
 pub const GHCB_VERSION_1: u16 = 1;
@@ -132,34 +148,28 @@ impl Ghcb {
     }
 }
 
-#[cfg(features = "ghcb")]
-pub fn ghcb_init() {
+/// Set up the GHCB of this CPU on a page from the VMPL pool. The page is
+/// shared with the MSR protocol, as there is no GHCB yet to ask through,
+/// and then registered with the hypervisor.
+#[cfg(feature = "ghcb")]
+pub fn ghcb_init(fd: i32) -> Result<(), i32> {
     STATIC_ASSERT!(size_of::<Ghcb>() == PAGE_SIZE as usize);
 
-    //
-    // Perform GHCB allocation in a loop to avoid allocation order failures
-    // for large vCPU counts.
-    //
-    let count: usize = percpu_count();
-    for i in 0..count {
-        let frame: PhysFrame = match mem_allocate_frame() {
-            Some(f) => f,
-            None => vc_terminate_svsm_enomem(),
-        };
-        let va: VirtAddr = pgtable_pa_to_va(frame.start_address());
-
-        pgtable_make_pages_shared(va, PAGE_SIZE);
-        memset(va.as_mut_ptr(), 0, PAGE_SIZE as usize);
+    let percpu = this_cpu().ok_or(libc::ENODEV)?;
+    let page: PageRef = PageRef::alloc(fd, PageOwner::Vmpl).ok_or(libc::ENOMEM)?;
+    let va: VirtAddr = page.va();
 
-        unsafe {
-            PERCPU.set_ghcb_for(va, i);
-
-            if i == 0 {
-                // Register the BSPs GHCB
-                vc_register_ghcb(frame.start_address());
-            }
-        }
+    if pgtable_make_ghcb_shared(va).is_err() {
+        vc_terminate_svsm_page_err();
     }
+    memset(va.as_mut_ptr(), 0, PAGE_SIZE as usize);
+
+    vc_register_ghcb(page.pa());
+    // the page stays with this CPU
+    let _ = page.into_raw();
+    unsafe { (*percpu).set_ghcb(va.as_mut_ptr()) };
+
+    Ok(())
 }
 
 impl Display for Ghcb {
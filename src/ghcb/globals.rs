@@ -48,6 +48,8 @@ pub const VMPL_TERM_VMPL0_SEV_FEATURES: u64 = 10;
 pub const VMPL_TERM_INCORRECT_VMPL: u64 = 11;
 /// 12
 pub const VMPL_TERM_VMPL1_SEV_FEATURES: u64 = 12;
+/// 13
+pub const VMPL_TERM_FATAL_EXCEPTION: u64 = 13;
 
 /// 12
 pub const PAGE_SHIFT: u64 = 12;
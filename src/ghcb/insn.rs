@@ -0,0 +1,391 @@
+/* SPDX-License-Identifier: MIT */
+
+//! Minimal x86-64 instruction decoder for the #VC handler.
+//!
+//! Only the instructions that can raise a #VC and that we know how to
+//! emulate through the GHCB are understood: CPUID, RDMSR/WRMSR,
+//! RDTSC/RDTSCP, IN/OUT and plain MOV/MOVZX to or from memory (MMIO).
+
+/// Longest legal x86 instruction
+pub const MAX_INSN_SIZE: usize = 15;
+
+const PREFIX_OPSIZE: u8 = 0x66;
+const PREFIX_FS: u8 = 0x64;
+const PREFIX_GS: u8 = 0x65;
+
+/// Segment override applied to a memory operand
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SegOverride {
+    None,
+    Fs,
+    Gs,
+}
+
+/// Instructions the #VC handler knows how to emulate
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InsnKind {
+    Cpuid,
+    Rdmsr,
+    Wrmsr,
+    Rdtsc,
+    Rdtscp,
+    /// IN from the port in the immediate (`Some`) or in DX (`None`)
+    In(Option<u8>),
+    /// OUT to the port in the immediate (`Some`) or in DX (`None`)
+    Out(Option<u8>),
+    /// MOV reg -> mem
+    MmioWrite,
+    /// MOV imm -> mem
+    MmioWriteImm,
+    /// MOV mem -> reg
+    MmioRead,
+    /// MOVZX mem -> reg
+    MmioReadZeroExtend,
+}
+
+/// ModRM memory operand
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MemOperand {
+    /// Base register number, `None` for no base
+    pub base: Option<u8>,
+    /// Index register number and scale
+    pub index: Option<(u8, u8)>,
+    pub disp: i64,
+    pub rip_relative: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Insn {
+    pub kind: InsnKind,
+    /// Total instruction length in bytes
+    pub len: usize,
+    /// Size of the memory or I/O access in bytes
+    pub size: usize,
+    /// Size of the register operand in bytes. Only MOVZX has it differ
+    /// from `size`.
+    pub dst_size: usize,
+    /// Register operand (ModRM.reg extended with REX.R)
+    pub reg: u8,
+    /// Whether a REX prefix was present (changes byte register encoding)
+    pub rex: bool,
+    pub mem: MemOperand,
+    pub seg: SegOverride,
+    pub imm: u64,
+}
+
+struct Cursor<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn next(&mut self) -> Option<u8> {
+        let b = *self.bytes.get(self.pos)?;
+        self.pos += 1;
+        if self.pos > MAX_INSN_SIZE {
+            return None;
+        }
+        Some(b)
+    }
+
+    fn read_le(&mut self, len: usize) -> Option<u64> {
+        let mut value: u64 = 0;
+        for i in 0..len {
+            value |= (self.next()? as u64) << (i * 8);
+        }
+        Some(value)
+    }
+
+    fn read_signed(&mut self, len: usize) -> Option<i64> {
+        let value = self.read_le(len)?;
+        let shift = 64 - len * 8;
+        Some(((value << shift) as i64) >> shift)
+    }
+}
+
+/// Decode a ModRM byte (and SIB/displacement, if any) for a memory operand.
+/// Register-direct forms (mod == 3) are rejected: they never touch MMIO.
+fn decode_modrm(cur: &mut Cursor, rex: u8) -> Option<(u8, MemOperand)> {
+    let modrm = cur.next()?;
+    let md = modrm >> 6;
+    let reg = ((modrm >> 3) & 7) | ((rex & 0x4) << 1);
+    let rm = modrm & 7;
+    let mut mem = MemOperand::default();
+
+    if md == 3 {
+        return None;
+    }
+
+    if rm == 4 {
+        let sib = cur.next()?;
+        let scale = 1 << (sib >> 6);
+        let index = ((sib >> 3) & 7) | ((rex & 0x2) << 2);
+        let base = (sib & 7) | ((rex & 0x1) << 3);
+
+        // index 4 without REX.X means no index
+        if index != 4 {
+            mem.index = Some((index, scale));
+        }
+        if md == 0 && (sib & 7) == 5 {
+            mem.disp = cur.read_signed(4)?;
+        } else {
+            mem.base = Some(base);
+        }
+    } else if md == 0 && rm == 5 {
+        mem.rip_relative = true;
+        mem.disp = cur.read_signed(4)?;
+    } else {
+        mem.base = Some(rm | ((rex & 0x1) << 3));
+    }
+
+    match md {
+        1 => mem.disp = cur.read_signed(1)?,
+        2 => mem.disp = cur.read_signed(4)?,
+        _ => {}
+    }
+
+    Some((reg, mem))
+}
+
+impl Insn {
+    /// Decode the instruction at the start of `bytes`
+    pub fn decode(bytes: &[u8]) -> Option<Insn> {
+        let mut cur = Cursor { bytes, pos: 0 };
+        let mut opsize = false;
+        let mut seg = SegOverride::None;
+        let mut rex: u8 = 0;
+
+        let mut op = cur.next()?;
+        loop {
+            match op {
+                PREFIX_OPSIZE => opsize = true,
+                PREFIX_FS => seg = SegOverride::Fs,
+                PREFIX_GS => seg = SegOverride::Gs,
+                // other legacy prefixes don't matter for what we emulate
+                0xf0 | 0xf2 | 0xf3 | 0x2e | 0x36 | 0x3e | 0x26 => {}
+                _ => break,
+            }
+            op = cur.next()?;
+        }
+        if op & 0xf0 == 0x40 {
+            rex = op;
+            op = cur.next()?;
+        }
+
+        let size = if rex & 0x8 != 0 {
+            8
+        } else if opsize {
+            2
+        } else {
+            4
+        };
+
+        let mut insn = Insn {
+            kind: InsnKind::Cpuid,
+            len: 0,
+            size,
+            dst_size: size,
+            reg: 0,
+            rex: rex != 0,
+            mem: MemOperand::default(),
+            seg,
+            imm: 0,
+        };
+
+        match op {
+            0x0f => match cur.next()? {
+                0xa2 => insn.kind = InsnKind::Cpuid,
+                0x32 => insn.kind = InsnKind::Rdmsr,
+                0x30 => insn.kind = InsnKind::Wrmsr,
+                0x31 => insn.kind = InsnKind::Rdtsc,
+                0x01 => match cur.next()? {
+                    0xf9 => insn.kind = InsnKind::Rdtscp,
+                    _ => return None,
+                },
+                op2 @ (0xb6 | 0xb7) => {
+                    let (reg, mem) = decode_modrm(&mut cur, rex)?;
+                    insn.kind = InsnKind::MmioReadZeroExtend;
+                    insn.reg = reg;
+                    insn.mem = mem;
+                    // the memory operand size, the destination keeps the
+                    // operand size and is zero-extended
+                    insn.size = if op2 == 0xb6 { 1 } else { 2 };
+                }
+                _ => return None,
+            },
+            0xe4 | 0xe5 => {
+                insn.kind = InsnKind::In(Some(cur.next()?));
+                insn.size = if op == 0xe4 { 1 } else { insn.size.min(4) };
+            }
+            0xe6 | 0xe7 => {
+                insn.kind = InsnKind::Out(Some(cur.next()?));
+                insn.size = if op == 0xe6 { 1 } else { insn.size.min(4) };
+            }
+            0xec | 0xed => {
+                insn.kind = InsnKind::In(None);
+                insn.size = if op == 0xec { 1 } else { insn.size.min(4) };
+            }
+            0xee | 0xef => {
+                insn.kind = InsnKind::Out(None);
+                insn.size = if op == 0xee { 1 } else { insn.size.min(4) };
+            }
+            0x88..=0x8b => {
+                let (reg, mem) = decode_modrm(&mut cur, rex)?;
+                insn.kind = if op <= 0x89 {
+                    InsnKind::MmioWrite
+                } else {
+                    InsnKind::MmioRead
+                };
+                insn.reg = reg;
+                insn.mem = mem;
+                if op & 1 == 0 {
+                    insn.size = 1;
+                }
+            }
+            0xc6 | 0xc7 => {
+                let (reg, mem) = decode_modrm(&mut cur, rex)?;
+                // only /0 is MOV
+                if reg & 7 != 0 {
+                    return None;
+                }
+                insn.kind = InsnKind::MmioWriteImm;
+                insn.mem = mem;
+                if op == 0xc6 {
+                    insn.size = 1;
+                }
+                // imm32 is sign-extended for 64-bit operands
+                let imm_len = insn.size.min(4);
+                insn.imm = cur.read_signed(imm_len)? as u64;
+            }
+            _ => return None,
+        }
+
+        if insn.kind != InsnKind::MmioReadZeroExtend {
+            insn.dst_size = insn.size;
+        }
+        insn.len = cur.pos;
+        Some(insn)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Expected decoding: kind, len, size, dst_size, reg
+    type Expect = (InsnKind, usize, usize, usize, u8);
+
+    #[test]
+    fn decode_known_insns() {
+        let table: &[(&[u8], Expect)] = &[
+            (&[0x0f, 0xa2], (InsnKind::Cpuid, 2, 4, 4, 0)),
+            (&[0x0f, 0x32], (InsnKind::Rdmsr, 2, 4, 4, 0)),
+            (&[0x0f, 0x30], (InsnKind::Wrmsr, 2, 4, 4, 0)),
+            (&[0x0f, 0x31], (InsnKind::Rdtsc, 2, 4, 4, 0)),
+            (&[0x0f, 0x01, 0xf9], (InsnKind::Rdtscp, 3, 4, 4, 0)),
+            // in al, 0x60 / in ax, 0x70 / in eax, dx / out dx, al
+            (&[0xe4, 0x60], (InsnKind::In(Some(0x60)), 2, 1, 1, 0)),
+            (&[0x66, 0xe5, 0x70], (InsnKind::In(Some(0x70)), 3, 2, 2, 0)),
+            (&[0xed], (InsnKind::In(None), 1, 4, 4, 0)),
+            (&[0xee], (InsnKind::Out(None), 1, 1, 1, 0)),
+            (&[0x48, 0xe7, 0x80], (InsnKind::Out(Some(0x80)), 3, 4, 4, 0)),
+            // mov [rdi], eax / mov rax, [rbx + 8] / mov ah, [rcx * 4 + 0x1000]
+            (&[0x89, 0x07], (InsnKind::MmioWrite, 2, 4, 4, 0)),
+            (&[0x48, 0x8b, 0x43, 0x08], (InsnKind::MmioRead, 4, 8, 8, 0)),
+            (
+                &[0x8a, 0x24, 0x8d, 0x00, 0x10, 0x00, 0x00],
+                (InsnKind::MmioRead, 7, 1, 1, 4),
+            ),
+            // mov r9d, [rip + 0x10]
+            (
+                &[0x44, 0x8b, 0x0d, 0x10, 0x00, 0x00, 0x00],
+                (InsnKind::MmioRead, 7, 4, 4, 9),
+            ),
+            // mov dword [rax], imm32 / mov word [rax], imm16 / mov byte [rax], imm8
+            (
+                &[0xc7, 0x00, 0x78, 0x56, 0x34, 0x12],
+                (InsnKind::MmioWriteImm, 6, 4, 4, 0),
+            ),
+            (&[0x66, 0xc7, 0x00, 0x34, 0x12], (InsnKind::MmioWriteImm, 5, 2, 2, 0)),
+            (&[0xc6, 0x00, 0x7f], (InsnKind::MmioWriteImm, 3, 1, 1, 0)),
+            // movzx eax, byte [rdi] / movzx ax, byte [rdi] / movzx rax, word [rdi]
+            (&[0x0f, 0xb6, 0x07], (InsnKind::MmioReadZeroExtend, 3, 1, 4, 0)),
+            (&[0x66, 0x0f, 0xb6, 0x07], (InsnKind::MmioReadZeroExtend, 4, 1, 2, 0)),
+            (&[0x48, 0x0f, 0xb7, 0x07], (InsnKind::MmioReadZeroExtend, 4, 2, 8, 0)),
+            // movzx r10d, byte [r8]: REX without W keeps 32 bits
+            (&[0x45, 0x0f, 0xb6, 0x10], (InsnKind::MmioReadZeroExtend, 4, 1, 4, 10)),
+        ];
+
+        for (bytes, (kind, len, size, dst_size, reg)) in table {
+            let insn = Insn::decode(bytes).unwrap_or_else(|| panic!("{:02x?}", bytes));
+            assert_eq!(insn.kind, *kind, "{:02x?}", bytes);
+            assert_eq!(insn.len, *len, "{:02x?}", bytes);
+            assert_eq!(insn.size, *size, "{:02x?}", bytes);
+            assert_eq!(insn.dst_size, *dst_size, "{:02x?}", bytes);
+            assert_eq!(insn.reg, *reg, "{:02x?}", bytes);
+        }
+    }
+
+    #[test]
+    fn decode_operands() {
+        // mov rax, [rbx + 8]
+        let insn = Insn::decode(&[0x48, 0x8b, 0x43, 0x08]).unwrap();
+        assert_eq!(insn.mem.base, Some(3));
+        assert_eq!(insn.mem.index, None);
+        assert_eq!(insn.mem.disp, 8);
+
+        // mov ah, [rcx * 4 + 0x1000]: no base
+        let insn = Insn::decode(&[0x8a, 0x24, 0x8d, 0x00, 0x10, 0x00, 0x00]).unwrap();
+        assert!(!insn.rex);
+        assert_eq!(insn.mem.base, None);
+        assert_eq!(insn.mem.index, Some((1, 4)));
+        assert_eq!(insn.mem.disp, 0x1000);
+
+        // mov r9d, [rip + 0x10]
+        let insn = Insn::decode(&[0x44, 0x8b, 0x0d, 0x10, 0x00, 0x00, 0x00]).unwrap();
+        assert!(insn.mem.rip_relative);
+        assert_eq!(insn.mem.disp, 0x10);
+
+        // mov eax, gs:[0x28]
+        let insn = Insn::decode(&[0x65, 0x8b, 0x04, 0x25, 0x28, 0x00, 0x00, 0x00]).unwrap();
+        assert_eq!(insn.seg, SegOverride::Gs);
+        assert_eq!(insn.mem.base, None);
+        assert_eq!(insn.mem.index, None);
+        assert_eq!(insn.mem.disp, 0x28);
+        assert_eq!(insn.len, 8);
+
+        // movzx eax, byte [r8 - 1]
+        let insn = Insn::decode(&[0x41, 0x0f, 0xb6, 0x40, 0xff]).unwrap();
+        assert_eq!(insn.mem.base, Some(8));
+        assert_eq!(insn.mem.disp, -1);
+
+        // mov qword [rax], -1: imm32 is sign-extended
+        let insn = Insn::decode(&[0x48, 0xc7, 0x00, 0xff, 0xff, 0xff, 0xff]).unwrap();
+        assert_eq!(insn.size, 8);
+        assert_eq!(insn.imm, u64::MAX);
+        let insn = Insn::decode(&[0x66, 0xc7, 0x00, 0x34, 0x12]).unwrap();
+        assert_eq!(insn.imm, 0x1234);
+    }
+
+    #[test]
+    fn reject_unsupported() {
+        let table: &[&[u8]] = &[
+            &[],
+            // mov eax, eax: register-direct never touches MMIO
+            &[0x8b, 0xc0],
+            // mov /1 is not MOV
+            &[0xc7, 0x08, 0x00, 0x00, 0x00, 0x00],
+            // ud2, truncated cpuid and rdtscp
+            &[0x0f, 0x0b],
+            &[0x0f],
+            &[0x0f, 0x01],
+            // mov rax, [rbx + disp32] cut short
+            &[0x48, 0x8b, 0x83, 0x00, 0x10],
+            // longer than 15 bytes
+            &[0x66; 16],
+        ];
+        for bytes in table {
+            assert!(Insn::decode(bytes).is_none(), "{:02x?}", bytes);
+        }
+    }
+}
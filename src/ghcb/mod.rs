@@ -6,6 +6,8 @@ pub mod vmsa;
 pub mod ghcb;
 /// #VC (Virtualization Exception) module
 pub mod vc;
+/// Instruction decoder for #VC emulation
+pub mod insn;
 
 pub use ghcb::Ghcb;
 pub use vc::vc_init;
\ No newline at end of file
@@ -11,12 +11,13 @@ use crate::*;
 
 use std::arch::asm;
 use std::mem::size_of;
+use std::ptr;
 use libc::memset;
 use x86_64::addr::PhysAddr;
 use x86_64::addr::VirtAddr;
 use x86_64::instructions::hlt;
 use x86_64::registers::control::Cr2;
-use x86_64::registers::model_specific::Msr;
+use x86_64::registers::model_specific::{FsBase, GsBase, Msr};
 use x86_64::structures::idt::*;
 use x86_64::structures::paging::frame::PhysFrame;
 
@@ -27,19 +28,20 @@ use x86_64::registers::control::Cr4;
 use x86_64::registers::control::Cr4Flags;
 use x86_64::registers::xcontrol::XCr0;
 
-use self::mm::mem_allocate_frames;
-use self::mm::mem_free_frames;
 use self::mm::pgtable_make_pages_private;
 use self::mm::pgtable_make_pages_shared;
 use self::mm::pgtable_pa_to_va;
 use self::mm::pgtable_va_to_pa;
 use self::mm::PAGE_2MB_SIZE;
 use self::mm::PAGE_SIZE;
+use self::sys::core::DuneTrapFrame;
 use self::sys::ioctl::vmpl_ioctl::VmplFile;
+use self::sys::percpu::this_cpu;
 
 use super::ghcb::GHCB_USAGE;
 use super::ghcb::GHCB_VERSION_1;
 use super::globals::*;
+use super::insn::{Insn, InsnKind, SegOverride, MAX_INSN_SIZE};
 use super::ghcb::get_early_ghcb;
 use super::ghcb::SHARED_BUFFER_SIZE;
 use super::vmsa::Vmsa;
@@ -95,6 +97,22 @@ macro_rules! GHCB_MSR_REGISTER_GHCB {
 /// 0x13
 const GHCB_MSR_REGISTER_GHCB_RES: u64 = 0x13;
 
+// MSR protocol: Page State Change, one 4K page per request
+/// 0x14
+const GHCB_MSR_PSC_REQ: u64 = 0x014;
+macro_rules! GHCB_MSR_PSC {
+    ($pa: expr, $op: expr) => {
+        (($pa) | ($op) | GHCB_MSR_PSC_REQ)
+    };
+}
+/// 0x15
+const GHCB_MSR_PSC_RES: u64 = 0x015;
+macro_rules! GHCB_MSR_PSC_ERROR {
+    ($x: expr) => {
+        (($x) >> 32)
+    };
+}
+
 // MSR protocol: Hypervisor feature support
 /// 0x80
 const GHCB_MSR_HV_FEATURE_REQ: u64 = 0x080;
@@ -116,10 +134,22 @@ const RESCIND: u32 = 0;
 const VALIDATE: u32 = 1;
 
 // VMGEXIT exit codes
+/// 0x6e
+const GHCB_NAE_RDTSC: u64 = 0x6e;
 /// 0x72
 const GHCB_NAE_CPUID: u64 = 0x72;
 /// 0x7b
 const GHCB_NAE_IOIO: u64 = 0x7b;
+/// 0x7c
+const GHCB_NAE_MSR: u64 = 0x7c;
+/// 0x87
+const GHCB_NAE_RDTSCP: u64 = 0x87;
+/// 0x400
+const GHCB_NAE_NPF: u64 = 0x400;
+/// 0x80000001
+const GHCB_NAE_MMIO_READ: u64 = 0x80000001;
+/// 0x80000002
+const GHCB_NAE_MMIO_WRITE: u64 = 0x80000002;
 /// 0x80000010
 const GHCB_NAE_PSC: u64 = 0x80000010;
 /// 0x80000013
@@ -222,6 +252,12 @@ pub fn vc_terminate_unhandled_vc() -> ! {
     vc_terminate(VMPL_REASON_CODE_SET, VMPL_TERM_UNHANDLED_VC);
 }
 
+/// Terminate SVSM due to a fatal exception
+#[inline]
+pub fn vc_terminate_fatal_exception() -> ! {
+    vc_terminate(VMPL_REASON_CODE_SET, VMPL_TERM_FATAL_EXCEPTION);
+}
+
 /// Terminate SVSM with generic GHCB reason
 #[inline]
 pub fn vc_terminate_ghcb_general() -> ! {
@@ -319,12 +355,16 @@ fn vc_establish_protocol() {
 }
 
 fn vc_get_ghcb() -> *mut Ghcb {
-    unsafe {
-        let va: VirtAddr = PERCPU.ghcb();
-        let ghcb: *mut Ghcb = va.as_mut_ptr();
-
-        ghcb
+    // the GHCB is only reachable from VMPL mode
+    let ghcb: *mut Ghcb = match this_cpu() {
+        Some(percpu) => unsafe { (*percpu).get_ghcb() },
+        None => ptr::null_mut(),
+    };
+    if ghcb.is_null() {
+        vc_terminate_ghcb_general();
     }
+
+    ghcb
 }
 
 unsafe fn vc_perform_vmgexit(ghcb: *mut Ghcb, code: u64, info1: u64, info2: u64) {
@@ -607,70 +647,494 @@ impl PscOp {
     funcs!(entries, [PscOpData; PSC_ENTRIES]);
 }
 
-pub fn vc_init(fd: VmplFile) -> VirtAddr {
-    let ghcb_pa: PhysAddr = pgtable_va_to_pa(get_early_ghcb());
+// PSC entry: GFN in bits 51:12, operation in 55:52, 2M page in bit 56
+const PSC_GFN_MASK: u64 = 0x000f_ffff_ffff_f000;
+/// Bit 56
+const PSC_2M_PAGE: u64 = BIT!(56);
 
-    vc_establish_protocol();
-    vc_register_ghcb(ghcb_pa);
+/// 6
+const PVALIDATE_FAIL_SIZE_MISMATCH: u32 = 6;
+/// PVALIDATE left the RMP entry unchanged (CF set)
+const PVALIDATE_FAIL_NO_UPDATE: u32 = 255;
 
-    get_early_ghcb()
+/// Validate or rescind the page at `va`, of RMP size `size`. Returns 0 on
+/// success.
+fn pvalidate(va: VirtAddr, size: u32, validate: u32) -> u32 {
+    let rax: u64;
+    let no_update: u8;
+
+    unsafe {
+        asm!(
+            ".byte 0xf2,0x0f,0x01,0xff",
+            "setc {0}",
+            out(reg_byte) no_update,
+            inout("rax") va.as_u64() => rax,
+            in("rcx") size as u64,
+            in("rdx") validate as u64,
+            options(nostack)
+        );
+    }
+
+    let ret: u32 = LOWER_32BITS!(rax) as u32;
+    if ret == 0 && no_update != 0 {
+        return PVALIDATE_FAIL_NO_UPDATE;
+    }
+    ret
+}
+
+/// PVALIDATE the entries of `op` from `cur_entry` to `end_entry` through
+/// the direct map. A 2M entry backed by 4K RMP entries is done page by page.
+fn pvalidate_psc_entries(op: &PscOp, validate: u32) {
+    let first: usize = op.header.cur_entry as usize;
+    let last: usize = op.header.end_entry as usize;
+
+    for i in first..=last {
+        let entry: u64 = op.entries[i].data;
+        let va: VirtAddr = pgtable_pa_to_va(PhysAddr::new(entry & PSC_GFN_MASK));
+
+        let mut ret: u32;
+        if entry & PSC_2M_PAGE != 0 {
+            ret = pvalidate(va, RMP_2M, validate);
+            if ret == PVALIDATE_FAIL_SIZE_MISMATCH {
+                let pages = PAGE_2MB_SIZE / PAGE_SIZE as u64;
+                for offset in (0..pages).map(|page| page * PAGE_SIZE as u64) {
+                    ret = pvalidate(va + offset, RMP_4K, validate);
+                    if ret != 0 {
+                        break;
+                    }
+                }
+            }
+        } else {
+            ret = pvalidate(va, RMP_4K, validate);
+        }
+
+        if ret != 0 {
+            vc_terminate_svsm_psc();
+        }
+    }
+}
+
+/// Fill `op` with entries changing `[begin, end)` to `state`, using 2M
+/// entries where aligned. Returns where it stopped, `end` unless `op` is
+/// full.
+fn build_psc_entries(op: &mut PscOp, begin: PhysAddr, end: PhysAddr, state: u64) -> PhysAddr {
+    let mut pa: PhysAddr = begin;
+    let mut i: usize = 0;
+
+    while pa < end && i < PSC_ENTRIES {
+        let mut entry: u64 = pa.as_u64() | state;
+        if pa.is_aligned(PAGE_2MB_SIZE) && end - pa >= PAGE_2MB_SIZE {
+            entry |= PSC_2M_PAGE;
+            pa += PAGE_2MB_SIZE;
+        } else {
+            pa += PAGE_SIZE as u64;
+        }
+
+        op.entries[i].data = entry;
+        i += 1;
+    }
+
+    op.header.cur_entry = 0;
+    op.header.end_entry = (i - 1) as u16;
+
+    pa
+}
+
+/// Change the frames `[begin, end)` to `state` with Page State Change
+/// requests, one shared buffer of entries at a time. Pages are rescinded
+/// before they become shared and validated once they are private.
+fn perform_page_state_change(ghcb: *mut Ghcb, begin: PhysAddr, end: PhysAddr, state: u64) {
+    let mut op: PscOp = PscOp::new();
+    let mut pa: PhysAddr = begin;
+
+    while pa < end {
+        let next: PhysAddr = build_psc_entries(&mut op, pa, end, state);
+        let last: u16 = op.header.end_entry;
+        let size: usize = size_of::<PscOpHeader>() + size_of::<PscOpData>() * (last as usize + 1);
+
+        if state == PSC_SHARED {
+            pvalidate_psc_entries(&op, RESCIND);
+        }
+
+        // The hypervisor may stop early and report how far it got in
+        // cur_entry, so keep asking until it is past end_entry
+        while op.header.cur_entry <= last {
+            let cur: u16 = op.header.cur_entry;
+
+            unsafe {
+                (*ghcb).set_shared_buffer(&op as *const PscOp as *const u8, size);
+                vc_perform_vmgexit(ghcb, GHCB_NAE_PSC, 0, 0);
+
+                if !(*ghcb).is_sw_exit_info_2_valid() || (*ghcb).sw_exit_info_2() != 0 {
+                    vc_terminate_svsm_psc();
+                }
+
+                (*ghcb).shared_buffer(&mut op as *mut PscOp as *mut u8, size);
+                (*ghcb).clear();
+            }
+
+            // Progress must only go forward
+            let (new_cur, new_end) = (op.header.cur_entry, op.header.end_entry);
+            if new_end != last || new_cur < cur {
+                vc_terminate_svsm_psc();
+            }
+        }
+
+        if state == PSC_PRIVATE {
+            op.header.cur_entry = 0;
+            pvalidate_psc_entries(&op, VALIDATE);
+        }
+
+        pa = next;
+    }
 }
 
-#[cfg(feature = "ghcb")]
-fn setup_ghcb(dune_fd: RawFd) -> Result<*mut Ghcb, std::io::Error> {
-    log::info!("setup ghcb");
+/// Make the frames `[begin, end)` shared with the hypervisor. The caller
+/// maps them without the C-bit afterwards. The GHCB in use cannot convert
+/// itself, see `vc_make_pages_shared_msr`.
+pub fn vc_make_pages_shared(begin: PhysAddr, end: PhysAddr) {
+    assert!(begin.is_aligned(PAGE_SIZE as u64) && end.is_aligned(PAGE_SIZE as u64));
+
+    let ghcb: *mut Ghcb = vc_get_ghcb();
+    let ghcb_pa: PhysAddr = pgtable_va_to_pa(VirtAddr::new(ghcb as u64));
+    assert!(ghcb_pa < begin || ghcb_pa >= end);
+
+    perform_page_state_change(ghcb, begin, end, PSC_SHARED);
+}
 
-    let ghcb = unsafe {
-        mmap(
-            GHCB_MMAP_BASE as *mut libc::c_void,
-            PAGE_SIZE,
-            PROT_READ | PROT_WRITE,
-            MAP_SHARED | MAP_FIXED,
-            dune_fd,
-            0,
-        )
+/// Make the frames `[begin, end)` shared a page at a time with the MSR
+/// protocol, for pages set up before there is a GHCB to ask through, like
+/// the GHCB itself. The caller maps them without the C-bit afterwards.
+pub fn vc_make_pages_shared_msr(begin: PhysAddr, end: PhysAddr) {
+    assert!(begin.is_aligned(PAGE_SIZE as u64) && end.is_aligned(PAGE_SIZE as u64));
+
+    let mut pa: PhysAddr = begin;
+    while pa < end {
+        if pvalidate(pgtable_pa_to_va(pa), RMP_4K, RESCIND) != 0 {
+            vc_terminate_svsm_psc();
+        }
+
+        let response: u64 = vc_msr_protocol(GHCB_MSR_PSC!(pa.as_u64(), PSC_SHARED));
+        if GHCB_MSR_INFO!(response) != GHCB_MSR_PSC_RES || GHCB_MSR_PSC_ERROR!(response) != 0 {
+            vc_terminate_svsm_psc();
+        }
+
+        pa += PAGE_SIZE as u64;
+    }
+}
+
+/// Make the frames `[begin, end)` private and validate them. The caller
+/// maps them with the C-bit beforehand.
+pub fn vc_make_pages_private(begin: PhysAddr, end: PhysAddr) {
+    assert!(begin.is_aligned(PAGE_SIZE as u64) && end.is_aligned(PAGE_SIZE as u64));
+
+    perform_page_state_change(vc_get_ghcb(), begin, end, PSC_PRIVATE);
+}
+
+trap_entry!(__vmpl_vc_entry, 29, vc_handler, err);
+
+/// Read general purpose register `reg` (ModRM numbering) from the trap frame
+fn vc_get_reg(tf: &DuneTrapFrame, reg: u8) -> u64 {
+    match reg {
+        0 => tf.rax(),
+        1 => tf.rcx(),
+        2 => tf.rdx(),
+        3 => tf.rbx(),
+        4 => tf.rsp(),
+        5 => tf.rbp(),
+        6 => tf.rsi(),
+        7 => tf.rdi(),
+        8 => tf.r8(),
+        9 => tf.r9(),
+        10 => tf.r10(),
+        11 => tf.r11(),
+        12 => tf.r12(),
+        13 => tf.r13(),
+        14 => tf.r14(),
+        _ => tf.r15(),
+    }
+}
+
+/// Write general purpose register `reg` (ModRM numbering) in the trap frame
+fn vc_set_reg(tf: &mut DuneTrapFrame, reg: u8, value: u64) {
+    match reg {
+        0 => tf.set_rax(value),
+        1 => tf.set_rcx(value),
+        2 => tf.set_rdx(value),
+        3 => tf.set_rbx(value),
+        4 => tf.set_rsp(value),
+        5 => tf.set_rbp(value),
+        6 => tf.set_rsi(value),
+        7 => tf.set_rdi(value),
+        8 => tf.set_r8(value),
+        9 => tf.set_r9(value),
+        10 => tf.set_r10(value),
+        11 => tf.set_r11(value),
+        12 => tf.set_r12(value),
+        13 => tf.set_r13(value),
+        14 => tf.set_r14(value),
+        _ => tf.set_r15(value),
+    }
+}
+
+/// Merge a `size`-byte result into a register the way the CPU would: 32-bit
+/// results zero-extend, 8 and 16-bit results preserve the upper bits.
+fn vc_merge(old: u64, value: u64, size: usize) -> u64 {
+    match size {
+        1 => (old & !0xff) | (value & 0xff),
+        2 => (old & !0xffff) | (value & 0xffff),
+        4 => value & 0xffffffff,
+        _ => value,
+    }
+}
+
+/// Register operand of a byte-sized instruction: without a REX prefix,
+/// encodings 4-7 are AH, CH, DH and BH
+fn vc_get_reg_sized(tf: &DuneTrapFrame, insn: &Insn) -> u64 {
+    if insn.size == 1 && !insn.rex && (4..8).contains(&insn.reg) {
+        return (vc_get_reg(tf, insn.reg - 4) >> 8) & 0xff;
+    }
+    vc_get_reg(tf, insn.reg)
+}
+
+fn vc_set_reg_sized(tf: &mut DuneTrapFrame, insn: &Insn, value: u64, size: usize) {
+    if size == 1 && !insn.rex && (4..8).contains(&insn.reg) {
+        let reg = insn.reg - 4;
+        let old = vc_get_reg(tf, reg);
+        vc_set_reg(tf, reg, (old & !0xff00) | ((value & 0xff) << 8));
+        return;
+    }
+    let old = vc_get_reg(tf, insn.reg);
+    vc_set_reg(tf, insn.reg, vc_merge(old, value, size));
+}
+
+/// Compute the linear address of the memory operand
+fn vc_effective_addr(tf: &DuneTrapFrame, insn: &Insn) -> u64 {
+    let mem = &insn.mem;
+    let mut addr: u64 = mem.disp as u64;
+
+    if mem.rip_relative {
+        addr = addr.wrapping_add(tf.rip() + insn.len as u64);
+    }
+    if let Some(base) = mem.base {
+        addr = addr.wrapping_add(vc_get_reg(tf, base));
+    }
+    if let Some((index, scale)) = mem.index {
+        addr = addr.wrapping_add(vc_get_reg(tf, index).wrapping_mul(scale as u64));
+    }
+
+    match insn.seg {
+        SegOverride::Fs => addr.wrapping_add(FsBase::read().as_u64()),
+        SegOverride::Gs => addr.wrapping_add(GsBase::read().as_u64()),
+        SegOverride::None => addr,
+    }
+}
+
+fn vc_handle_cpuid(tf: &mut DuneTrapFrame) {
+    let (eax, ebx, ecx, edx) = vc_cpuid_vmgexit(tf.rax() as u32, tf.rcx() as u32);
+
+    tf.set_rax(eax as u64);
+    tf.set_rbx(ebx as u64);
+    tf.set_rcx(ecx as u64);
+    tf.set_rdx(edx as u64);
+}
+
+unsafe fn vc_handle_msr(ghcb: *mut Ghcb, tf: &mut DuneTrapFrame, write: bool) {
+    (*ghcb).set_rcx(LOWER_32BITS!(tf.rcx()) as u64);
+    if write {
+        (*ghcb).set_rax(LOWER_32BITS!(tf.rax()) as u64);
+        (*ghcb).set_rdx(LOWER_32BITS!(tf.rdx()) as u64);
+    }
+
+    vc_perform_vmgexit(ghcb, GHCB_NAE_MSR, write as u64, 0);
+
+    if !write {
+        if !(*ghcb).is_rax_valid() || !(*ghcb).is_rdx_valid() {
+            vc_terminate_svsm_resp_invalid();
+        }
+        tf.set_rax(LOWER_32BITS!((*ghcb).rax()) as u64);
+        tf.set_rdx(LOWER_32BITS!((*ghcb).rdx()) as u64);
+    }
+}
+
+unsafe fn vc_handle_rdtsc(ghcb: *mut Ghcb, tf: &mut DuneTrapFrame, rdtscp: bool) {
+    let code = if rdtscp { GHCB_NAE_RDTSCP } else { GHCB_NAE_RDTSC };
+
+    vc_perform_vmgexit(ghcb, code, 0, 0);
+
+    if !(*ghcb).is_rax_valid() || !(*ghcb).is_rdx_valid() {
+        vc_terminate_svsm_resp_invalid();
+    }
+    tf.set_rax(LOWER_32BITS!((*ghcb).rax()) as u64);
+    tf.set_rdx(LOWER_32BITS!((*ghcb).rdx()) as u64);
+
+    if rdtscp {
+        if !(*ghcb).is_rcx_valid() {
+            vc_terminate_svsm_resp_invalid();
+        }
+        tf.set_rcx(LOWER_32BITS!((*ghcb).rcx()) as u64);
+    }
+}
+
+unsafe fn vc_handle_ioio(ghcb: *mut Ghcb, tf: &mut DuneTrapFrame, insn: &Insn) {
+    let (port, is_in) = match insn.kind {
+        InsnKind::In(imm) => (imm.map_or(LOWER_16BITS!(tf.rdx()), |p| p as u16), true),
+        InsnKind::Out(imm) => (imm.map_or(LOWER_16BITS!(tf.rdx()), |p| p as u16), false),
+        _ => vc_terminate_unhandled_vc(),
     };
 
-    if ghcb == MAP_FAILED {
-        return Err(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            "dune: failed to map GHCB",
-        ));
+    let mut ioio: u64 = (port as u64) << 16;
+    ioio |= IOIO_ADDR_64;
+    ioio |= match insn.size {
+        1 => IOIO_SIZE_8,
+        2 => IOIO_SIZE_16,
+        _ => IOIO_SIZE_32,
+    };
+
+    if is_in {
+        ioio |= IOIO_TYPE_IN;
+        (*ghcb).set_rax(0);
+    } else {
+        (*ghcb).set_rax(vc_merge(0, tf.rax(), insn.size));
     }
 
-    log::debug!("dune: GHCB at {:?}", ghcb);
-    unsafe {
-        memset(ghcb, 0, mem::size_of::<Ghcb>());
-        ghcb_set_version(ghcb, GHCB_PROTOCOL_MIN);
-        ghcb_set_usage(ghcb, GHCB_DEFAULT_USAGE);
-        ghcb_set_sw_exit_code(ghcb, GHCB_NAE_RUN_VMPL);
-        ghcb_set_sw_exit_info_1(ghcb, 0);
-        ghcb_set_sw_exit_info_2(ghcb, 0);
+    vc_perform_vmgexit(ghcb, GHCB_NAE_IOIO, ioio, 0);
+
+    if is_in {
+        if !(*ghcb).is_rax_valid() {
+            vc_terminate_svsm_resp_invalid();
+        }
+        tf.set_rax(vc_merge(tf.rax(), (*ghcb).rax(), insn.size));
     }
+}
 
-    Ok(ghcb)
+unsafe fn vc_handle_mmio(ghcb: *mut Ghcb, tf: &mut DuneTrapFrame, insn: &Insn) {
+    let va: u64 = vc_effective_addr(tf, insn);
+    let gpa: u64 = pgtable_va_to_pa(VirtAddr::new(va)).as_u64();
+    let size: usize = insn.size;
+    let mut data = [0u8; 8];
+
+    match insn.kind {
+        InsnKind::MmioWrite | InsnKind::MmioWriteImm => {
+            let value = match insn.kind {
+                InsnKind::MmioWrite => vc_get_reg_sized(tf, insn),
+                _ => insn.imm,
+            };
+            data.copy_from_slice(&value.to_le_bytes());
+            (*ghcb).set_shared_buffer(data.as_ptr(), size);
+
+            vc_perform_vmgexit(ghcb, GHCB_NAE_MMIO_WRITE, gpa, size as u64);
+        }
+        InsnKind::MmioRead | InsnKind::MmioReadZeroExtend => {
+            // also points sw_scratch at the shared buffer
+            (*ghcb).set_shared_buffer(data.as_ptr(), size);
+
+            vc_perform_vmgexit(ghcb, GHCB_NAE_MMIO_READ, gpa, size as u64);
+
+            (*ghcb).shared_buffer(data.as_mut_ptr(), size);
+            let value = u64::from_le_bytes(data);
+            if insn.kind == InsnKind::MmioReadZeroExtend {
+                // MOVZX writes the full destination operand
+                let reg = insn.reg;
+                vc_set_reg(tf, reg, vc_merge(vc_get_reg(tf, reg), value, insn.dst_size));
+            } else {
+                vc_set_reg_sized(tf, insn, value, size);
+            }
+        }
+        _ => vc_terminate_unhandled_vc(),
+    }
 }
 
-#[cfg(feature = "ghcb")]
-fn vc_init(dune_fd: RawFd) -> Result<*mut Ghcb, std::io::Error> {
-    log::info!("setup GHCB");
-    let ghcb_va = setup_ghcb(dune_fd)?;
-    if ghcb_va.is_null() {
-        log::error!("failed to setup GHCB");
-        return Err(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            "failed to setup GHCB",
-        ));
+/// #VC handler
+///
+/// The error code is the exit code of the intercepted instruction. Decode
+/// the instruction at RIP, emulate it through the GHCB, write the results
+/// back into the trap frame and step over it.
+extern "C" fn vc_handler(_vector: u64, tf: &mut DuneTrapFrame) {
+    let exit_code: u64 = tf.err() as u64;
+    let ghcb: *mut Ghcb = vc_get_ghcb();
+
+    let bytes = unsafe { std::slice::from_raw_parts(tf.rip() as *const u8, MAX_INSN_SIZE) };
+    let insn: Insn = match Insn::decode(bytes) {
+        Some(insn) => insn,
+        None => vc_terminate_unhandled_vc(),
+    };
+
+    unsafe {
+        match (exit_code, insn.kind) {
+            (GHCB_NAE_CPUID, InsnKind::Cpuid) => vc_handle_cpuid(tf),
+            (GHCB_NAE_MSR, InsnKind::Rdmsr) => vc_handle_msr(ghcb, tf, false),
+            (GHCB_NAE_MSR, InsnKind::Wrmsr) => vc_handle_msr(ghcb, tf, true),
+            (GHCB_NAE_RDTSC, InsnKind::Rdtsc) => vc_handle_rdtsc(ghcb, tf, false),
+            (GHCB_NAE_RDTSCP, InsnKind::Rdtscp) => vc_handle_rdtsc(ghcb, tf, true),
+            (GHCB_NAE_IOIO, InsnKind::In(_) | InsnKind::Out(_)) => vc_handle_ioio(ghcb, tf, &insn),
+            (GHCB_NAE_NPF, _) => vc_handle_mmio(ghcb, tf, &insn),
+            _ => vc_terminate_unhandled_vc(),
+        }
+
+        (*ghcb).clear();
     }
 
-    log::info!("setup VC");
+    tf.set_rip(tf.rip() + insn.len as u64);
+}
 
-    let ghcb_pa = unsafe { pgtable_va_to_pa(ghcb_va as VirtAddr) };
-    log::debug!("ghcb_pa: {:x}", ghcb_pa);
+pub fn vc_init(fd: VmplFile) -> VirtAddr {
+    let ghcb_pa: PhysAddr = pgtable_va_to_pa(get_early_ghcb());
 
     vc_establish_protocol();
     vc_register_ghcb(ghcb_pa);
-    vc_set_ghcb(ghcb_va);
 
-    Ok(ghcb_va)
-}
\ No newline at end of file
+    get_early_ghcb()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries(op: &PscOp) -> Vec<u64> {
+        let last: usize = op.header.end_entry as usize;
+        (0..=last).map(|i| op.entries[i].data).collect()
+    }
+
+    #[test]
+    fn psc_entries_use_2m_where_aligned() {
+        let mut op: PscOp = PscOp::new();
+        let end: PhysAddr = PhysAddr::new(0x40_1000);
+        let next: PhysAddr = build_psc_entries(&mut op, PhysAddr::new(0x1f_f000), end, PSC_SHARED);
+
+        assert_eq!(next, end);
+        assert_eq!(
+            entries(&op),
+            vec![
+                0x1f_f000 | PSC_SHARED,
+                0x20_0000 | PSC_SHARED | PSC_2M_PAGE,
+                0x40_0000 | PSC_SHARED,
+            ]
+        );
+        assert_eq!({ op.header.cur_entry }, 0);
+    }
+
+    #[test]
+    fn psc_entries_short_of_2m_stay_4k() {
+        let mut op: PscOp = PscOp::new();
+        let end: PhysAddr = PhysAddr::new(0x20_2000);
+        build_psc_entries(&mut op, PhysAddr::new(0x20_0000), end, PSC_PRIVATE);
+
+        assert_eq!(entries(&op), vec![0x20_0000 | PSC_PRIVATE, 0x20_1000 | PSC_PRIVATE]);
+    }
+
+    #[test]
+    fn psc_entries_stop_when_full() {
+        let mut op: PscOp = PscOp::new();
+        op.header.cur_entry = 7;
+        let begin: PhysAddr = PhysAddr::new(0x1000);
+        let end: PhysAddr = begin + (PSC_ENTRIES as u64 + 3) * PAGE_SIZE as u64;
+        let next: PhysAddr = build_psc_entries(&mut op, begin, end, PSC_SHARED);
+
+        // the caller resumes from where the full buffer stopped
+        assert_eq!(next, begin + PSC_ENTRIES as u64 * PAGE_SIZE as u64);
+        assert_eq!({ op.header.cur_entry }, 0);
+        assert_eq!(op.header.end_entry as usize, PSC_ENTRIES - 1);
+        assert_eq!({ op.entries[PSC_ENTRIES - 1].data }, (next - PAGE_SIZE as u64).as_u64() | PSC_SHARED);
+    }
+}
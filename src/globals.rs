@@ -12,16 +12,7 @@ pub const GD_UD: u32 = 0x28;
 pub const GD_UT: u32 = 0x30;
 pub const GD_TSS: u32 = 0x38;
 pub const GD_TSS2: u32 = 0x40;
-pub(crate) const NR_GDT_ENTRIES: u32 = 9;
-
-pub const KERNEL_CODE32: u64 = 0x00cf9b000000ffff; // [G], [D], L, AVL, [P], DPL=0, [1], [1], C, [R], [A]
-pub const KERNEL_CODE64: u64 = 0x00af9b000000ffff; // [G], D, [L], AVL, [P], DPL=0, [1], [1], C, [R], [A]
-pub const KERNEL_DATA: u64 = 0x00cf93000000ffff; // [G], [B], L, AVL, [P], DPL=0, [1], [0], E, [W], [A]
-pub const USER_CODE32: u64 = 0x00cffb000000ffff; // [G], [D], L, AVL, [P], DPL=3, [1], [1], C, [R], [A]
-pub const USER_DATA: u64 = 0x00cff3000000ffff; // [G], [D], L, AVL, [P], DPL=3, [1], [0], E, [W], [A]
-pub const USER_CODE64: u64 = 0x00affb000000ffff; // [G], D, [L], AVL, [P], DPL=3, [1], [1], C, [R], [A]
-pub const TSS: u64 = 0x0080890000000000; // [G], B, L, AVL, [P], DPL=0, [0], [0], [0], [0], [0]
-pub const TSS2: u64 = 0x0000000000000000; // [G], B, L, AVL, [P], DPL=0, [0], [0], [0], [0], [0]
+pub(crate) const NR_GDT_ENTRIES: usize = 9;
 
 pub const VSYSCALL_ADDR: u64 = 0xffffffffff600000;
 
@@ -0,0 +1,245 @@
+/// Copy-on-write
+/// `clone_address_space` shares the frames of an address space with a new
+/// one, read-only and marked `PTE_COW`, with a reference per sharer. The
+/// first write to such a page faults, and `cow_fault` gives the writer its
+/// own copy, or the page itself once nobody else shares it.
+#[cfg(feature = "mm")]
+pub mod cow {
+    use std::ptr;
+    use std::sync::atomic::{AtomicI32, Ordering};
+
+    use log::{debug, info, warn};
+    use x86_64::registers::control::Cr3;
+    use x86_64::structures::paging::PhysFrame;
+    use x86_64::PhysAddr;
+
+    use crate::mm::{
+        get_page, hugepage_alloc, hugepage_free, paging_mode, pgtable_flush, pgtable_pa_to_va,
+        pte_frame, register_pgflt_handler, sev_enc_mask, vmpl_pa2page, vmpl_page_is_from_pool,
+        AccessKind, FaultAction, Page, PageFaultInfo, PageOwner, PageRef, PageSize, PageTable,
+        Prot, VmplFrames, PGSIZE, PTE_ADDR_MASK, PTE_COW, PTE_WRITE,
+    };
+    use crate::sys::core::DuneTrapFrame;
+
+    /// VMPL device, set by `cow_init`
+    static COW_FD: AtomicI32 = AtomicI32::new(-1);
+
+    /// Metadata of `pa` if it is a frame from the VMPL pool. Other frames
+    /// belong to Linux and are shared without reference counting.
+    fn pool_page(pa: PhysAddr) -> Option<*mut Page> {
+        if !vmpl_page_is_from_pool(pa) {
+            return None;
+        }
+        Some(vmpl_pa2page(pa))
+    }
+
+    /// Take a reference for a new sharer of the frame at `pa`. Huge frames
+    /// are counted on their first page, as `hugepage_alloc` does.
+    fn cow_get(pa: PhysAddr, size: PageSize) {
+        match size {
+            // the reference stays with the sharing entry
+            PageSize::Size4K => {
+                let _ = PageRef::from_pa(pa).map(PageRef::into_raw);
+            }
+            _ => {
+                if let Some(pg) = pool_page(pa) {
+                    get_page(unsafe { &*pg });
+                }
+            }
+        }
+    }
+
+    /// Drop a reference to the frame at `pa`, freeing it with the last one
+    fn cow_put(pa: PhysAddr, size: PageSize) {
+        if pool_page(pa).is_none() {
+            return;
+        }
+        match size {
+            PageSize::Size4K => drop(unsafe { PageRef::from_raw(pa) }),
+            _ => hugepage_free(pa, size),
+        }
+    }
+
+    fn cow_refs(pa: PhysAddr) -> Option<u64> {
+        pool_page(pa).map(|pg| unsafe { (*pg).ref_count.load(Ordering::SeqCst) })
+    }
+
+    fn address_space(root: PhysFrame) -> Result<PageTable<VmplFrames>, i32> {
+        let fd = COW_FD.load(Ordering::Relaxed);
+        if fd < 0 {
+            return Err(libc::ENODEV);
+        }
+        Ok(PageTable::new(root.start_address(), VmplFrames::new(fd), paging_mode()))
+    }
+
+    /// Flush the lower half, where writable pages turned read-only
+    fn flush_user() {
+        let end = paging_mode().user_va_end();
+        pgtable_flush(0, end as usize);
+    }
+
+    /// Create an address space sharing the lower half of `root` copy-on-
+    /// write. The upper half is shared outright. `root` must not change
+    /// while it is cloned, e.g. its user context must not be running.
+    pub fn clone_address_space(root: PhysFrame) -> Result<PhysFrame, i32> {
+        let fd = COW_FD.load(Ordering::Relaxed);
+        let mut parent = address_space(root)?;
+        let child = parent.clone_cow(VmplFrames::new(fd), cow_get)?;
+        flush_user();
+
+        info!("cow: cloned {:#x} into {:#x}", root.start_address(), child.root());
+        Ok(PhysFrame::containing_address(child.root()))
+    }
+
+    /// Release an address space from `clone_address_space`. It must not be
+    /// in use on any CPU.
+    pub fn free_address_space(root: PhysFrame) -> Result<(), i32> {
+        let pgtable = address_space(root)?;
+        pgtable.destroy(cow_put);
+        debug!("cow: freed {:#x}", root.start_address());
+        Ok(())
+    }
+
+    /// The COW leaf `pte` of `size` made writable and pointed at `frame`
+    fn cow_pte(pte: u64, size: PageSize, frame: PhysAddr) -> u64 {
+        // the C-bit stays with the flags
+        let frame_mask = PTE_ADDR_MASK & !(size.bytes() - 1) & !sev_enc_mask();
+        frame.as_u64() | (pte & !frame_mask & !PTE_COW) | PTE_WRITE
+    }
+
+    /// Make the COW leaf `pte` mapping `va` writable, copying the frame if
+    /// it is still shared
+    fn cow_break(va: u64, size: PageSize, pte: &mut u64) -> Result<(), i32> {
+        let old = pte_frame(*pte, size);
+        if cow_refs(old) == Some(1) {
+            // the other sharers are gone, take the frame over
+            *pte = cow_pte(*pte, size, old);
+            return Ok(());
+        }
+
+        let fd = COW_FD.load(Ordering::Relaxed);
+        let new = hugepage_alloc(fd, size, PageOwner::Cow).ok_or(libc::ENOMEM)?;
+        unsafe {
+            ptr::copy_nonoverlapping(
+                pgtable_pa_to_va(old).as_ptr::<u8>(),
+                pgtable_pa_to_va(new).as_mut_ptr::<u8>(),
+                size.bytes() as usize,
+            );
+        }
+        *pte = cow_pte(*pte, size, new);
+        cow_put(old, size);
+
+        debug!("cow: copied {:?} page at {:#x} to {:#x}", size, va, new);
+        Ok(())
+    }
+
+    /// Page fault handler for writes to COW pages
+    pub fn cow_fault(info: &PageFaultInfo, _tf: &mut DuneTrapFrame) -> FaultAction {
+        if info.access() != AccessKind::Write || !info.is_present() || info.is_pkey() {
+            return FaultAction::Continue;
+        }
+        // `protect` keeps PTE_COW on pages made read-only, the VMA says
+        // whether the write is allowed at all
+        if info.vma().is_some_and(|vma| !vma.prot().contains(Prot::Write)) {
+            return FaultAction::Continue;
+        }
+        let mut pgtable = match address_space(Cr3::read().0) {
+            Ok(pgtable) => pgtable,
+            Err(_) => return FaultAction::Continue,
+        };
+
+        let page = info.addr() & !(PGSIZE as u64 - 1);
+        let mut res = None;
+        pgtable.walk(page, PGSIZE, |va, size, pte| {
+            if *pte & PTE_COW != 0 {
+                res = Some(cow_break(va, size, pte).map(|()| (va, size)));
+            }
+        });
+
+        match res {
+            None => FaultAction::Continue,
+            Some(Ok((va, size))) => {
+                pgtable_flush(va, size.bytes() as usize);
+                FaultAction::Retry
+            }
+            Some(Err(e)) => {
+                warn!("cow: cannot copy the page at {:#x}: {}", info.addr(), e);
+                FaultAction::Signal(libc::SIGBUS)
+            }
+        }
+    }
+
+    pub fn cow_init(fd: i32) -> Result<(), i32> {
+        info!("cow init");
+        COW_FD.store(fd, Ordering::Relaxed);
+        register_pgflt_handler(cow_fault);
+        Ok(())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::mm::test_frames::Arena;
+        use crate::mm::{
+            PagingMode, PAGE_2MB_SIZE, PERM_BIG, PERM_R, PERM_U, PERM_W, PTE_NX, PTE_PRESENT,
+            PTE_PS, PTE_USER,
+        };
+
+        #[test]
+        fn cow_pte_takes_new_frame() {
+            let pte = 0x5000 | PTE_PRESENT | PTE_USER | PTE_COW | PTE_NX;
+            let pte = cow_pte(pte, PageSize::Size4K, PhysAddr::new(0x9000));
+            assert_eq!(pte, 0x9000 | PTE_PRESENT | PTE_USER | PTE_WRITE | PTE_NX);
+        }
+
+        #[test]
+        fn cow_pte_keeps_huge_flags() {
+            // bit 12 is PAT in a 2M leaf, not part of the frame
+            let pte = 0x40_0000 | 1 << 12 | PTE_PRESENT | PTE_PS | PTE_COW;
+            let pte = cow_pte(pte, PageSize::Size2M, PhysAddr::new(0x80_0000));
+            assert_eq!(pte, 0x80_0000 | 1 << 12 | PTE_PRESENT | PTE_PS | PTE_WRITE);
+        }
+
+        #[test]
+        fn break_in_child_leaves_parent_shared() {
+            let mut parent = PageTable::create(Arena::new(), PagingMode::Level4).unwrap();
+            parent
+                .map(0x1000, PhysAddr::new(0x5000), PGSIZE, PERM_R | PERM_W | PERM_U)
+                .unwrap();
+            parent
+                .map(0x20_0000, PhysAddr::new(0x40_0000), PAGE_2MB_SIZE as usize, PERM_R | PERM_W | PERM_BIG)
+                .unwrap();
+            let mut child = parent.clone_cow(Arena::new(), |_, _| {}).unwrap();
+
+            // what cow_fault does on a write, with the copies at 0x9000
+            // and 0x80_0000
+            let mut broken = Vec::new();
+            child.walk(0x1000, 0x40_0000, |va, size, pte| {
+                assert_ne!(*pte & PTE_COW, 0);
+                let new = match size {
+                    PageSize::Size4K => PhysAddr::new(0x9000),
+                    _ => PhysAddr::new(0x80_0000),
+                };
+                *pte = cow_pte(*pte, size, new);
+                broken.push((va, size));
+            });
+            assert_eq!(broken, vec![(0x1000, PageSize::Size4K), (0x20_0000, PageSize::Size2M)]);
+
+            assert_eq!(child.translate(0x1abc), Some(PhysAddr::new(0x9abc)));
+            assert_eq!(child.translate(0x30_0000), Some(PhysAddr::new(0x90_0000)));
+            child.walk(0x1000, 0x40_0000, |_, _, pte| {
+                assert_eq!(*pte & (PTE_COW | PTE_WRITE), PTE_WRITE);
+            });
+
+            // the parent still shares the old frames until it writes
+            assert_eq!(parent.translate(0x1abc), Some(PhysAddr::new(0x5abc)));
+            assert_eq!(parent.translate(0x30_0000), Some(PhysAddr::new(0x50_0000)));
+            parent.walk(0x1000, 0x40_0000, |_, _, pte| {
+                assert_eq!(*pte & (PTE_COW | PTE_WRITE), PTE_COW);
+            });
+
+            child.destroy(|_, _| {});
+            parent.destroy(|_, _| {});
+        }
+    }
+}
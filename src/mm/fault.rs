@@ -0,0 +1,297 @@
+/// Page fault dispatching
+/// Decodes CR2 and the #PF error code and hands the fault to the registered
+/// handlers, which may fix up the mapping, deliver a signal or give up.
+#[cfg(feature = "mm")]
+pub mod fault {
+    use std::cell::Cell;
+    use std::fmt::{self, Display};
+    use std::sync::RwLock;
+
+    use lazy_static::lazy_static;
+    use log::{debug, warn};
+    use x86_64::structures::idt::PageFaultErrorCode;
+
+    use crate::mm::{current_domain_pkey, pkey_lookup, Pkey, VmplVma, VMPL_VM};
+    use crate::sys::core::DuneTrapFrame;
+
+    /// Kind of access that caused the fault
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum AccessKind {
+        Read,
+        Write,
+        Exec,
+    }
+
+    impl Display for AccessKind {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                AccessKind::Read => write!(f, "read"),
+                AccessKind::Write => write!(f, "write"),
+                AccessKind::Exec => write!(f, "exec"),
+            }
+        }
+    }
+
+    /// Decoded page fault
+    #[derive(Debug, Clone)]
+    pub struct PageFaultInfo<'a> {
+        addr: u64,
+        rip: u64,
+        error_code: PageFaultErrorCode,
+        access: AccessKind,
+        /// Borrowed from `VMPL_VM`, which stays read-locked during dispatch
+        vma: Option<&'a VmplVma>,
+        pkey: Option<Pkey>,
+    }
+
+    impl<'a> PageFaultInfo<'a> {
+        pub fn new(addr: u64, rip: u64, error_code: PageFaultErrorCode) -> Self {
+            let access = if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+                AccessKind::Exec
+            } else if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+                AccessKind::Write
+            } else {
+                AccessKind::Read
+            };
+
+            Self {
+                addr,
+                rip,
+                error_code,
+                access,
+                vma: None,
+                pkey: None,
+            }
+        }
+
+        pub fn addr(&self) -> u64 {
+            self.addr
+        }
+
+        pub fn rip(&self) -> u64 {
+            self.rip
+        }
+
+        pub fn error_code(&self) -> PageFaultErrorCode {
+            self.error_code
+        }
+
+        pub fn access(&self) -> AccessKind {
+            self.access
+        }
+
+        /// The page was present, i.e. this is a protection violation
+        pub fn is_present(&self) -> bool {
+            self.error_code
+                .contains(PageFaultErrorCode::PROTECTION_VIOLATION)
+        }
+
+        /// The access was made from CPL3
+        pub fn is_user(&self) -> bool {
+            self.error_code.contains(PageFaultErrorCode::USER_MODE)
+        }
+
+        /// A reserved bit was set in one of the paging structures
+        pub fn is_reserved(&self) -> bool {
+            self.error_code.contains(PageFaultErrorCode::MALFORMED_TABLE)
+        }
+
+        /// The access was denied by the protection key rights in PKRU
+        pub fn is_pkey(&self) -> bool {
+            self.error_code.contains(PageFaultErrorCode::PROTECTION_KEY)
+        }
+
+        /// Key the faulting address was tagged with, for PK violations
+        pub fn pkey(&self) -> Option<Pkey> {
+            self.pkey
+        }
+
+        /// The VMA covering the faulting address, if any
+        pub fn vma(&self) -> Option<&'a VmplVma> {
+            self.vma
+        }
+    }
+
+    impl Display for PageFaultInfo<'_> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(
+                f,
+                "#PF {} at {:#x} (rip {:#x}, error code {:#x}){}{}{}{}",
+                self.access,
+                self.addr,
+                self.rip,
+                self.error_code.bits(),
+                if self.is_present() { " present" } else { " not-present" },
+                if self.is_user() { " user" } else { "" },
+                if self.is_reserved() { " reserved" } else { "" },
+                if self.is_pkey() { " pkey" } else { "" },
+            )?;
+            if let Some(pkey) = self.pkey {
+                write!(f, " ({})", pkey)?;
+            }
+            match self.vma {
+                Some(vma) => write!(f, " in {}", vma),
+                None => write!(f, " outside any vma"),
+            }
+        }
+    }
+
+    /// What to do once a handler has looked at the fault
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum FaultAction {
+        /// Not handled, try the next handler
+        Continue,
+        /// The mapping has been fixed up, retry the faulting instruction
+        Retry,
+        /// Deliver the given signal to the process
+        Signal(i32),
+        /// The fault is fatal
+        Abort,
+    }
+
+    /// Times in a row the same fault may come back after its signal was
+    /// delivered before it is treated as fatal
+    pub const PGFLT_SIGNAL_RETRIES: u32 = 3;
+
+    thread_local! {
+        /// Address and RIP of the last fault a signal was delivered for,
+        /// and how many times in a row it happened
+        static LAST_SIGNAL: Cell<(u64, u64, u32)> = const { Cell::new((0, 0, 0)) };
+    }
+
+    /// Turn `action` into `Abort` once a signal for the same fault has been
+    /// delivered `PGFLT_SIGNAL_RETRIES` times in a row without fixing it
+    fn limit_signal_retries(info: &PageFaultInfo, action: FaultAction) -> FaultAction {
+        let FaultAction::Signal(_) = action else {
+            LAST_SIGNAL.set((0, 0, 0));
+            return action;
+        };
+
+        let (addr, rip, count) = LAST_SIGNAL.get();
+        let count = if (addr, rip) == (info.addr, info.rip) { count + 1 } else { 1 };
+        LAST_SIGNAL.set((info.addr, info.rip, count));
+        if count > PGFLT_SIGNAL_RETRIES {
+            warn!("{} keeps faulting after {} signals", info, PGFLT_SIGNAL_RETRIES);
+            LAST_SIGNAL.set((0, 0, 0));
+            return FaultAction::Abort;
+        }
+        action
+    }
+
+    /// Page fault handler, called in registration order
+    pub type PageFaultHandler = fn(&PageFaultInfo, &mut DuneTrapFrame) -> FaultAction;
+
+    lazy_static! {
+        static ref PGFLT_HANDLERS: RwLock<Vec<PageFaultHandler>> = RwLock::new(Vec::new());
+    }
+
+    pub fn register_pgflt_handler(handler: PageFaultHandler) {
+        PGFLT_HANDLERS.write().unwrap().push(handler);
+    }
+
+    pub fn unregister_pgflt_handler(handler: PageFaultHandler) {
+        PGFLT_HANDLERS
+            .write()
+            .unwrap()
+            .retain(|h| *h as usize != handler as usize);
+    }
+
+    /// Dispatch a page fault at `addr` to the registered handlers.
+    ///
+    /// Faults nobody claims are reported as SIGSEGV when they come from user
+    /// mode and are fatal otherwise. A signal that does not fix the fault
+    /// is only retried `PGFLT_SIGNAL_RETRIES` times.
+    ///
+    /// This runs in exception context, possibly on top of code holding
+    /// `VMPL_VM` or the handler list, so it neither blocks nor allocates:
+    /// both are only try-locked for reading and held while the handlers
+    /// run. A fault taken while `VMPL_VM` is locked for writing has no
+    /// VMA, and one taken while the handler list is being changed is left
+    /// unclaimed. Handlers in turn must not lock `VMPL_VM` for writing nor
+    /// register or unregister handlers.
+    pub fn handle_page_fault(
+        addr: u64,
+        error_code: PageFaultErrorCode,
+        tf: &mut DuneTrapFrame,
+    ) -> FaultAction {
+        let vm = VMPL_VM.try_read().ok();
+        let mut info = PageFaultInfo::new(addr, tf.rip(), error_code);
+        info.vma = vm.as_deref().and_then(|vm| vm.as_ref()).and_then(|vm| vm.find_vma(addr));
+        if info.is_pkey() {
+            info.pkey = pkey_lookup(addr);
+            warn!(
+                "protection key violation: {} in domain {}",
+                info,
+                current_domain_pkey()
+            );
+        } else {
+            debug!("{}", info);
+        }
+
+        let handlers = PGFLT_HANDLERS.try_read();
+        if handlers.is_err() {
+            warn!("page fault handlers are being changed, {} is unclaimed", info);
+        }
+        let action = handlers
+            .iter()
+            .flat_map(|handlers| handlers.iter())
+            .map(|handler| handler(&info, tf))
+            .find(|action| *action != FaultAction::Continue)
+            .unwrap_or(if info.is_user() {
+                FaultAction::Signal(libc::SIGSEGV)
+            } else {
+                FaultAction::Abort
+            });
+        limit_signal_retries(&info, action)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn signal_retries_are_bounded() {
+            let user = PageFaultErrorCode::USER_MODE;
+            let fault = PageFaultInfo::new(0x1000, 0x400000, user);
+            let other = PageFaultInfo::new(0x2000, 0x400000, user);
+            let segv = FaultAction::Signal(libc::SIGSEGV);
+
+            for _ in 0..PGFLT_SIGNAL_RETRIES {
+                assert_eq!(limit_signal_retries(&fault, segv), segv);
+            }
+            assert_eq!(limit_signal_retries(&fault, segv), FaultAction::Abort);
+
+            // another fault, or one that got fixed, starts over
+            assert_eq!(limit_signal_retries(&fault, segv), segv);
+            assert_eq!(limit_signal_retries(&other, segv), segv);
+            assert_eq!(limit_signal_retries(&other, FaultAction::Retry), FaultAction::Retry);
+            assert_eq!(limit_signal_retries(&other, segv), segv);
+        }
+
+        const CLAIMED_ADDR: u64 = 0xdead_0000;
+
+        fn claim_test_addr(info: &PageFaultInfo, _tf: &mut DuneTrapFrame) -> FaultAction {
+            if info.addr() != CLAIMED_ADDR {
+                return FaultAction::Continue;
+            }
+            assert!(info.vma().is_none());
+            FaultAction::Retry
+        }
+
+        #[test]
+        fn dispatch_with_vm_write_locked() {
+            register_pgflt_handler(claim_test_addr);
+            let mut tf = DuneTrapFrame::default();
+
+            // a fault taken inside a VMPL_VM writer must not wait for it
+            let vm = VMPL_VM.write().unwrap();
+            let action = handle_page_fault(CLAIMED_ADDR, PageFaultErrorCode::empty(), &mut tf);
+            drop(vm);
+            unregister_pgflt_handler(claim_test_addr);
+            assert_eq!(action, FaultAction::Retry);
+
+            let action = handle_page_fault(CLAIMED_ADDR, PageFaultErrorCode::empty(), &mut tf);
+            assert_eq!(action, FaultAction::Abort);
+        }
+    }
+}
@@ -0,0 +1,465 @@
+/// Global allocator
+/// `VmplHeap` serves Rust allocations made in VMPL mode from frames of its
+/// own pool, so `Box` and `Vec` keep working without glibc growing its
+/// heap through system calls. Requests up to `HEAP_MAX_SLAB` bytes come
+/// from per-size-class slabs carved out of 4K frames, larger ones get a
+/// buddy block of their own, and ones past 2M a run of frames straight
+/// from the kernel. Heap frames are mapped in a window of their own at
+/// `HEAP_MMAP_BASE`.
+///
+/// Before VMPL entry, and for requests made while the heap is inside its
+/// pool, the system allocator is used. Frees tell heap blocks from system
+/// ones by whether they lie in the window. With the `heap` feature,
+/// `VmplHeap` is the `#[global_allocator]`.
+#[cfg(feature = "mm")]
+pub mod heap {
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+    use std::fmt::{self, Display};
+    use std::ptr;
+    use std::sync::atomic::{AtomicI32, AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    use lazy_static::lazy_static;
+    use log::info;
+    use x86_64::PhysAddr;
+
+    use crate::mm::{
+        vmpl_pa2page, vmpl_page_mark, PageOwner, PagePool, BUDDY_MAX_ORDER, HEAP_MMAP_BASE,
+        HEAP_MMAP_SIZE, PGSHIFT, PGSIZE,
+    };
+    use crate::sys::percpu::this_cpu;
+
+    /// Smallest and largest slab objects
+    pub const HEAP_MIN_SLAB: usize = 16;
+    pub const HEAP_MAX_SLAB: usize = 2048;
+    /// Power-of-two classes from `HEAP_MIN_SLAB` to `HEAP_MAX_SLAB`
+    pub const HEAP_NR_CLASSES: usize = (HEAP_MAX_SLAB / HEAP_MIN_SLAB).trailing_zeros() as usize + 1;
+    /// Frames requested from the kernel each time the heap pool runs dry (2M)
+    pub const HEAP_GROW_SIZE: usize = 512;
+
+    /// VMPL device, set by `heap_init`
+    static HEAP_FD: AtomicI32 = AtomicI32::new(-1);
+
+    static SLAB_FRAMES: AtomicUsize = AtomicUsize::new(0);
+    static BLOCK_FRAMES: AtomicUsize = AtomicUsize::new(0);
+    static FALLBACKS: AtomicUsize = AtomicUsize::new(0);
+
+    lazy_static! {
+        /// Frames for the heap, kept apart from the VMPL pool, whose lock
+        /// may be held by whoever is allocating
+        static ref HEAP_POOL: PagePool = PagePool::with_base("heap", HEAP_GROW_SIZE, HEAP_MMAP_BASE);
+    }
+
+    thread_local! {
+        /// Set while this thread is inside `HEAP_POOL`. The pool allocates
+        /// under its lock, so those requests go to the system allocator.
+        static IN_POOL: Cell<bool> = const { Cell::new(false) };
+    }
+
+    /// A free slab object, linked through its first word
+    struct FreeObject {
+        next: *mut FreeObject,
+    }
+
+    struct FreeList {
+        head: *mut FreeObject,
+        nr_free: usize,
+    }
+
+    // only touched under its class lock
+    unsafe impl Send for FreeList {}
+
+    impl FreeList {
+        const EMPTY: FreeList = FreeList {
+            head: ptr::null_mut(),
+            nr_free: 0,
+        };
+
+        fn push(&mut self, obj: *mut u8) {
+            let obj = obj as *mut FreeObject;
+            unsafe { (*obj).next = self.head };
+            self.head = obj;
+            self.nr_free += 1;
+        }
+
+        fn pop(&mut self) -> Option<*mut u8> {
+            if self.head.is_null() {
+                return None;
+            }
+            let obj = self.head;
+            self.head = unsafe { (*obj).next };
+            self.nr_free -= 1;
+            Some(obj as *mut u8)
+        }
+    }
+
+    /// Free objects by size class. Slab frames are never given back.
+    static CLASSES: [Mutex<FreeList>; HEAP_NR_CLASSES] =
+        [const { Mutex::new(FreeList::EMPTY) }; HEAP_NR_CLASSES];
+
+    /// Slab class of `layout`, `None` if it needs a block of its own
+    pub fn size_class(layout: Layout) -> Option<usize> {
+        let size = layout.size().max(layout.align()).max(HEAP_MIN_SLAB);
+        if size > HEAP_MAX_SLAB {
+            return None;
+        }
+        Some((size.next_power_of_two() / HEAP_MIN_SLAB).trailing_zeros() as usize)
+    }
+
+    pub fn class_size(class: usize) -> usize {
+        HEAP_MIN_SLAB << class
+    }
+
+    /// Buddy order of a block for `layout`. Past `BUDDY_MAX_ORDER` the
+    /// block is a run of `block_pages` frames instead.
+    pub fn block_order(layout: Layout) -> usize {
+        let npages = layout.size().max(layout.align()).div_ceil(PGSIZE);
+        npages.next_power_of_two().trailing_zeros() as usize
+    }
+
+    /// Frames in the block for `layout`
+    pub fn block_pages(layout: Layout) -> usize {
+        match block_order(layout) {
+            order if order <= BUDDY_MAX_ORDER => 1 << order,
+            _ => layout.size().div_ceil(PGSIZE),
+        }
+    }
+
+    /// Bytes the heap sets aside for `layout`
+    fn capacity(layout: Layout) -> usize {
+        match size_class(layout) {
+            Some(class) => class_size(class),
+            None => block_pages(layout) << PGSHIFT,
+        }
+    }
+
+    /// Run `f` with this thread's requests sent to the system allocator
+    fn in_pool<R>(f: impl FnOnce() -> R) -> R {
+        let old = IN_POOL.replace(true);
+        let ret = f();
+        IN_POOL.set(old);
+        ret
+    }
+
+    /// The VMPL device if this request should come from the heap
+    fn heap_fd() -> Option<i32> {
+        let fd = HEAP_FD.load(Ordering::Relaxed);
+        if fd < 0 || IN_POOL.get() || this_cpu().is_none() {
+            return None;
+        }
+        Some(fd)
+    }
+
+    /// Address of the heap frame `pa`
+    fn heap_va(pa: PhysAddr) -> *mut u8 {
+        (HEAP_MMAP_BASE + pa.as_u64()) as *mut u8
+    }
+
+    /// Frame of `ptr`, if it lies in the heap window
+    fn heap_frame(ptr: *mut u8) -> Option<PhysAddr> {
+        let offset = (ptr as u64).checked_sub(HEAP_MMAP_BASE)?;
+        (offset < HEAP_MMAP_SIZE).then(|| PhysAddr::new(offset & !(PGSIZE as u64 - 1)))
+    }
+
+    /// Mark `npages` frames from `pa` as handed out to the heap. Heap
+    /// frames are not reference counted, and not tracked by
+    /// `page_track_alloc`, which allocates under its own lock.
+    fn mark_frames(pa: PhysAddr, npages: usize) {
+        for i in 0..npages {
+            let pg = unsafe { &*vmpl_pa2page(pa + ((i as u64) << PGSHIFT)) };
+            vmpl_page_mark(pg);
+            pg.set_owner(PageOwner::Heap);
+        }
+    }
+
+    fn clear_frames(pa: PhysAddr, npages: usize) {
+        for i in 0..npages {
+            let pg = unsafe { &*vmpl_pa2page(pa + ((i as u64) << PGSHIFT)) };
+            pg.vmpl.store(0, Ordering::SeqCst);
+            pg.set_owner(PageOwner::None);
+        }
+    }
+
+    fn slab_alloc(fd: i32, class: usize) -> *mut u8 {
+        if let Some(obj) = CLASSES[class].lock().unwrap().pop() {
+            return obj;
+        }
+
+        // no class lock held here, the pool may free objects of its own
+        let pa = match in_pool(|| HEAP_POOL.alloc(fd, 0)) {
+            Some(pa) => pa,
+            None => return ptr::null_mut(),
+        };
+        mark_frames(pa, 1);
+        SLAB_FRAMES.fetch_add(1, Ordering::Relaxed);
+
+        let base = heap_va(pa);
+        carve(&mut CLASSES[class].lock().unwrap(), base, class_size(class));
+        base
+    }
+
+    /// Cut the frame at `base` into objects of `size`. The first is kept
+    /// for the caller, the rest go on `list` to be handed out in address
+    /// order.
+    fn carve(list: &mut FreeList, base: *mut u8, size: usize) {
+        for offset in (size..PGSIZE).step_by(size).rev() {
+            list.push(unsafe { base.add(offset) });
+        }
+    }
+
+    fn slab_free(class: usize, ptr: *mut u8) {
+        CLASSES[class].lock().unwrap().push(ptr);
+    }
+
+    fn block_alloc(fd: i32, layout: Layout) -> *mut u8 {
+        let order = block_order(layout);
+        let npages = block_pages(layout);
+        let pa = if order <= BUDDY_MAX_ORDER {
+            // buddy blocks are aligned to their size
+            in_pool(|| HEAP_POOL.alloc(fd, order))
+        } else if layout.align() <= PGSIZE {
+            in_pool(|| HEAP_POOL.fetch(fd, npages).ok())
+        } else {
+            None
+        };
+        let pa = match pa {
+            Some(pa) => pa,
+            None => return ptr::null_mut(),
+        };
+
+        mark_frames(pa, npages);
+        BLOCK_FRAMES.fetch_add(npages, Ordering::Relaxed);
+        heap_va(pa)
+    }
+
+    /// Runs of frames past 2M go back to the pool as ordinary blocks
+    fn block_free(pa: PhysAddr, layout: Layout) {
+        let order = block_order(layout);
+        let npages = block_pages(layout);
+        clear_frames(pa, npages);
+        BLOCK_FRAMES.fetch_sub(npages, Ordering::Relaxed);
+
+        in_pool(|| {
+            if order <= BUDDY_MAX_ORDER {
+                HEAP_POOL.free(pa, order);
+            } else {
+                HEAP_POOL.add(pa, npages);
+            }
+        });
+    }
+
+    /// Allocator backed by VMPL frames in VMPL mode, and by the system
+    /// allocator otherwise
+    #[derive(Debug, Default, Clone, Copy)]
+    pub struct VmplHeap;
+
+    unsafe impl GlobalAlloc for VmplHeap {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let fd = match heap_fd() {
+                Some(fd) => fd,
+                None => {
+                    if IN_POOL.get() {
+                        FALLBACKS.fetch_add(1, Ordering::Relaxed);
+                    }
+                    return System.alloc(layout);
+                }
+            };
+
+            let ptr = match size_class(layout) {
+                Some(class) => slab_alloc(fd, class),
+                None => block_alloc(fd, layout),
+            };
+            if ptr.is_null() {
+                FALLBACKS.fetch_add(1, Ordering::Relaxed);
+                return System.alloc(layout);
+            }
+            ptr
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            let pa = match heap_frame(ptr) {
+                Some(pa) => pa,
+                None => return System.dealloc(ptr, layout),
+            };
+            match size_class(layout) {
+                Some(class) => slab_free(class, ptr),
+                None => block_free(pa, layout),
+            }
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+            let ours = heap_frame(ptr).is_some();
+            if ours && capacity(layout) == capacity(new_layout) {
+                return ptr;
+            }
+            if !ours && heap_fd().is_none() {
+                return System.realloc(ptr, layout, new_size);
+            }
+
+            let new = self.alloc(new_layout);
+            if !new.is_null() {
+                ptr::copy_nonoverlapping(ptr, new, layout.size().min(new_size));
+                self.dealloc(ptr, layout);
+            }
+            new
+        }
+    }
+
+    /// Installed by the `heap` feature
+    #[cfg(feature = "heap")]
+    #[global_allocator]
+    static GLOBAL: VmplHeap = VmplHeap;
+
+    /// Heap counters
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+    pub struct HeapStats {
+        /// Frames carved into slab objects
+        pub slab_frames: usize,
+        /// Bytes of free slab objects
+        pub slab_free: usize,
+        /// Frames in blocks handed out
+        pub block_frames: usize,
+        /// Requests in VMPL mode that went to the system allocator
+        pub fallbacks: usize,
+    }
+
+    impl Display for HeapStats {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(
+                f,
+                "{} slab frames, {} bytes free; {} block frames; {} fallbacks",
+                self.slab_frames, self.slab_free, self.block_frames, self.fallbacks
+            )
+        }
+    }
+
+    pub fn heap_stats() -> HeapStats {
+        let slab_free = CLASSES
+            .iter()
+            .enumerate()
+            .map(|(class, list)| list.lock().unwrap().nr_free * class_size(class))
+            .sum();
+        HeapStats {
+            slab_frames: SLAB_FRAMES.load(Ordering::Relaxed),
+            slab_free,
+            block_frames: BLOCK_FRAMES.load(Ordering::Relaxed),
+            fallbacks: FALLBACKS.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Serve allocations from VMPL frames once in VMPL mode. The pool
+    /// grows on first use.
+    pub fn heap_init(fd: i32) -> Result<(), i32> {
+        info!("heap init");
+        HEAP_FD.store(fd, Ordering::Relaxed);
+        Ok(())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn layout(size: usize, align: usize) -> Layout {
+            Layout::from_size_align(size, align).unwrap()
+        }
+
+        #[test]
+        fn size_classes() {
+            assert_eq!(HEAP_NR_CLASSES, 8);
+            assert_eq!(size_class(layout(1, 1)), Some(0));
+            assert_eq!(size_class(layout(16, 8)), Some(0));
+            assert_eq!(size_class(layout(17, 8)), Some(1));
+            assert_eq!(size_class(layout(8, 256)), Some(4));
+            assert_eq!(size_class(layout(HEAP_MAX_SLAB, 8)), Some(HEAP_NR_CLASSES - 1));
+            assert_eq!(size_class(layout(HEAP_MAX_SLAB + 1, 8)), None);
+            assert_eq!(size_class(layout(8, PGSIZE)), None);
+
+            for size in 1..=HEAP_MAX_SLAB {
+                let class = size_class(layout(size, 1)).unwrap();
+                assert!(class_size(class) >= size);
+                assert!(class == 0 || class_size(class - 1) < size);
+            }
+        }
+
+        #[test]
+        fn block_sizes() {
+            assert_eq!(block_order(layout(HEAP_MAX_SLAB + 1, 8)), 0);
+            assert_eq!(block_order(layout(PGSIZE + 1, 8)), 1);
+            assert_eq!(block_order(layout(3 * PGSIZE, 8)), 2);
+            assert_eq!(block_order(layout(8, 1 << 21)), BUDDY_MAX_ORDER);
+            assert_eq!(block_pages(layout(3 * PGSIZE, 8)), 4);
+            assert_eq!(block_pages(layout((2 << 20) + 1, 8)), 513);
+        }
+
+        #[test]
+        fn system_before_entry() {
+            // `heap_init` was not called, so everything goes to the system
+            // allocator
+            let heap = VmplHeap;
+            let small = layout(100, 8);
+            unsafe {
+                let p = heap.alloc(small);
+                assert!(!p.is_null());
+                p.write_bytes(0xaa, small.size());
+
+                let p = heap.realloc(p, small, 3 * PGSIZE);
+                assert!(!p.is_null());
+                assert_eq!(*p.add(small.size() - 1), 0xaa);
+                heap.dealloc(p, layout(3 * PGSIZE, 8));
+            }
+            assert_eq!(heap_stats().fallbacks, 0);
+        }
+
+        #[test]
+        fn free_list_is_lifo() {
+            let mut objs = [0u64; 4];
+            let objs: Vec<*mut u8> = (0..4).map(|i| unsafe { objs.as_mut_ptr().add(i) } as *mut u8).collect();
+
+            let mut list = FreeList::EMPTY;
+            assert_eq!(list.pop(), None);
+            for obj in objs.iter() {
+                list.push(*obj);
+            }
+            assert_eq!(list.nr_free, 4);
+            for obj in objs.iter().rev() {
+                assert_eq!(list.pop(), Some(*obj));
+            }
+            assert_eq!(list.nr_free, 0);
+            assert_eq!(list.pop(), None);
+        }
+
+        #[test]
+        fn slab_carve_order() {
+            let frame = layout(PGSIZE, PGSIZE);
+            let base = unsafe { System.alloc(frame) };
+            assert!(!base.is_null());
+
+            let size = class_size(2);
+            let mut list = FreeList::EMPTY;
+            carve(&mut list, base, size);
+            assert_eq!(list.nr_free, PGSIZE / size - 1);
+            // the first object stays with the caller
+            for offset in (size..PGSIZE).step_by(size) {
+                assert_eq!(list.pop(), Some(base.wrapping_add(offset)));
+            }
+            assert_eq!(list.pop(), None);
+
+            unsafe { System.dealloc(base, frame) };
+        }
+
+        #[test]
+        fn heap_window_bounds() {
+            let pa = PhysAddr::new(0x5000);
+            assert_eq!(heap_frame(heap_va(pa)), Some(pa));
+            assert_eq!(heap_frame(heap_va(pa).wrapping_add(0x123)), Some(pa));
+            assert_eq!(heap_frame((HEAP_MMAP_BASE - 1) as *mut u8), None);
+            assert_eq!(heap_frame((HEAP_MMAP_BASE + HEAP_MMAP_SIZE) as *mut u8), None);
+
+            // system allocations are never in the window
+            let mut boxed = Box::new(0u64);
+            assert_eq!(heap_frame(&mut *boxed as *mut u64 as *mut u8), None);
+        }
+    }
+}
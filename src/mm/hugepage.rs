@@ -0,0 +1,250 @@
+/// Huge pages
+/// Mappings asking for `PERM_BIG` or `PERM_BIG_1GB` get 2M or 1G frames
+/// mapped with the PS bit. 2M frames come from the buddy allocator, 1G
+/// frames from a reserve set aside by `hugepage_reserve_1gb`. When none is
+/// available the mapping falls back to the next smaller size, and the
+/// fallback is counted.
+#[cfg(feature = "mm")]
+pub mod hugepage {
+    use std::fmt::{self, Display};
+    use std::ptr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    use lazy_static::lazy_static;
+    use log::{debug, info};
+    use x86_64::PhysAddr;
+
+    use crate::mm::{
+        get_page, page_block_clear, page_block_mark, page_block_set_owner, pgtable_map,
+        pgtable_pa_to_va, pgtable_unmap, pgtable_walk, pte_frame, put_page, vmpl_pa2page,
+        vmpl_page2pa, vmpl_page_alloc_order, vmpl_page_free_order, vmpl_page_is_from_pool,
+        PageOwner, PageRef, PageSize, BUDDY_ORDER_2MB, PAGE_1GB_SIZE, PERM_BIG, PERM_BIG_1GB,
+        PGSHIFT, PGSIZE, VMPL_POOL,
+    };
+
+    const PAGES_PER_1GB: usize = (PAGE_1GB_SIZE >> PGSHIFT) as usize;
+    const ORDER_1GB: usize = PAGES_PER_1GB.trailing_zeros() as usize;
+
+    lazy_static! {
+        /// Free 1G frames
+        static ref HUGE_1GB_FREE: Mutex<Vec<PhysAddr>> = Mutex::new(Vec::new());
+    }
+
+    static MAPPED_2MB: AtomicUsize = AtomicUsize::new(0);
+    static MAPPED_1GB: AtomicUsize = AtomicUsize::new(0);
+    static FALLBACK_2MB: AtomicUsize = AtomicUsize::new(0);
+    static FALLBACK_1GB: AtomicUsize = AtomicUsize::new(0);
+
+    /// Huge-page counters
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+    pub struct HugePageStats {
+        /// Huge pages mapped so far
+        pub mapped_2mb: usize,
+        pub mapped_1gb: usize,
+        /// Huge pages requested but mapped with smaller pages
+        pub fallback_2mb: usize,
+        pub fallback_1gb: usize,
+        /// 1G frames left in the reserve
+        pub free_1gb: usize,
+    }
+
+    impl Display for HugePageStats {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(
+                f,
+                "2M: {} mapped, {} fallbacks; 1G: {} mapped, {} fallbacks, {} free",
+                self.mapped_2mb, self.fallback_2mb, self.mapped_1gb, self.fallback_1gb, self.free_1gb
+            )
+        }
+    }
+
+    pub fn hugepage_stats() -> HugePageStats {
+        HugePageStats {
+            mapped_2mb: MAPPED_2MB.load(Ordering::Relaxed),
+            mapped_1gb: MAPPED_1GB.load(Ordering::Relaxed),
+            fallback_2mb: FALLBACK_2MB.load(Ordering::Relaxed),
+            fallback_1gb: FALLBACK_1GB.load(Ordering::Relaxed),
+            free_1gb: HUGE_1GB_FREE.lock().unwrap().len(),
+        }
+    }
+
+    /// Reserve `count` 1G frames. The kernel gives no alignment guarantee,
+    /// so one extra gigabyte is requested and the unaligned ends go to the
+    /// 4K/2M pool.
+    pub fn hugepage_reserve_1gb(fd: i32, count: usize) -> Result<(), i32> {
+        let npages = (count + 1) * PAGES_PER_1GB;
+        let pa = VMPL_POOL.fetch(fd, npages)?;
+        let end = pa + ((npages as u64) << PGSHIFT);
+        let start = pa.align_up(PAGE_1GB_SIZE);
+        let reserved = start + count as u64 * PAGE_1GB_SIZE;
+
+        if start > pa {
+            VMPL_POOL.add(pa, ((start - pa) >> PGSHIFT) as usize);
+        }
+        if end > reserved {
+            VMPL_POOL.add(reserved, ((end - reserved) >> PGSHIFT) as usize);
+        }
+
+        let mut free = HUGE_1GB_FREE.lock().unwrap();
+        for i in 0..count as u64 {
+            free.push(start + i * PAGE_1GB_SIZE);
+        }
+        info!("hugepage: reserved {} 1G frames at {:#x}", count, start);
+        Ok(())
+    }
+
+    /// Allocate a frame of `size` for `owner`, with a reference held on its
+    /// first page
+    pub fn hugepage_alloc(fd: i32, size: PageSize, owner: PageOwner) -> Option<PhysAddr> {
+        let pg = match size {
+            // single frames are counted by `PageRef`
+            PageSize::Size4K => return PageRef::alloc(fd, owner).map(PageRef::into_raw),
+            PageSize::Size2M => vmpl_page_alloc_order(fd, BUDDY_ORDER_2MB),
+            PageSize::Size1G => {
+                let pa = HUGE_1GB_FREE.lock().unwrap().pop()?;
+                page_block_mark(pa, ORDER_1GB, owner);
+                vmpl_pa2page(pa)
+            }
+        };
+        let pg = unsafe { pg.as_ref() }?;
+        let pa = vmpl_page2pa(pg);
+        if size == PageSize::Size2M {
+            page_block_set_owner(pa, BUDDY_ORDER_2MB, owner);
+        }
+
+        get_page(pg);
+        debug!("hugepage: allocated {:?} frame {:#x}", size, pa);
+        Some(pa)
+    }
+
+    /// Drop a reference to a frame from `hugepage_alloc`, freeing it with
+    /// the last one
+    pub fn hugepage_free(pa: PhysAddr, size: PageSize) {
+        let pg = unsafe { &*vmpl_pa2page(pa) };
+        match size {
+            PageSize::Size4K => drop(unsafe { PageRef::from_raw(pa) }),
+            _ if !put_page(pg) => {}
+            PageSize::Size2M => vmpl_page_free_order(pg, BUDDY_ORDER_2MB),
+            PageSize::Size1G => {
+                page_block_clear(pa, ORDER_1GB);
+                HUGE_1GB_FREE.lock().unwrap().push(pa);
+            }
+        }
+    }
+
+    fn smaller(size: PageSize) -> PageSize {
+        match size {
+            PageSize::Size1G => PageSize::Size2M,
+            _ => PageSize::Size4K,
+        }
+    }
+
+    fn perm_for(perm: u32, size: PageSize) -> u32 {
+        let perm = perm & !(PERM_BIG | PERM_BIG_1GB);
+        match size {
+            PageSize::Size4K => perm,
+            PageSize::Size2M => perm | PERM_BIG,
+            PageSize::Size1G => perm | PERM_BIG_1GB,
+        }
+    }
+
+    fn count_fallback(size: PageSize) {
+        match size {
+            PageSize::Size1G => FALLBACK_1GB.fetch_add(1, Ordering::Relaxed),
+            PageSize::Size2M => FALLBACK_2MB.fetch_add(1, Ordering::Relaxed),
+            PageSize::Size4K => 0,
+        };
+    }
+
+    /// Back the `size` page at `va` with a fresh frame, or with smaller
+    /// pages if no frame of that size is left
+    fn hugepage_map_one(fd: i32, va: u64, perm: u32, size: PageSize) -> Result<(), i32> {
+        let pa = match hugepage_alloc(fd, size, PageOwner::Hugepage) {
+            Some(pa) => pa,
+            None if size == PageSize::Size4K => return Err(libc::ENOMEM),
+            None => {
+                count_fallback(size);
+                let smaller = smaller(size);
+                for i in 0..size.bytes() / smaller.bytes() {
+                    hugepage_map_one(fd, va + i * smaller.bytes(), perm, smaller)?;
+                }
+                return Ok(());
+            }
+        };
+
+        // anonymous memory starts zeroed
+        unsafe {
+            ptr::write_bytes(pgtable_pa_to_va(pa).as_mut_ptr::<u8>(), 0, size.bytes() as usize);
+        }
+        if let Err(e) = pgtable_map(va, pa, size.bytes() as usize, perm_for(perm, size)) {
+            hugepage_free(pa, size);
+            return Err(e);
+        }
+        match size {
+            PageSize::Size2M => MAPPED_2MB.fetch_add(1, Ordering::Relaxed),
+            PageSize::Size1G => MAPPED_1GB.fetch_add(1, Ordering::Relaxed),
+            PageSize::Size4K => 0,
+        };
+        Ok(())
+    }
+
+    /// Map `[va, va + len)` to fresh frames with `perm`, using the page size
+    /// `PERM_BIG`/`PERM_BIG_1GB` ask for where the range is aligned to it.
+    /// The unaligned head and tail use smaller pages.
+    pub fn hugepage_map(fd: i32, va: u64, len: usize, perm: u32) -> Result<(), i32> {
+        let start = va;
+        let end = start + len as u64;
+        if start % PGSIZE as u64 != 0 || len % PGSIZE != 0 {
+            return Err(libc::EINVAL);
+        }
+
+        let mut addr = start;
+        while addr < end {
+            // largest allowed page that is aligned here and fits
+            let mut size = PageSize::from_perm(perm);
+            while size != PageSize::Size4K && (addr % size.bytes() != 0 || end - addr < size.bytes()) {
+                size = smaller(size);
+            }
+
+            if let Err(e) = hugepage_map_one(fd, addr, perm, size) {
+                // includes what a fallback mapped of the failed page
+                let _ = hugepage_unmap(va, (addr + size.bytes() - start) as usize);
+                return Err(e);
+            }
+            addr += size.bytes();
+        }
+        Ok(())
+    }
+
+    /// Pool frames mapped in `[va, va + len)`. Frames from Linux have no
+    /// reference for the mapping.
+    fn pool_frames(va: u64, len: usize) -> Result<Vec<(PhysAddr, PageSize)>, i32> {
+        let mut frames = Vec::new();
+        pgtable_walk(va, len, |_, size, pte| {
+            let pa = pte_frame(*pte, size);
+            if vmpl_page_is_from_pool(pa) {
+                frames.push((pa, size));
+            }
+        })?;
+        Ok(frames)
+    }
+
+    /// True if `[va, va + len)` maps frames from the pool, which only this
+    /// page table knows about
+    pub fn hugepage_mapped(va: u64, len: usize) -> bool {
+        pool_frames(va, len).is_ok_and(|frames| !frames.is_empty())
+    }
+
+    /// Unmap `[va, va + len)` and drop the references its pool frames
+    /// hold. Frames from Linux are only unmapped.
+    pub fn hugepage_unmap(va: u64, len: usize) -> Result<(), i32> {
+        let frames = pool_frames(va, len)?;
+
+        pgtable_unmap(va, len)?;
+        for (pa, size) in frames {
+            hugepage_free(pa, size);
+        }
+        Ok(())
+    }
+}
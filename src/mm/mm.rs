@@ -5,9 +5,10 @@ pub mod mm {
     use libc::{getrlimit, rlimit, setrlimit, RLIMIT_DATA, RLIMIT_STACK};
     use log::{error, info};
 
-    use crate::mm::page::common::page_init;
-    use crate::mm::pgtable::pgtable_init;
-    use crate::mm::vm::vm_init;
+    use crate::mm::{
+        cow_init, heap_init, mmap_init, page_init, page_leak_check, page_stats, pgtable_init,
+        vm_init,
+    };
 
     pub fn setup_stack(stack_size: usize) -> Result<(), i32> {
         info!("setup stack");
@@ -51,13 +52,26 @@ pub mod mm {
         Ok(())
     }
 
-    pub fn mm_init(fd: i32) -> Result<(), i32> {
+    pub fn mm_init(fd: i32, map_full: bool) -> Result<(), i32> {
         info!("mm init");
 
         page_init(fd)?;
-        mm_init(fd)?;
         pgtable_init(fd)?;
+        vm_init(fd, map_full)?;
+        mmap_init(fd)?;
+        cow_init(fd)?;
+        heap_init(fd)?;
 
         Ok(())
     }
+
+    /// Report the frames still referenced. They go back to the kernel
+    /// when the VMPL fd is closed.
+    pub fn mm_exit() {
+        info!("mm exit");
+
+        if page_leak_check() > 0 {
+            page_stats();
+        }
+    }
 }
@@ -0,0 +1,406 @@
+/// Memory-management system calls
+/// `mmap`, `munmap`, `mprotect`, `mremap` and `brk` made in VMPL mode are
+/// forwarded to Linux, and on success `VMPL_VM`, the VMPL page table and
+/// the VMPL permissions are brought in line with the kernel's view. The
+/// `VMPL_VM` write lock is held from the forwarded call to the last update,
+/// so other threads never see the two views disagree.
+#[cfg(feature = "mm")]
+pub mod mmap {
+    use std::io;
+    use std::sync::atomic::{AtomicI32, Ordering};
+
+    use libc::{
+        getrlimit, rlimit, MAP_ANONYMOUS, MAP_FAILED, MAP_HUGETLB, MAP_HUGE_1GB, MAP_HUGE_MASK,
+        MAP_HUGE_SHIFT, MAP_PRIVATE, MAP_SHARED, MREMAP_DONTUNMAP, RLIMIT_DATA, RLIM_INFINITY,
+        SYS_brk, SYS_mmap, SYS_mprotect, SYS_mremap, SYS_munmap,
+    };
+    use log::{debug, info, warn};
+
+    use crate::mm::{
+        hugepage_map, hugepage_mapped, hugepage_unmap, pgtable_protect_present, vm_set_vmpl, Prot,
+        VmplVm, VmplVma, VmplVmaType, PERM_BIG, PERM_BIG_1GB, PGSIZE, VMPL_VM,
+    };
+
+    /// VMPL device, set by `mmap_init`
+    static MMAP_FD: AtomicI32 = AtomicI32::new(-1);
+
+    fn page_align(len: u64) -> Option<u64> {
+        Some(len.checked_add(PGSIZE as u64 - 1)? & !(PGSIZE as u64 - 1))
+    }
+
+    fn last_errno() -> i32 {
+        io::Error::last_os_error()
+            .raw_os_error()
+            .unwrap_or(libc::EIO)
+    }
+
+    /// Forward a system call to Linux
+    fn host_syscall(nr: i64, args: [u64; 6]) -> Result<u64, i32> {
+        let ret = unsafe { libc::syscall(nr, args[0], args[1], args[2], args[3], args[4], args[5]) };
+        if ret == -1 {
+            return Err(last_errno());
+        }
+        Ok(ret as u64)
+    }
+
+    /// Fail with ENOMEM if `grow` more bytes of data would pass
+    /// `RLIMIT_DATA`. The limit is read on every call, so growth from
+    /// `setup_heap` takes effect at once.
+    fn check_data_limit(vm: &VmplVm, grow: u64) -> Result<(), i32> {
+        let mut rl: rlimit = unsafe { std::mem::zeroed() };
+        if unsafe { getrlimit(RLIMIT_DATA, &mut rl) } != 0 || rl.rlim_cur == RLIM_INFINITY {
+            return Ok(());
+        }
+        if vm.data_size() + grow > rl.rlim_cur {
+            warn!(
+                "mmap: {:#x} more bytes of data would exceed RLIMIT_DATA {:#x}",
+                grow, rl.rlim_cur
+            );
+            return Err(libc::ENOMEM);
+        }
+        Ok(())
+    }
+
+    /// Drop the VMPL translations of `[start, end)`. Linux frames went
+    /// with the Linux mapping, pool frames lose the reference the mapping
+    /// held and are freed with the last one.
+    fn drop_translations(start: u64, end: u64) {
+        match hugepage_unmap(start, (end - start) as usize) {
+            Ok(()) | Err(libc::ENODEV) => {}
+            Err(e) => warn!("mmap: failed to unmap {:#x}-{:#x}: {}", start, end, e),
+        }
+    }
+
+    /// Give `[start, end)` the VMPL permissions for `prot`
+    fn set_vmpl(start: u64, end: u64, prot: Prot) -> Result<(), i32> {
+        let fd = MMAP_FD.load(Ordering::Relaxed);
+        if fd < 0 {
+            return Ok(());
+        }
+        vm_set_vmpl(fd, start, end, prot)
+    }
+
+    /// The huge-page permission `MAP_HUGETLB` asks for, 2M unless the
+    /// flags pick 1G
+    fn huge_perm(flags: i32) -> Option<u32> {
+        if flags & MAP_HUGETLB == 0 {
+            return None;
+        }
+        match flags & (MAP_HUGE_MASK << MAP_HUGE_SHIFT) {
+            MAP_HUGE_1GB => Some(PERM_BIG_1GB),
+            _ => Some(PERM_BIG),
+        }
+    }
+
+    /// Back `[start, end)` with huge frames from the pool. Linux only
+    /// reserved the range.
+    fn map_huge(start: u64, end: u64, prot: Prot, big: u32) -> Result<(), i32> {
+        let fd = MMAP_FD.load(Ordering::Relaxed);
+        if fd < 0 {
+            return Ok(());
+        }
+        hugepage_map(fd, start, (end - start) as usize, prot.perm() | big)
+    }
+
+    /// Path of the file behind `fd`, as `/proc/<pid>/maps` would show it
+    fn fd_path(fd: i32) -> Option<String> {
+        std::fs::read_link(format!("/proc/self/fd/{}", fd))
+            .ok()
+            .map(|path| path.to_string_lossy().into_owned())
+    }
+
+    fn with_vm<R>(f: impl FnOnce(&mut VmplVm) -> Result<R, i32>) -> Result<R, i32> {
+        let mut vm = VMPL_VM.write().unwrap();
+        f(vm.as_mut().ok_or(libc::ENODEV)?)
+    }
+
+    pub fn vmpl_mmap(
+        addr: u64,
+        len: u64,
+        prot: i32,
+        flags: i32,
+        fd: i32,
+        offset: u64,
+    ) -> Result<u64, i32> {
+        let len = match page_align(len) {
+            Some(len) if len > 0 => len,
+            _ => return Err(libc::EINVAL),
+        };
+        let prot_bits = prot as u64;
+        let prot = Prot::from(prot);
+        let share = (flags & (MAP_SHARED | MAP_PRIVATE)) as u64;
+        // private anonymous huge pages come from our pool, Linux usually
+        // has none reserved
+        let anon_private = flags & MAP_ANONYMOUS != 0 && flags & MAP_PRIVATE != 0;
+        let huge = huge_perm(flags).filter(|_| anon_private);
+        let flags = match huge {
+            Some(_) => flags & !(MAP_HUGETLB | (MAP_HUGE_MASK << MAP_HUGE_SHIFT)),
+            None => flags,
+        };
+        let mut vma = VmplVma::new(0, len, share, prot, offset);
+        if flags & MAP_ANONYMOUS == 0 {
+            vma.set_vm_file(fd_path(fd));
+        }
+
+        with_vm(|vm| {
+            if vma.is_data() {
+                check_data_limit(vm, len)?;
+            }
+
+            let args = [addr, len, prot_bits, flags as u64, fd as u64, offset];
+            let start = host_syscall(SYS_mmap, args)?;
+            if start == MAP_FAILED as u64 {
+                return Err(libc::ENOMEM);
+            }
+            let end = start + len;
+
+            // MAP_FIXED replaces whatever was there, and anything else
+            // found there was unmapped behind our back
+            vm.remove(start, end)?;
+            drop_translations(start, end);
+            if !vm.in_range(start, end) {
+                debug!("mmap: {:#x}-{:#x} outside the VMPL range", start, end);
+                return Ok(start);
+            }
+
+            vma.relocate(start);
+            let res = set_vmpl(start, end, prot)
+                .and_then(|()| huge.map_or(Ok(()), |big| map_huge(start, end, prot, big)))
+                .and_then(|()| vm.insert(vma));
+            if let Err(e) = res {
+                warn!("mmap: failed to track {:#x}-{:#x}: {}", start, end, e);
+                drop_translations(start, end);
+                let _ = host_syscall(SYS_munmap, [start, len, 0, 0, 0, 0]);
+                let _ = vm.remove(start, end);
+                return Err(e);
+            }
+            Ok(start)
+        })
+    }
+
+    pub fn vmpl_munmap(addr: u64, len: u64) -> Result<(), i32> {
+        let end = match page_align(len).and_then(|len| addr.checked_add(len)) {
+            Some(end) if len > 0 && addr % PGSIZE as u64 == 0 => end,
+            _ => return Err(libc::EINVAL),
+        };
+
+        with_vm(|vm| {
+            host_syscall(SYS_munmap, [addr, len, 0, 0, 0, 0])?;
+            vm.remove(addr, end)?;
+            drop_translations(addr, end);
+            Ok(())
+        })
+    }
+
+    pub fn vmpl_mprotect(addr: u64, len: u64, prot: i32) -> Result<(), i32> {
+        let end = match page_align(len).and_then(|len| addr.checked_add(len)) {
+            Some(end) if addr % PGSIZE as u64 == 0 => end,
+            _ => return Err(libc::EINVAL),
+        };
+        let prot_bits = prot as u64;
+        let prot = Prot::from(prot);
+
+        with_vm(|vm| {
+            // making private memory writable is new data
+            let grow: u64 = vm
+                .find_overlapping(addr, end)
+                .filter(|vma| !vma.is_data() && vma.flags() & MAP_SHARED as u64 == 0)
+                .filter(|vma| vma.vma_type() != VmplVmaType::Stack)
+                .map(|vma| vma.end().min(end) - vma.start().max(addr))
+                .sum();
+            if prot.contains(Prot::Write) && grow > 0 {
+                check_data_limit(vm, grow)?;
+            }
+
+            host_syscall(SYS_mprotect, [addr, len, prot_bits, 0, 0, 0])?;
+            if addr == end {
+                return Ok(());
+            }
+            match vm.protect(addr, end, prot) {
+                // not tracked by the VMPL view
+                Ok(()) | Err(libc::ENOMEM) => {}
+                Err(e) => return Err(e),
+            }
+
+            match pgtable_protect_present(addr, (end - addr) as usize, prot.perm()) {
+                Ok(()) | Err(libc::ENODEV) => {}
+                Err(e) => warn!("mprotect: page table update failed: {}", e),
+            }
+            set_vmpl(addr, end, prot)
+        })
+    }
+
+    pub fn vmpl_mremap(
+        old_addr: u64,
+        old_len: u64,
+        new_len: u64,
+        flags: i32,
+        new_addr: u64,
+    ) -> Result<u64, i32> {
+        let (old_len, new_len) = match (page_align(old_len), page_align(new_len)) {
+            (Some(old_len), Some(new_len)) if new_len > 0 && old_addr % PGSIZE as u64 == 0 => {
+                (old_len, new_len)
+            }
+            _ => return Err(libc::EINVAL),
+        };
+        let old_end = old_addr.checked_add(old_len).ok_or(libc::EINVAL)?;
+
+        with_vm(|vm| {
+            // Linux cannot move frames only the VMPL page table knows
+            if old_len > 0 && hugepage_mapped(old_addr, old_len as usize) {
+                return Err(libc::EINVAL);
+            }
+            let old: Vec<VmplVma> = vm.find_overlapping(old_addr, old_end).cloned().collect();
+            if new_len > old_len && old.iter().any(|vma| vma.is_data()) {
+                check_data_limit(vm, new_len - old_len)?;
+            }
+
+            let args = [old_addr, old_len, new_len, flags as u64, new_addr, 0];
+            let start = host_syscall(SYS_mremap, args)?;
+            let end = start + new_len;
+
+            // an old length of 0 duplicates a shared mapping, which is left
+            // untracked
+            let mut moved = Vec::new();
+            if old_len > 0 {
+                moved = vm.remove(old_addr, old_end)?;
+                drop_translations(old_addr, old_end);
+            }
+            if flags & MREMAP_DONTUNMAP != 0 {
+                // the old range stays, without its pages
+                for vma in moved.iter().cloned() {
+                    vm.insert(vma)?;
+                }
+            }
+            vm.remove(start, end)?;
+            drop_translations(start, end);
+
+            // lay the old VMAs out at the new place, cut to the new length,
+            // and let the last one take any growth
+            moved.retain(|vma| vma.start() - old_addr < new_len);
+            for vma in moved.iter_mut() {
+                let offset = vma.start() - old_addr;
+                let len = vma.len().min(new_len - offset);
+                vma.relocate(start + offset);
+                vma.set_end(start + offset + len);
+            }
+            if new_len > old_len {
+                if let Some(last) = moved.last_mut() {
+                    last.set_end(end);
+                }
+            }
+
+            for vma in moved {
+                if !vm.in_range(vma.start(), vma.end()) {
+                    continue;
+                }
+                set_vmpl(vma.start(), vma.end(), vma.prot())?;
+                vm.insert(vma)?;
+            }
+            Ok(start)
+        })
+    }
+
+    /// Move the program break to `addr` and return the new break. As with
+    /// Linux, the old break is returned when it cannot move.
+    pub fn vmpl_brk(addr: u64) -> u64 {
+        let mut guard = VMPL_VM.write().unwrap();
+        let vm = match guard.as_mut() {
+            Some(vm) => vm,
+            None => return host_syscall(SYS_brk, [addr, 0, 0, 0, 0, 0]).unwrap_or(0),
+        };
+
+        let (start_brk, brk) = (vm.start_brk(), vm.brk());
+        if addr < start_brk {
+            return brk;
+        }
+        let old_end = page_align(brk).unwrap_or(brk);
+        let new_end = match page_align(addr) {
+            Some(new_end) => new_end,
+            None => return brk,
+        };
+        if new_end > old_end && check_data_limit(vm, new_end - old_end).is_err() {
+            return brk;
+        }
+
+        let ret = host_syscall(SYS_brk, [addr, 0, 0, 0, 0, 0]).unwrap_or(brk);
+        if ret != addr {
+            return brk;
+        }
+
+        if new_end > old_end {
+            let prot = Prot::Read | Prot::Write;
+            let mut heap = VmplVma::new(old_end, new_end, MAP_PRIVATE as u64, prot, 0);
+            heap.set_vm_file(Some("[heap]".to_string()));
+            let res = set_vmpl(old_end, new_end, prot).and_then(|()| vm.insert(heap));
+            if let Err(e) = res {
+                warn!("brk: failed to track {:#x}-{:#x}: {}", old_end, new_end, e);
+                let _ = host_syscall(SYS_brk, [brk, 0, 0, 0, 0, 0]);
+                return brk;
+            }
+        } else if new_end < old_end {
+            let _ = vm.remove(new_end, old_end);
+            drop_translations(new_end, old_end);
+        }
+
+        vm.set_brk(start_brk, addr);
+        addr
+    }
+
+    /// Emulate `nr` if it is a memory-management system call. Returns what
+    /// the system call returns, negative errno on failure, or `None` for
+    /// calls that should go to Linux unchanged.
+    pub fn mm_syscall(nr: u64, args: [u64; 6]) -> Option<i64> {
+        if VMPL_VM.read().unwrap().is_none() {
+            return None;
+        }
+
+        let ret = match nr as i64 {
+            SYS_mmap => vmpl_mmap(
+                args[0],
+                args[1],
+                args[2] as i32,
+                args[3] as i32,
+                args[4] as i32,
+                args[5],
+            ),
+            SYS_munmap => vmpl_munmap(args[0], args[1]).map(|()| 0),
+            SYS_mprotect => vmpl_mprotect(args[0], args[1], args[2] as i32).map(|()| 0),
+            SYS_mremap => vmpl_mremap(args[0], args[1], args[2], args[3] as i32, args[4]),
+            SYS_brk => Ok(vmpl_brk(args[0])),
+            _ => return None,
+        };
+        Some(ret.map_or_else(|e| -(e as i64), |v| v as i64))
+    }
+
+    /// Track the heap the process already has
+    pub fn mmap_init(fd: i32) -> Result<(), i32> {
+        info!("mmap init");
+        MMAP_FD.store(fd, Ordering::Relaxed);
+
+        let brk = host_syscall(SYS_brk, [0; 6])?;
+        with_vm(|vm| {
+            let start_brk = vm
+                .iter()
+                .find(|vma| vma.vma_type() == VmplVmaType::Heap)
+                .map_or(brk, |heap| heap.start());
+            vm.set_brk(start_brk, brk);
+            info!("mmap: heap {:#x}-{:#x}", start_brk, brk);
+            Ok(())
+        })
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn huge_page_flags() {
+            assert_eq!(huge_perm(MAP_PRIVATE | MAP_ANONYMOUS), None);
+            assert_eq!(huge_perm(MAP_HUGETLB), Some(PERM_BIG));
+            assert_eq!(huge_perm(MAP_HUGETLB | libc::MAP_HUGE_2MB), Some(PERM_BIG));
+            assert_eq!(huge_perm(MAP_HUGETLB | MAP_HUGE_1GB), Some(PERM_BIG_1GB));
+            assert_eq!(huge_perm(MAP_HUGE_1GB), None);
+        }
+    }
+}
@@ -3,10 +3,32 @@ pub mod pgtable;
 pub mod vma;
 pub mod vm;
 pub mod mm;
+pub mod fault;
+pub mod pkey;
+pub mod hugepage;
+pub mod mmap;
+pub mod cow;
+pub mod heap;
 
 
 pub use page::*;
-pub use pgtable::*;
-pub use vma::*;
-pub use vm::*;
-pub use mm::*;
\ No newline at end of file
+#[cfg(feature = "mm")]
+pub use pgtable::pgtable::*;
+#[cfg(feature = "mm")]
+pub use vma::vma::*;
+#[cfg(feature = "mm")]
+pub use vm::vm::*;
+#[cfg(feature = "mm")]
+pub use mm::mm::*;
+#[cfg(feature = "mm")]
+pub use fault::fault::*;
+#[cfg(feature = "mm")]
+pub use pkey::pkey::*;
+#[cfg(feature = "mm")]
+pub use hugepage::hugepage::*;
+#[cfg(feature = "mm")]
+pub use mmap::mmap::*;
+#[cfg(feature = "mm")]
+pub use cow::cow::*;
+#[cfg(feature = "mm")]
+pub use heap::heap::*;
\ No newline at end of file
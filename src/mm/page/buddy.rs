@@ -0,0 +1,389 @@
+/// Buddy frame allocator
+/// Frames come from the kernel in batches through `VMPL_IOCTL_GET_PAGES`
+/// and are mapped at `PGTABLE_MMAP_BASE`, or the base of their pool. Free
+/// blocks of 4K up to 2M are kept per order and merged with their buddy
+/// when both halves are free.
+#[cfg(feature = "mm")]
+pub mod buddy {
+    use std::collections::BTreeSet;
+    use std::fs::File;
+    use std::mem::ManuallyDrop;
+    use std::os::unix::io::FromRawFd;
+    use std::sync::atomic::Ordering;
+    use std::sync::Mutex;
+
+    use log::{debug, info, warn};
+    use x86_64::PhysAddr;
+
+    use crate::mm::{
+        do_mapping, page_track_alloc, page_track_free, section_add, vmpl_pa2page, Page, PageOwner,
+        PAGE_FLAG_POOL, PGSHIFT, PGTABLE_MMAP_BASE,
+    };
+    use crate::sys::core::GetPagesParams;
+    use crate::sys::ioctl::vmpl_ioctl::VmplFile;
+
+    /// Largest block, 2M
+    pub const BUDDY_MAX_ORDER: usize = 9;
+    pub const BUDDY_ORDER_2MB: usize = 9;
+
+    /// Free blocks by order, as page frame numbers
+    #[derive(Debug, Default)]
+    pub struct BuddyAllocator {
+        free: [BTreeSet<u64>; BUDDY_MAX_ORDER + 1],
+        nr_free: usize,
+        nr_total: usize,
+    }
+
+    impl BuddyAllocator {
+        pub fn new() -> BuddyAllocator {
+            BuddyAllocator::default()
+        }
+
+        /// Hand `npages` frames starting at `pa` to the allocator
+        pub fn add_range(&mut self, pa: PhysAddr, npages: usize) {
+            let mut pfn = pa.as_u64() >> PGSHIFT;
+            let end = pfn + npages as u64;
+
+            while pfn < end {
+                // biggest block aligned at pfn that fits in what is left
+                let align = pfn.trailing_zeros() as usize;
+                let fit = (63 - (end - pfn).leading_zeros()) as usize;
+                let order = align.min(fit).min(BUDDY_MAX_ORDER);
+
+                self.insert(pfn, order);
+                pfn += 1 << order;
+            }
+            self.nr_total += npages;
+            self.nr_free += npages;
+        }
+
+        /// Allocate a naturally aligned block of `1 << order` frames
+        pub fn alloc(&mut self, order: usize) -> Option<PhysAddr> {
+            if order > BUDDY_MAX_ORDER {
+                return None;
+            }
+
+            let mut cur = (order..=BUDDY_MAX_ORDER).find(|o| !self.free[*o].is_empty())?;
+            let pfn = self.free[cur].pop_first()?;
+
+            // give back the upper halves we do not need
+            while cur > order {
+                cur -= 1;
+                self.free[cur].insert(pfn + (1 << cur));
+            }
+            self.nr_free -= 1 << order;
+            Some(PhysAddr::new(pfn << PGSHIFT))
+        }
+
+        /// Return a block from `alloc(order)`
+        pub fn free(&mut self, pa: PhysAddr, order: usize) {
+            let pfn = pa.as_u64() >> PGSHIFT;
+            assert!(order <= BUDDY_MAX_ORDER);
+            assert_eq!(pfn & ((1 << order) - 1), 0, "misaligned block {:#x}", pa);
+
+            self.insert(pfn, order);
+            self.nr_free += 1 << order;
+        }
+
+        fn insert(&mut self, mut pfn: u64, mut order: usize) {
+            while order < BUDDY_MAX_ORDER {
+                let buddy = pfn ^ (1 << order);
+                if !self.free[order].remove(&buddy) {
+                    break;
+                }
+                pfn = pfn.min(buddy);
+                order += 1;
+            }
+            let fresh = self.free[order].insert(pfn);
+            assert!(fresh, "double free of frame {:#x}", pfn << PGSHIFT);
+        }
+
+        /// Free frames
+        pub fn nr_free(&self) -> usize {
+            self.nr_free
+        }
+
+        /// Frames ever added
+        pub fn nr_total(&self) -> usize {
+            self.nr_total
+        }
+
+        /// Free blocks of `order`
+        pub fn nr_blocks(&self, order: usize) -> usize {
+            self.free.get(order).map_or(0, |blocks| blocks.len())
+        }
+    }
+
+    /// A pool of frames from the kernel, handed out by a buddy allocator
+    pub struct PagePool {
+        name: &'static str,
+        grow_size: usize,
+        /// Fetched frames are mapped at `base + pa`
+        base: u64,
+        buddy: Mutex<BuddyAllocator>,
+        /// Batches from the kernel that could not be mapped. There is no
+        /// call to give frames back, so the next `fetch` retries them.
+        unmapped: Mutex<Vec<(PhysAddr, usize)>>,
+    }
+
+    impl PagePool {
+        /// `grow_size` frames are requested each time the pool runs dry.
+        /// They are mapped in the direct map.
+        pub fn new(name: &'static str, grow_size: usize) -> PagePool {
+            PagePool::with_base(name, grow_size, PGTABLE_MMAP_BASE)
+        }
+
+        /// Like `new`, with the frames mapped at `base + pa` instead
+        pub fn with_base(name: &'static str, grow_size: usize, base: u64) -> PagePool {
+            PagePool {
+                name,
+                grow_size,
+                base,
+                buddy: Mutex::new(BuddyAllocator::new()),
+                unmapped: Mutex::new(Vec::new()),
+            }
+        }
+
+        /// Take `npages` frames from a batch that failed to map, splitting
+        /// it if it is bigger
+        fn take_unmapped(&self, npages: usize) -> Option<PhysAddr> {
+            let mut unmapped = self.unmapped.lock().unwrap();
+            let i = unmapped.iter().position(|(_, n)| *n >= npages)?;
+            let (pa, n) = unmapped.swap_remove(i);
+            if n > npages {
+                unmapped.push((pa + ((npages as u64) << PGSHIFT), n - npages));
+            }
+            Some(pa)
+        }
+
+        /// Get `npages` contiguous frames from the kernel and map them. They
+        /// are not handed out until given to `add`.
+        pub fn fetch(&self, fd: i32, npages: usize) -> Result<PhysAddr, i32> {
+            // borrow the fd, it stays owned by the caller
+            let file = ManuallyDrop::new(unsafe { File::from_raw_fd(fd) });
+            let mut vmpl_file = ManuallyDrop::new(VmplFile::new(unsafe { File::from_raw_fd(fd) }));
+
+            let phys = match self.take_unmapped(npages) {
+                Some(phys) => phys,
+                None => {
+                    let mut param = GetPagesParams::new(npages, 0);
+                    vmpl_file
+                        .get_pages(&mut param)
+                        .map_err(|e| e.raw_os_error().unwrap_or(libc::EIO))?;
+                    PhysAddr::new(param.phys())
+                }
+            };
+
+            let len = npages << PGSHIFT;
+            let mapped = section_add(phys, len as u64).and_then(|()| {
+                do_mapping(&file, self.base, phys, len).map_err(|e| e.raw_os_error().unwrap_or(libc::EIO))
+            });
+            if let Err(e) = mapped {
+                warn!("{}: cannot map frames {:#x}+{:#x}: {}", self.name, phys, len, e);
+                self.unmapped.lock().unwrap().push((phys, npages));
+                return Err(e);
+            }
+
+            for pg in block_pages(phys, npages) {
+                pg.flags.fetch_or(PAGE_FLAG_POOL, Ordering::SeqCst);
+            }
+            debug!("{}: got frames {:#x}+{:#x}", self.name, phys, len);
+            Ok(phys)
+        }
+
+        /// Hand frames from `fetch` to the allocator
+        pub fn add(&self, pa: PhysAddr, npages: usize) {
+            self.buddy.lock().unwrap().add_range(pa, npages);
+        }
+
+        /// Get `npages` more frames from the kernel and add them to the pool
+        pub fn grow(&self, fd: i32, npages: usize) -> Result<(), i32> {
+            let pa = self.fetch(fd, npages)?;
+            self.add(pa, npages);
+            Ok(())
+        }
+
+        /// Allocate `1 << order` contiguous frames, growing the pool if needed
+        pub fn alloc(&self, fd: i32, order: usize) -> Option<PhysAddr> {
+            if let Some(pa) = self.buddy.lock().unwrap().alloc(order) {
+                return Some(pa);
+            }
+
+            // a batch may come back unaligned, so ask for enough to hold
+            // an aligned block of this order
+            let npages = self.grow_size.max(2 << order);
+            if let Err(e) = self.grow(fd, npages) {
+                warn!("{}: failed to grow the pool: {}", self.name, e);
+                return None;
+            }
+            self.buddy.lock().unwrap().alloc(order)
+        }
+
+        pub fn free(&self, pa: PhysAddr, order: usize) {
+            self.buddy.lock().unwrap().free(pa, order);
+        }
+
+        pub fn nr_free(&self) -> usize {
+            self.buddy.lock().unwrap().nr_free()
+        }
+
+        pub fn nr_total(&self) -> usize {
+            self.buddy.lock().unwrap().nr_total()
+        }
+
+        pub fn stats(&self) {
+            let buddy = self.buddy.lock().unwrap();
+            info!(
+                "{}: {} of {} frames free",
+                self.name,
+                buddy.nr_free(),
+                buddy.nr_total()
+            );
+            for order in 0..=BUDDY_MAX_ORDER {
+                info!("{}:   order {}: {} blocks", self.name, order, buddy.nr_blocks(order));
+            }
+        }
+    }
+
+    /// Metadata of the `npages` frames at `pa`
+    fn block_pages(pa: PhysAddr, npages: usize) -> impl Iterator<Item = &'static Page> {
+        // sections live until `section_exit`
+        (0..npages as u64).map(move |i| unsafe { &*vmpl_pa2page(pa + (i << PGSHIFT)) })
+    }
+
+    /// Mark the frames of the block at `pa` as handed out to `owner`, with
+    /// no references yet
+    pub fn page_block_mark(pa: PhysAddr, order: usize, owner: PageOwner) {
+        for pg in block_pages(pa, 1 << order) {
+            pg.vmpl.store(1, Ordering::SeqCst);
+            pg.ref_count.store(0, Ordering::SeqCst);
+            pg.set_owner(owner);
+        }
+        page_track_alloc(vmpl_pa2page(pa));
+    }
+
+    /// Hand the frames of the marked block at `pa` over to `owner`
+    pub fn page_block_set_owner(pa: PhysAddr, order: usize, owner: PageOwner) {
+        for pg in block_pages(pa, 1 << order) {
+            pg.set_owner(owner);
+        }
+    }
+
+    /// Mark the frames of the block at `pa` as back in the pool
+    pub fn page_block_clear(pa: PhysAddr, order: usize) {
+        for pg in block_pages(pa, 1 << order) {
+            assert_eq!(pg.ref_count.load(Ordering::SeqCst), 0);
+            pg.vmpl.store(0, Ordering::SeqCst);
+            pg.set_owner(PageOwner::None);
+        }
+        page_track_free(vmpl_pa2page(pa));
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn pa(pfn: u64) -> PhysAddr {
+            PhysAddr::new(pfn << PGSHIFT)
+        }
+
+        #[test]
+        fn add_range_splits_into_aligned_blocks() {
+            let mut buddy = BuddyAllocator::new();
+            // pfn 3..1030: 3, 4-7, 8-15, ..., 512-1023, 1024-1027, 1028-1029
+            buddy.add_range(pa(3), 1027);
+            assert_eq!(buddy.nr_total(), 1027);
+            assert_eq!(buddy.nr_free(), 1027);
+            assert_eq!(buddy.nr_blocks(0), 1);
+            assert_eq!(buddy.nr_blocks(1), 1);
+            assert_eq!(buddy.nr_blocks(2), 2);
+            for order in 3..BUDDY_MAX_ORDER {
+                assert_eq!(buddy.nr_blocks(order), 1, "order {}", order);
+            }
+            assert_eq!(buddy.nr_blocks(BUDDY_MAX_ORDER), 1);
+        }
+
+        #[test]
+        fn alloc_splits_and_aligns() {
+            let mut buddy = BuddyAllocator::new();
+            buddy.add_range(pa(512), 512);
+
+            let one = buddy.alloc(0).unwrap();
+            assert_eq!(one, pa(512));
+            // the rest of the 2M block is left as one block per order
+            for order in 0..BUDDY_MAX_ORDER {
+                assert_eq!(buddy.nr_blocks(order), 1);
+            }
+
+            let four = buddy.alloc(2).unwrap();
+            assert_eq!(four, pa(516));
+            assert_eq!(buddy.nr_free(), 512 - 5);
+            assert_eq!(buddy.alloc(BUDDY_MAX_ORDER), None);
+            assert_eq!(buddy.alloc(BUDDY_MAX_ORDER + 1), None);
+        }
+
+        #[test]
+        fn free_merges_buddies() {
+            let mut buddy = BuddyAllocator::new();
+            buddy.add_range(pa(0), 512);
+
+            let blocks: Vec<PhysAddr> = (0..512).map(|_| buddy.alloc(0).unwrap()).collect();
+            assert_eq!(buddy.nr_free(), 0);
+            assert_eq!(buddy.alloc(0), None);
+
+            // odd frames first, nothing can merge
+            for pa in blocks.iter().skip(1).step_by(2) {
+                buddy.free(*pa, 0);
+            }
+            assert_eq!(buddy.nr_blocks(0), 256);
+
+            for pa in blocks.iter().step_by(2) {
+                buddy.free(*pa, 0);
+            }
+            assert_eq!(buddy.nr_free(), 512);
+            assert_eq!(buddy.nr_blocks(0), 0);
+            assert_eq!(buddy.nr_blocks(BUDDY_MAX_ORDER), 1);
+            assert_eq!(buddy.alloc(BUDDY_MAX_ORDER), Some(pa(0)));
+        }
+
+        #[test]
+        #[should_panic(expected = "double free")]
+        fn double_free_panics() {
+            let mut buddy = BuddyAllocator::new();
+            buddy.add_range(pa(0), 2);
+            let first = buddy.alloc(0).unwrap();
+            let _second = buddy.alloc(0).unwrap();
+            buddy.free(first, 0);
+            buddy.free(first, 0);
+        }
+
+        #[test]
+        fn pool_hands_out_added_frames() {
+            let pool = PagePool::new("test", 16);
+            pool.add(pa(1024), 512);
+            assert_eq!(pool.nr_total(), 512);
+
+            // served without growing, so the fd is never used
+            let block = pool.alloc(-1, BUDDY_ORDER_2MB).unwrap();
+            assert_eq!(block, pa(1024));
+            assert_eq!(pool.nr_free(), 0);
+
+            pool.free(block, BUDDY_ORDER_2MB);
+            assert_eq!(pool.nr_free(), 512);
+            let one = pool.alloc(-1, 0).unwrap();
+            pool.free(one, 0);
+            assert_eq!(pool.alloc(-1, BUDDY_ORDER_2MB), Some(block));
+        }
+
+        #[test]
+        fn unmapped_batches_are_reused() {
+            let pool = PagePool::new("test", 16);
+            pool.unmapped.lock().unwrap().push((pa(64), 16));
+
+            assert_eq!(pool.take_unmapped(32), None);
+            assert_eq!(pool.take_unmapped(4), Some(pa(64)));
+            assert_eq!(pool.take_unmapped(12), Some(pa(68)));
+            assert_eq!(pool.take_unmapped(1), None);
+        }
+    }
+}
@@ -1,33 +1,95 @@
 #[cfg(feature = "mm")]
 pub mod common {
-    use lazy_static::lazy_static;
-    use libc::{mmap, MAP_FAILED, MAP_SHARED, PROT_READ, PROT_WRITE};
-    use log::warn;
+    use libc::{mmap, munmap, MAP_FAILED, MAP_FIXED_NOREPLACE, MAP_SHARED, PROT_READ, PROT_WRITE};
+    use log::info;
     use std::fs::File;
     use std::io::Error;
-    use std::mem;
     use std::os::unix::io::AsRawFd;
-    use std::ptr::NonNull;
     use std::sync::atomic::{AtomicU64, Ordering};
     use x86_64::PhysAddr;
 
-    use crate::mm::pgtable::{PGSIZE, PGTABLE_MMAP_BASE};
+    use crate::mm::{PGSIZE, PGTABLE_MMAP_BASE, PGTABLE_MMAP_SIZE};
     use std::fmt;
 
-    use crate::mm::page::dune::*;
-    use crate::mm::page::vmpl::*;
+    use crate::mm::{heap_stats, hugepage_stats};
+    use crate::mm::page::dune::dune::*;
+    use crate::mm::{
+        page_ref_underflow, page_scan, section_bytes, section_count, section_exit,
+        section_for_each_page, section_pa2page, section_page2pa,
+    };
+    use crate::mm::page::vmpl::vmpl::*;
 
-    pub const PAGEBASE: PhysAddr = PhysAddr::zero(); // Replace with actual value
-    pub const MAX_PAGES: usize = 0x100000;
     pub const PAGE_FLAG_POOL: u64 = 1 << 0;
     pub const PAGE_FLAG_MAPPED: u64 = 1 << 1;
 
+    /// Who a frame was handed out to, for accounting and leak reports
+    #[repr(u64)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum PageOwner {
+        None = 0,
+        Vmpl,
+        Dune,
+        Pgtable,
+        Hugepage,
+        Cow,
+        Heap,
+    }
+
+    impl PageOwner {
+        pub const COUNT: usize = 7;
+        pub const ALL: [PageOwner; PageOwner::COUNT] = [
+            PageOwner::None,
+            PageOwner::Vmpl,
+            PageOwner::Dune,
+            PageOwner::Pgtable,
+            PageOwner::Hugepage,
+            PageOwner::Cow,
+            PageOwner::Heap,
+        ];
+
+        pub fn from_raw(raw: u64) -> PageOwner {
+            PageOwner::ALL.get(raw as usize).copied().unwrap_or(PageOwner::None)
+        }
+
+        pub fn name(&self) -> &'static str {
+            match self {
+                PageOwner::None => "none",
+                PageOwner::Vmpl => "vmpl",
+                PageOwner::Dune => "dune",
+                PageOwner::Pgtable => "pgtable",
+                PageOwner::Hugepage => "hugepage",
+                PageOwner::Cow => "cow",
+                PageOwner::Heap => "heap",
+            }
+        }
+    }
+
+    impl fmt::Display for PageOwner {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str(self.name())
+        }
+    }
+
     #[derive(Default)]
     pub struct Page {
         link: Option<Box<Page>>,
-        ref_count: AtomicU64,
-        flags: AtomicU64,
-        vmpl: AtomicU64,
+        pub(crate) ref_count: AtomicU64,
+        pub(crate) flags: AtomicU64,
+        pub(crate) vmpl: AtomicU64,
+        /// A `PageOwner`
+        pub(crate) owner: AtomicU64,
+        /// Frame number, set when the section is added
+        pub(crate) pfn: u64,
+    }
+
+    impl Page {
+        pub fn owner(&self) -> PageOwner {
+            PageOwner::from_raw(self.owner.load(Ordering::Relaxed))
+        }
+
+        pub fn set_owner(&self, owner: PageOwner) {
+            self.owner.store(owner as u64, Ordering::Relaxed);
+        }
     }
 
     impl fmt::Debug for Page {
@@ -36,32 +98,41 @@ pub mod common {
                 .field("ref_count", &self.ref_count)
                 .field("flags", &self.flags)
                 .field("vmpl", &self.vmpl)
+                .field("owner", &self.owner())
                 .finish()
         }
     }
 
-    lazy_static! {
-        pub static ref PAGES: [Page; MAX_PAGES] = {
-            let mut pages = [Page {
-                link: None,
-                ref_count: AtomicU64::new(0),
-                flags: AtomicU64::new(0),
-                vmpl: AtomicU64::new(0),
-            }; MAX_PAGES];
-            pages
-        };
+    /// Call `f` on the metadata of every frame that has some
+    pub fn for_each_page<F: FnMut(PhysAddr, &Page)>(f: F) {
+        section_for_each_page(f)
+    }
+
+    /// Metadata of the frame at `pa`, if its range was ever fetched
+    pub fn page_lookup(pa: PhysAddr) -> Option<*mut Page> {
+        section_pa2page(pa)
     }
 
     pub static mut NUM_DUNE_PAGES: i32 = 0;
     pub static mut NUM_VMPL_PAGES: i32 = 0;
 
-    pub fn do_mapping(fd: &File, phys: PhysAddr, len: usize) -> Result<*mut libc::c_void, Error> {
+    /// Map the frames `[phys, phys + len)` at `base + phys`, `base` being
+    /// `PGTABLE_MMAP_BASE` for the direct map. Fails with `EEXIST` if
+    /// something else is mapped there.
+    pub fn do_mapping(
+        fd: &File,
+        base: u64,
+        phys: PhysAddr,
+        len: usize,
+    ) -> Result<*mut libc::c_void, Error> {
+        assert!(phys.as_u64() + len as u64 <= PGTABLE_MMAP_SIZE);
+        let va = (base + phys.as_u64()) as *mut libc::c_void;
         let addr = unsafe {
             mmap(
-                (PGTABLE_MMAP_BASE + phys.as_u64()).as_u64() as *mut libc::c_void,
+                va,
                 len,
                 PROT_READ | PROT_WRITE,
-                MAP_SHARED,
+                MAP_SHARED | MAP_FIXED_NOREPLACE,
                 fd.as_raw_fd(),
                 phys.as_u64() as libc::off_t,
             )
@@ -70,6 +141,15 @@ pub mod common {
         if addr == MAP_FAILED {
             return Err(Error::last_os_error());
         }
+        if addr != va {
+            // kernels before 4.17 take MAP_FIXED_NOREPLACE as a hint
+            unsafe { munmap(addr, len) };
+            return Err(Error::from_raw_os_error(libc::EEXIST));
+        }
+
+        if base != PGTABLE_MMAP_BASE {
+            return Ok(addr);
+        }
 
         log::debug!("Marking page {:x}-{:x} as mapped", phys, phys + len as u64);
         for i in (0..len).step_by(PGSIZE) {
@@ -80,65 +160,70 @@ pub mod common {
         Ok(addr)
     }
 
-    pub fn get_page(pg: &mut Page) {
-        assert!(!PAGES.is_none());
-        assert!(pg as *mut _ >= PAGES.unwrap().as_ptr());
-        assert!(pg as *mut _ < unsafe { PAGES.unwrap().as_ptr().offset(MAX_PAGES as isize) });
+    /// True if `pg` is the metadata of some frame, not a copy of it
+    pub fn is_page(pg: &Page) -> bool {
+        section_pa2page(section_page2pa(pg)) == Some(pg as *const Page as *mut Page)
+    }
+
+    /// Take a reference to a frame that was handed out
+    pub fn get_page(pg: &Page) {
+        assert!(is_page(pg));
         assert_eq!(pg.vmpl.load(Ordering::SeqCst), 1);
 
         pg.ref_count.fetch_add(1, Ordering::SeqCst);
     }
 
-    pub fn put_page(pg: &mut Page) {
-        assert!(!PAGES.is_none());
-        assert!(pg as *mut _ >= PAGES.unwrap().as_ptr());
-        assert!(pg as *mut _ < unsafe { PAGES.unwrap().as_ptr().offset(MAX_PAGES as isize) });
+    /// Drop a reference, true if it was the last one. Dropping one that
+    /// was never taken is reported by `page_ref_underflow`.
+    pub fn put_page(pg: &Page) -> bool {
+        assert!(is_page(pg));
         assert_eq!(pg.vmpl.load(Ordering::SeqCst), 1);
-        assert!(pg.ref_count.load(Ordering::SeqCst) > 0);
 
-        pg.ref_count.fetch_sub(1, Ordering::SeqCst);
+        let old = pg.ref_count.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |refs| {
+            refs.checked_sub(1)
+        });
+        match old {
+            Ok(refs) => refs == 1,
+            Err(_) => {
+                page_ref_underflow(pg);
+                false
+            }
+        }
     }
 
     pub fn page_init(fd: i32) -> Result<(), i32> {
-        unsafe {
-            PAGES = Some(
-                NonNull::new(libc::malloc(mem::size_of::<Page>() * MAX_PAGES) as *mut Page)
-                    .ok_or(libc::ENOMEM)?,
-            );
-
-            if vmpl_page_init(fd) != 0 {
-                return Err(libc::ENOMEM);
-            }
+        // metadata is added as the pools fetch frames
+        if vmpl_page_init(fd) != 0 {
+            return Err(libc::ENOMEM);
+        }
 
-            if dune_page_init(fd) != 0 {
-                return Err(libc::ENOMEM);
-            }
+        if dune_page_init(fd) != 0 {
+            return Err(libc::ENOMEM);
         }
 
         Ok(())
     }
 
     pub fn page_exit() {
-        unsafe {
-            libc::free(PAGES.unwrap().as_ptr() as *mut libc::c_void);
-            vmpl_page_exit();
-            dune_page_exit();
-        }
+        vmpl_page_exit();
+        dune_page_exit();
+        section_exit();
     }
 
     pub fn page_stats() {
-        println!("Page Stats:");
+        info!("Page Stats: {}", page_scan());
+        info!("Page Metadata: {} sections, {} KiB", section_count(), section_bytes() >> 10);
         vmpl_page_stats();
         dune_page_stats();
+        info!("Huge Pages: {}", hugepage_stats());
+        info!("Heap: {}", heap_stats());
     }
 
     #[test]
     pub fn page_test(vmpl_fd: i32) {
         log::info!("Page Test");
-        unsafe {
-            vmpl_page_test(vmpl_fd);
-            dune_page_test(vmpl_fd);
-        }
+        vmpl_page_test(vmpl_fd);
+        dune_page_test(vmpl_fd);
         log::info!("Page Test Passed");
     }
 }
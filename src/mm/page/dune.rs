@@ -1,22 +1,22 @@
 // -----------------------DUNE PAGE MANAGEMENT-----------------------
 #[cfg(feature = "mm")]
 pub mod dune {
-    use crate::mm::page::common::*;
-    use crate::mm::pgtable::{PGSHIFT, PGSIZE, PGTABLE_MMAP_BASE};
-    use core::sync::atomic::Ordering;
+    use crate::mm::{page_block_clear, page_block_mark, PagePool};
+    use crate::mm::page::common::common::*;
+    use crate::mm::{PGSHIFT, PGSIZE, PGTABLE_MMAP_BASE};
+    use lazy_static::lazy_static;
     use log::info;
     use x86_64::PhysAddr;
 
-    use super::vmpl::*;
+    use crate::mm::PageRef;
+    use crate::mm::page::vmpl::vmpl::*;
 
+    /// Frames requested from the kernel each time the Dune pool runs dry
+    pub const DUNE_PAGE_GROW_SIZE: usize = 512;
 
-    pub fn dune_page_put(pg: *mut Page) {
-        unsafe {
-            put_page(&mut *pg);
-            if (*pg).ref_count.load(Ordering::SeqCst) == 0 {
-                dune_page_free(pg);
-            }
-        }
+    lazy_static! {
+        /// Frames for the Dune path, kept apart from the VMPL pool
+        static ref DUNE_POOL: PagePool = PagePool::new("dune", DUNE_PAGE_GROW_SIZE);
     }
 
     #[cfg(feature = "mm")]
@@ -25,7 +25,7 @@ pub mod dune {
     }
 
     #[cfg(feature = "mm")]
-    pub fn dune_page2pa(pg: *mut Page) -> PhysAddr {
+    pub fn dune_page2pa(pg: &Page) -> PhysAddr {
         vmpl_page2pa(pg)
     }
 
@@ -34,68 +34,51 @@ pub mod dune {
         vmpl_page_is_from_pool(pa)
     }
 
-    #[cfg(feature = "mm")]
-    pub fn dune_page_get(pg: *mut Page) {
-        vmpl_page_get(pg);
-    }
-
-    #[cfg(feature = "mm")]
-    pub fn dune_page_get_addr(pa: PhysAddr) -> *mut Page {
-        if pa < PAGEBASE {
-            return std::ptr::null_mut();
-        }
-        let pg = dune_pa2page(pa);
-        if dune_page_is_from_pool(pa) {
-            dune_page_get(pg);
-        }
-        pg
-    }
-
     #[cfg(feature = "mm")]
     pub fn dune_page_mark_addr(pa: PhysAddr) {
-        if pa >= PAGEBASE {
-            vmpl_page_mark_addr(pa);
-        }
+        vmpl_page_mark_addr(pa);
     }
 
     #[cfg(feature = "mm")]
-    pub fn dune_page_put_addr(pa: PhysAddr) {
-        let pg = dune_pa2page(pa);
-        if dune_page_is_from_pool(pa) {
-            dune_page_put(pg);
+    pub fn dune_page_init(fd: i32) -> i32 {
+        match DUNE_POOL.grow(fd, DUNE_PAGE_GROW_SIZE) {
+            Ok(()) => 0,
+            Err(e) => e,
         }
     }
 
     #[cfg(feature = "mm")]
-    pub fn dune_page_init(fd: i32) -> i32 {
-        let _ = fd;
-        0
-    }
+    pub fn dune_page_exit() {}
 
     #[cfg(feature = "mm")]
     pub fn dune_page_alloc(fd: i32) -> *mut Page {
-        unsafe {
-            let pg = vmpl_page_alloc(fd);
-            (*pg).vmpl.store(1, Ordering::SeqCst);
-            (*pg).ref_count.store(0, Ordering::SeqCst);
-            pg
+        match DUNE_POOL.alloc(fd, 0) {
+            Some(pa) => {
+                page_block_mark(pa, 0, PageOwner::Dune);
+                dune_pa2page(pa)
+            }
+            None => std::ptr::null_mut(),
         }
     }
 
     #[cfg(feature = "mm")]
-    pub fn dune_page_free(pg: *mut Page) {
-        vmpl_page_free(pg);
+    pub fn dune_page_free(pg: &Page) {
+        let pa = dune_page2pa(pg);
+        page_block_clear(pa, 0);
+        DUNE_POOL.free(pa, 0);
     }
 
     #[cfg(feature = "mm")]
     pub fn dune_page_stats() {
         info!("Dune Page Stats:");
+        DUNE_POOL.stats();
     }
 
     #[cfg(feature = "mm")]
     pub fn dune_page_test(fd: i32) {
         info!("Dune Page Test");
-        let pg = dune_page_alloc(fd);
-        dune_page_free(pg);
+        let page = PageRef::alloc_dune(fd).unwrap();
+        assert!(dune_page_is_from_pool(page.pa()));
+        assert_eq!(page.owner(), PageOwner::Dune);
     }
 }
\ No newline at end of file
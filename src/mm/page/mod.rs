@@ -1,7 +1,22 @@
 pub mod common;
 pub mod vmpl;
 pub mod dune;
+pub mod buddy;
+pub mod stats;
+pub mod pageref;
+pub mod section;
 
-pub use common::*;
-pub use vmpl::*;
-pub use dune::*;
\ No newline at end of file
+#[cfg(feature = "mm")]
+pub use common::common::*;
+#[cfg(feature = "mm")]
+pub use vmpl::vmpl::*;
+#[cfg(feature = "mm")]
+pub use dune::dune::*;
+#[cfg(feature = "mm")]
+pub use buddy::buddy::*;
+#[cfg(feature = "mm")]
+pub use stats::stats::*;
+#[cfg(feature = "mm")]
+pub use pageref::pageref::*;
+#[cfg(feature = "mm")]
+pub use section::section::*;
\ No newline at end of file
@@ -0,0 +1,232 @@
+/// Counted page references
+/// A `PageRef` holds one reference to a 4K frame handed out by the VMPL
+/// or Dune pool. Cloning takes another, and dropping the last one gives
+/// the frame back to its pool, so a frame cannot be freed while a
+/// `PageRef` to it is alive.
+#[cfg(feature = "mm")]
+pub mod pageref {
+    use std::fmt;
+    use std::ptr::NonNull;
+    use std::slice;
+    use std::sync::atomic::Ordering;
+
+    use x86_64::{PhysAddr, VirtAddr};
+
+    use crate::mm::{
+        dune_page_alloc, dune_page_free, get_page, page_block_set_owner, pgtable_pa_to_va, put_page,
+        vmpl_pa2page, vmpl_page2pa, vmpl_page_alloc, vmpl_page_free, vmpl_page_is_from_pool,
+        vmpl_page_is_mapped, Page, PageOwner, PGSIZE,
+    };
+
+    /// A reference to a pool frame
+    pub struct PageRef {
+        pg: NonNull<Page>,
+    }
+
+    // `Page` is only changed through atomics
+    unsafe impl Send for PageRef {}
+    unsafe impl Sync for PageRef {}
+
+    impl PageRef {
+        /// Wrap `pg`, taking a reference
+        fn get(pg: NonNull<Page>) -> PageRef {
+            get_page(unsafe { pg.as_ref() });
+            PageRef { pg }
+        }
+
+        /// Allocate a VMPL frame for `owner`, holding its first reference.
+        /// Dune frames come from `alloc_dune`, as the owner picks the pool
+        /// the frame goes back to.
+        pub fn alloc(fd: i32, owner: PageOwner) -> Option<PageRef> {
+            if owner == PageOwner::Dune {
+                return None;
+            }
+            let pg = NonNull::new(vmpl_page_alloc(fd))?;
+            page_block_set_owner(vmpl_page2pa(unsafe { pg.as_ref() }), 0, owner);
+            Some(PageRef::get(pg))
+        }
+
+        /// Allocate a frame from the Dune pool
+        pub fn alloc_dune(fd: i32) -> Option<PageRef> {
+            NonNull::new(dune_page_alloc(fd)).map(PageRef::get)
+        }
+
+        /// Take a new reference to the frame at `pa`. It must be a pool
+        /// frame that is handed out.
+        pub fn from_pa(pa: PhysAddr) -> Option<PageRef> {
+            if pa.as_u64() % PGSIZE as u64 != 0 || !vmpl_page_is_from_pool(pa) {
+                return None;
+            }
+
+            let pg = NonNull::new(vmpl_pa2page(pa))?;
+            if unsafe { pg.as_ref() }.vmpl.load(Ordering::SeqCst) == 0 {
+                return None;
+            }
+            Some(PageRef::get(pg))
+        }
+
+        /// Give up the reference without dropping it, e.g. to store the
+        /// frame in a page table. `from_raw` takes it back.
+        pub fn into_raw(self) -> PhysAddr {
+            let pa = self.pa();
+            std::mem::forget(self);
+            pa
+        }
+
+        /// Take back a reference given up by `into_raw`
+        ///
+        /// # Safety
+        ///
+        /// `pa` must come from `into_raw`, and each such reference may be
+        /// taken back only once.
+        pub unsafe fn from_raw(pa: PhysAddr) -> PageRef {
+            PageRef {
+                pg: NonNull::new_unchecked(vmpl_pa2page(pa)),
+            }
+        }
+
+        pub fn pa(&self) -> PhysAddr {
+            vmpl_page2pa(self.page())
+        }
+
+        /// Address of the frame in the direct map
+        pub fn va(&self) -> VirtAddr {
+            pgtable_pa_to_va(self.pa())
+        }
+
+        pub fn page(&self) -> &Page {
+            unsafe { self.pg.as_ref() }
+        }
+
+        pub fn owner(&self) -> PageOwner {
+            self.page().owner()
+        }
+
+        pub fn ref_count(&self) -> u64 {
+            self.page().ref_count.load(Ordering::SeqCst)
+        }
+
+        /// The frame in the direct map, where `PagePool::fetch` mapped it
+        /// in and out of VMPL mode
+        fn contents(&self) -> *mut u8 {
+            let pa = self.pa();
+            assert!(vmpl_page_is_mapped(pa), "pageref: {:#x} is not in the direct map", pa);
+            pgtable_pa_to_va(pa).as_mut_ptr()
+        }
+
+        /// The contents of the frame
+        pub fn as_slice(&self) -> &[u8] {
+            unsafe { slice::from_raw_parts(self.contents(), PGSIZE) }
+        }
+
+        /// The contents of the frame, if this is its only reference
+        pub fn get_mut(&mut self) -> Option<&mut [u8]> {
+            if self.ref_count() != 1 {
+                return None;
+            }
+            Some(unsafe { slice::from_raw_parts_mut(self.contents(), PGSIZE) })
+        }
+    }
+
+    impl Clone for PageRef {
+        fn clone(&self) -> PageRef {
+            PageRef::get(self.pg)
+        }
+    }
+
+    impl Drop for PageRef {
+        fn drop(&mut self) {
+            if !put_page(self.page()) {
+                return;
+            }
+            match self.owner() {
+                PageOwner::Dune => dune_page_free(self.page()),
+                _ => vmpl_page_free(self.page()),
+            }
+        }
+    }
+
+    impl PartialEq for PageRef {
+        fn eq(&self, other: &PageRef) -> bool {
+            self.pg == other.pg
+        }
+    }
+
+    impl Eq for PageRef {}
+
+    impl fmt::Debug for PageRef {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_struct("PageRef")
+                .field("pa", &self.pa())
+                .field("page", self.page())
+                .finish()
+        }
+    }
+
+    impl TryFrom<PhysAddr> for PageRef {
+        type Error = i32;
+
+        /// Like `from_pa`, EINVAL if `pa` is not a handed-out pool frame
+        fn try_from(pa: PhysAddr) -> Result<PageRef, i32> {
+            PageRef::from_pa(pa).ok_or(libc::EINVAL)
+        }
+    }
+
+    impl From<&PageRef> for PhysAddr {
+        fn from(page: &PageRef) -> PhysAddr {
+            page.pa()
+        }
+    }
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::mm::{section_add, PAGE_FLAG_POOL, VMPL_POOL};
+
+        /// Pool frames with metadata but no backing memory
+        fn pool_frames(base: u64, npages: usize) {
+            let pa = PhysAddr::new(base);
+            section_add(pa, (npages * PGSIZE) as u64).unwrap();
+            for i in 0..npages {
+                let pg = vmpl_pa2page(pa + (i * PGSIZE) as u64);
+                unsafe { (*pg).flags.fetch_or(PAGE_FLAG_POOL, Ordering::SeqCst) };
+            }
+            VMPL_POOL.add(pa, npages);
+        }
+
+        #[test]
+        fn alloc_rejects_dune_owner() {
+            // before touching the pool, which has no frames here
+            assert!(PageRef::alloc(-1, PageOwner::Dune).is_none());
+        }
+
+        #[test]
+        fn references_follow_clones_and_raw() {
+            pool_frames(13 << 30, 4);
+
+            let page = PageRef::alloc(-1, PageOwner::Cow).unwrap();
+            let pa = page.pa();
+            assert_eq!(page.owner(), PageOwner::Cow);
+            assert_eq!(page.ref_count(), 1);
+
+            let copy = page.clone();
+            assert_eq!(copy, page);
+            assert_eq!(page.ref_count(), 2);
+            drop(copy);
+            assert_eq!(page.ref_count(), 1);
+
+            // a raw reference keeps the frame while the PageRef is gone
+            let raw = PageRef::from_pa(pa).unwrap().into_raw();
+            assert_eq!(raw, pa);
+            drop(page);
+            assert_eq!(PageRef::from_pa(pa).map(|page| page.ref_count()), Some(2));
+            let page = unsafe { PageRef::from_raw(raw) };
+            assert_eq!(page.ref_count(), 1);
+            assert_eq!(PhysAddr::from(&page), pa);
+
+            // the last reference gives the frame back
+            drop(page);
+            assert!(PageRef::from_pa(pa).is_none());
+            assert_eq!(PageRef::try_from(pa), Err(libc::EINVAL));
+        }
+    }
+}
@@ -0,0 +1,207 @@
+/// Sparse page metadata
+/// `Page` entries are allocated in sections of `PAGES_PER_SECTION` frames,
+/// only for the physical ranges frames actually come from. A two-level
+/// table indexed by physical address finds the section, so `pa2page` is
+/// two loads, and each `Page` keeps its frame number for `page2pa`.
+/// Sections are never freed before `section_exit`, so lookups take no
+/// lock.
+#[cfg(feature = "mm")]
+pub mod section {
+    use std::mem;
+    use std::ptr;
+    use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    use lazy_static::lazy_static;
+    use log::debug;
+    use x86_64::PhysAddr;
+
+    use crate::mm::{Page, PGSHIFT};
+
+    /// Physical address bits x86-64 can have
+    pub const MAX_PHYS_BITS: usize = 52;
+    /// 128M of frames per section
+    pub const SECTION_SHIFT: usize = 27;
+    pub const PAGES_PER_SECTION: usize = 1 << (SECTION_SHIFT - PGSHIFT);
+
+    const SECTION_BITS: usize = MAX_PHYS_BITS - SECTION_SHIFT;
+    /// Sections per second-level table
+    const LEAF_BITS: usize = 13;
+    const LEAF_SIZE: usize = 1 << LEAF_BITS;
+    const ROOT_SIZE: usize = 1 << (SECTION_BITS - LEAF_BITS);
+
+    type Leaf = [AtomicPtr<Page>; LEAF_SIZE];
+
+    /// Second-level tables, each holding the `Page` arrays of `LEAF_SIZE`
+    /// sections
+    static ROOT: [AtomicPtr<Leaf>; ROOT_SIZE] = [const { AtomicPtr::new(ptr::null_mut()) }; ROOT_SIZE];
+
+    static NR_SECTIONS: AtomicUsize = AtomicUsize::new(0);
+
+    lazy_static! {
+        /// Serializes adding sections
+        static ref SECTION_LOCK: Mutex<()> = Mutex::new(());
+    }
+
+    fn section_nr(pa: u64) -> usize {
+        (pa >> SECTION_SHIFT) as usize
+    }
+
+    fn leaf(nr: usize) -> Option<&'static Leaf> {
+        let leaf = ROOT.get(nr >> LEAF_BITS)?.load(Ordering::Acquire);
+        unsafe { leaf.as_ref() }
+    }
+
+    fn section(nr: usize) -> Option<*mut Page> {
+        let pages = leaf(nr)?[nr & (LEAF_SIZE - 1)].load(Ordering::Acquire);
+        (!pages.is_null()).then_some(pages)
+    }
+
+    /// Zeroed memory for `count` entries of `T`
+    fn alloc_zeroed<T>(count: usize) -> Result<*mut T, i32> {
+        let ptr = unsafe { libc::calloc(count, mem::size_of::<T>()) } as *mut T;
+        if ptr.is_null() {
+            return Err(libc::ENOMEM);
+        }
+        Ok(ptr)
+    }
+
+    /// Make sure the frames of `[pa, pa + len)` have metadata. New entries
+    /// are unused `Page`s.
+    pub fn section_add(pa: PhysAddr, len: u64) -> Result<(), i32> {
+        let end = pa.as_u64().checked_add(len).ok_or(libc::EINVAL)?;
+        if len == 0 || end > 1 << MAX_PHYS_BITS {
+            return Err(libc::EINVAL);
+        }
+
+        let _guard = SECTION_LOCK.lock().unwrap();
+        for nr in section_nr(pa.as_u64())..=section_nr(end - 1) {
+            let slot = &ROOT[nr >> LEAF_BITS];
+            if slot.load(Ordering::Acquire).is_null() {
+                // all-null pointers are a valid, empty leaf
+                slot.store(alloc_zeroed::<AtomicPtr<Page>>(LEAF_SIZE)? as *mut Leaf, Ordering::Release);
+            }
+
+            let entry = &leaf(nr).unwrap()[nr & (LEAF_SIZE - 1)];
+            if !entry.load(Ordering::Acquire).is_null() {
+                continue;
+            }
+
+            // all-zero is a valid, unused `Page`
+            let pages = alloc_zeroed::<Page>(PAGES_PER_SECTION)?;
+            let first = (nr * PAGES_PER_SECTION) as u64;
+            for i in 0..PAGES_PER_SECTION {
+                unsafe { (*pages.add(i)).pfn = first + i as u64 };
+            }
+            entry.store(pages, Ordering::Release);
+            NR_SECTIONS.fetch_add(1, Ordering::Relaxed);
+            debug!("page: metadata for {:#x}+{:#x}", first << PGSHIFT, 1u64 << SECTION_SHIFT);
+        }
+        Ok(())
+    }
+
+    /// Metadata of the frame at `pa`, if its section has been added
+    pub fn section_pa2page(pa: PhysAddr) -> Option<*mut Page> {
+        let pa = pa.as_u64();
+        let pages = section(section_nr(pa))?;
+        let index = ((pa >> PGSHIFT) as usize) & (PAGES_PER_SECTION - 1);
+        Some(unsafe { pages.add(index) })
+    }
+
+    /// Frame `pg` describes
+    pub fn section_page2pa(pg: &Page) -> PhysAddr {
+        PhysAddr::new(pg.pfn << PGSHIFT)
+    }
+
+    /// Sections added so far
+    pub fn section_count() -> usize {
+        NR_SECTIONS.load(Ordering::Relaxed)
+    }
+
+    /// Bytes of metadata allocated
+    pub fn section_bytes() -> usize {
+        section_count() * PAGES_PER_SECTION * mem::size_of::<Page>()
+    }
+
+    /// Call `f` on every frame with metadata, in address order
+    pub fn section_for_each_page<F: FnMut(PhysAddr, &Page)>(mut f: F) {
+        for (i, slot) in ROOT.iter().enumerate() {
+            let leaf = match unsafe { slot.load(Ordering::Acquire).as_ref() } {
+                Some(leaf) => leaf,
+                None => continue,
+            };
+            for (j, entry) in leaf.iter().enumerate() {
+                let pages = entry.load(Ordering::Acquire);
+                if pages.is_null() {
+                    continue;
+                }
+                let first = (((i << LEAF_BITS) | j) * PAGES_PER_SECTION) as u64;
+                for k in 0..PAGES_PER_SECTION {
+                    f(PhysAddr::new((first + k as u64) << PGSHIFT), unsafe { &*pages.add(k) });
+                }
+            }
+        }
+    }
+
+    /// Free all metadata. No `Page` may be in use.
+    pub fn section_exit() {
+        let _guard = SECTION_LOCK.lock().unwrap();
+        for slot in ROOT.iter() {
+            let leaf = slot.swap(ptr::null_mut(), Ordering::AcqRel);
+            if leaf.is_null() {
+                continue;
+            }
+            for entry in unsafe { &*leaf }.iter() {
+                let pages = entry.load(Ordering::Acquire);
+                if !pages.is_null() {
+                    unsafe { libc::free(pages as *mut libc::c_void) };
+                }
+            }
+            unsafe { libc::free(leaf as *mut libc::c_void) };
+        }
+        NR_SECTIONS.store(0, Ordering::Relaxed);
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        // the table is global, so each test uses its own addresses
+        const GIB: u64 = 1 << 30;
+
+        #[test]
+        fn lookup_round_trip() {
+            let base = 5 * GIB;
+            section_add(PhysAddr::new(base + 0x1000), 0x3000).unwrap();
+
+            for pa in [base, base + 0x1000, base + (1 << SECTION_SHIFT) - 0x1000] {
+                let pg = section_pa2page(PhysAddr::new(pa)).unwrap();
+                assert_eq!(section_page2pa(unsafe { &*pg }), PhysAddr::new(pa));
+            }
+            assert!(section_pa2page(PhysAddr::new(base + (1 << SECTION_SHIFT))).is_none());
+            assert!(section_pa2page(PhysAddr::new(base - 0x1000)).is_none());
+        }
+
+        #[test]
+        fn ranges_across_sections() {
+            // above 4G and across a leaf boundary
+            let base = (LEAF_SIZE as u64) << SECTION_SHIFT;
+            let before = section_count();
+            section_add(PhysAddr::new(base - 0x1000), 0x2000).unwrap();
+            section_add(PhysAddr::new(base), 0x1000).unwrap();
+            assert_eq!(section_count() - before, 2);
+
+            let pg = section_pa2page(PhysAddr::new(base - 0x1000)).unwrap();
+            unsafe { (*pg).ref_count.store(3, Ordering::Relaxed) };
+            let pg = section_pa2page(PhysAddr::new(base - 0x1000)).unwrap();
+            assert_eq!(unsafe { (*pg).ref_count.load(Ordering::Relaxed) }, 3);
+            assert_eq!(section_page2pa(unsafe { &*pg }).as_u64(), base - 0x1000);
+        }
+
+        #[test]
+        fn reject_bad_ranges() {
+            assert_eq!(section_add(PhysAddr::new(0x1000), 0), Err(libc::EINVAL));
+            assert_eq!(section_add(PhysAddr::new((1 << MAX_PHYS_BITS) - 0x1000), 0x2000), Err(libc::EINVAL));
+        }
+    }
+}
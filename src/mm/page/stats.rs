@@ -0,0 +1,279 @@
+/// Page accounting
+/// Counts frames by state and owner from their `Page` metadata, reports
+/// the frames still referenced at exit, and catches references dropped
+/// more often than taken. Debug builds remember where each block was
+/// allocated, for the leak report.
+#[cfg(feature = "mm")]
+pub mod stats {
+    #[cfg(debug_assertions)]
+    use std::backtrace::Backtrace;
+    #[cfg(debug_assertions)]
+    use std::collections::HashMap;
+    use std::fmt::{self, Display};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    #[cfg(debug_assertions)]
+    use std::sync::Mutex;
+
+    #[cfg(debug_assertions)]
+    use lazy_static::lazy_static;
+    use log::{debug, error, info, warn};
+    use x86_64::PhysAddr;
+
+    use crate::mm::{for_each_page, vmpl_page2pa, Page, PageOwner, PAGE_FLAG_MAPPED, PAGE_FLAG_POOL};
+
+    /// Buckets of `PageStats::refs`, the last one counts the rest
+    pub const PAGE_REF_BUCKETS: usize = 4;
+
+    static UNDERFLOWS: AtomicUsize = AtomicUsize::new(0);
+
+    #[cfg(debug_assertions)]
+    lazy_static! {
+        /// Where the blocks handed out were allocated, by their first `Page`
+        static ref ALLOC_SITES: Mutex<HashMap<usize, Backtrace>> = Mutex::new(HashMap::new());
+    }
+
+    /// Frames by state
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+    pub struct PageStats {
+        /// Frames fetched into a pool
+        pub pool: usize,
+        /// Pool frames not handed out
+        pub free: usize,
+        /// Frames mapped at `PGTABLE_MMAP_BASE`
+        pub mapped: usize,
+        /// Frames handed out, marked VMPL
+        pub vmpl: usize,
+        /// Frames handed out with 0, 1, 2, and 3 or more references
+        pub refs: [usize; PAGE_REF_BUCKETS],
+        /// Frames handed out, by `PageOwner`
+        pub owners: [usize; PageOwner::COUNT],
+    }
+
+    impl PageStats {
+        /// Count the frame `pg` describes
+        pub fn add(&mut self, pg: &Page) {
+            let flags = pg.flags.load(Ordering::Relaxed);
+            let vmpl = pg.vmpl.load(Ordering::Relaxed) != 0;
+
+            if flags & PAGE_FLAG_POOL != 0 {
+                self.pool += 1;
+                if !vmpl {
+                    self.free += 1;
+                }
+            }
+            if flags & PAGE_FLAG_MAPPED != 0 {
+                self.mapped += 1;
+            }
+            if vmpl {
+                let refs = pg.ref_count.load(Ordering::Relaxed) as usize;
+                self.vmpl += 1;
+                self.refs[refs.min(PAGE_REF_BUCKETS - 1)] += 1;
+                self.owners[pg.owner() as usize] += 1;
+            }
+        }
+    }
+
+    impl Display for PageStats {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(
+                f,
+                "{} pool, {} free, {} mapped, {} vmpl; refs 0/1/2/3+: {}/{}/{}/{}",
+                self.pool,
+                self.free,
+                self.mapped,
+                self.vmpl,
+                self.refs[0],
+                self.refs[1],
+                self.refs[2],
+                self.refs[3]
+            )?;
+            for owner in PageOwner::ALL {
+                let count = self.owners[owner as usize];
+                if count > 0 {
+                    write!(f, "; {}: {}", owner, count)?;
+                }
+            }
+            Ok(())
+        }
+    }
+
+    /// Count every frame with metadata
+    pub fn page_scan() -> PageStats {
+        let mut stats = PageStats::default();
+        for_each_page(|_, pg| stats.add(pg));
+        stats
+    }
+
+    /// Remember where the block starting at `pg` was allocated. Only debug
+    /// builds keep a backtrace.
+    pub fn page_track_alloc(pg: *const Page) {
+        #[cfg(debug_assertions)]
+        ALLOC_SITES
+            .lock()
+            .unwrap()
+            .insert(pg as usize, Backtrace::force_capture());
+        #[cfg(not(debug_assertions))]
+        let _ = pg;
+    }
+
+    /// Forget the block starting at `pg`, it is free again
+    pub fn page_track_free(pg: *const Page) {
+        #[cfg(debug_assertions)]
+        ALLOC_SITES.lock().unwrap().remove(&(pg as usize));
+        #[cfg(not(debug_assertions))]
+        let _ = pg;
+    }
+
+    /// Log where the block starting at `pg` was allocated, if known
+    fn report_alloc_site(pg: *const Page) {
+        #[cfg(debug_assertions)]
+        if let Some(backtrace) = ALLOC_SITES.lock().unwrap().get(&(pg as usize)) {
+            debug!("page:   allocated at:\n{}", backtrace);
+        }
+        #[cfg(not(debug_assertions))]
+        let _ = pg;
+    }
+
+    /// `put_page` found no reference to drop. The count stays at zero.
+    /// Debug builds panic.
+    pub fn page_ref_underflow(pg: &Page) {
+        let pa = vmpl_page2pa(pg);
+        UNDERFLOWS.fetch_add(1, Ordering::Relaxed);
+        error!("page: reference count underflow on {:#x}, owner {}", pa, pg.owner());
+        report_alloc_site(pg);
+
+        if cfg!(debug_assertions) {
+            panic!("page: put_page on unreferenced frame {:#x}", pa);
+        }
+    }
+
+    /// Reference count underflows caught so far
+    pub fn page_ref_underflows() -> usize {
+        UNDERFLOWS.load(Ordering::Relaxed)
+    }
+
+    /// Frames still referenced, gathered page by page like `PageStats`
+    #[derive(Debug, Default)]
+    struct LeakCheck {
+        leaked: usize,
+        unreferenced: usize,
+        owners: [usize; PageOwner::COUNT],
+    }
+
+    impl LeakCheck {
+        fn add(&mut self, pa: PhysAddr, pg: &Page) {
+            // heap frames are not reference counted
+            if pg.vmpl.load(Ordering::Relaxed) == 0 || pg.owner() == PageOwner::Heap {
+                return;
+            }
+            let refs = pg.ref_count.load(Ordering::Relaxed);
+            if refs == 0 {
+                self.unreferenced += 1;
+                return;
+            }
+
+            self.leaked += 1;
+            self.owners[pg.owner() as usize] += 1;
+            debug!("page: {:#x} still has {} references, owner {}", pa, refs, pg.owner());
+            report_alloc_site(pg);
+        }
+
+        fn report(&self) -> usize {
+            if self.unreferenced > 0 {
+                warn!("page: {} frames handed out without references", self.unreferenced);
+            }
+            if page_ref_underflows() > 0 {
+                warn!("page: {} reference count underflows", page_ref_underflows());
+            }
+            if self.leaked == 0 {
+                info!("page: no frames leaked");
+                return 0;
+            }
+
+            warn!("page: {} frames still referenced", self.leaked);
+            for owner in PageOwner::ALL {
+                if self.owners[owner as usize] > 0 {
+                    warn!("page:   {}: {}", owner, self.owners[owner as usize]);
+                }
+            }
+            self.leaked
+        }
+    }
+
+    /// Report the frames still referenced, by owner, with where they were
+    /// allocated in debug builds. Returns how many there are.
+    pub fn page_leak_check() -> usize {
+        let mut check = LeakCheck::default();
+        for_each_page(|pa, pg| check.add(pa, pg));
+        check.report()
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn page(flags: u64, vmpl: u64, refs: u64, owner: PageOwner) -> Page {
+            let pg = Page::default();
+            pg.flags.store(flags, Ordering::Relaxed);
+            pg.vmpl.store(vmpl, Ordering::Relaxed);
+            pg.ref_count.store(refs, Ordering::Relaxed);
+            pg.set_owner(owner);
+            pg
+        }
+
+        #[test]
+        fn count_by_state() {
+            let mut stats = PageStats::default();
+            stats.add(&page(0, 0, 0, PageOwner::None));
+            stats.add(&page(PAGE_FLAG_POOL | PAGE_FLAG_MAPPED, 0, 0, PageOwner::None));
+            stats.add(&page(PAGE_FLAG_POOL | PAGE_FLAG_MAPPED, 1, 1, PageOwner::Pgtable));
+            stats.add(&page(PAGE_FLAG_POOL, 1, 0, PageOwner::Vmpl));
+            stats.add(&page(PAGE_FLAG_POOL, 1, 7, PageOwner::Cow));
+            stats.add(&page(PAGE_FLAG_MAPPED, 1, 2, PageOwner::Dune));
+
+            assert_eq!(stats.pool, 4);
+            assert_eq!(stats.free, 1);
+            assert_eq!(stats.mapped, 3);
+            assert_eq!(stats.vmpl, 4);
+            assert_eq!(stats.refs, [1, 1, 1, 1]);
+            assert_eq!(stats.owners, [0, 1, 1, 1, 0, 1, 0]);
+            assert_eq!(
+                stats.to_string(),
+                "4 pool, 1 free, 3 mapped, 4 vmpl; refs 0/1/2/3+: 1/1/1/1; \
+                 vmpl: 1; dune: 1; pgtable: 1; cow: 1"
+            );
+        }
+
+        #[test]
+        fn owner_from_raw() {
+            for owner in PageOwner::ALL {
+                assert_eq!(PageOwner::from_raw(owner as u64), owner);
+            }
+            assert_eq!(PageOwner::from_raw(99), PageOwner::None);
+        }
+
+        #[test]
+        fn leak_check() {
+            let arena = [
+                page(PAGE_FLAG_POOL, 0, 0, PageOwner::None),
+                page(PAGE_FLAG_POOL, 1, 1, PageOwner::Pgtable),
+                page(PAGE_FLAG_POOL, 1, 3, PageOwner::Cow),
+                page(PAGE_FLAG_POOL, 1, 0, PageOwner::Vmpl),
+                page(PAGE_FLAG_POOL, 1, 1, PageOwner::Heap),
+            ];
+
+            let mut check = LeakCheck::default();
+            for (i, pg) in arena.iter().enumerate() {
+                check.add(PhysAddr::new(i as u64 * 0x1000), pg);
+            }
+            assert_eq!(check.unreferenced, 1);
+            assert_eq!(check.owners[PageOwner::Pgtable as usize], 1);
+            assert_eq!(check.owners[PageOwner::Cow as usize], 1);
+            assert_eq!(check.report(), 2);
+
+            let mut check = LeakCheck::default();
+            check.add(PhysAddr::new(0), &arena[0]);
+            assert_eq!(check.report(), 0);
+        }
+    }
+}
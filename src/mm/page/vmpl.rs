@@ -6,93 +6,130 @@ pub mod vmpl {
     use std::ptr;
     use std::sync::atomic::{AtomicU64, Ordering};
 
+    use lazy_static::lazy_static;
+    use log::info;
     use x86_64::PhysAddr;
 
-    use crate::mm::page::common::{MAX_PAGES, PAGEBASE, PAGES};
-    use crate::mm::pgtable::PGSHIFT;
+    use crate::mm::{
+        is_page, page_block_clear, page_block_mark, page_lookup, section_page2pa, PagePool, PageRef,
+        BUDDY_ORDER_2MB, PGSHIFT,
+    };
 
-    use super::common::{get_page, put_page, Page, PAGE_FLAG_MAPPED, PAGE_FLAG_POOL};
+    use crate::mm::{Page, PageOwner, PAGE_FLAG_MAPPED, PAGE_FLAG_POOL};
 
     pub fn vmpl_pa2page(pa: PhysAddr) -> *mut Page {
-        assert!(pa >= PAGEBASE);
-        assert!(pa < (PAGEBASE + ((MAX_PAGES as u64) << PGSHIFT)));
-        unsafe { PAGES.unwrap().as_ptr().offset((pa - PAGEBASE) as isize) }
+        match page_lookup(pa) {
+            Some(pg) => pg,
+            None => panic!("page: no metadata for {:#x}", pa),
+        }
     }
 
-    pub fn vmpl_page2pa(pg: *mut Page) -> PhysAddr {
-        assert!(pg >= unsafe { PAGES.unwrap().as_ptr() });
-        PAGEBASE + ((pg as usize - unsafe { PAGES.unwrap().as_ptr() as usize }) << PGSHIFT)
-    }
+    /// Frames requested from the kernel each time the pool runs dry (2M)
+    pub const VMPL_PAGE_GROW_SIZE: usize = 512;
 
-    pub fn vmpl_page_is_from_pool(pa: PhysAddr) -> bool {
-        let pg = vmpl_pa2page(pa);
-        unsafe { (*pg).flags.load(Ordering::SeqCst) & PAGE_FLAG_POOL != 0 }
+    lazy_static! {
+        pub(crate) static ref VMPL_POOL: PagePool = PagePool::new("vmpl", VMPL_PAGE_GROW_SIZE);
     }
 
-    pub fn vmpl_page_is_mapped(pa: PhysAddr) -> bool {
-        let pg = vmpl_pa2page(pa);
-        unsafe { (*pg).flags.load(Ordering::SeqCst) & PAGE_FLAG_MAPPED != 0 }
+    pub fn vmpl_page2pa(pg: &Page) -> PhysAddr {
+        assert!(is_page(pg));
+        section_page2pa(pg)
     }
 
-    pub fn vmpl_page_mark(pg: *mut Page) {
-        unsafe {
-            (*pg).vmpl.store(1, Ordering::SeqCst);
-            (*pg).ref_count.store(0, Ordering::SeqCst);
+    /// Frames without metadata never came from a pool
+    pub fn vmpl_page_is_from_pool(pa: PhysAddr) -> bool {
+        match page_lookup(pa) {
+            Some(pg) => unsafe { (*pg).flags.load(Ordering::SeqCst) & PAGE_FLAG_POOL != 0 },
+            None => false,
         }
     }
 
-    pub fn vmpl_page_mark_addr(pa: PhysAddr) {
-        if pa >= PAGEBASE {
-            vmpl_page_mark(vmpl_pa2page(pa));
+    pub fn vmpl_page_is_mapped(pa: PhysAddr) -> bool {
+        match page_lookup(pa) {
+            Some(pg) => unsafe { (*pg).flags.load(Ordering::SeqCst) & PAGE_FLAG_MAPPED != 0 },
+            None => false,
         }
     }
 
-    pub fn vmpl_page_get(pg: *mut Page) {
-        get_page(unsafe { &mut *pg });
+    pub fn vmpl_page_mark(pg: &Page) {
+        pg.vmpl.store(1, Ordering::SeqCst);
+        pg.ref_count.store(0, Ordering::SeqCst);
     }
 
-    pub fn vmpl_page_get_addr(pa: PhysAddr) -> *mut Page {
-        if pa < PAGEBASE {
-            return std::ptr::null_mut();
-        }
-        let pg = vmpl_pa2page(pa);
-        if vmpl_page_is_from_pool(pa) {
-            vmpl_page_get(pg);
+    pub fn vmpl_page_mark_addr(pa: PhysAddr) {
+        if let Some(pg) = page_lookup(pa) {
+            // sections live until `section_exit`
+            vmpl_page_mark(unsafe { &*pg });
         }
-        pg
     }
 
-    pub fn vmpl_page_put(pg: *mut Page) {
-        put_page(unsafe { &mut *pg });
-        if unsafe { (*pg).ref_count.load(Ordering::SeqCst) } == 0 {
-            unsafe { vmpl_page_free(pg) };
+    pub fn vmpl_page_init(fd: i32) -> i32 {
+        match VMPL_POOL.grow(fd, VMPL_PAGE_GROW_SIZE) {
+            Ok(()) => 0,
+            Err(e) => e,
         }
     }
 
-    pub fn vmpl_page_put_addr(pa: PhysAddr) {
-        let pg = vmpl_pa2page(pa);
-        if vmpl_page_is_from_pool(pa) {
-            vmpl_page_put(pg);
+    /// The frames go back to the kernel when the VMPL fd is closed
+    pub fn vmpl_page_exit() {}
+
+    /// Allocate `1 << order` contiguous frames, marked VMPL with no
+    /// references. Callers allocating for a subsystem retag them.
+    pub fn vmpl_page_alloc_order(fd: i32, order: usize) -> *mut Page {
+        match VMPL_POOL.alloc(fd, order) {
+            Some(pa) => {
+                page_block_mark(pa, order, PageOwner::Vmpl);
+                vmpl_pa2page(pa)
+            }
+            None => ptr::null_mut(),
         }
     }
 
-    pub fn vmpl_page_init(fd: i32) -> i32 {
-        todo!("vmpl_page_init: {}", fd);
+    pub fn vmpl_page_alloc(fd: i32) -> *mut Page {
+        vmpl_page_alloc_order(fd, 0)
     }
 
-    pub fn vmpl_page_alloc(fd: i32) -> *mut Page {
-        todo!("vmpl_page_alloc: {}", fd);
+    /// Free a block from `vmpl_page_alloc_order`; no references may be
+    /// left. Single frames held by a `PageRef` are freed by its drop.
+    pub fn vmpl_page_free_order(pg: &Page, order: usize) {
+        let pa = vmpl_page2pa(pg);
+        page_block_clear(pa, order);
+        VMPL_POOL.free(pa, order);
     }
 
-    pub fn vmpl_page_free(pg: *mut Page) {
-        todo!("vmpl_page_free: {:?}", pg);
+    pub fn vmpl_page_free(pg: &Page) {
+        vmpl_page_free_order(pg, 0)
     }
 
     pub fn vmpl_page_stats() {
-        todo!("vmpl_page_stats");
+        VMPL_POOL.stats();
     }
 
     pub fn vmpl_page_test(vmpl_fd: i32) {
-        todo!("vmpl_page_test: {}", vmpl_fd);
+        info!("VMPL Page Test");
+        let free = VMPL_POOL.nr_free();
+
+        let pages: Vec<PageRef> = (0..4)
+            .map(|_| PageRef::alloc(vmpl_fd, PageOwner::Vmpl).unwrap())
+            .collect();
+        for page in pages.iter() {
+            assert!(vmpl_page_is_from_pool(page.pa()));
+            assert_eq!(page.ref_count(), 1);
+        }
+
+        let copy = PageRef::from_pa(pages[0].pa()).unwrap();
+        assert_eq!(copy, pages[0]);
+        assert_eq!(copy.ref_count(), 2);
+        drop(copy);
+        drop(pages);
+
+        let huge = vmpl_page_alloc_order(vmpl_fd, BUDDY_ORDER_2MB);
+        assert!(!huge.is_null());
+        let huge = unsafe { &*huge };
+        assert_eq!(vmpl_page2pa(huge).as_u64() & ((1 << (PGSHIFT + BUDDY_ORDER_2MB)) - 1), 0);
+        vmpl_page_free_order(huge, BUDDY_ORDER_2MB);
+
+        // the pool may have grown, but everything is back
+        assert!(VMPL_POOL.nr_free() >= free);
     }
 }
@@ -1,59 +1,1475 @@
+/// VMPL page tables
+/// 4- or 5-level (LA57) x86-64 page tables rooted at the CR3 the VMPL
+/// driver set up.
+/// Table pages come from a `PgtableFrames` source, so the same code runs
+/// on the VMPL page pool and on an in-memory arena in the tests.
 #[cfg(feature = "mm")]
 pub mod pgtable {
+    use std::arch::asm;
+    use std::arch::x86_64::__cpuid_count;
+    use std::fs::File;
+    use std::mem::ManuallyDrop;
+    use std::ops::Range;
+    use std::os::unix::io::FromRawFd;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Mutex;
+
+    use lazy_static::lazy_static;
+    #[cfg(feature = "apic")]
+    use log::warn;
+    use log::{debug, info};
+    use x86_64::instructions::tlb;
+    use x86_64::registers::control::Cr4;
+    use x86_64::registers::model_specific::Msr;
     use x86_64::{PhysAddr, VirtAddr};
 
-    pub const PGTABLE_MMAP_BASE: PhysAddr = PhysAddr::zero(); // Replace with actual value
-    pub const PGTABLE_MMAP_SIZE: u64 = 0x0; // Replace with actual value
+    use crate::ghcb::globals::MSR_SEV_STATUS;
+    use crate::ghcb::vc::{vc_make_pages_private, vc_make_pages_shared, vc_make_pages_shared_msr};
+    use crate::mm::{
+        PageOwner, PageRef, PERM_BIG, PERM_BIG_1GB, PERM_COW, PERM_R, PERM_U, PERM_UC, PERM_USR1,
+        PERM_USR2, PERM_USR3, PERM_W, PERM_X,
+    };
+    use crate::sys::ioctl::vmpl_ioctl::VmplFile;
+    #[cfg(feature = "apic")]
+    use crate::sys::apic::apic::APIC_ROUTING;
+    use crate::sys::percpu::this_cpu;
+    #[cfg(feature = "apic")]
+    use crate::sys::smp::smp::tlb_shootdown;
+
+    /// Pool frames are mapped at `PGTABLE_MMAP_BASE + pa`, in the same
+    /// place in the process and in VMPL mode. The window sits between the
+    /// program and the mmap area Linux fills top-down.
+    pub const PGTABLE_MMAP_BASE: u64 = 0x2000_0000_0000;
+    /// Physical addresses below this can be mapped (16T)
+    pub const PGTABLE_MMAP_SIZE: u64 = 1 << 44;
+    /// Heap frames are mapped at `HEAP_MMAP_BASE + pa` instead, right
+    /// above the direct map, so the heap knows its blocks by address
+    pub const HEAP_MMAP_BASE: u64 = PGTABLE_MMAP_BASE + PGTABLE_MMAP_SIZE;
+    pub const HEAP_MMAP_SIZE: u64 = PGTABLE_MMAP_SIZE;
     pub const PGSHIFT: usize = 12;
     pub const PGSIZE: usize = 1 << PGSHIFT;
     pub const PAGE_SIZE: usize = 1 << PGSHIFT;
     pub const PAGE_2MB_SIZE: u64 = 1 << 21;
+    pub const PAGE_1GB_SIZE: u64 = 1 << 30;
+
+    /// Entries in a page-table page
+    pub const PTES_PER_TABLE: usize = 512;
+
+    const CR4_LA57: u64 = 1 << 12;
+    const CPUID_7_ECX_LA57: u32 = 1 << 16;
+    /// SEV capabilities, the C-bit position in EBX[5:0]
+    const CPUID_SEV_INFO: u32 = 0x8000_001f;
+    const SEV_STATUS_ENABLED: u64 = 1 << 0;
+
+    pub const PTE_PRESENT: u64 = 1 << 0;
+    pub const PTE_WRITE: u64 = 1 << 1;
+    pub const PTE_USER: u64 = 1 << 2;
+    pub const PTE_PWT: u64 = 1 << 3;
+    pub const PTE_PCD: u64 = 1 << 4;
+    pub const PTE_ACCESSED: u64 = 1 << 5;
+    pub const PTE_DIRTY: u64 = 1 << 6;
+    pub const PTE_PS: u64 = 1 << 7;
+    pub const PTE_GLOBAL: u64 = 1 << 8;
+    /// Software bits, ignored by the MMU
+    pub const PTE_COW: u64 = 1 << 9;
+    pub const PTE_USR1: u64 = 1 << 10;
+    pub const PTE_USR2: u64 = 1 << 11;
+    pub const PTE_USR3: u64 = 1 << 52;
+    pub const PTE_NX: u64 = 1 << 63;
+    pub const PTE_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+    /// Protection key, bits 62:59 of a leaf entry
+    pub const PTE_PKEY_SHIFT: u64 = 59;
+    pub const PTE_PKEY_MASK: u64 = 0xf << PTE_PKEY_SHIFT;
+
+    /// Non-leaf entries grant everything, the leaf decides
+    const PTE_TABLE_FLAGS: u64 = PTE_PRESENT | PTE_WRITE | PTE_USER;
+    /// Leaf bits `protect` leaves alone
+    const PTE_KEEP_MASK: u64 =
+        PTE_ADDR_MASK | PTE_PKEY_MASK | PTE_PS | PTE_ACCESSED | PTE_DIRTY | PTE_GLOBAL | PTE_COW;
+
+    /// Paging depth, PML4 or PML5 at the root
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum PagingMode {
+        Level4,
+        Level5,
+    }
+
+    impl PagingMode {
+        pub fn from_cr4(cr4: u64) -> PagingMode {
+            if cr4 & CR4_LA57 != 0 {
+                PagingMode::Level5
+            } else {
+                PagingMode::Level4
+            }
+        }
+
+        /// CR4.LA57 when running in VMPL mode. Before that, the mode Linux
+        /// runs in: it enables LA57 whenever the CPU has it (unless booted
+        /// with `no5lvl`), and only a 5-level kernel maps above 47 bits.
+        pub fn detect() -> PagingMode {
+            if this_cpu().is_some() {
+                return PagingMode::from_cr4(Cr4::read_raw());
+            }
+
+            let leaf = unsafe { __cpuid_count(7, 0) };
+            if leaf.ecx & CPUID_7_ECX_LA57 == 0 {
+                return PagingMode::Level4;
+            }
+
+            let hint = 1u64 << 47;
+            let addr = unsafe {
+                libc::mmap(
+                    hint as *mut libc::c_void,
+                    PGSIZE,
+                    libc::PROT_NONE,
+                    libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_FIXED_NOREPLACE,
+                    -1,
+                    0,
+                )
+            };
+            if addr == libc::MAP_FAILED {
+                return PagingMode::Level4;
+            }
+            unsafe { libc::munmap(addr, PGSIZE) };
+
+            if addr as u64 == hint {
+                PagingMode::Level5
+            } else {
+                PagingMode::Level4
+            }
+        }
+
+        pub fn levels(&self) -> usize {
+            match self {
+                PagingMode::Level4 => 4,
+                PagingMode::Level5 => 5,
+            }
+        }
+
+        /// Implemented virtual address bits
+        pub fn va_bits(&self) -> u32 {
+            match self {
+                PagingMode::Level4 => 48,
+                PagingMode::Level5 => 57,
+            }
+        }
+
+        /// `va` with the bits above `va_bits` copied from the top
+        /// implemented one, bit 47 or bit 56 with LA57. `VirtAddr` only
+        /// knows 48-bit addresses, so page-table addresses stay `u64`.
+        pub fn sign_extend(&self, va: u64) -> u64 {
+            let shift = 64 - self.va_bits();
+            (((va << shift) as i64) >> shift) as u64
+        }
+
+        pub fn is_canonical(&self, va: u64) -> bool {
+            self.sign_extend(va) == va
+        }
+
+        /// End of the lower canonical half, where user mappings go
+        pub fn user_va_end(&self) -> u64 {
+            1 << (self.va_bits() - 1)
+        }
+    }
+
+    lazy_static! {
+        static ref PAGING_MODE: PagingMode = PagingMode::detect();
+    }
+
+    /// Paging mode of the VMPL page table
+    pub fn paging_mode() -> PagingMode {
+        *PAGING_MODE
+    }
+
+    /// C-bit of the guest, not probed yet while `u64::MAX`
+    static SEV_ENC_MASK: AtomicU64 = AtomicU64::new(u64::MAX);
+
+    /// The SEV C-bit, set in entries mapping private memory. Zero outside
+    /// SEV guests, and before VMPL mode where `MSR_SEV_STATUS` cannot be
+    /// read.
+    pub fn sev_enc_mask() -> u64 {
+        let mask = SEV_ENC_MASK.load(Ordering::Relaxed);
+        if mask != u64::MAX {
+            return mask;
+        }
+        if this_cpu().is_none() {
+            return 0;
+        }
+
+        let status = unsafe { Msr::new(MSR_SEV_STATUS).read() };
+        let mask = if status & SEV_STATUS_ENABLED != 0 {
+            let leaf = unsafe { __cpuid_count(CPUID_SEV_INFO, 0) };
+            1 << (leaf.ebx & 0x3f)
+        } else {
+            0
+        };
+        SEV_ENC_MASK.store(mask, Ordering::Relaxed);
+        mask
+    }
+
+    /// Address bits of an entry, without the C-bit
+    fn addr_mask() -> u64 {
+        PTE_ADDR_MASK & !sev_enc_mask()
+    }
+
+    /// Size of the page a leaf entry maps
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum PageSize {
+        Size4K,
+        Size2M,
+        Size1G,
+    }
+
+    impl PageSize {
+        /// Page size requested by `PERM_BIG`/`PERM_BIG_1GB`
+        pub fn from_perm(perm: u32) -> PageSize {
+            if perm & PERM_BIG_1GB != 0 {
+                PageSize::Size1G
+            } else if perm & PERM_BIG != 0 {
+                PageSize::Size2M
+            } else {
+                PageSize::Size4K
+            }
+        }
+
+        fn from_level(level: usize) -> PageSize {
+            match level {
+                1 => PageSize::Size4K,
+                2 => PageSize::Size2M,
+                _ => PageSize::Size1G,
+            }
+        }
+
+        /// Level the leaf entry lives at, 1 for a PT entry
+        pub fn level(&self) -> usize {
+            match self {
+                PageSize::Size4K => 1,
+                PageSize::Size2M => 2,
+                PageSize::Size1G => 3,
+            }
+        }
+
+        pub fn bytes(&self) -> u64 {
+            level_size(self.level())
+        }
+    }
+
+    /// Leaf PTE flags for the `PERM_*` bits. x86 has no present-but-
+    /// unreadable pages, so every mapping is at least readable.
+    pub fn pte_flags(perm: u32) -> u64 {
+        let mut flags = PTE_PRESENT;
+        if perm & PERM_W != 0 {
+            flags |= PTE_WRITE;
+        }
+        if perm & PERM_X == 0 {
+            flags |= PTE_NX;
+        }
+        if perm & PERM_U != 0 {
+            flags |= PTE_USER;
+        }
+        if perm & PERM_UC != 0 {
+            flags |= PTE_PCD | PTE_PWT;
+        }
+        if perm & PERM_COW != 0 {
+            flags |= PTE_COW;
+        }
+        if perm & PERM_USR1 != 0 {
+            flags |= PTE_USR1;
+        }
+        if perm & PERM_USR2 != 0 {
+            flags |= PTE_USR2;
+        }
+        if perm & PERM_USR3 != 0 {
+            flags |= PTE_USR3;
+        }
+        flags
+    }
+
+    /// Leaf `pte` with its permissions replaced by `flags`. A COW leaf
+    /// stays read-only whatever `flags` grant: its frame is still shared,
+    /// and `cow_fault` makes it writable on the first write.
+    fn pte_protect(pte: u64, flags: u64) -> u64 {
+        let pte = (pte & PTE_KEEP_MASK) | flags;
+        if pte & PTE_COW != 0 {
+            return pte & !PTE_WRITE;
+        }
+        pte
+    }
+
+    /// `PERM_*` bits of a present leaf entry, without the page size
+    pub fn pte_perm(pte: u64) -> u32 {
+        let mut perm = PERM_R;
+        if pte & PTE_WRITE != 0 {
+            perm |= PERM_W;
+        }
+        if pte & PTE_NX == 0 {
+            perm |= PERM_X;
+        }
+        if pte & PTE_USER != 0 {
+            perm |= PERM_U;
+        }
+        if pte & PTE_PCD != 0 {
+            perm |= PERM_UC;
+        }
+        if pte & PTE_COW != 0 {
+            perm |= PERM_COW;
+        }
+        if pte & PTE_USR1 != 0 {
+            perm |= PERM_USR1;
+        }
+        if pte & PTE_USR2 != 0 {
+            perm |= PERM_USR2;
+        }
+        if pte & PTE_USR3 != 0 {
+            perm |= PERM_USR3;
+        }
+        perm
+    }
+
+    fn level_size(level: usize) -> u64 {
+        1 << (PGSHIFT + 9 * (level - 1))
+    }
+
+    fn pte_index(va: u64, level: usize) -> usize {
+        ((va >> (PGSHIFT + 9 * (level - 1))) as usize) & (PTES_PER_TABLE - 1)
+    }
+
+    fn is_leaf(pte: u64, level: usize) -> bool {
+        level == 1 || pte & PTE_PS != 0
+    }
+
+    /// Frame a leaf at `level` maps (bit 12 is PAT in huge entries)
+    fn leaf_addr(pte: u64, level: usize) -> PhysAddr {
+        PhysAddr::new(pte & addr_mask() & !(level_size(level) - 1))
+    }
+
+    fn table_addr(pte: u64) -> PhysAddr {
+        PhysAddr::new(pte & addr_mask())
+    }
+
+    /// Frame the leaf `pte` of `size` maps
+    pub fn pte_frame(pte: u64, size: PageSize) -> PhysAddr {
+        leaf_addr(pte, size.level())
+    }
+
+    /// Source of page-table pages
+    pub trait PgtableFrames {
+        /// Allocate a zeroed page-table page
+        fn alloc_table(&mut self) -> Option<PhysAddr>;
+        fn free_table(&mut self, pa: PhysAddr);
+        /// The entries of the page-table page at `pa`
+        fn table(&self, pa: PhysAddr) -> *mut u64;
+    }
+
+    /// Table pages from the VMPL page pool, reached through the direct map
+    /// at `PGTABLE_MMAP_BASE`
+    pub struct VmplFrames {
+        fd: i32,
+    }
+
+    impl VmplFrames {
+        pub fn new(fd: i32) -> VmplFrames {
+            VmplFrames { fd }
+        }
+    }
+
+    impl PgtableFrames for VmplFrames {
+        fn alloc_table(&mut self) -> Option<PhysAddr> {
+            let mut page = PageRef::alloc(self.fd, PageOwner::Pgtable)?;
+            page.get_mut()?.fill(0);
+            // the reference stays with the entry pointing at the table
+            Some(page.into_raw())
+        }
+
+        fn free_table(&mut self, pa: PhysAddr) {
+            drop(unsafe { PageRef::from_raw(pa) });
+        }
+
+        fn table(&self, pa: PhysAddr) -> *mut u64 {
+            pgtable_pa_to_va(pa).as_mut_ptr()
+        }
+    }
+
+    /// A 4- or 5-level page table
+    ///
+    /// The methods only edit entries; flushing the TLB is up to the caller
+    /// (the `pgtable_*` functions do it for the VMPL page table).
+    pub struct PageTable<F: PgtableFrames> {
+        root: PhysAddr,
+        frames: F,
+        mode: PagingMode,
+    }
+
+    impl<F: PgtableFrames> PageTable<F> {
+        /// Use the existing table at `root`
+        pub fn new(root: PhysAddr, frames: F, mode: PagingMode) -> PageTable<F> {
+            PageTable { root, frames, mode }
+        }
+
+        /// Allocate an empty table
+        pub fn create(mut frames: F, mode: PagingMode) -> Result<PageTable<F>, i32> {
+            let root = frames.alloc_table().ok_or(libc::ENOMEM)?;
+            Ok(PageTable { root, frames, mode })
+        }
+
+        pub fn mode(&self) -> PagingMode {
+            self.mode
+        }
+
+        pub fn root(&self) -> PhysAddr {
+            self.root
+        }
+
+        pub fn frames(&self) -> &F {
+            &self.frames
+        }
+
+        fn entry(&self, table: PhysAddr, va: u64, level: usize) -> *mut u64 {
+            unsafe { self.frames.table(table).add(pte_index(va, level)) }
+        }
+
+        fn table_is_empty(&self, table: PhysAddr) -> bool {
+            let entries = self.frames.table(table);
+            (0..PTES_PER_TABLE).all(|i| unsafe { *entries.add(i) } == 0)
+        }
+
+        /// Leaf entry mapping `va` and its level
+        fn lookup(&self, va: u64) -> Option<(*mut u64, usize)> {
+            let mut table = self.root;
+            for level in (1..=self.mode.levels()).rev() {
+                let entry = self.entry(table, va, level);
+                let pte = unsafe { *entry };
+
+                if pte & PTE_PRESENT == 0 {
+                    return None;
+                }
+                if is_leaf(pte, level) {
+                    return Some((entry, level));
+                }
+                table = table_addr(pte);
+            }
+            None
+        }
+
+        /// Check `[va, va + len)` is page aligned, canonical, and does not
+        /// start or end inside a huge page
+        fn check_range(&self, va: u64, len: usize) -> Result<Range<u64>, i32> {
+            let start = va;
+            let end = start.checked_add(len as u64).ok_or(libc::EINVAL)?;
+            if len == 0 || start % PGSIZE as u64 != 0 || len % PGSIZE != 0 {
+                return Err(libc::EINVAL);
+            }
+            let top = self.mode.va_bits() - 1;
+            if !self.mode.is_canonical(end - 1) || (start ^ (end - 1)) >> top != 0 {
+                return Err(libc::EINVAL);
+            }
+
+            for addr in [start, end] {
+                if let Some((_, level)) = self.lookup(addr) {
+                    if addr & (level_size(level) - 1) != 0 {
+                        return Err(libc::EINVAL);
+                    }
+                }
+            }
+            Ok(start..end)
+        }
+
+        /// Fail with ENOMEM if part of `range` is not mapped
+        fn check_mapped(&mut self, range: Range<u64>) -> Result<(), i32> {
+            let mut next = range.start;
+            let mut hole = false;
+            self.walk_range(range.clone(), &mut |va, size, _| {
+                hole |= va > next;
+                next = va + size.bytes();
+            });
+
+            if hole || next < range.end {
+                return Err(libc::ENOMEM);
+            }
+            Ok(())
+        }
+
+        /// Physical address `va` maps to
+        pub fn translate(&self, va: u64) -> Option<PhysAddr> {
+            let (entry, level) = self.lookup(va)?;
+            let offset = va & (level_size(level) - 1);
+            Some(leaf_addr(unsafe { *entry }, level) + offset)
+        }
+
+        /// Map `[va, va + len)` to `[pa, pa + len)` with `perm`. `PERM_BIG`
+        /// and `PERM_BIG_1GB` select 2M and 1G pages, and the range must be
+        /// aligned to that size. Pages are mapped private, with the C-bit
+        /// in SEV guests. Nothing is mapped on error.
+        pub fn map(
+            &mut self,
+            va: u64,
+            pa: PhysAddr,
+            len: usize,
+            perm: u32,
+        ) -> Result<(), i32> {
+            let size = PageSize::from_perm(perm);
+            let step = size.bytes();
+            let len = len as u64;
+            if va % step != 0 || pa.as_u64() % step != 0 || len % step != 0 {
+                return Err(libc::EINVAL);
+            }
+            self.check_range(va, len as usize)?;
+
+            let flags = pte_flags(perm);
+            let mut offset = 0;
+            while offset < len {
+                let res = self.map_one(
+                    va + offset,
+                    pa.as_u64() + offset,
+                    size.level(),
+                    flags,
+                );
+                if let Err(e) = res {
+                    if offset > 0 {
+                        let _ = self.unmap(va, offset as usize);
+                    }
+                    return Err(e);
+                }
+                offset += step;
+            }
+            Ok(())
+        }
+
+        fn map_one(&mut self, va: u64, pa: u64, target: usize, flags: u64) -> Result<(), i32> {
+            let mut table = self.root;
+            for level in (target + 1..=self.mode.levels()).rev() {
+                let entry = self.entry(table, va, level);
+                let pte = unsafe { *entry };
+
+                if pte & PTE_PRESENT == 0 {
+                    let child = self.frames.alloc_table().ok_or(libc::ENOMEM)?;
+                    unsafe { *entry = child.as_u64() | PTE_TABLE_FLAGS | sev_enc_mask() };
+                    table = child;
+                } else if is_leaf(pte, level) {
+                    return Err(libc::EEXIST);
+                } else {
+                    table = table_addr(pte);
+                }
+            }
+
+            let entry = self.entry(table, va, target);
+            if unsafe { *entry } & PTE_PRESENT != 0 {
+                return Err(libc::EEXIST);
+            }
+            let ps = if target > 1 { PTE_PS } else { 0 };
+            unsafe { *entry = pa | flags | ps | sev_enc_mask() };
+            Ok(())
+        }
+
+        /// Unmap `[va, va + len)` and free the table pages left empty.
+        /// Holes are skipped, but huge pages cannot be split.
+        pub fn unmap(&mut self, va: u64, len: usize) -> Result<(), i32> {
+            let range = self.check_range(va, len)?;
+            self.unmap_level(self.root, self.mode.levels(), range);
+            Ok(())
+        }
+
+        /// Clear the leaves of `range` under `table`, true if it ends up empty
+        fn unmap_level(&mut self, table: PhysAddr, level: usize, range: Range<u64>) -> bool {
+            let size = level_size(level);
+            let mut addr = range.start;
+            while addr < range.end {
+                let next = (addr & !(size - 1)).saturating_add(size).min(range.end);
+                let entry = self.entry(table, addr, level);
+                let pte = unsafe { *entry };
+
+                if pte & PTE_PRESENT != 0 {
+                    if is_leaf(pte, level) {
+                        unsafe { *entry = 0 };
+                    } else if self.unmap_level(table_addr(pte), level - 1, addr..next) {
+                        unsafe { *entry = 0 };
+                        self.frames.free_table(table_addr(pte));
+                    }
+                }
+                addr = next;
+            }
+            self.table_is_empty(table)
+        }
+
+        /// Change the permissions of `[va, va + len)`, which must be mapped.
+        /// The page size bits of `perm` are ignored.
+        pub fn protect(&mut self, va: u64, len: usize, perm: u32) -> Result<(), i32> {
+            let range = self.check_range(va, len)?;
+            self.check_mapped(range.clone())?;
+
+            let flags = pte_flags(perm);
+            self.walk_range(range, &mut |_, _, pte| *pte = pte_protect(*pte, flags));
+            Ok(())
+        }
+
+        /// Like `protect`, but pages that are not mapped are skipped
+        pub fn protect_present(&mut self, va: u64, len: usize, perm: u32) -> Result<(), i32> {
+            let range = self.check_range(va, len)?;
+
+            let flags = pte_flags(perm);
+            self.walk_range(range, &mut |_, _, pte| *pte = pte_protect(*pte, flags));
+            Ok(())
+        }
+
+        /// Tag the pages of `[va, va + len)` with protection key `pkey`.
+        /// Every page must be mapped, and huge pages covered whole: the
+        /// key is per leaf, so tagging part of one fails instead.
+        pub fn set_pkey(&mut self, va: u64, len: usize, pkey: u8) -> Result<(), i32> {
+            let range = self.check_range(va, len)?;
+            self.check_mapped(range.clone())?;
+
+            self.walk_range(range, &mut |_, _, pte| {
+                *pte = (*pte & !PTE_PKEY_MASK) | ((pkey as u64) << PTE_PKEY_SHIFT);
+            });
+            Ok(())
+        }
+
+        /// Physical ranges backing `[va, va + len)`, which must be mapped.
+        /// Frames that follow each other are merged into one range.
+        pub fn phys_ranges(&mut self, va: u64, len: usize) -> Result<Vec<Range<u64>>, i32> {
+            let range = self.check_range(va, len)?;
+            self.check_mapped(range.clone())?;
+
+            let mut ranges: Vec<Range<u64>> = Vec::new();
+            self.walk_range(range, &mut |_, size, pte| {
+                let pa = pte_frame(*pte, size).as_u64();
+                match ranges.last_mut() {
+                    Some(last) if last.end == pa => last.end += size.bytes(),
+                    _ => ranges.push(pa..pa + size.bytes()),
+                }
+            });
+            Ok(ranges)
+        }
+
+        /// Set or clear the C-bit `mask` in the leaves of `[va, va + len)`.
+        /// Every page must be mapped.
+        pub fn set_encrypted(
+            &mut self,
+            va: u64,
+            len: usize,
+            mask: u64,
+            encrypted: bool,
+        ) -> Result<(), i32> {
+            let range = self.check_range(va, len)?;
+            self.check_mapped(range.clone())?;
+
+            self.walk_range(range, &mut |_, _, pte| {
+                if encrypted {
+                    *pte |= mask;
+                } else {
+                    *pte &= !mask;
+                }
+            });
+            Ok(())
+        }
+
+        /// Copy the table for a new address space on `frames`. Leaves in
+        /// the lower half are shared: writable ones become read-only and
+        /// `PTE_COW` in both tables, and `share` is called on each shared
+        /// frame so it can take a reference. Upper-half entries are copied
+        /// as they are, so both tables must reach the same table pages.
+        pub fn clone_cow<G, S>(&mut self, frames: G, mut share: S) -> Result<PageTable<G>, i32>
+        where
+            G: PgtableFrames,
+            S: FnMut(PhysAddr, PageSize),
+        {
+            let mut child = PageTable::create(frames, self.mode)?;
+            let levels = self.mode.levels();
+            let (src, dst) = (self.root, child.root);
+            // references are taken once nothing can fail any more
+            let mut shared = Vec::new();
+
+            for index in 0..PTES_PER_TABLE {
+                let pte = unsafe { *self.frames.table(src).add(index) };
+                if pte & PTE_PRESENT == 0 {
+                    continue;
+                }
+                if index >= PTES_PER_TABLE / 2 {
+                    unsafe { *child.frames.table(dst).add(index) = pte };
+                    continue;
+                }
+
+                let res = child.frames.alloc_table().ok_or(libc::ENOMEM).and_then(|table| {
+                    unsafe { *child.frames.table(dst).add(index) = table.as_u64() | (pte & !addr_mask()) };
+                    self.clone_level(&mut child, table_addr(pte), table, levels - 1, &mut shared)
+                });
+                if let Err(e) = res {
+                    child.destroy(|_, _| {});
+                    return Err(e);
+                }
+            }
+
+            for (pa, size) in shared {
+                share(pa, size);
+            }
+            Ok(child)
+        }
+
+        fn clone_level<G: PgtableFrames>(
+            &mut self,
+            child: &mut PageTable<G>,
+            src: PhysAddr,
+            dst: PhysAddr,
+            level: usize,
+            shared: &mut Vec<(PhysAddr, PageSize)>,
+        ) -> Result<(), i32> {
+            for index in 0..PTES_PER_TABLE {
+                let entry = unsafe { self.frames.table(src).add(index) };
+                let mut pte = unsafe { *entry };
+                if pte & PTE_PRESENT == 0 {
+                    continue;
+                }
+
+                if is_leaf(pte, level) {
+                    if pte & PTE_WRITE != 0 {
+                        pte = (pte & !PTE_WRITE) | PTE_COW;
+                        unsafe { *entry = pte };
+                    }
+                    unsafe { *child.frames.table(dst).add(index) = pte };
+                    shared.push((leaf_addr(pte, level), PageSize::from_level(level)));
+                } else {
+                    let table = child.frames.alloc_table().ok_or(libc::ENOMEM)?;
+                    unsafe { *child.frames.table(dst).add(index) = table.as_u64() | (pte & !addr_mask()) };
+                    self.clone_level(child, table_addr(pte), table, level - 1, shared)?;
+                }
+            }
+            Ok(())
+        }
+
+        /// Tear down a table from `clone_cow`: call `release` on every leaf
+        /// of the lower half and free its table pages. The upper half is
+        /// shared and left alone.
+        pub fn destroy<R>(mut self, mut release: R)
+        where
+            R: FnMut(PhysAddr, PageSize),
+        {
+            let levels = self.mode.levels();
+            let root = self.root;
+            for index in 0..PTES_PER_TABLE / 2 {
+                let pte = unsafe { *self.frames.table(root).add(index) };
+                if pte & PTE_PRESENT != 0 {
+                    self.destroy_level(table_addr(pte), levels - 1, &mut release);
+                }
+            }
+            self.frames.free_table(root);
+        }
+
+        fn destroy_level(
+            &mut self,
+            table: PhysAddr,
+            level: usize,
+            release: &mut dyn FnMut(PhysAddr, PageSize),
+        ) {
+            for index in 0..PTES_PER_TABLE {
+                let pte = unsafe { *self.frames.table(table).add(index) };
+                if pte & PTE_PRESENT == 0 {
+                    continue;
+                }
+                if is_leaf(pte, level) {
+                    release(leaf_addr(pte, level), PageSize::from_level(level));
+                } else {
+                    self.destroy_level(table_addr(pte), level - 1, release);
+                }
+            }
+            self.frames.free_table(table);
+        }
+
+        /// Call `f` on each present leaf overlapping `[va, va + len)`, with
+        /// the address and size of the page it maps
+        pub fn walk<W>(&mut self, va: u64, len: usize, mut f: W)
+        where
+            W: FnMut(u64, PageSize, &mut u64),
+        {
+            self.walk_range(va..va.saturating_add(len as u64), &mut f);
+        }
+
+        fn walk_range(
+            &mut self,
+            range: Range<u64>,
+            f: &mut dyn FnMut(u64, PageSize, &mut u64),
+        ) {
+            self.walk_level(self.root, self.mode.levels(), range, f);
+        }
+
+        fn walk_level(
+            &mut self,
+            table: PhysAddr,
+            level: usize,
+            range: Range<u64>,
+            f: &mut dyn FnMut(u64, PageSize, &mut u64),
+        ) {
+            let size = level_size(level);
+            let mut addr = range.start;
+            while addr < range.end {
+                let base = addr & !(size - 1);
+                let next = base.saturating_add(size).min(range.end);
+                let entry = self.entry(table, addr, level);
+                let pte = unsafe { *entry };
+
+                if pte & PTE_PRESENT != 0 {
+                    if is_leaf(pte, level) {
+                        f(base, PageSize::from_level(level), unsafe { &mut *entry });
+                    } else {
+                        self.walk_level(table_addr(pte), level - 1, addr..next, f);
+                    }
+                }
+                addr = next;
+            }
+        }
+    }
+
+    lazy_static! {
+        /// The VMPL page table, set up by `pgtable_init`
+        static ref PGTABLE: Mutex<Option<PageTable<VmplFrames>>> = Mutex::new(None);
+    }
+
+    fn with_pgtable<R>(
+        f: impl FnOnce(&mut PageTable<VmplFrames>) -> Result<R, i32>,
+    ) -> Result<R, i32> {
+        let mut pgtable = PGTABLE.lock().unwrap();
+        f(pgtable.as_mut().ok_or(libc::ENODEV)?)
+    }
+
+    /// Above this many pages a full flush is cheaper than INVLPG
+    const TLB_FLUSH_ALL_THRESHOLD: u64 = 33;
+
+    /// INVLPG `va` on this CPU. `tlb::flush` takes a `VirtAddr`, which
+    /// cannot hold a 57-bit address.
+    fn flush_page(va: u64) {
+        unsafe { asm!("invlpg [{}]", in(reg) va, options(nostack, preserves_flags)) };
+    }
+
+    /// Flush `[start, end)` from the TLB of this CPU. Large ranges, such
+    /// as a whole half of the address space, reload CR3 instead.
+    pub(crate) fn flush_local(start: u64, end: u64) {
+        let start = start & !(PGSIZE as u64 - 1);
+        let pages = (end.saturating_sub(start) + PGSIZE as u64 - 1) / PGSIZE as u64;
+        if pages > TLB_FLUSH_ALL_THRESHOLD {
+            tlb::flush_all();
+            return;
+        }
+
+        for i in 0..pages {
+            flush_page(start + i * PGSIZE as u64);
+        }
+    }
+
+    /// Flush `[va, va + len)` on every CPU running VMPL code. Only the
+    /// local TLB needs flushing while a single CPU is registered.
+    pub(crate) fn pgtable_flush(va: u64, len: usize) {
+        let end = va + len as u64;
+        #[cfg(feature = "apic")]
+        if APIC_ROUTING.cpus().nth(1).is_some() {
+            if let Err(e) = tlb_shootdown(va..end) {
+                warn!("pgtable: TLB shootdown failed: {}", e);
+            }
+            return;
+        }
+        flush_local(va, end);
+    }
 
     pub fn pgtable_init(fd: i32) -> Result<(), i32> {
-        let _ = fd;
-        println!("pgtable init");
+        info!("pgtable init");
+
+        // borrow the fd, it stays owned by the caller
+        let mut file = ManuallyDrop::new(VmplFile::new(unsafe { File::from_raw_fd(fd) }));
+        let cr3 = file
+            .get_cr3()
+            .map_err(|e| e.raw_os_error().unwrap_or(libc::EIO))?;
+
+        let root = PhysAddr::new(cr3 & addr_mask());
+        let mode = paging_mode();
+        debug!("pgtable: root at {:#x}, {:?}", root, mode);
+        *PGTABLE.lock().unwrap() = Some(PageTable::new(root, VmplFrames::new(fd), mode));
         Ok(())
     }
 
     pub fn pgtable_cleanup() {
-        println!("pgtable cleanup");
+        info!("pgtable cleanup");
+        PGTABLE.lock().unwrap().take();
     }
 
+    /// Physical address of `va`, zero if it is not mapped
     pub fn pgtable_va_to_pa(va: VirtAddr) -> PhysAddr {
-        let _ = va;
-        println!("pgtable va to pa");
-        PhysAddr::zero()
+        if let Some(pa) = va.as_u64().checked_sub(PGTABLE_MMAP_BASE) {
+            if pa < PGTABLE_MMAP_SIZE {
+                return PhysAddr::new(pa);
+            }
+        }
+        with_pgtable(|pgtable| Ok(pgtable.translate(va.as_u64())))
+            .ok()
+            .flatten()
+            .unwrap_or(PhysAddr::zero())
     }
 
+    /// Address of `pa` in the direct map
     pub fn pgtable_pa_to_va(pa: PhysAddr) -> VirtAddr {
-        let _ = pa;
-        println!("pgtable pa to va");
-        VirtAddr::zero()
+        assert!(pa.as_u64() < PGTABLE_MMAP_SIZE, "pgtable: {:#x} is past the direct map", pa);
+        VirtAddr::new(PGTABLE_MMAP_BASE + pa.as_u64())
     }
 
-    pub fn pgtable_make_pages_shared(va: VirtAddr, len: usize) -> Result<(), i32> {
-        let _ = va;
-        let _ = len;
-        println!("pgtable make pages shared");
+    pub fn pgtable_map(va: u64, pa: PhysAddr, len: usize, perm: u32) -> Result<(), i32> {
+        with_pgtable(|pgtable| pgtable.map(va, pa, len, perm))
+    }
+
+    pub fn pgtable_unmap(va: u64, len: usize) -> Result<(), i32> {
+        with_pgtable(|pgtable| pgtable.unmap(va, len))?;
+        pgtable_flush(va, len);
         Ok(())
     }
 
-    pub fn pgtable_make_pages_private(va: VirtAddr, len: usize) -> Result<(), i32> {
-        let _ = va;
-        let _ = len;
-        println!("pgtable make pages private");
+    pub fn pgtable_protect(va: u64, len: usize, perm: u32) -> Result<(), i32> {
+        with_pgtable(|pgtable| pgtable.protect(va, len, perm))?;
+        pgtable_flush(va, len);
         Ok(())
     }
 
-    pub fn mem_allocate_frames(len: u64) -> Result<(), i32> {
-        let _ = len;
-        println!("mem allocate frames");
+    pub fn pgtable_protect_present(va: u64, len: usize, perm: u32) -> Result<(), i32> {
+        with_pgtable(|pgtable| pgtable.protect_present(va, len, perm))?;
+        pgtable_flush(va, len);
         Ok(())
     }
 
-    pub fn mem_free_frames(len: u64) -> Result<(), i32> {
-        let _ = len;
-        println!("mem free frames");
+    /// Call `f` on each leaf of the VMPL page table in `[va, va + len)`
+    pub fn pgtable_walk<W>(va: u64, len: usize, f: W) -> Result<(), i32>
+    where
+        W: FnMut(u64, PageSize, &mut u64),
+    {
+        with_pgtable(|pgtable| {
+            pgtable.walk(va, len, f);
+            Ok(())
+        })
+    }
+
+    /// Page-aligned bounds of `[va, va + len)`
+    fn page_range(va: u64, len: usize) -> (u64, usize) {
+        let start = va & !(PGSIZE as u64 - 1);
+        let end = (va + len as u64 + PGSIZE as u64 - 1) & !(PGSIZE as u64 - 1);
+        (start, (end - start) as usize)
+    }
+
+    /// Share the pages of `[va, va + len)` with the hypervisor, e.g. for
+    /// I/O buffers. Their frames go through a Page State Change and are
+    /// then mapped without the C-bit. Every page must be mapped, and the
+    /// contents are lost.
+    pub fn pgtable_make_pages_shared(va: VirtAddr, len: usize) -> Result<(), i32> {
+        make_pages_shared(va, len, vc_make_pages_shared)
+    }
+
+    /// Share the page at `va` that becomes the GHCB of this CPU. There is
+    /// no GHCB to send a Page State Change through yet, so its frame is
+    /// converted with the MSR protocol.
+    pub fn pgtable_make_ghcb_shared(va: VirtAddr) -> Result<(), i32> {
+        make_pages_shared(va, PGSIZE, vc_make_pages_shared_msr)
+    }
+
+    fn make_pages_shared(va: VirtAddr, len: usize, convert: fn(PhysAddr, PhysAddr)) -> Result<(), i32> {
+        let mask = sev_enc_mask();
+        if mask == 0 {
+            return Ok(());
+        }
+
+        let (start, len) = page_range(va.as_u64(), len);
+        // the GHCB calls translate addresses, so the table is not held
+        let ranges = with_pgtable(|pgtable| pgtable.phys_ranges(start, len))?;
+        for range in &ranges {
+            convert(PhysAddr::new(range.start), PhysAddr::new(range.end));
+        }
+
+        with_pgtable(|pgtable| pgtable.set_encrypted(start, len, mask, false))?;
+        pgtable_flush(start, len);
+        debug!("pgtable: shared {:#x}+{:#x}", start, len);
         Ok(())
     }
-}
\ No newline at end of file
+
+    /// Make the pages of `[va, va + len)` private again: they are mapped
+    /// with the C-bit, and their frames go through a Page State Change and
+    /// are validated. Every page must be mapped.
+    pub fn pgtable_make_pages_private(va: VirtAddr, len: usize) -> Result<(), i32> {
+        let mask = sev_enc_mask();
+        if mask == 0 {
+            return Ok(());
+        }
+
+        let (start, len) = page_range(va.as_u64(), len);
+        let ranges = with_pgtable(|pgtable| {
+            let ranges = pgtable.phys_ranges(start, len)?;
+            pgtable.set_encrypted(start, len, mask, true)?;
+            Ok(ranges)
+        })?;
+        pgtable_flush(start, len);
+
+        for range in &ranges {
+            vc_make_pages_private(PhysAddr::new(range.start), PhysAddr::new(range.end));
+        }
+        debug!("pgtable: made {:#x}+{:#x} private", start, len);
+        Ok(())
+    }
+
+    /// Tag the pages of `[va, va + len)` with protection key `pkey`. Every
+    /// page must be mapped. The caller flushes the TLB.
+    pub fn pgtable_set_pkey(va: u64, len: usize, pkey: u8) -> Result<(), i32> {
+        let (start, len) = page_range(va, len);
+        with_pgtable(|pgtable| pgtable.set_pkey(start, len, pkey))
+    }
+
+    /// Page-table frames for tests, shared with the modules that build
+    /// on `PageTable`
+    #[cfg(test)]
+    pub(crate) mod test_frames {
+        use super::*;
+
+        /// Table pages in process memory, at made-up physical addresses
+        pub(crate) struct Arena {
+            pages: Vec<*mut [u64; PTES_PER_TABLE]>,
+            free: Vec<usize>,
+            pub(crate) live: usize,
+        }
+
+        impl Arena {
+            pub(crate) fn new() -> Arena {
+                Arena {
+                    pages: Vec::new(),
+                    free: Vec::new(),
+                    live: 0,
+                }
+            }
+
+            fn index(pa: PhysAddr) -> usize {
+                (pa.as_u64() >> PGSHIFT) as usize - 1
+            }
+        }
+
+        impl PgtableFrames for Arena {
+            fn alloc_table(&mut self) -> Option<PhysAddr> {
+                let index = match self.free.pop() {
+                    Some(index) => {
+                        unsafe { *self.pages[index] = [0; PTES_PER_TABLE] };
+                        index
+                    }
+                    None => {
+                        self.pages
+                            .push(Box::into_raw(Box::new([0; PTES_PER_TABLE])));
+                        self.pages.len() - 1
+                    }
+                };
+                self.live += 1;
+                Some(PhysAddr::new((index as u64 + 1) << PGSHIFT))
+            }
+
+            fn free_table(&mut self, pa: PhysAddr) {
+                self.free.push(Arena::index(pa));
+                self.live -= 1;
+            }
+
+            fn table(&self, pa: PhysAddr) -> *mut u64 {
+                self.pages[Arena::index(pa)] as *mut u64
+            }
+        }
+
+        impl Drop for Arena {
+            fn drop(&mut self) {
+                for page in self.pages.drain(..) {
+                    drop(unsafe { Box::from_raw(page) });
+                }
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::test_frames::Arena;
+        use super::*;
+
+        fn pgtable() -> PageTable<Arena> {
+            PageTable::create(Arena::new(), PagingMode::Level4).unwrap()
+        }
+
+        fn pa(addr: u64) -> PhysAddr {
+            PhysAddr::new(addr)
+        }
+
+        #[test]
+        fn perm_to_flags() {
+            assert_eq!(pte_flags(PERM_R), PTE_PRESENT | PTE_NX);
+            assert_eq!(pte_flags(PERM_R | PERM_W | PERM_X), PTE_PRESENT | PTE_WRITE);
+            assert_eq!(
+                pte_flags(PERM_R | PERM_U | PERM_UC | PERM_COW),
+                PTE_PRESENT | PTE_NX | PTE_USER | PTE_PCD | PTE_PWT | PTE_COW
+            );
+            for perm in [
+                PERM_R,
+                PERM_R | PERM_W | PERM_U,
+                PERM_R | PERM_X | PERM_USR1 | PERM_USR3,
+            ] {
+                assert_eq!(pte_perm(pte_flags(perm)), perm);
+            }
+        }
+
+        #[test]
+        fn map_and_translate_4k() {
+            let mut pgtable = pgtable();
+            pgtable
+                .map(0x40_0000, pa(0x1234_5000), 2 * PGSIZE, PERM_R | PERM_W)
+                .unwrap();
+
+            assert_eq!(pgtable.translate(0x40_0000), Some(pa(0x1234_5000)));
+            assert_eq!(pgtable.translate(0x40_1abc), Some(pa(0x1234_6abc)));
+            assert_eq!(pgtable.translate(0x40_2000), None);
+            // root, PDPT, PD and PT
+            assert_eq!(pgtable.frames().live, 4);
+        }
+
+        #[test]
+        fn map_huge_pages() {
+            let mut pgtable = pgtable();
+            pgtable
+                .map(
+                    0x20_0000,
+                    pa(0x4000_0000),
+                    PAGE_2MB_SIZE as usize,
+                    PERM_R | PERM_BIG,
+                )
+                .unwrap();
+            pgtable
+                .map(
+                    0x8000_0000,
+                    pa(0x1_0000_0000),
+                    PAGE_1GB_SIZE as usize,
+                    PERM_R | PERM_BIG_1GB,
+                )
+                .unwrap();
+
+            assert_eq!(pgtable.translate(0x21_2345), Some(pa(0x4001_2345)));
+            assert_eq!(pgtable.translate(0xa123_4567), Some(pa(0x1_2123_4567)));
+
+            let mut sizes = Vec::new();
+            pgtable.walk(0, 0x1_0000_0000, |va, size, _| sizes.push((va, size)));
+            assert_eq!(
+                sizes,
+                vec![
+                    (0x20_0000, PageSize::Size2M),
+                    (0x8000_0000, PageSize::Size1G)
+                ]
+            );
+        }
+
+        #[test]
+        fn map_rejects_bad_ranges() {
+            let mut pgtable = pgtable();
+            let big = PERM_R | PERM_BIG;
+            assert_eq!(
+                pgtable.map(0x1000, pa(0x20_0000), PAGE_2MB_SIZE as usize, big),
+                Err(libc::EINVAL)
+            );
+            assert_eq!(
+                pgtable.map(0x20_0000, pa(0x1000), PAGE_2MB_SIZE as usize, big),
+                Err(libc::EINVAL)
+            );
+            assert_eq!(
+                pgtable.map(0x1000, pa(0x1000), 0x800, PERM_R),
+                Err(libc::EINVAL)
+            );
+            // crosses from the lower into the upper canonical half
+            assert_eq!(
+                pgtable.map(0x7fff_ffff_f000, pa(0), 2 * PGSIZE, PERM_R),
+                Err(libc::EINVAL)
+            );
+        }
+
+        #[test]
+        fn map_existing_fails_and_rolls_back() {
+            let mut pgtable = pgtable();
+            pgtable.map(0x3000, pa(0x9000), PGSIZE, PERM_R).unwrap();
+
+            assert_eq!(
+                pgtable.map(0x1000, pa(0x1000), 4 * PGSIZE, PERM_R),
+                Err(libc::EEXIST)
+            );
+            assert_eq!(pgtable.translate(0x1000), None);
+            assert_eq!(pgtable.translate(0x2000), None);
+            assert_eq!(pgtable.translate(0x3000), Some(pa(0x9000)));
+
+            // the 2M slot holds a page table
+            assert_eq!(
+                pgtable.map(0, pa(0), PAGE_2MB_SIZE as usize, PERM_R | PERM_BIG),
+                Err(libc::EEXIST)
+            );
+        }
+
+        #[test]
+        fn unmap_frees_tables() {
+            let mut pgtable = pgtable();
+            pgtable
+                .map(0x40_0000, pa(0x10_0000), 4 * PGSIZE, PERM_R)
+                .unwrap();
+
+            pgtable.unmap(0x40_1000, PGSIZE).unwrap();
+            assert_eq!(pgtable.translate(0x40_1000), None);
+            assert_eq!(pgtable.translate(0x40_2000), Some(pa(0x10_2000)));
+            assert_eq!(pgtable.frames().live, 4);
+
+            // holes are fine
+            pgtable.unmap(0x40_0000, 4 * PGSIZE).unwrap();
+            assert_eq!(pgtable.frames().live, 1);
+        }
+
+        #[test]
+        fn unmap_cannot_split_huge_pages() {
+            let mut pgtable = pgtable();
+            pgtable
+                .map(
+                    0x20_0000,
+                    pa(0x20_0000),
+                    PAGE_2MB_SIZE as usize,
+                    PERM_R | PERM_BIG,
+                )
+                .unwrap();
+
+            assert_eq!(pgtable.unmap(0x20_1000, PGSIZE), Err(libc::EINVAL));
+            assert_eq!(pgtable.unmap(0x1f_f000, 2 * PGSIZE), Err(libc::EINVAL));
+            pgtable.unmap(0x20_0000, PAGE_2MB_SIZE as usize).unwrap();
+            assert_eq!(pgtable.translate(0x20_0000), None);
+        }
+
+        #[test]
+        fn protect_keeps_mapping() {
+            let mut pgtable = pgtable();
+            pgtable
+                .map(0x1000, pa(0x5000), 2 * PGSIZE, PERM_R | PERM_W | PERM_U)
+                .unwrap();
+
+            pgtable
+                .protect(0x1000, 2 * PGSIZE, PERM_R | PERM_X)
+                .unwrap();
+            assert_eq!(pgtable.translate(0x2000), Some(pa(0x6000)));
+
+            let mut perms = Vec::new();
+            pgtable.walk(0x1000, 2 * PGSIZE, |_, _, pte| perms.push(pte_perm(*pte)));
+            assert_eq!(perms, vec![PERM_R | PERM_X, PERM_R | PERM_X]);
+
+            assert_eq!(
+                pgtable.protect(0x1000, 3 * PGSIZE, PERM_R),
+                Err(libc::ENOMEM)
+            );
+
+            // the hole is skipped
+            pgtable
+                .protect_present(0x1000, 3 * PGSIZE, PERM_R | PERM_U)
+                .unwrap();
+            let mut perms = Vec::new();
+            pgtable.walk(0x1000, 3 * PGSIZE, |_, _, pte| perms.push(pte_perm(*pte)));
+            assert_eq!(perms, vec![PERM_R | PERM_U, PERM_R | PERM_U]);
+        }
+
+        #[test]
+        fn clone_cow_shares_frames() {
+            let mut parent = pgtable();
+            parent
+                .map(0x1000, pa(0x5000), 2 * PGSIZE, PERM_R | PERM_W | PERM_U)
+                .unwrap();
+            parent.map(0x3000, pa(0x9000), PGSIZE, PERM_R).unwrap();
+            parent
+                .map(0x20_0000, pa(0x40_0000), PAGE_2MB_SIZE as usize, PERM_R | PERM_W | PERM_BIG)
+                .unwrap();
+
+            let mut shared = Vec::new();
+            let mut child = parent
+                .clone_cow(Arena::new(), |pa, size| shared.push((pa.as_u64(), size)))
+                .unwrap();
+            assert_eq!(
+                shared,
+                vec![
+                    (0x5000, PageSize::Size4K),
+                    (0x6000, PageSize::Size4K),
+                    (0x9000, PageSize::Size4K),
+                    (0x40_0000, PageSize::Size2M),
+                ]
+            );
+
+            // writable leaves turn read-only and COW on both sides
+            let mut ptes = Vec::new();
+            parent.walk(0x1000, 0x40_0000, |_, _, pte| ptes.push(*pte));
+            assert!(ptes.iter().all(|pte| pte & PTE_WRITE == 0));
+            assert_eq!(ptes.iter().filter(|pte| *pte & PTE_COW != 0).count(), 3);
+            let mut ptes = Vec::new();
+            child.walk(0x1000, 0x40_0000, |_, _, pte| ptes.push(*pte));
+            assert_eq!(ptes.iter().filter(|pte| *pte & PTE_COW != 0).count(), 3);
+            assert_eq!(child.translate(0x2abc), Some(pa(0x6abc)));
+            assert_eq!(child.translate(0x30_0000), Some(pa(0x50_0000)));
+
+            let mut released = 0;
+            child.destroy(|_, _| released += 1);
+            assert_eq!(released, 4);
+        }
+
+        #[test]
+        fn protect_keeps_cow_read_only() {
+            let mut parent = pgtable();
+            parent
+                .map(0x1000, pa(0x5000), PGSIZE, PERM_R | PERM_W | PERM_U)
+                .unwrap();
+            let mut child = parent.clone_cow(Arena::new(), |_, _| {}).unwrap();
+            child
+                .map(0x2000, pa(0x6000), PGSIZE, PERM_R | PERM_U)
+                .unwrap();
+
+            // mprotect(PROT_WRITE) must not make the shared frame writable
+            child
+                .protect(0x1000, 2 * PGSIZE, PERM_R | PERM_W | PERM_U)
+                .unwrap();
+            let mut ptes = Vec::new();
+            child.walk(0x1000, 2 * PGSIZE, |_, _, pte| ptes.push(*pte));
+            assert_eq!(ptes[0] & (PTE_COW | PTE_WRITE), PTE_COW);
+            assert_eq!(ptes[1] & (PTE_COW | PTE_WRITE), PTE_WRITE);
+
+            // nor does dropping and restoring write access lose the COW
+            child.protect(0x1000, PGSIZE, PERM_R | PERM_U).unwrap();
+            child
+                .protect_present(0x1000, PGSIZE, PERM_R | PERM_W | PERM_U)
+                .unwrap();
+            let mut ptes = Vec::new();
+            child.walk(0x1000, PGSIZE, |_, _, pte| ptes.push(*pte));
+            assert_eq!(ptes[0] & (PTE_COW | PTE_WRITE), PTE_COW);
+        }
+
+        #[test]
+        fn direct_map_round_trip() {
+            let pa = PhysAddr::new(0x1_2345_6000);
+            let va = pgtable_pa_to_va(pa);
+            assert_eq!(va.as_u64(), PGTABLE_MMAP_BASE + 0x1_2345_6000);
+            // no page table needed for the direct map
+            assert_eq!(pgtable_va_to_pa(VirtAddr::new(va.as_u64() + 0x10)).as_u64(), 0x1_2345_6010);
+            assert!(paging_mode().is_canonical(PGTABLE_MMAP_BASE + PGTABLE_MMAP_SIZE - 1));
+        }
+
+        #[test]
+        fn set_pkey_tags_leaves() {
+            let mut pgtable = pgtable();
+            pgtable.map(0x1000, pa(0x1000), PGSIZE, PERM_R).unwrap();
+            pgtable.set_pkey(0x1000, PGSIZE, 5).unwrap();
+
+            pgtable.walk(0x1000, PGSIZE, |_, _, pte| {
+                assert_eq!((*pte & PTE_PKEY_MASK) >> PTE_PKEY_SHIFT, 5);
+                assert_eq!(*pte & PTE_ADDR_MASK, 0x1000);
+            });
+            assert_eq!(pgtable.set_pkey(0x2000, PGSIZE, 5), Err(libc::ENOMEM));
+        }
+
+        #[test]
+        fn set_pkey_keeps_huge_pages_whole() {
+            let mut pgtable = pgtable();
+            let huge = PAGE_2MB_SIZE as usize;
+            pgtable.map(0x20_0000, pa(0x20_0000), huge, PERM_R | PERM_BIG).unwrap();
+
+            assert_eq!(pgtable.set_pkey(0x20_0000, PGSIZE, 5), Err(libc::EINVAL));
+            assert_eq!(pgtable.set_pkey(0x20_1000, huge - PGSIZE, 5), Err(libc::EINVAL));
+            pgtable.walk(0x20_0000, huge, |_, _, pte| {
+                assert_eq!(*pte & PTE_PKEY_MASK, 0);
+            });
+
+            pgtable.set_pkey(0x20_0000, huge, 5).unwrap();
+            pgtable.walk(0x20_0000, huge, |_, _, pte| {
+                assert_eq!((*pte & PTE_PKEY_MASK) >> PTE_PKEY_SHIFT, 5);
+            });
+        }
+
+        #[test]
+        fn phys_ranges_and_c_bit() {
+            let c_bit = 1 << 51;
+            let mut pgtable = pgtable();
+            pgtable.map(0x1000, pa(0x10000), 2 * PGSIZE, PERM_R | PERM_W).unwrap();
+            pgtable.map(0x3000, pa(0x40000), PGSIZE, PERM_R).unwrap();
+            pgtable.map(0x20_0000, pa(0x20_0000), PAGE_2MB_SIZE as usize, PERM_R | PERM_BIG).unwrap();
+
+            let ranges = pgtable.phys_ranges(0x1000, 3 * PGSIZE).unwrap();
+            assert_eq!(ranges, vec![0x10000..0x12000, 0x40000..0x41000]);
+            let ranges = pgtable.phys_ranges(0x20_0000, PAGE_2MB_SIZE as usize).unwrap();
+            assert_eq!(ranges, vec![0x20_0000..0x40_0000]);
+            assert_eq!(pgtable.phys_ranges(0x1000, 4 * PGSIZE), Err(libc::ENOMEM));
+
+            pgtable.set_encrypted(0x1000, 3 * PGSIZE, c_bit, true).unwrap();
+            pgtable.set_encrypted(0x1000, PGSIZE, c_bit, false).unwrap();
+            let mut bits = Vec::new();
+            pgtable.walk(0x1000, 3 * PGSIZE, |_, _, pte| bits.push(*pte & c_bit != 0));
+            assert_eq!(bits, vec![false, true, true]);
+            assert_eq!(pgtable.set_encrypted(0x20_1000, PGSIZE, c_bit, true), Err(libc::EINVAL));
+        }
+
+        #[test]
+        fn upper_half() {
+            let mut pgtable = pgtable();
+            pgtable
+                .map(
+                    0xffff_8000_0000_0000,
+                    pa(0x7000),
+                    PGSIZE,
+                    PERM_R | PERM_W,
+                )
+                .unwrap();
+            assert_eq!(
+                pgtable.translate(0xffff_8000_0000_0010),
+                Some(pa(0x7010))
+            );
+
+            let mut leaves = Vec::new();
+            pgtable.walk(0xffff_8000_0000_0000, PGSIZE, |va, _, _| leaves.push(va));
+            assert_eq!(leaves, vec![0xffff_8000_0000_0000]);
+        }
+
+        #[test]
+        fn canonical_addresses() {
+            let (l4, l5) = (PagingMode::Level4, PagingMode::Level5);
+            assert!(l4.is_canonical(0x7fff_ffff_ffff));
+            assert!(!l4.is_canonical(0x8000_0000_0000));
+            assert!(l4.is_canonical(0xffff_8000_0000_0000));
+            assert!(l5.is_canonical(0x8000_0000_0000));
+            assert!(l5.is_canonical(0x00ff_ffff_ffff_ffff));
+            assert!(!l5.is_canonical(0x0100_0000_0000_0000));
+            assert!(l5.is_canonical(0xff00_0000_0000_0000));
+            assert_eq!(l4.user_va_end(), 1 << 47);
+            assert_eq!(l5.user_va_end(), 1 << 56);
+            // LA57 extends from bit 56, not bit 47
+            assert_eq!(l4.sign_extend(0x0000_8000_0000_0000), 0xffff_8000_0000_0000);
+            assert_eq!(l5.sign_extend(0x0000_8000_0000_0000), 0x0000_8000_0000_0000);
+            assert_eq!(l5.sign_extend(0x0100_0000_0000_0000), 0xff00_0000_0000_0000);
+        }
+
+        #[test]
+        fn la57_tables() {
+            let mode = PagingMode::Level5;
+            let mut pgtable = PageTable::create(Arena::new(), mode).unwrap();
+            let low = 0x00ff_0000_0020_0000;
+            let high = 0xff00_0000_0000_0000;
+            assert!(mode.is_canonical(low) && mode.is_canonical(high));
+
+            pgtable.map(low, pa(0x20_0000), PGSIZE, PERM_R).unwrap();
+            pgtable
+                .map(
+                    high,
+                    pa(0x4000_0000),
+                    PAGE_2MB_SIZE as usize,
+                    PERM_R | PERM_BIG,
+                )
+                .unwrap();
+            // PML5, P4D, PDPT, PD, PT for the first, P4D, PDPT, PD for the second
+            assert_eq!(pgtable.frames().live, 8);
+            assert_eq!(pgtable.translate(low), Some(pa(0x20_0000)));
+
+            let mut leaves = Vec::new();
+            pgtable.walk(high, PAGE_2MB_SIZE as usize, |va, size, _| leaves.push((va, size)));
+            assert_eq!(leaves, vec![(0xff00_0000_0000_0000, PageSize::Size2M)]);
+
+            // a 4-level table cannot hold it
+            let mut pgtable4 = PageTable::create(Arena::new(), PagingMode::Level4).unwrap();
+            assert_eq!(
+                pgtable4.map(low, pa(0x20_0000), PGSIZE, PERM_R),
+                Err(libc::EINVAL)
+            );
+
+            pgtable.unmap(low, PGSIZE).unwrap();
+            pgtable.unmap(high, PAGE_2MB_SIZE as usize).unwrap();
+            assert_eq!(pgtable.frames().live, 1);
+        }
+    }
+}
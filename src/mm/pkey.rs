@@ -0,0 +1,361 @@
+/// Memory protection key domains
+/// Pages tagged with a key are only reachable while PKRU grants that key
+/// access, which gives cheap isolation between parts of the process.
+#[cfg(feature = "mm")]
+pub mod pkey {
+    use core::arch::x86_64::__cpuid_count;
+    use std::arch::asm;
+    use std::fmt::{self, Display};
+    use std::marker::PhantomData;
+    use std::ops::Range;
+    use std::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+    use std::sync::RwLock;
+
+    use lazy_static::lazy_static;
+    use log::{debug, info};
+    use x86_64::registers::control::{Cr4, Cr4Flags};
+
+    use crate::error::VmplError;
+    use crate::ghcb::globals::PAGE_SIZE;
+    #[cfg(not(feature = "apic"))]
+    use crate::mm::flush_local;
+    use crate::mm::{paging_mode, pgtable_set_pkey};
+    #[cfg(feature = "apic")]
+    use crate::sys::smp::smp::tlb_shootdown;
+    use crate::sys::percpu::this_cpu;
+
+    /// Number of protection keys, key 0 is the default for every page
+    pub const NR_PKEYS: u8 = 16;
+
+    const PKRU_AD: u32 = 0x1;
+    const PKRU_WD: u32 = 0x2;
+
+    /// CPUID.(EAX=07H,ECX=0):ECX.PKU[bit 3]
+    const CPUID_7_ECX_PKU: u32 = 1 << 3;
+
+    /// Allocated keys, key 0 is always taken
+    static PKEY_BITMAP: AtomicU16 = AtomicU16::new(1);
+
+    /// Set once CR4.PKE is on; RDPKRU and WRPKRU raise #UD before that
+    static PKU_ENABLED: AtomicBool = AtomicBool::new(false);
+
+    lazy_static! {
+        /// Ranges tagged with `pkey_mprotect`, to report faults
+        static ref PKEY_RANGES: RwLock<Vec<(Range<u64>, Pkey)>> = RwLock::new(Vec::new());
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Pkey(u8);
+
+    impl Pkey {
+        pub const DEFAULT: Pkey = Pkey(0);
+
+        pub fn id(&self) -> u8 {
+            self.0
+        }
+    }
+
+    impl Display for Pkey {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "pkey {}", self.0)
+        }
+    }
+
+    /// Rights PKRU grants on a key
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum PkeyRights {
+        ReadWrite,
+        ReadOnly,
+        NoAccess,
+    }
+
+    impl PkeyRights {
+        fn bits(&self) -> u32 {
+            match self {
+                PkeyRights::ReadWrite => 0,
+                PkeyRights::ReadOnly => PKRU_WD,
+                PkeyRights::NoAccess => PKRU_AD | PKRU_WD,
+            }
+        }
+    }
+
+    pub fn rdpkru() -> u32 {
+        let pkru: u32;
+        unsafe {
+            asm!(
+                "rdpkru",
+                in("ecx") 0,
+                out("eax") pkru,
+                out("edx") _,
+                options(nomem, nostack, preserves_flags),
+            );
+        }
+        pkru
+    }
+
+    pub fn wrpkru(pkru: u32) {
+        unsafe {
+            asm!(
+                "wrpkru",
+                in("eax") pkru,
+                in("ecx") 0,
+                in("edx") 0,
+                options(nostack, preserves_flags),
+            );
+        }
+    }
+
+    pub fn pku_supported() -> bool {
+        unsafe { __cpuid_count(7, 0) }.ecx & CPUID_7_ECX_PKU != 0
+    }
+
+    /// Turn on protection keys for the current CPU. Every CPU entering
+    /// VMPL mode calls this; without PKU, keys cannot be allocated.
+    pub fn pkey_init() {
+        if !pku_supported() {
+            info!("pkey: PKU not supported");
+            return;
+        }
+        unsafe { Cr4::update(|flags| flags.insert(Cr4Flags::PROTECTION_KEY_USER)) };
+        PKU_ENABLED.store(true, Ordering::SeqCst);
+    }
+
+    fn pkru_with(pkru: u32, pkey: Pkey, rights: PkeyRights) -> u32 {
+        let shift = pkey.0 as u32 * 2;
+        (pkru & !((PKRU_AD | PKRU_WD) << shift)) | (rights.bits() << shift)
+    }
+
+    /// Set the rights of the current CPU on `pkey`
+    pub fn pkey_set(pkey: Pkey, rights: PkeyRights) {
+        wrpkru(pkru_with(rdpkru(), pkey, rights));
+    }
+
+    /// Allocate a key with `rights` as the initial rights
+    pub fn pkey_alloc(rights: PkeyRights) -> Result<Pkey, VmplError> {
+        if !PKU_ENABLED.load(Ordering::SeqCst) {
+            return Err(VmplError::Sys(libc::ENOSPC));
+        }
+
+        let mut bitmap = PKEY_BITMAP.load(Ordering::SeqCst);
+        loop {
+            let id = (!bitmap).trailing_zeros() as u8;
+            if id >= NR_PKEYS {
+                return Err(VmplError::Sys(libc::ENOSPC));
+            }
+            match PKEY_BITMAP.compare_exchange(
+                bitmap,
+                bitmap | (1 << id),
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            ) {
+                Ok(_) => {
+                    let pkey = Pkey(id);
+                    pkey_set(pkey, rights);
+                    return Ok(pkey);
+                }
+                Err(current) => bitmap = current,
+            }
+        }
+    }
+
+    /// Release `pkey`. Pages still tagged with it go back to the default key.
+    pub fn pkey_free(pkey: Pkey) -> Result<(), VmplError> {
+        if pkey == Pkey::DEFAULT || pkey.0 >= NR_PKEYS {
+            return Err(VmplError::Sys(libc::EINVAL));
+        }
+
+        let ranges: Vec<Range<u64>> = PKEY_RANGES
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(_, k)| *k == pkey)
+            .map(|(r, _)| r.clone())
+            .collect();
+        for range in ranges {
+            pkey_mprotect(range, Pkey::DEFAULT)?;
+        }
+
+        pkey_set(pkey, PkeyRights::ReadWrite);
+        PKEY_BITMAP.fetch_and(!(1 << pkey.0), Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Tag `range` with `pkey` in the VMPL page tables
+    pub fn pkey_mprotect(range: Range<u64>, pkey: Pkey) -> Result<(), VmplError> {
+        if range.start % PAGE_SIZE != 0 || range.end <= range.start {
+            return Err(VmplError::Sys(libc::EINVAL));
+        }
+        if pkey != Pkey::DEFAULT && PKEY_BITMAP.load(Ordering::SeqCst) & (1 << pkey.0) == 0 {
+            return Err(VmplError::Sys(libc::EINVAL));
+        }
+
+        let mode = paging_mode();
+        if !mode.is_canonical(range.start) || !mode.is_canonical(range.end - 1) {
+            return Err(VmplError::Sys(libc::EINVAL));
+        }
+        let len = (range.end - range.start) as usize;
+        pgtable_set_pkey(range.start, len, pkey.0).map_err(VmplError::Sys)?;
+
+        #[cfg(feature = "apic")]
+        tlb_shootdown(range.clone())?;
+        #[cfg(not(feature = "apic"))]
+        flush_local(range.start, range.end);
+
+        pkey_ranges_tag(&mut PKEY_RANGES.write().unwrap(), range.clone(), pkey);
+        debug!("pkey: tagged {:#x}-{:#x} with {}", range.start, range.end, pkey);
+        Ok(())
+    }
+
+    /// Record `range` as tagged with `pkey`. Ranges it partly covers keep
+    /// their key outside of it.
+    fn pkey_ranges_tag(ranges: &mut Vec<(Range<u64>, Pkey)>, range: Range<u64>, pkey: Pkey) {
+        let old = std::mem::take(ranges);
+        for (r, key) in old {
+            if r.end <= range.start || r.start >= range.end {
+                ranges.push((r, key));
+                continue;
+            }
+            if r.start < range.start {
+                ranges.push((r.start..range.start, key));
+            }
+            if r.end > range.end {
+                ranges.push((range.end..r.end, key));
+            }
+        }
+        if pkey != Pkey::DEFAULT {
+            ranges.push((range, pkey));
+        }
+    }
+
+    /// Key `addr` was tagged with, if any
+    pub fn pkey_lookup(addr: u64) -> Option<Pkey> {
+        PKEY_RANGES
+            .read()
+            .ok()?
+            .iter()
+            .find(|(r, _)| r.contains(&addr))
+            .map(|(_, k)| *k)
+    }
+
+    /// An isolation domain: memory tagged with `pkey`, reachable with
+    /// `rights` while the domain is entered
+    #[derive(Debug, Clone, Copy)]
+    pub struct Domain {
+        pkey: Pkey,
+        rights: PkeyRights,
+    }
+
+    impl Domain {
+        /// Allocate a key for a new domain, inaccessible outside of it
+        pub fn new(rights: PkeyRights) -> Result<Domain, VmplError> {
+            Ok(Domain {
+                pkey: pkey_alloc(PkeyRights::NoAccess)?,
+                rights,
+            })
+        }
+
+        pub fn pkey(&self) -> Pkey {
+            self.pkey
+        }
+
+        /// Put `range` in this domain
+        pub fn protect(&self, range: Range<u64>) -> Result<(), VmplError> {
+            pkey_mprotect(range, self.pkey)
+        }
+    }
+
+    /// Rights granted by `enter_domain`, revoked when dropped
+    ///
+    /// PKRU is per CPU, so the guard stays on the thread that entered.
+    pub struct DomainGuard {
+        saved_pkru: u32,
+        saved_pkey: Option<i32>,
+        _not_send: PhantomData<*const ()>,
+    }
+
+    /// Grant the rights of `domain` on the current CPU until the guard is
+    /// dropped. The active key is kept in the per-CPU `pkey` slot.
+    pub fn enter_domain(domain: &Domain) -> DomainGuard {
+        let saved_pkru = rdpkru();
+        wrpkru(pkru_with(saved_pkru, domain.pkey, domain.rights));
+
+        let saved_pkey = this_cpu().map(|percpu| unsafe {
+            let old = (*percpu).pkey();
+            (*percpu).set_pkey(domain.pkey.0 as i32);
+            old
+        });
+
+        DomainGuard {
+            saved_pkru,
+            saved_pkey,
+            _not_send: PhantomData,
+        }
+    }
+
+    /// Leave the domain entered with `guard`
+    pub fn leave_domain(guard: DomainGuard) {
+        drop(guard);
+    }
+
+    impl Drop for DomainGuard {
+        fn drop(&mut self) {
+            wrpkru(self.saved_pkru);
+            if let (Some(pkey), Some(percpu)) = (self.saved_pkey, this_cpu()) {
+                unsafe { (*percpu).set_pkey(pkey) };
+            }
+        }
+    }
+
+    /// Key of the domain the current CPU is in (0 outside any domain)
+    pub fn current_domain_pkey() -> Pkey {
+        this_cpu().map_or(Pkey::DEFAULT, |percpu| Pkey(unsafe { (*percpu).pkey() } as u8))
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn tag(ranges: &mut Vec<(Range<u64>, Pkey)>, range: Range<u64>, key: u8) {
+            pkey_ranges_tag(ranges, range, Pkey(key));
+            ranges.sort_by_key(|(r, _)| r.start);
+        }
+
+        #[test]
+        fn pkru_bits() {
+            assert_eq!(pkru_with(0, Pkey(1), PkeyRights::NoAccess), 0b1100);
+            assert_eq!(pkru_with(0xffff_ffff, Pkey(15), PkeyRights::ReadOnly), 0xbfff_ffff);
+            assert_eq!(pkru_with(0b1100, Pkey(1), PkeyRights::ReadWrite), 0);
+        }
+
+        #[test]
+        fn retag_splits_ranges() {
+            let mut ranges = Vec::new();
+            tag(&mut ranges, 0x1000..0x9000, 1);
+            tag(&mut ranges, 0x3000..0x5000, 2);
+            assert_eq!(
+                ranges,
+                vec![(0x1000..0x3000, Pkey(1)), (0x3000..0x5000, Pkey(2)), (0x5000..0x9000, Pkey(1))]
+            );
+
+            // back to the default key leaves a hole
+            tag(&mut ranges, 0x2000..0x4000, 0);
+            assert_eq!(
+                ranges,
+                vec![(0x1000..0x2000, Pkey(1)), (0x4000..0x5000, Pkey(2)), (0x5000..0x9000, Pkey(1))]
+            );
+        }
+
+        #[test]
+        fn retag_covering_ranges() {
+            let mut ranges = Vec::new();
+            tag(&mut ranges, 0x1000..0x2000, 1);
+            tag(&mut ranges, 0x3000..0x4000, 2);
+            tag(&mut ranges, 0x8000..0x9000, 3);
+            tag(&mut ranges, 0x1000..0x5000, 4);
+            assert_eq!(ranges, vec![(0x1000..0x5000, Pkey(4)), (0x8000..0x9000, Pkey(3))]);
+
+            tag(&mut ranges, 0..0x10000, 0);
+            assert!(ranges.is_empty());
+        }
+    }
+}
@@ -1,24 +1,600 @@
 #[cfg(feature = "mm")]
 pub mod vm {
-    use std::collections::HashMap;
-    use std::sync::Mutex;
+    use std::collections::BTreeMap;
+    use std::fmt::{self, Display};
+    use std::fs::File;
+    use std::mem::ManuallyDrop;
+    use std::ops::Bound;
+    use std::os::unix::io::FromRawFd;
+    use std::sync::RwLock;
+    use std::time::{SystemTime, UNIX_EPOCH};
 
-    use x86_64::VirtAddr;
+    use lazy_static::lazy_static;
+    use libc::pid_t;
+    use log::{debug, info, warn};
 
-    use super::{FitAlgorithm, VmplVma};
+    use crate::ghcb::globals::RMP_4K;
+    use crate::mm::{paging_mode, parse_procmaps, FitAlgorithm, Prot, VmplVma, PGSIZE};
+    use crate::sys::ioctl::vmpl_ioctl::VmplFile;
 
     pub struct VmplVm {
-        vma_dict: HashMap<VirtAddr, VmplVma>,
-        fit_algorithm: FitAlgorithm, // Assuming FitAlgorithm is an enum you've defined
+        /// VMAs by start address, never overlapping
+        vma_dict: BTreeMap<u64, VmplVma>,
+        fit_algorithm: FitAlgorithm,
         va_start: u64,
         va_end: u64,
-        phys_limit: usize,
-        mmap_base: usize,
-        start_stack: usize,
-        lock: Mutex<()>,
+        /// Program break, from where the heap starts to its current end
+        start_brk: u64,
+        brk: u64,
+        /// Where `FitAlgorithm::NextFit` resumes
+        next_fit: u64,
+        /// xorshift state for `FitAlgorithm::RandomFit`
+        rand_state: u64,
     }
 
-    pub fn vm_init(fd: i32) -> Result<(), i32> {
-        todo!("vm_init");
+    lazy_static! {
+        /// Address space of the current process as seen from VMPL mode
+        pub static ref VMPL_VM: RwLock<Option<VmplVm>> = RwLock::new(None);
     }
-}
\ No newline at end of file
+
+    fn page_align(len: u64) -> u64 {
+        (len + PGSIZE as u64 - 1) & !(PGSIZE as u64 - 1)
+    }
+
+    impl VmplVm {
+        /// Empty address space covering the lower canonical half, which is
+        /// 47 bits with 4-level paging and 56 bits with LA57
+        pub fn new(fit_algorithm: FitAlgorithm) -> VmplVm {
+            VmplVm::with_range(fit_algorithm, PGSIZE as u64, paging_mode().user_va_end())
+        }
+
+        /// Empty address space managing `[va_start, va_end)`
+        pub fn with_range(fit_algorithm: FitAlgorithm, va_start: u64, va_end: u64) -> VmplVm {
+            let seed = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_nanos() as u64);
+
+            VmplVm {
+                vma_dict: BTreeMap::new(),
+                fit_algorithm,
+                va_start,
+                va_end,
+                start_brk: 0,
+                brk: 0,
+                next_fit: va_start,
+                rand_state: seed | 1,
+            }
+        }
+
+        pub fn va_start(&self) -> u64 {
+            self.va_start
+        }
+
+        pub fn va_end(&self) -> u64 {
+            self.va_end
+        }
+
+        pub fn fit_algorithm(&self) -> FitAlgorithm {
+            self.fit_algorithm
+        }
+
+        pub fn set_fit_algorithm(&mut self, fit_algorithm: FitAlgorithm) {
+            self.fit_algorithm = fit_algorithm;
+        }
+
+        pub fn start_brk(&self) -> u64 {
+            self.start_brk
+        }
+
+        pub fn brk(&self) -> u64 {
+            self.brk
+        }
+
+        pub fn set_brk(&mut self, start_brk: u64, brk: u64) {
+            self.start_brk = start_brk;
+            self.brk = brk;
+        }
+
+        /// Bytes in VMAs that count against `RLIMIT_DATA`
+        pub fn data_size(&self) -> u64 {
+            self.iter().filter(|vma| vma.is_data()).map(|vma| vma.len()).sum()
+        }
+
+        /// Seed `FitAlgorithm::RandomFit`, for reproducible layouts
+        pub fn set_random_seed(&mut self, seed: u64) {
+            self.rand_state = seed | 1;
+        }
+
+        /// Whether `[start, end)` lies inside the managed range
+        pub fn in_range(&self, start: u64, end: u64) -> bool {
+            self.va_start <= start && start < end && end <= self.va_end
+        }
+
+        /// Find the VMA covering `addr`, if any
+        pub fn find_vma(&self, addr: u64) -> Option<&VmplVma> {
+            self.vma_dict
+                .range(..=addr)
+                .next_back()
+                .map(|(_, vma)| vma)
+                .filter(|vma| vma.contains(addr))
+        }
+
+        /// VMAs overlapping `[start, end)`, in address order
+        pub fn find_overlapping(&self, start: u64, end: u64) -> impl Iterator<Item = &VmplVma> {
+            // the VMA before `start` may reach into the range
+            let first = self
+                .vma_dict
+                .range(..start)
+                .next_back()
+                .filter(|(_, vma)| vma.end() > start)
+                .map_or(start, |(key, _)| *key);
+
+            self.vma_dict
+                .range(first..end)
+                .map(|(_, vma)| vma)
+                .filter(move |vma| vma.overlaps(start, end))
+        }
+
+        pub fn overlaps(&self, start: u64, end: u64) -> bool {
+            self.find_overlapping(start, end).next().is_some()
+        }
+
+        pub fn iter(&self) -> impl Iterator<Item = &VmplVma> {
+            self.vma_dict.values()
+        }
+
+        pub fn len(&self) -> usize {
+            self.vma_dict.len()
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.vma_dict.is_empty()
+        }
+
+        /// Add `vma`, which must not overlap an existing one. It is merged
+        /// with compatible neighbours.
+        pub fn insert(&mut self, vma: VmplVma) -> Result<(), i32> {
+            let (start, end) = (vma.start(), vma.end());
+            if start % PGSIZE as u64 != 0 || end % PGSIZE as u64 != 0 || !self.in_range(start, end) {
+                return Err(libc::EINVAL);
+            }
+            if self.overlaps(start, end) {
+                return Err(libc::EEXIST);
+            }
+
+            self.vma_dict.insert(start, vma);
+            self.merge(start);
+            Ok(())
+        }
+
+        /// Reserve `len` bytes at a place chosen by the fit algorithm and
+        /// return its start
+        pub fn allocate(&mut self, len: u64, prot: Prot, flags: u64) -> Result<u64, i32> {
+            let len = page_align(len);
+            let start = self.find_free(len, PGSIZE as u64).ok_or(libc::ENOMEM)?;
+            self.insert(VmplVma::new(start, start + len, flags, prot, 0))?;
+            Ok(start)
+        }
+
+        /// Unused ranges, in address order
+        fn gaps(&self) -> Vec<(u64, u64)> {
+            let mut gaps = Vec::new();
+            let mut cursor = self.va_start;
+            for vma in self.vma_dict.values() {
+                if vma.start() > cursor {
+                    gaps.push((cursor, vma.start()));
+                }
+                cursor = cursor.max(vma.end());
+            }
+            if cursor < self.va_end {
+                gaps.push((cursor, self.va_end));
+            }
+            gaps
+        }
+
+        /// Start of a free, `align`-aligned range of `len` bytes, picked by
+        /// the fit algorithm
+        pub fn find_free(&mut self, len: u64, align: u64) -> Option<u64> {
+            if len == 0 || !align.is_power_of_two() {
+                return None;
+            }
+
+            // aligned start and usable size of each gap that fits
+            let fits: Vec<(u64, u64, u64)> = self
+                .gaps()
+                .into_iter()
+                .filter_map(|(start, end)| {
+                    let aligned = start.checked_add(align - 1)? & !(align - 1);
+                    (aligned < end && end - aligned >= len).then(|| (aligned, end, end - aligned))
+                })
+                .collect();
+
+            let start = match self.fit_algorithm {
+                FitAlgorithm::FirstFit => fits.first().map(|(start, _, _)| *start),
+                FitAlgorithm::NextFit => {
+                    let cursor = self.next_fit;
+                    fits.iter()
+                        .find_map(|(start, end, _)| {
+                            let aligned = cursor.max(*start).checked_add(align - 1)? & !(align - 1);
+                            (aligned < *end && end - aligned >= len).then_some(aligned)
+                        })
+                        .or_else(|| fits.first().map(|(start, _, _)| *start))
+                }
+                FitAlgorithm::BestFit => fits
+                    .iter()
+                    .min_by_key(|(start, _, size)| (*size, *start))
+                    .map(|(start, _, _)| *start),
+                FitAlgorithm::WorstFit => fits
+                    .iter()
+                    .max_by_key(|(start, _, size)| (*size, u64::MAX - *start))
+                    .map(|(start, _, _)| *start),
+                FitAlgorithm::RandomFit => {
+                    if fits.is_empty() {
+                        None
+                    } else {
+                        let (start, _, size) = fits[(self.next_random() % fits.len() as u64) as usize];
+                        let slots = (size - len) / align + 1;
+                        Some(start + (self.next_random() % slots) * align)
+                    }
+                }
+            }?;
+
+            self.next_fit = start + len;
+            Some(start)
+        }
+
+        fn next_random(&mut self) -> u64 {
+            let mut x = self.rand_state;
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            self.rand_state = x;
+            x
+        }
+
+        /// Split the VMA containing `addr` so that a VMA starts at `addr`
+        pub fn split(&mut self, addr: u64) -> Result<(), i32> {
+            if addr % PGSIZE as u64 != 0 {
+                return Err(libc::EINVAL);
+            }
+            let key = match self.find_vma(addr) {
+                Some(vma) if vma.start() == addr => return Ok(()),
+                Some(vma) => vma.start(),
+                None => return Err(libc::ENOENT),
+            };
+
+            let upper = self.vma_dict.get_mut(&key).unwrap().split_at(addr);
+            self.vma_dict.insert(addr, upper);
+            Ok(())
+        }
+
+        /// Merge the VMA at `start` with compatible neighbours
+        pub fn merge(&mut self, mut start: u64) {
+            let prev = self
+                .vma_dict
+                .range(..start)
+                .next_back()
+                .map(|(key, _)| *key);
+            if let Some(prev) = prev {
+                if self.vma_dict[&prev].can_merge(&self.vma_dict[&start]) {
+                    let vma = self.vma_dict.remove(&start).unwrap();
+                    self.vma_dict.get_mut(&prev).unwrap().merge(vma);
+                    start = prev;
+                }
+            }
+
+            let next = self
+                .vma_dict
+                .range((Bound::Excluded(start), Bound::Unbounded))
+                .next()
+                .map(|(key, _)| *key);
+            if let Some(next) = next {
+                if self.vma_dict[&start].can_merge(&self.vma_dict[&next]) {
+                    let vma = self.vma_dict.remove(&next).unwrap();
+                    self.vma_dict.get_mut(&start).unwrap().merge(vma);
+                }
+            }
+        }
+
+        /// Remove `[start, end)`, splitting VMAs that straddle its ends, and
+        /// return what was removed
+        pub fn remove(&mut self, start: u64, end: u64) -> Result<Vec<VmplVma>, i32> {
+            if start % PGSIZE as u64 != 0 || end % PGSIZE as u64 != 0 || start >= end {
+                return Err(libc::EINVAL);
+            }
+
+            for addr in [start, end] {
+                match self.split(addr) {
+                    Ok(()) | Err(libc::ENOENT) => {}
+                    Err(e) => return Err(e),
+                }
+            }
+
+            let keys: Vec<u64> = self.vma_dict.range(start..end).map(|(key, _)| *key).collect();
+            Ok(keys
+                .into_iter()
+                .filter_map(|key| self.vma_dict.remove(&key))
+                .collect())
+        }
+
+        /// Add the mappings of process `pid` (`None` for this process) that
+        /// lie in the managed range, and return how many were added
+        pub fn import_procmaps(&mut self, pid: Option<pid_t>) -> Result<usize, i32> {
+            let mut entries = Vec::new();
+            parse_procmaps(pid, |entry| entries.push(entry.clone()))
+                .map_err(|e| e.raw_os_error().unwrap_or(libc::EIO))?;
+
+            let mut count = 0;
+            for entry in entries {
+                if !self.in_range(entry.begin(), entry.end()) {
+                    debug!("vm: skipping {:?} at {:#x}", entry.vma_type(), entry.begin());
+                    continue;
+                }
+                match self.insert(VmplVma::from(&entry)) {
+                    Ok(()) => count += 1,
+                    Err(libc::EEXIST) => {
+                        warn!("vm: {:#x}-{:#x} already tracked", entry.begin(), entry.end())
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            Ok(count)
+        }
+
+        /// Set the protection of `[start, end)`, which must be covered by
+        /// VMAs. VMAs straddling the ends are split.
+        pub fn protect(&mut self, start: u64, end: u64, prot: Prot) -> Result<(), i32> {
+            if start % PGSIZE as u64 != 0 || end % PGSIZE as u64 != 0 || start >= end {
+                return Err(libc::EINVAL);
+            }
+            let mut next = start;
+            for vma in self.find_overlapping(start, end) {
+                if vma.start() > next {
+                    break;
+                }
+                next = vma.end();
+            }
+            if next < end {
+                return Err(libc::ENOMEM);
+            }
+
+            self.split(start)?;
+            match self.split(end) {
+                Ok(()) | Err(libc::ENOENT) => {}
+                Err(e) => return Err(e),
+            }
+
+            let keys: Vec<u64> = self.vma_dict.range(start..end).map(|(key, _)| *key).collect();
+            for key in &keys {
+                self.vma_dict.get_mut(key).unwrap().set_prot(prot);
+            }
+            for key in keys.into_iter().chain([end]) {
+                if self.vma_dict.contains_key(&key) {
+                    self.merge(key);
+                }
+            }
+            Ok(())
+        }
+
+        pub fn dump(&self) {
+            println!("{}", self);
+        }
+    }
+
+    impl Display for VmplVm {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            writeln!(
+                f,
+                "vmpl-vm: {:x}-{:x} {:?}, {} vmas",
+                self.va_start,
+                self.va_end,
+                self.fit_algorithm,
+                self.vma_dict.len()
+            )?;
+            for vma in self.vma_dict.values() {
+                writeln!(f, "{}", vma)?;
+            }
+            Ok(())
+        }
+    }
+
+    /// Give the pages of `[start, end)` the VMPL permissions matching `prot`
+    pub fn vm_set_vmpl(fd: i32, start: u64, end: u64, prot: Prot) -> Result<(), i32> {
+        // borrow the fd, it stays owned by the caller
+        let mut file = ManuallyDrop::new(VmplFile::new(unsafe { File::from_raw_fd(fd) }));
+        let nr_pages = u32::try_from((end - start) / PGSIZE as u64).map_err(|_| libc::EOVERFLOW)?;
+
+        file.set_user_vmpl_pages(start, RMP_4K, prot.vmpl_attrs() as u32, nr_pages)
+            .map_err(|e| e.raw_os_error().unwrap_or(libc::EIO))
+    }
+
+    /// Give the pages of `vma` the VMPL permissions matching its protection
+    pub fn vm_apply_vmpl(fd: i32, vma: &VmplVma) -> Result<(), i32> {
+        vm_set_vmpl(fd, vma.start(), vma.end(), vma.prot())
+    }
+
+    /// Set up the address space. With `map_full`, the mappings the process
+    /// already has are imported and keep their access in VMPL mode.
+    pub fn vm_init(fd: i32, map_full: bool) -> Result<(), i32> {
+        info!("vm init");
+
+        let mut vm = VmplVm::new(FitAlgorithm::FirstFit);
+        info!("vm: managing {:#x}-{:#x}", vm.va_start(), vm.va_end());
+
+        if map_full {
+            let count = vm.import_procmaps(None)?;
+            info!("vm: imported {} mappings", count);
+            for vma in vm.iter() {
+                vm_apply_vmpl(fd, vma)?;
+            }
+        }
+
+        *VMPL_VM.write().unwrap() = Some(vm);
+        Ok(())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        const PG: u64 = PGSIZE as u64;
+
+        fn vm(fit: FitAlgorithm) -> VmplVm {
+            VmplVm::with_range(fit, 0x1000, 0x10_0000)
+        }
+
+        fn vma(start: u64, end: u64, prot: Prot) -> VmplVma {
+            VmplVma::new(start, end, 0, prot, 0)
+        }
+
+        fn ranges(vm: &VmplVm) -> Vec<(u64, u64)> {
+            vm.iter().map(|vma| (vma.start(), vma.end())).collect()
+        }
+
+        /// Leave gaps of 2, 8 and 4 pages, then the rest
+        fn fragmented(fit: FitAlgorithm) -> VmplVm {
+            let mut vm = vm(fit);
+            vm.insert(vma(0x1000, 0x2000, Prot::Read)).unwrap();
+            vm.insert(vma(0x4000, 0x5000, Prot::Write)).unwrap();
+            vm.insert(vma(0xd000, 0xe000, Prot::Read)).unwrap();
+            vm.insert(vma(0x12000, 0x13000, Prot::Write)).unwrap();
+            vm
+        }
+
+        #[test]
+        fn insert_and_find() {
+            let mut vm = vm(FitAlgorithm::FirstFit);
+            vm.insert(vma(0x2000, 0x4000, Prot::Read)).unwrap();
+            vm.insert(vma(0x8000, 0x9000, Prot::Exec)).unwrap();
+
+            assert_eq!(vm.find_vma(0x3fff).map(|v| v.start()), Some(0x2000));
+            assert!(vm.find_vma(0x4000).is_none());
+            assert!(vm.find_vma(0x1000).is_none());
+            assert_eq!(vm.find_vma(0x8000).map(|v| v.end()), Some(0x9000));
+
+            let hits: Vec<u64> = vm.find_overlapping(0x3000, 0x8001).map(|v| v.start()).collect();
+            assert_eq!(hits, vec![0x2000, 0x8000]);
+        }
+
+        #[test]
+        fn insert_rejects_overlap_and_bounds() {
+            let mut vm = vm(FitAlgorithm::FirstFit);
+            vm.insert(vma(0x2000, 0x4000, Prot::Read)).unwrap();
+
+            assert_eq!(vm.insert(vma(0x3000, 0x5000, Prot::Write)), Err(libc::EEXIST));
+            assert_eq!(vm.insert(vma(0x1000, 0x3000, Prot::Write)), Err(libc::EEXIST));
+            assert_eq!(vm.insert(vma(0x0, 0x1000, Prot::Write)), Err(libc::EINVAL));
+            assert_eq!(vm.insert(vma(0xf_f000, 0x10_1000, Prot::Write)), Err(libc::EINVAL));
+            assert_eq!(vm.insert(vma(0x5800, 0x6000, Prot::Write)), Err(libc::EINVAL));
+        }
+
+        #[test]
+        fn merge_neighbours() {
+            let mut vm = vm(FitAlgorithm::FirstFit);
+            vm.insert(vma(0x2000, 0x3000, Prot::Read)).unwrap();
+            vm.insert(vma(0x4000, 0x5000, Prot::Read)).unwrap();
+            vm.insert(vma(0x3000, 0x4000, Prot::Read)).unwrap();
+            assert_eq!(ranges(&vm), vec![(0x2000, 0x5000)]);
+
+            // different protection stays apart
+            vm.insert(vma(0x5000, 0x6000, Prot::Write)).unwrap();
+            assert_eq!(ranges(&vm), vec![(0x2000, 0x5000), (0x5000, 0x6000)]);
+        }
+
+        #[test]
+        fn split_and_remove() {
+            let mut vm = vm(FitAlgorithm::FirstFit);
+            vm.insert(vma(0x2000, 0x8000, Prot::Read)).unwrap();
+
+            vm.split(0x4000).unwrap();
+            assert_eq!(ranges(&vm), vec![(0x2000, 0x4000), (0x4000, 0x8000)]);
+            assert_eq!(vm.split(0x9000), Err(libc::ENOENT));
+
+            let removed = vm.remove(0x3000, 0x6000).unwrap();
+            let removed: Vec<(u64, u64)> = removed.iter().map(|v| (v.start(), v.end())).collect();
+            assert_eq!(removed, vec![(0x3000, 0x4000), (0x4000, 0x6000)]);
+            assert_eq!(ranges(&vm), vec![(0x2000, 0x3000), (0x6000, 0x8000)]);
+
+            // removing a hole is fine
+            assert!(vm.remove(0x9000, 0xa000).unwrap().is_empty());
+        }
+
+        #[test]
+        fn protect_splits_and_merges() {
+            let mut vm = vm(FitAlgorithm::FirstFit);
+            vm.insert(vma(0x2000, 0x8000, Prot::Read)).unwrap();
+
+            vm.protect(0x3000, 0x5000, Prot::Read | Prot::Write).unwrap();
+            assert_eq!(ranges(&vm), vec![(0x2000, 0x3000), (0x3000, 0x5000), (0x5000, 0x8000)]);
+            assert_eq!(vm.find_vma(0x4000).unwrap().prot(), Prot::Read | Prot::Write);
+            assert_eq!(vm.data_size(), 2 * PG);
+
+            // back to the old protection, one VMA again
+            vm.protect(0x3000, 0x5000, Prot::Read).unwrap();
+            assert_eq!(ranges(&vm), vec![(0x2000, 0x8000)]);
+
+            assert_eq!(vm.protect(0x7000, 0x9000, Prot::None), Err(libc::ENOMEM));
+            assert_eq!(ranges(&vm), vec![(0x2000, 0x8000)]);
+        }
+
+        #[test]
+        fn first_fit() {
+            let mut vm = fragmented(FitAlgorithm::FirstFit);
+            assert_eq!(vm.allocate(2 * PG, Prot::Exec, 0), Ok(0x2000));
+            assert_eq!(vm.allocate(3 * PG, Prot::Exec, 0), Ok(0x5000));
+        }
+
+        #[test]
+        fn next_fit() {
+            let mut vm = fragmented(FitAlgorithm::NextFit);
+            assert_eq!(vm.allocate(PG, Prot::Exec, 0), Ok(0x2000));
+            // resumes after the last allocation instead of the lowest gap
+            assert_eq!(vm.allocate(PG, Prot::None, 0), Ok(0x3000));
+            assert_eq!(vm.allocate(PG, Prot::Exec, 0), Ok(0x5000));
+
+            vm.remove(0x2000, 0x4000).unwrap();
+            assert_eq!(vm.allocate(PG, Prot::None, 0), Ok(0x6000));
+        }
+
+        #[test]
+        fn best_fit() {
+            let mut vm = fragmented(FitAlgorithm::BestFit);
+            assert_eq!(vm.allocate(3 * PG, Prot::Exec, 0), Ok(0xe000));
+            assert_eq!(vm.allocate(2 * PG, Prot::Exec, 0), Ok(0x2000));
+        }
+
+        #[test]
+        fn worst_fit() {
+            let mut vm = fragmented(FitAlgorithm::WorstFit);
+            assert_eq!(vm.allocate(PG, Prot::Exec, 0), Ok(0x13000));
+        }
+
+        #[test]
+        fn random_fit() {
+            let mut vm = fragmented(FitAlgorithm::RandomFit);
+            vm.set_random_seed(42);
+            for _ in 0..32 {
+                let start = vm.allocate(PG, Prot::Exec, 0).unwrap();
+                assert_eq!(start % PG, 0);
+                assert!(vm.in_range(start, start + PG));
+            }
+            // every allocation got its own pages
+            assert_eq!(vm.iter().map(|v| v.len()).sum::<u64>(), 36 * PG);
+        }
+
+        #[test]
+        fn aligned_and_exhausted() {
+            let mut vm = fragmented(FitAlgorithm::FirstFit);
+            assert_eq!(vm.find_free(PG, 0x10000), Some(0x10000));
+            assert_eq!(vm.find_free(0x100_0000, PG), None);
+            assert_eq!(vm.allocate(0x100_0000, Prot::Read, 0), Err(libc::ENOMEM));
+        }
+
+        #[test]
+        fn vmpl_page_count_overflow() {
+            // rejected before the fd is used
+            let end = (u32::MAX as u64 + 1) * PG;
+            assert_eq!(vm_set_vmpl(0, 0, end, Prot::Read), Err(libc::EOVERFLOW));
+        }
+    }
+}
@@ -1,7 +1,12 @@
 #[cfg(feature = "mm")]
 pub mod vma {
-    use crate::BIT;
-    use libc::{PROT_EXEC, PROT_NONE, PROT_READ, PROT_WRITE};
+    use crate::ghcb::globals::{VMPL_R, VMPL_W, VMPL_X_USER};
+    use crate::{getter_func, BIT};
+    use libc::{pid_t, MAP_PRIVATE, MAP_SHARED, PROT_EXEC, PROT_NONE, PROT_READ, PROT_WRITE};
+    use std::fs::File;
+    use std::io::{self, BufRead, BufReader};
+    use std::ops::BitOr;
+    use std::str::FromStr;
     use std::{
         default,
         fmt::{self, Display},
@@ -28,6 +33,8 @@ pub mod vma {
     pub const PERM_UTEXT: u32 = PERM_R | PERM_U | PERM_W;
     pub const PERM_USTACK: u32 = PERM_UTEXT;
 
+    /// What backs a mapping, from the path column of `/proc/<pid>/maps`
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
     pub enum VmplVmaType {
         File,
         Anonymous,
@@ -41,46 +48,38 @@ pub mod vma {
 
     impl From<&str> for VmplVmaType {
         fn from(path: &str) -> Self {
-            if !path.is_empty() && path.chars().next().unwrap() != '[' {
-                return VmplVmaType::File;
-            }
-            if path.is_empty() {
+            if path.is_empty() || path.starts_with("[anon:") {
                 return VmplVmaType::Anonymous;
             }
-            if path == "[heap]" {
-                return VmplVmaType::Heap;
-            }
-            if path.starts_with("[stack") {
-                if path == "[stack]"
-                    || (path.chars().nth(6).unwrap() == ':' && path.chars().last().unwrap() == ']')
-                {
-                    return VmplVmaType::Stack;
-                }
-            }
-            if path == "[vsyscall]" {
-                return VmplVmaType::Vsyscall;
-            }
-            if path == "[vdso]" {
-                return VmplVmaType::Vdso;
+            if !path.starts_with('[') {
+                return VmplVmaType::File;
             }
-            if path == "[vvar]" {
-                return VmplVmaType::Vvar;
+            match path {
+                "[heap]" => VmplVmaType::Heap,
+                "[stack]" => VmplVmaType::Stack,
+                "[vsyscall]" => VmplVmaType::Vsyscall,
+                "[vdso]" => VmplVmaType::Vdso,
+                "[vvar]" => VmplVmaType::Vvar,
+                // thread stacks on older kernels
+                _ if path.starts_with("[stack:") && path.ends_with(']') => VmplVmaType::Stack,
+                _ => VmplVmaType::Unknown,
             }
-            VmplVmaType::Unknown
         }
     }
 
+    /// One line of `/proc/<pid>/maps`
+    #[derive(Debug, Default, Clone, PartialEq, Eq)]
     pub struct ProcmapEntry {
         begin: u64,
         end: u64,
-        offset: u32,
+        offset: u64,
         r: bool,    // Readable
         w: bool,    // Writable
         x: bool,    // Executable
         p: bool,    // Private (or shared)
-        minor: u32, // New field for device
-        major: u32, // New field for device
-        inode: u32, // New field for inode
+        minor: u32, // Device minor
+        major: u32, // Device major
+        inode: u64,
         path: Option<String>,
     }
 
@@ -88,14 +87,14 @@ pub mod vma {
         pub fn new(
             begin: u64,
             end: u64,
-            offset: u32,
+            offset: u64,
             r: bool,
             w: bool,
             x: bool,
             p: bool,
             minor: u32,
             major: u32,
-            inode: u32,
+            inode: u64,
             path: Option<String>,
         ) -> Self {
             Self {
@@ -112,159 +111,211 @@ pub mod vma {
                 path,
             }
         }
-    }
 
-    impl
-        From<(
-            u64,
-            u64,
-            u32,
-            bool,
-            bool,
-            bool,
-            bool,
-            u32,
-            u32,
-            u32,
-            Option<String>,
-        )> for ProcmapEntry
-    {
-        fn from(
-            entry: (
-                u64,
-                u64,
-                u32,
-                bool,
-                bool,
-                bool,
-                bool,
-                u32,
-                u32,
-                u32,
-                Option<String>,
-            ),
-        ) -> Self {
-            Self {
-                begin: entry.0,
-                end: entry.1,
-                offset: entry.2,
-                r: entry.3,
-                w: entry.4,
-                x: entry.5,
-                p: entry.6,
-                minor: entry.7,
-                major: entry.8,
-                inode: entry.9,
-                path: entry.10,
+        getter_func!(begin, u64);
+        getter_func!(end, u64);
+        getter_func!(offset, u64);
+        getter_func!(r, bool);
+        getter_func!(w, bool);
+        getter_func!(x, bool);
+        getter_func!(p, bool);
+        getter_func!(minor, u32);
+        getter_func!(major, u32);
+        getter_func!(inode, u64);
+
+        pub fn path(&self) -> Option<&str> {
+            self.path.as_deref()
+        }
+
+        pub fn len(&self) -> u64 {
+            self.end - self.begin
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.end == self.begin
+        }
+
+        pub fn vma_type(&self) -> VmplVmaType {
+            VmplVmaType::from(self.path().unwrap_or(""))
+        }
+
+        pub fn prot(&self) -> Prot {
+            let mut prot = Prot::None;
+            if self.r {
+                prot = prot | Prot::Read;
             }
+            if self.w {
+                prot = prot | Prot::Write;
+            }
+            if self.x {
+                prot = prot | Prot::Exec;
+            }
+            prot
         }
     }
 
-    type ProcmapsCallback = fn(&ProcmapEntry, &mut ());
+    fn invalid_line(line: &str) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("bad maps line: {:?}", line),
+        )
+    }
+
+    impl FromStr for ProcmapEntry {
+        type Err = io::Error;
 
-    #[cfg(feature = "vm")]
-    fn parse_procmaps(callback: ProcmapsCallback, arg: &mut ()) -> Result<(), std::io::Error> {
-        let file = File::open("/proc/self/maps")?;
-        let reader = BufReader::new(file);
+        /// Parse `begin-end perms offset major:minor inode [path]`. The path
+        /// is everything after the inode and may contain spaces.
+        fn from_str(line: &str) -> Result<Self, Self::Err> {
+            let hex = |s: &str| u64::from_str_radix(s, 16).map_err(|_| invalid_line(line));
 
+            let mut fields = line.splitn(6, ' ');
+            let mut next = || fields.next().ok_or_else(|| invalid_line(line));
+            let (range, perms, offset, dev, inode) = (next()?, next()?, next()?, next()?, next()?);
+            let path = fields.next().map(str::trim_start).unwrap_or("");
+
+            let (begin, end) = range.split_once('-').ok_or_else(|| invalid_line(line))?;
+            let (major, minor) = dev.split_once(':').ok_or_else(|| invalid_line(line))?;
+            let perms = perms.as_bytes();
+            if perms.len() != 4 {
+                return Err(invalid_line(line));
+            }
+
+            Ok(ProcmapEntry {
+                begin: hex(begin)?,
+                end: hex(end)?,
+                offset: hex(offset)?,
+                r: perms[0] == b'r',
+                w: perms[1] == b'w',
+                x: perms[2] == b'x',
+                p: perms[3] == b'p',
+                minor: hex(minor)? as u32,
+                major: hex(major)? as u32,
+                inode: inode.parse().map_err(|_| invalid_line(line))?,
+                path: (!path.is_empty()).then(|| path.to_string()),
+            })
+        }
+    }
+
+    /// Call `callback` for each mapping in `reader`, in maps format
+    pub fn parse_procmaps_from<R: BufRead, F: FnMut(&ProcmapEntry)>(
+        reader: R,
+        mut callback: F,
+    ) -> io::Result<()> {
         for line in reader.lines() {
             let line = line?;
-            let mut path = [0; 256];
-            let mut entry = ProcmapEntry {
-                begin: 0,
-                end: 0,
-                offset: 0,
-                r: false,
-                w: false,
-                x: false,
-                p: false,
-                minor: 0,
-                major: 0,
-                inode: 0,
-                path: None,
-            };
-            use scan_fmt::scan_fmt;
-
-            let mut begin: u64;
-            let mut begin: u64;
-            let mut end: u64;
-            let mut read: char;
-            let mut write: char;
-            let mut execute: char;
-            let mut private: char;
-            let mut offset: u32;
-            let mut minor: u32;
-
-            let value = scan_fmt!(
-                &line,
-                "{x}-{x} {}{}{}{} {x} {:x}:{:x} {} {}",
-                begin,
-                end,
-                read,
-                write,
-                execute,
-                private,
-                offset,
-                minor,
-                major,
-                inode,
-                path
-            );
-            if let Some(value) = value {
-                callback(&ProcmapEntry::from(value), arg);
+            if line.is_empty() {
+                continue;
             }
+            callback(&line.parse()?);
         }
-
         Ok(())
     }
 
-    #[derive(Debug, Default)]
-    enum Prot {
-        #[default]
-        None,
-        Read,
-        Write,
-        Exec,
+    /// Call `callback` for each mapping of process `pid`, or of this process
+    /// if `None`
+    pub fn parse_procmaps<F: FnMut(&ProcmapEntry)>(pid: Option<pid_t>, callback: F) -> io::Result<()> {
+        let path = match pid {
+            Some(pid) => format!("/proc/{}/maps", pid),
+            None => "/proc/self/maps".to_string(),
+        };
+        parse_procmaps_from(BufReader::new(File::open(path)?), callback)
+    }
+
+    /// mmap protection, a combination of `PROT_*` bits. The constants keep
+    /// the names of the single-bit values, `Prot::Read | Prot::Write` is
+    /// read-write.
+    #[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct Prot(i32);
+
+    #[allow(non_upper_case_globals)]
+    impl Prot {
+        pub const None: Prot = Prot(PROT_NONE);
+        pub const Read: Prot = Prot(PROT_READ);
+        pub const Write: Prot = Prot(PROT_WRITE);
+        pub const Exec: Prot = Prot(PROT_EXEC);
+
+        pub fn bits(&self) -> i32 {
+            self.0
+        }
+
+        pub fn contains(&self, other: Prot) -> bool {
+            self.0 & other.0 == other.0
+        }
+
+        /// Page-table permissions for user-mode access. `PROT_NONE` pages
+        /// stay present but are only reachable from supervisor mode.
+        pub fn perm(&self) -> u32 {
+            if *self == Prot::None {
+                return PERM_NONE;
+            }
+            let mut perm = PERM_R | PERM_U;
+            if self.contains(Prot::Write) {
+                perm |= PERM_W;
+            }
+            if self.contains(Prot::Exec) {
+                perm |= PERM_X;
+            }
+            perm
+        }
+
+        /// VMPL permission attributes granting the same user access
+        pub fn vmpl_attrs(&self) -> u64 {
+            let mut attrs = 0;
+            if self.contains(Prot::Read) {
+                attrs |= VMPL_R;
+            }
+            if self.contains(Prot::Write) {
+                attrs |= VMPL_R | VMPL_W;
+            }
+            if self.contains(Prot::Exec) {
+                attrs |= VMPL_R | VMPL_X_USER;
+            }
+            attrs
+        }
+    }
+
+    impl BitOr for Prot {
+        type Output = Prot;
+
+        fn bitor(self, rhs: Prot) -> Prot {
+            Prot(self.0 | rhs.0)
+        }
     }
 
     impl From<i32> for Prot {
         fn from(prot: i32) -> Self {
-            match prot {
-                PROT_NONE => Prot::None,
-                PROT_READ => Prot::Read,
-                PROT_WRITE => Prot::Write,
-                PROT_EXEC => Prot::Exec,
-                _ => Prot::None,
-            }
+            Prot(prot & (PROT_READ | PROT_WRITE | PROT_EXEC))
         }
     }
 
     impl Display for Prot {
         fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-            match self {
-                Prot::None => write!(f, "----"),
-                Prot::Read => write!(f, "r---"),
-                Prot::Write => write!(f, "-w--"),
-                Prot::Exec => write!(f, "--x-"),
-            }
+            write!(
+                f,
+                "{}{}{}",
+                if self.contains(Prot::Read) { 'r' } else { '-' },
+                if self.contains(Prot::Write) { 'w' } else { '-' },
+                if self.contains(Prot::Exec) { 'x' } else { '-' },
+            )
         }
     }
 
-    #[derive(Debug, Default)]
+    #[derive(Debug, Default, Clone)]
     pub struct VmplVma {
         start: u64,
         end: u64,
-        offset: u32,
+        offset: u64,
         prot: Prot,
         flags: u64,
         minor: u32,
         major: u32,
-        inode: u32,
+        inode: u64,
         vm_file: Option<String>,
     }
 
     impl VmplVma {
-        pub fn new(start: u64, end: u64, flags: u64, prot: Prot, offset: u32) -> Self {
+        pub fn new(start: u64, end: u64, flags: u64, prot: Prot, offset: u64) -> Self {
             Self {
                 start,
                 end,
@@ -278,10 +329,98 @@ pub mod vma {
             }
         }
 
+        getter_func!(start, u64);
+        getter_func!(end, u64);
+        getter_func!(flags, u64);
+        getter_func!(offset, u64);
+        getter_func!(prot, Prot);
+        getter_func!(minor, u32);
+        getter_func!(major, u32);
+        getter_func!(inode, u64);
+
+        pub fn vm_file(&self) -> Option<&str> {
+            self.vm_file.as_deref()
+        }
+
+        pub fn vma_type(&self) -> VmplVmaType {
+            VmplVmaType::from(self.vm_file().unwrap_or(""))
+        }
+
+        pub fn is_file(&self) -> bool {
+            self.vma_type() == VmplVmaType::File
+        }
+
+        /// Whether the VMA counts against `RLIMIT_DATA`: private, writable
+        /// and not a stack
+        pub fn is_data(&self) -> bool {
+            self.prot.contains(Prot::Write)
+                && self.flags & MAP_SHARED as u64 == 0
+                && self.vma_type() != VmplVmaType::Stack
+        }
+
+        pub fn set_prot(&mut self, prot: Prot) {
+            self.prot = prot;
+        }
+
+        /// Name the file or pseudo-file backing the VMA
+        pub fn set_vm_file(&mut self, vm_file: Option<String>) {
+            self.vm_file = vm_file;
+        }
+
+        /// Move the VMA to `start`, keeping its length
+        pub fn relocate(&mut self, start: u64) {
+            self.end = start + self.len();
+            self.start = start;
+        }
+
+        /// Grow or shrink the VMA at its end
+        pub fn set_end(&mut self, end: u64) {
+            assert!(end > self.start);
+            self.end = end;
+        }
+
         pub fn len(&self) -> u64 {
             self.end - self.start
         }
 
+        pub fn contains(&self, addr: u64) -> bool {
+            self.start <= addr && addr < self.end
+        }
+
+        pub fn overlaps(&self, start: u64, end: u64) -> bool {
+            self.start < end && start < self.end
+        }
+
+        /// Cut at `addr` and return the upper part
+        pub fn split_at(&mut self, addr: u64) -> VmplVma {
+            assert!(self.start < addr && addr < self.end);
+            let mut upper = self.clone();
+            upper.start = addr;
+            if upper.is_file() {
+                upper.offset += addr - self.start;
+            }
+            self.end = addr;
+            upper
+        }
+
+        /// Whether `next` continues this VMA and can be merged into it
+        pub fn can_merge(&self, next: &VmplVma) -> bool {
+            self.end == next.start
+                && self.prot == next.prot
+                && self.flags == next.flags
+                && self.minor == next.minor
+                && self.major == next.major
+                && self.inode == next.inode
+                && self.vm_file == next.vm_file
+                && (!self.is_file() || self.offset + self.len() == next.offset)
+        }
+
+        /// Extend this VMA over `next`, see `can_merge`
+        pub fn merge(&mut self, next: VmplVma) {
+            assert!(self.can_merge(&next));
+            self.end = next.end;
+        }
+
         pub fn print(&self) {
             println!("{}", self);
         }
@@ -291,23 +430,43 @@ pub mod vma {
         }
     }
 
+    impl From<&ProcmapEntry> for VmplVma {
+        fn from(entry: &ProcmapEntry) -> Self {
+            let flags = if entry.p() { MAP_PRIVATE } else { MAP_SHARED };
+            Self {
+                start: entry.begin(),
+                end: entry.end(),
+                offset: entry.offset(),
+                prot: entry.prot(),
+                flags: flags as u64,
+                minor: entry.minor(),
+                major: entry.major(),
+                inode: entry.inode(),
+                vm_file: entry.path.clone(),
+            }
+        }
+    }
+
     impl fmt::Display for VmplVma {
         fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
             write!(
                 f,
-                "vmpl-vma: {:x}-{:x} {} {:08x} {:02x}:{:02x} {:8} {}",
+                "vmpl-vma: {:x}-{:x} {}{} {:08x} {:02x}:{:02x} {:8} {}",
                 self.start,
                 self.end,
                 self.prot,
+                if self.flags & MAP_SHARED as u64 != 0 { 's' } else { 'p' },
                 self.offset,
-                self.minor,
                 self.major,
+                self.minor,
                 self.inode,
                 self.vm_file.as_deref().unwrap_or(""),
             )
         }
     }
 
+    /// Placement policy of `VmplVm::allocate`
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
     pub enum FitAlgorithm {
         FirstFit,
         NextFit,
@@ -315,4 +474,92 @@ pub mod vma {
         WorstFit,
         RandomFit,
     }
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        const MAPS: &str = "\
+55d3c2a00000-55d3c2a02000 r--p 00000000 fd:01 1234567                    /usr/bin/cat
+55d3c2a02000-55d3c2a07000 r-xp 00002000 fd:01 1234567                    /usr/bin/cat
+55d3c3e1b000-55d3c3e3c000 rw-p 00000000 00:00 0                          [heap]
+7f0a1c000000-7f0a1c021000 rw-s 1ffffffff000 103:0a 98765432109           /tmp/with space (deleted)
+7f0a1c400000-7f0a1c401000 ---p 00000000 00:00 0 
+7ffd8a5e0000-7ffd8a601000 rw-p 00000000 00:00 0                          [stack]
+7ffd8a7f4000-7ffd8a7f8000 r--p 00000000 00:00 0                          [vvar]
+7ffd8a7f8000-7ffd8a7fa000 r-xp 00000000 00:00 0                          [vdso]
+ffffffffff600000-ffffffffff601000 --xp 00000000 00:00 0                  [vsyscall]
+";
+
+        fn entries() -> Vec<ProcmapEntry> {
+            let mut entries = Vec::new();
+            parse_procmaps_from(MAPS.as_bytes(), |e| entries.push(e.clone())).unwrap();
+            entries
+        }
+
+        #[test]
+        fn parse_fields() {
+            let entries = entries();
+            assert_eq!(entries.len(), 9);
+
+            let text = &entries[1];
+            assert_eq!((text.begin(), text.end()), (0x55d3c2a02000, 0x55d3c2a07000));
+            assert_eq!(text.offset(), 0x2000);
+            assert_eq!(text.prot(), Prot::Read | Prot::Exec);
+            assert!(text.p());
+            assert_eq!((text.major(), text.minor()), (0xfd, 0x01));
+            assert_eq!(text.path(), Some("/usr/bin/cat"));
+
+            // offsets and inodes beyond 32 bits, paths with spaces
+            let shared = &entries[3];
+            assert_eq!(shared.offset(), 0x1ffffffff000);
+            assert_eq!(shared.inode(), 98765432109);
+            assert_eq!((shared.major(), shared.minor()), (0x103, 0x0a));
+            assert!(!shared.p());
+            assert_eq!(shared.path(), Some("/tmp/with space (deleted)"));
+
+            let guard = &entries[4];
+            assert_eq!(guard.prot(), Prot::None);
+            assert_eq!(guard.path(), None);
+        }
+
+        #[test]
+        fn classify() {
+            let types: Vec<VmplVmaType> = entries().iter().map(|e| e.vma_type()).collect();
+            assert_eq!(
+                types,
+                vec![
+                    VmplVmaType::File,
+                    VmplVmaType::File,
+                    VmplVmaType::Heap,
+                    VmplVmaType::File,
+                    VmplVmaType::Anonymous,
+                    VmplVmaType::Stack,
+                    VmplVmaType::Vvar,
+                    VmplVmaType::Vdso,
+                    VmplVmaType::Vsyscall,
+                ]
+            );
+            assert_eq!(VmplVmaType::from("[stack:42]"), VmplVmaType::Stack);
+            assert_eq!(VmplVmaType::from("[stack"), VmplVmaType::Unknown);
+            assert_eq!(VmplVmaType::from("[anon:jit]"), VmplVmaType::Anonymous);
+        }
+
+        #[test]
+        fn reject_garbage() {
+            assert!("".parse::<ProcmapEntry>().is_err());
+            assert!("1000-2000 rw-p".parse::<ProcmapEntry>().is_err());
+            assert!("1000-zz rw-p 0 00:00 0".parse::<ProcmapEntry>().is_err());
+            assert!("1000-2000 rw 0 00:00 0".parse::<ProcmapEntry>().is_err());
+        }
+
+        #[test]
+        fn vma_from_entry() {
+            let vma = VmplVma::from(&entries()[3]);
+            assert_eq!(vma.flags(), MAP_SHARED as u64);
+            assert_eq!(vma.prot(), Prot::Read | Prot::Write);
+            assert!(vma.is_file());
+            assert_eq!(vma.prot().vmpl_attrs(), VMPL_R | VMPL_W);
+            assert_eq!(Prot::None.vmpl_attrs(), 0);
+        }
+    }
 }
\ No newline at end of file
@@ -1,83 +1,191 @@
 #[cfg(feature = "apic")]
 pub mod apic {
     use libc::sched_getcpu;
-    use std::alloc::{alloc, dealloc, Layout};
     use std::arch::asm;
-    use std::mem::size_of;
-    use std::ops::ShrAssign;
-    use std::ptr::null_mut;
-    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::OnceLock;
+
+    use lazy_static::lazy_static;
     use x86_64::registers::model_specific::Msr;
 
     use crate::error::VmplError;
 
     /// APIC related constants
-    const MSR_APIC_BASE: u32 = 0x1B;
-    const MSR_APIC_ICR: u32 = 0x830;
-    const MSR_APIC_EOI: u32 = 0x80B;
+    pub const MSR_APIC_ID: u32 = 0x802;
+    pub const MSR_APIC_EOI: u32 = 0x80B;
+    pub const MSR_APIC_ICR: u32 = 0x830;
+    pub const MSR_APIC_SELF_IPI: u32 = 0x83F;
 
-    const APIC_DM_FIXED: u32 = 0x00000;
-    const NMI_VECTOR: i32 = 0x02;
-    const APIC_DM_NMI: u32 = 0x00400;
-    const APIC_DEST_PHYSICAL: u32 = 0x00000;
-    const EOI_ACK: u32 = 0x0;
+    pub const APIC_DM_FIXED: u32 = 0x00000;
+    pub const NMI_VECTOR: i32 = 0x02;
+    pub const APIC_DM_NMI: u32 = 0x00400;
+    pub const APIC_DM_MASK: u32 = 0x00700;
+    pub const APIC_DEST_PHYSICAL: u32 = 0x00000;
+    pub const APIC_DEST_SELF: u32 = 0x40000;
+    pub const APIC_DEST_ALLINC: u32 = 0x80000;
+    pub const APIC_DEST_ALLBUT: u32 = 0xC0000;
+    pub const APIC_DEST_MASK: u32 = 0xC0000;
+    pub const APIC_VECTOR_MASK: u32 = 0x000FF;
+    const EOI_ACK: u64 = 0x0;
 
-    /// APIC routing table
-    static mut APIC_ROUTING: *mut u32 = null_mut();
-    static NUM_RT_ENTRIES: AtomicUsize = AtomicUsize::new(0);
+    /// First vector usable for IPIs, below are exceptions
+    const FIRST_IPI_VECTOR: u8 = 32;
 
-    pub fn apic_get_id() -> u32 {
-        unsafe {
-            let mut value: u64 = Msr::new(MSR_APIC_BASE).read();
-            value.shr_assign(24);
-            value as u32
-        }
+    /// Routing table entry of a CPU that hasn't entered VMPL mode yet
+    const NO_APIC_ID: u32 = u32::MAX;
+
+    /// Logical CPU to x2APIC ID routing table
+    ///
+    /// Indexed by the CPU number returned by `sched_getcpu()`; each entry is
+    /// filled by the thread running on that CPU when it enters VMPL mode.
+    pub struct ApicRouting {
+        entries: Vec<AtomicU32>,
     }
 
-    pub fn apic_setup() -> Result<(), VmplError> {
-        log::info!("setup apic");
-        let num_rt_entries = num_cpus::get_physical();
-
-        log::debug!("num rt entries: {}", num_rt_entries);
-        let layout =
-            Layout::from_size_align(num_rt_entries * size_of::<u32>(), size_of::<u32>()).unwrap();
-        unsafe {
-            APIC_ROUTING = alloc(layout) as *mut u32;
-            if APIC_ROUTING.is_null() {
-                log::error!("apic routing table allocation failed");
-                return Err(VmplError::ApicSetupFailed(libc::ENOMEM));
+    impl ApicRouting {
+        pub fn new(num_cpus: usize) -> ApicRouting {
+            ApicRouting {
+                entries: (0..num_cpus).map(|_| AtomicU32::new(NO_APIC_ID)).collect(),
             }
+        }
+
+        pub fn len(&self) -> usize {
+            self.entries.len()
+        }
 
-            NUM_RT_ENTRIES.store(num_rt_entries, Ordering::SeqCst);
-            std::ptr::write_bytes(APIC_ROUTING, 0, num_rt_entries);
-            asm!("mfence", options(nomem, nostack));
+        pub fn set(&self, cpu: usize, apic_id: u32) -> Result<(), VmplError> {
+            let entry = self.entries.get(cpu).ok_or(VmplError::ApicInvalidCpu(cpu))?;
+            entry.store(apic_id, Ordering::SeqCst);
+            Ok(())
         }
 
+        pub fn get(&self, cpu: usize) -> Result<u32, VmplError> {
+            let entry = self.entries.get(cpu).ok_or(VmplError::ApicInvalidCpu(cpu))?;
+            match entry.load(Ordering::SeqCst) {
+                NO_APIC_ID => Err(VmplError::ApicNotRouted(cpu)),
+                apic_id => Ok(apic_id),
+            }
+        }
+
+        pub fn clear(&self) {
+            for entry in self.entries.iter() {
+                entry.store(NO_APIC_ID, Ordering::SeqCst);
+            }
+        }
+
+        /// CPUs that have a routing entry
+        pub fn cpus(&self) -> impl Iterator<Item = usize> + '_ {
+            self.entries
+                .iter()
+                .enumerate()
+                .filter(|(_, e)| e.load(Ordering::SeqCst) != NO_APIC_ID)
+                .map(|(cpu, _)| cpu)
+        }
+
+        /// CPU whose entry holds `apic_id`
+        pub fn cpu_of(&self, apic_id: u32) -> Option<usize> {
+            if apic_id == NO_APIC_ID {
+                return None;
+            }
+            self.entries
+                .iter()
+                .position(|e| e.load(Ordering::SeqCst) == apic_id)
+        }
+    }
+
+    lazy_static! {
+        /// APIC routing table, sized by the configured (not online) CPUs so
+        /// that every `sched_getcpu()` value has a slot
+        pub static ref APIC_ROUTING: ApicRouting = {
+            let nr_cpus = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_CONF) };
+            ApicRouting::new(nr_cpus.max(1) as usize)
+        };
+    }
+
+    /// Access to the x2APIC registers
+    ///
+    /// Everything in this module (and the timer) goes through the installed
+    /// implementation, so an emulated APIC can stand in for the hardware.
+    pub trait ApicOps: Send + Sync {
+        fn read(&self, msr: u32) -> u64;
+        fn write(&self, msr: u32, value: u64);
+    }
+
+    /// The real x2APIC, accessed with rdmsr/wrmsr
+    pub struct X2Apic;
+
+    impl ApicOps for X2Apic {
+        fn read(&self, msr: u32) -> u64 {
+            unsafe { Msr::new(msr).read() }
+        }
+
+        fn write(&self, msr: u32, value: u64) {
+            unsafe { Msr::new(msr).write(value) };
+        }
+    }
+
+    /// Chosen once, so the EOI and IPI paths take no lock
+    static APIC_OPS: OnceLock<&'static dyn ApicOps> = OnceLock::new();
+
+    /// Install the APIC implementation, e.g. an `EmulatedApic`. Must come
+    /// before the first APIC access, which otherwise settles on `X2Apic`.
+    pub fn apic_set_ops(ops: &'static dyn ApicOps) -> Result<(), VmplError> {
+        APIC_OPS
+            .set(ops)
+            .map_err(|_| VmplError::ApicSetupFailed(libc::EBUSY))
+    }
+
+    fn apic_ops() -> &'static dyn ApicOps {
+        *APIC_OPS.get_or_init(|| &X2Apic)
+    }
+
+    pub(crate) fn apic_write(msr: u32, value: u64) {
+        apic_ops().write(msr, value);
+    }
+
+    pub(crate) fn apic_read(msr: u32) -> u64 {
+        apic_ops().read(msr)
+    }
+
+    /// x2APIC ID of the current CPU
+    pub fn apic_get_id() -> u32 {
+        apic_read(MSR_APIC_ID) as u32
+    }
+
+    pub fn apic_setup() -> Result<(), VmplError> {
+        log::info!("setup apic");
+        log::debug!("num rt entries: {}", APIC_ROUTING.len());
+        APIC_ROUTING.clear();
         Ok(())
     }
 
     pub fn apic_cleanup() {
-        let num_rt_entries = NUM_RT_ENTRIES.load(Ordering::SeqCst);
-        let layout =
-            Layout::from_size_align(num_rt_entries * size_of::<i32>(), size_of::<i32>()).unwrap();
-        unsafe { dealloc(APIC_ROUTING as *mut u8, layout) };
+        APIC_ROUTING.clear();
     }
 
-    pub fn apic_init_rt_entry() {
-        unsafe {
-            let core_id = sched_getcpu();
-            *APIC_ROUTING.offset(core_id as isize) = apic_get_id();
-            asm!("mfence", options(nomem, nostack));
-        };
+    /// Record the APIC ID of the CPU this thread runs on
+    pub fn apic_init_rt_entry() -> Result<(), VmplError> {
+        let cpu = unsafe { sched_getcpu() };
+        if cpu < 0 {
+            return Err(VmplError::Sys(libc::EINVAL));
+        }
+
+        APIC_ROUTING.set(cpu as usize, apic_get_id())?;
+        unsafe { asm!("mfence", options(nomem, nostack)) };
+        Ok(())
     }
 
-    pub fn apic_get_id_for_cpu(cpu: u32, error: &mut bool) -> u32 {
-        let num_rt_entries = NUM_RT_ENTRIES.load(Ordering::SeqCst);
-        if cpu >= num_rt_entries as u32 {
-            *error = true;
-            return 0;
-        }
-        unsafe { *APIC_ROUTING.offset(cpu as isize) }
+    /// Logical CPU of the caller, found by its APIC ID rather than by
+    /// `sched_getcpu()`, which can go stale and is not usable from an
+    /// interrupt handler
+    pub fn apic_current_cpu() -> Result<usize, VmplError> {
+        APIC_ROUTING
+            .cpu_of(apic_get_id())
+            .ok_or(VmplError::Sys(libc::EINVAL))
+    }
+
+    pub fn apic_get_id_for_cpu(cpu: usize) -> Result<u32, VmplError> {
+        APIC_ROUTING.get(cpu)
     }
 
     fn __prepare_icr(shortcut: u32, vector: i32, dest: u32) -> u32 {
@@ -89,15 +197,82 @@ pub mod apic {
         icr
     }
 
+    fn check_vector(vector: u8) -> Result<(), VmplError> {
+        if vector < FIRST_IPI_VECTOR {
+            return Err(VmplError::Sys(libc::EINVAL));
+        }
+        Ok(())
+    }
+
+    /// Write the ICR for a physical destination
     pub fn apic_send_ipi(vector: u8, dest_apic_id: u32) {
         let low = __prepare_icr(0, vector as i32, APIC_DEST_PHYSICAL);
-        let icr = ((dest_apic_id as u64) << 32) | low as u64;
-        unsafe { asm!("wrmsr", in("ecx") MSR_APIC_ICR, in("eax") icr, options(nomem, nostack)) };
+        apic_write(MSR_APIC_ICR, ((dest_apic_id as u64) << 32) | low as u64);
+    }
+
+    /// Send `vector` to a logical CPU
+    pub fn send_ipi(cpu: usize, vector: u8) -> Result<(), VmplError> {
+        check_vector(vector)?;
+        apic_send_ipi(vector, APIC_ROUTING.get(cpu)?);
+        Ok(())
+    }
+
+    /// Send an NMI to a logical CPU
+    pub fn send_nmi(cpu: usize) -> Result<(), VmplError> {
+        let dest = APIC_ROUTING.get(cpu)?;
+        let low = __prepare_icr(0, NMI_VECTOR, APIC_DEST_PHYSICAL);
+        apic_write(MSR_APIC_ICR, ((dest as u64) << 32) | low as u64);
+        Ok(())
+    }
+
+    /// Send `vector` to the current CPU through the x2APIC SELF IPI register
+    pub fn send_self_ipi(vector: u8) -> Result<(), VmplError> {
+        check_vector(vector)?;
+        apic_write(MSR_APIC_SELF_IPI, vector as u64);
+        Ok(())
+    }
+
+    fn send_ipi_shorthand(shortcut: u32, vector: u8) -> Result<(), VmplError> {
+        check_vector(vector)?;
+        let low = __prepare_icr(shortcut, vector as i32, APIC_DEST_PHYSICAL);
+        apic_write(MSR_APIC_ICR, low as u64);
+        Ok(())
+    }
+
+    /// Send `vector` to every CPU, including the current one
+    pub fn send_ipi_all(vector: u8) -> Result<(), VmplError> {
+        send_ipi_shorthand(APIC_DEST_ALLINC, vector)
+    }
+
+    /// Send `vector` to every CPU but the current one
+    pub fn send_ipi_all_but_self(vector: u8) -> Result<(), VmplError> {
+        send_ipi_shorthand(APIC_DEST_ALLBUT, vector)
+    }
+
+    /// Send `vector` to the current CPU using the ICR shorthand
+    pub fn send_ipi_self_shorthand(vector: u8) -> Result<(), VmplError> {
+        send_ipi_shorthand(APIC_DEST_SELF, vector)
+    }
+
+    /// Send `vector` to every CPU in `cpus`. All destinations are resolved
+    /// before any IPI is sent, so an unknown CPU sends nothing.
+    pub fn send_ipi_mask<I>(cpus: I, vector: u8) -> Result<(), VmplError>
+    where
+        I: IntoIterator<Item = usize>,
+    {
+        check_vector(vector)?;
+        let dests = cpus
+            .into_iter()
+            .map(|cpu| APIC_ROUTING.get(cpu))
+            .collect::<Result<Vec<u32>, VmplError>>()?;
+
+        for dest in dests {
+            apic_send_ipi(vector, dest);
+        }
+        Ok(())
     }
 
     pub fn apic_eoi() {
-        unsafe {
-            asm!("wrmsr", in("ecx") MSR_APIC_EOI, in("eax") EOI_ACK, options(nomem, nostack))
-        };
+        apic_write(MSR_APIC_EOI, EOI_ACK);
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,459 @@
+#[cfg(feature = "apic")]
+pub mod apic_emu {
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Mutex;
+
+    use crate::sys::apic::apic::*;
+    use crate::sys::timer::timer::{
+        APIC_LVT_MASKED, APIC_LVT_TIMER_MODE_MASK, APIC_LVT_TIMER_PERIODIC,
+        APIC_LVT_TIMER_TSCDEADLINE, MSR_APIC_LVT_TIMER, MSR_APIC_TDCR, MSR_APIC_TMCCT,
+        MSR_APIC_TMICT, MSR_TSC_DEADLINE,
+    };
+
+    /// 256-bit vector register, like IRR and ISR
+    #[derive(Default, Clone, Copy)]
+    struct VectorSet([u64; 4]);
+
+    impl VectorSet {
+        fn set(&mut self, vector: u8) {
+            self.0[vector as usize / 64] |= 1 << (vector % 64);
+        }
+
+        fn clear(&mut self, vector: u8) {
+            self.0[vector as usize / 64] &= !(1 << (vector % 64));
+        }
+
+        fn highest(&self) -> Option<u8> {
+            (0..4).rev().find(|i| self.0[*i] != 0).map(|i| {
+                (i * 64 + 63 - self.0[i].leading_zeros() as usize) as u8
+            })
+        }
+
+        fn vectors(&self) -> Vec<u8> {
+            (0..=255u8)
+                .filter(|v| self.0[*v as usize / 64] & (1 << (v % 64)) != 0)
+                .collect()
+        }
+    }
+
+    #[derive(Default)]
+    struct EmulatedState {
+        icr_writes: Vec<u64>,
+        nmi_pending: bool,
+        irr: VectorSet,
+        isr: VectorSet,
+        eoi_count: usize,
+        lvt_timer: u64,
+        tmict: u64,
+        tmcct: u64,
+        tdcr: u64,
+        tsc: u64,
+        tsc_deadline: u64,
+    }
+
+    impl EmulatedState {
+        fn deliver(&mut self, low: u32) {
+            if low & APIC_DM_MASK == APIC_DM_NMI {
+                self.nmi_pending = true;
+            } else {
+                self.irr.set((low & APIC_VECTOR_MASK) as u8);
+            }
+        }
+
+        fn timer_fire(&mut self) {
+            if self.lvt_timer & APIC_LVT_MASKED == 0 {
+                self.irr.set(self.lvt_timer as u8);
+            }
+        }
+    }
+
+    /// Software model of a single x2APIC
+    ///
+    /// Every ICR write is recorded. IPIs that target this APIC (by ID or
+    /// shorthand) and timer expirations become pending in the IRR;
+    /// `accept()` moves the highest one to the ISR like the CPU would, and
+    /// an EOI retires the highest in-service vector. Time only advances
+    /// through `tick()` and `advance_tsc()`.
+    pub struct EmulatedApic {
+        apic_id: AtomicU32,
+        state: Mutex<EmulatedState>,
+    }
+
+    impl EmulatedApic {
+        pub fn new(apic_id: u32) -> EmulatedApic {
+            EmulatedApic {
+                apic_id: AtomicU32::new(apic_id),
+                state: Mutex::new(EmulatedApic::power_on()),
+            }
+        }
+
+        fn power_on() -> EmulatedState {
+            EmulatedState {
+                lvt_timer: APIC_LVT_MASKED,
+                ..Default::default()
+            }
+        }
+
+        /// Back to the power-on state, answering to `apic_id`. The ops are
+        /// installed once, so this is how one instance serves many tests.
+        pub fn reset(&self, apic_id: u32) {
+            self.apic_id.store(apic_id, Ordering::Relaxed);
+            *self.state.lock().unwrap() = EmulatedApic::power_on();
+        }
+
+        fn apic_id(&self) -> u32 {
+            self.apic_id.load(Ordering::Relaxed)
+        }
+
+        /// All values written to the ICR, oldest first
+        pub fn icr_writes(&self) -> Vec<u64> {
+            self.state.lock().unwrap().icr_writes.clone()
+        }
+
+        /// Vectors requested but not yet accepted
+        pub fn pending(&self) -> Vec<u8> {
+            self.state.lock().unwrap().irr.vectors()
+        }
+
+        /// Vectors accepted and waiting for an EOI
+        pub fn in_service(&self) -> Vec<u8> {
+            self.state.lock().unwrap().isr.vectors()
+        }
+
+        /// Consume a pending NMI
+        pub fn take_nmi(&self) -> bool {
+            std::mem::take(&mut self.state.lock().unwrap().nmi_pending)
+        }
+
+        pub fn eoi_count(&self) -> usize {
+            self.state.lock().unwrap().eoi_count
+        }
+
+        /// Accept the highest pending vector, if it outranks the in-service one
+        pub fn accept(&self) -> Option<u8> {
+            let mut state = self.state.lock().unwrap();
+            let vector = state.irr.highest()?;
+            if let Some(busy) = state.isr.highest() {
+                if vector >> 4 <= busy >> 4 {
+                    return None;
+                }
+            }
+            state.irr.clear(vector);
+            state.isr.set(vector);
+            Some(vector)
+        }
+
+        /// Advance the APIC timer by `ticks` counts (after the divider)
+        pub fn tick(&self, ticks: u64) {
+            let mut state = self.state.lock().unwrap();
+            let mode = state.lvt_timer & APIC_LVT_TIMER_MODE_MASK;
+            if mode == APIC_LVT_TIMER_TSCDEADLINE || state.tmcct == 0 {
+                return;
+            }
+
+            let mut ticks = ticks;
+            while ticks >= state.tmcct && state.tmcct != 0 {
+                ticks -= state.tmcct;
+                state.timer_fire();
+                state.tmcct = if mode == APIC_LVT_TIMER_PERIODIC {
+                    state.tmict
+                } else {
+                    0
+                };
+            }
+            state.tmcct = state.tmcct.saturating_sub(ticks);
+        }
+
+        /// Advance the TSC, firing an armed TSC deadline
+        pub fn advance_tsc(&self, cycles: u64) {
+            let mut state = self.state.lock().unwrap();
+            state.tsc += cycles;
+            let mode = state.lvt_timer & APIC_LVT_TIMER_MODE_MASK;
+            if mode == APIC_LVT_TIMER_TSCDEADLINE
+                && state.tsc_deadline != 0
+                && state.tsc >= state.tsc_deadline
+            {
+                state.tsc_deadline = 0;
+                state.timer_fire();
+            }
+        }
+
+        pub fn lvt_timer(&self) -> u64 {
+            self.state.lock().unwrap().lvt_timer
+        }
+
+        pub fn tdcr(&self) -> u64 {
+            self.state.lock().unwrap().tdcr
+        }
+    }
+
+    impl ApicOps for EmulatedApic {
+        fn read(&self, msr: u32) -> u64 {
+            let state = self.state.lock().unwrap();
+            match msr {
+                MSR_APIC_ID => self.apic_id() as u64,
+                MSR_APIC_LVT_TIMER => state.lvt_timer,
+                MSR_APIC_TMICT => state.tmict,
+                MSR_APIC_TMCCT => state.tmcct,
+                MSR_APIC_TDCR => state.tdcr,
+                MSR_TSC_DEADLINE => state.tsc_deadline,
+                _ => 0,
+            }
+        }
+
+        fn write(&self, msr: u32, value: u64) {
+            let mut state = self.state.lock().unwrap();
+            match msr {
+                MSR_APIC_ICR => {
+                    state.icr_writes.push(value);
+                    let low = value as u32;
+                    let dest = (value >> 32) as u32;
+                    let to_self = match low & APIC_DEST_MASK {
+                        APIC_DEST_SELF | APIC_DEST_ALLINC => true,
+                        APIC_DEST_ALLBUT => false,
+                        _ => dest == self.apic_id(),
+                    };
+                    if to_self {
+                        state.deliver(low);
+                    }
+                }
+                MSR_APIC_SELF_IPI => state.irr.set(value as u8),
+                MSR_APIC_EOI => {
+                    state.eoi_count += 1;
+                    if let Some(vector) = state.isr.highest() {
+                        state.isr.clear(vector);
+                    }
+                }
+                MSR_APIC_LVT_TIMER => {
+                    // a mode change disarms the timer
+                    if (state.lvt_timer ^ value) & APIC_LVT_TIMER_MODE_MASK != 0 {
+                        state.tmcct = 0;
+                        state.tsc_deadline = 0;
+                    }
+                    state.lvt_timer = value;
+                }
+                MSR_APIC_TMICT => {
+                    state.tmict = value;
+                    state.tmcct = value;
+                }
+                MSR_APIC_TDCR => state.tdcr = value,
+                MSR_TSC_DEADLINE => state.tsc_deadline = value,
+                _ => {}
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use std::sync::{Mutex, MutexGuard, OnceLock};
+        use std::time::Duration;
+
+        use super::*;
+        use crate::error::VmplError;
+        use crate::sys::timer::timer::*;
+
+        /// The APIC ops and the routing table are global
+        static LOCK: Mutex<()> = Mutex::new(());
+        static EMU: OnceLock<&'static EmulatedApic> = OnceLock::new();
+
+        fn setup(apic_id: u32) -> (MutexGuard<'static, ()>, &'static EmulatedApic) {
+            let guard = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+            let emu = *EMU.get_or_init(|| {
+                let emu: &'static EmulatedApic = Box::leak(Box::new(EmulatedApic::new(0)));
+                apic_set_ops(emu).unwrap();
+                emu
+            });
+            emu.reset(apic_id);
+            APIC_ROUTING.clear();
+            (guard, emu)
+        }
+
+        #[test]
+        fn routing_table() {
+            let routing = ApicRouting::new(4);
+            assert!(matches!(routing.get(1), Err(VmplError::ApicNotRouted(1))));
+            assert!(matches!(routing.get(4), Err(VmplError::ApicInvalidCpu(4))));
+            assert!(matches!(routing.set(4, 1), Err(VmplError::ApicInvalidCpu(4))));
+
+            routing.set(1, 7).unwrap();
+            routing.set(3, 9).unwrap();
+            assert_eq!(routing.get(1).unwrap(), 7);
+            assert_eq!(routing.cpus().collect::<Vec<_>>(), vec![1, 3]);
+
+            routing.clear();
+            assert_eq!(routing.cpus().count(), 0);
+        }
+
+        #[test]
+        fn rt_entry_uses_apic_id() {
+            let (_guard, _emu) = setup(42);
+            apic_init_rt_entry().unwrap();
+            let cpu = unsafe { libc::sched_getcpu() } as usize;
+            assert_eq!(apic_get_id_for_cpu(cpu).unwrap(), 42);
+            assert_eq!(apic_current_cpu().unwrap(), cpu);
+        }
+
+        #[test]
+        fn current_cpu_follows_apic_id() {
+            let routing = ApicRouting::new(4);
+            routing.set(1, 3).unwrap();
+            routing.set(2, 7).unwrap();
+            assert_eq!(routing.cpu_of(7), Some(2));
+            assert_eq!(routing.cpu_of(5), None);
+
+            let (_guard, emu) = setup(7);
+            assert!(apic_current_cpu().is_err());
+            APIC_ROUTING.set(0, 3).unwrap();
+            assert!(apic_current_cpu().is_err());
+            emu.reset(3);
+            assert_eq!(apic_current_cpu().unwrap(), 0);
+        }
+
+        #[test]
+        fn fixed_ipi_format() {
+            let (_guard, emu) = setup(1);
+            APIC_ROUTING.set(0, 5).unwrap();
+
+            send_ipi(0, 0x40).unwrap();
+            assert_eq!(emu.icr_writes(), vec![(5 << 32) | 0x40]);
+            // not addressed to this APIC
+            assert!(emu.pending().is_empty());
+
+            assert!(send_ipi(0, 0x10).is_err());
+            assert_eq!(emu.icr_writes().len(), 1);
+        }
+
+        #[test]
+        fn nmi_format() {
+            let (_guard, emu) = setup(5);
+            APIC_ROUTING.set(0, 5).unwrap();
+
+            send_nmi(0).unwrap();
+            assert_eq!(emu.icr_writes(), vec![(5 << 32) | APIC_DM_NMI as u64]);
+            assert!(emu.take_nmi());
+            assert!(emu.pending().is_empty());
+        }
+
+        #[test]
+        fn shorthands() {
+            let (_guard, emu) = setup(0);
+
+            send_ipi_all_but_self(0x50).unwrap();
+            assert!(emu.pending().is_empty());
+            send_ipi_all(0x51).unwrap();
+            send_ipi_self_shorthand(0x52).unwrap();
+
+            assert_eq!(
+                emu.icr_writes(),
+                vec![
+                    APIC_DEST_ALLBUT as u64 | 0x50,
+                    APIC_DEST_ALLINC as u64 | 0x51,
+                    APIC_DEST_SELF as u64 | 0x52,
+                ]
+            );
+            assert_eq!(emu.pending(), vec![0x51, 0x52]);
+        }
+
+        #[test]
+        fn mask_resolves_before_sending() {
+            let (_guard, emu) = setup(0);
+            APIC_ROUTING.set(0, 3).unwrap();
+
+            let invalid = APIC_ROUTING.len();
+            assert!(send_ipi_mask([0, invalid], 0x60).is_err());
+            assert!(emu.icr_writes().is_empty());
+
+            send_ipi_mask([0], 0x60).unwrap();
+            assert_eq!(emu.icr_writes(), vec![(3 << 32) | 0x60]);
+        }
+
+        #[test]
+        fn accept_and_eoi() {
+            let (_guard, emu) = setup(0);
+
+            send_self_ipi(0x30).unwrap();
+            send_self_ipi(0x80).unwrap();
+            assert_eq!(emu.accept(), Some(0x80));
+            // same or lower priority class waits for the EOI
+            assert_eq!(emu.accept(), None);
+            assert_eq!(emu.in_service(), vec![0x80]);
+
+            apic_eoi();
+            assert!(emu.in_service().is_empty());
+            assert_eq!(emu.accept(), Some(0x30));
+            apic_eoi();
+            assert_eq!(emu.eoi_count(), 2);
+            assert!(emu.pending().is_empty() && emu.in_service().is_empty());
+        }
+
+        #[test]
+        fn timer_oneshot() {
+            let (_guard, emu) = setup(0);
+            timer_set_frequencies(1_000_000_000, 1_000_000);
+
+            set_oneshot(Duration::from_millis(1), 0xec).unwrap();
+            assert_eq!(emu.lvt_timer(), APIC_LVT_TIMER_ONESHOT | 0xec);
+            assert_eq!(emu.tdcr(), 0x3);
+            assert_eq!(emu.read(MSR_APIC_TMICT), 1000);
+
+            emu.tick(999);
+            assert!(emu.pending().is_empty());
+            emu.tick(1);
+            assert_eq!(emu.pending(), vec![0xec]);
+            assert_eq!(emu.read(MSR_APIC_TMCCT), 0);
+
+            // one-shot does not re-arm
+            assert_eq!(emu.accept(), Some(0xec));
+            apic_eoi();
+            emu.tick(5000);
+            assert!(emu.pending().is_empty());
+        }
+
+        #[test]
+        fn timer_periodic_and_stop() {
+            let (_guard, emu) = setup(0);
+            timer_set_frequencies(1_000_000_000, 1_000_000);
+
+            set_periodic(Duration::from_micros(100), 0xed).unwrap();
+            emu.tick(250);
+            assert_eq!(emu.pending(), vec![0xed]);
+            assert_eq!(emu.read(MSR_APIC_TMCCT), 50);
+
+            emu.accept();
+            apic_eoi();
+            timer_stop();
+            emu.tick(1000);
+            assert!(emu.pending().is_empty());
+        }
+
+        #[test]
+        fn timer_masked_does_not_fire() {
+            let (_guard, emu) = setup(0);
+            emu.write(MSR_APIC_LVT_TIMER, APIC_LVT_MASKED | 0xee);
+            emu.write(MSR_APIC_TMICT, 10);
+            emu.tick(10);
+            assert!(emu.pending().is_empty());
+        }
+
+        #[test]
+        fn timer_tsc_deadline() {
+            let (_guard, emu) = setup(0);
+            emu.write(MSR_APIC_LVT_TIMER, APIC_LVT_TIMER_TSCDEADLINE | 0xef);
+            emu.write(MSR_TSC_DEADLINE, 100);
+
+            emu.tick(1000);
+            emu.advance_tsc(99);
+            assert!(emu.pending().is_empty());
+            emu.advance_tsc(1);
+            assert_eq!(emu.pending(), vec![0xef]);
+            assert_eq!(emu.read(MSR_TSC_DEADLINE), 0);
+        }
+
+        #[test]
+        fn timer_needs_calibration() {
+            let (_guard, _emu) = setup(0);
+            timer_set_frequencies(0, 0);
+            assert!(set_oneshot(Duration::from_millis(1), 0xec).is_err());
+        }
+    }
+}
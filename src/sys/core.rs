@@ -2,10 +2,14 @@
 // pgtable.c
 
 use std::fmt::Display;
+use std::io::Error;
 
 use log::error;
 
-use crate::{funcs, start::__dune_go_dune};
+use crate::funcs;
+#[cfg(feature = "mm")]
+use crate::mm::mm_syscall;
+use crate::start::{__dune_go_dune, DUNE_FD};
 
 #[repr(C)]
 #[derive(Debug, Default)]
@@ -94,6 +98,9 @@ impl VmsaSeg {
             base: base,
         }
     }
+
+    funcs!(selector, u16);
+    funcs!(attrib, u16);
 }
 
 impl Display for VmsaSeg {
@@ -166,6 +173,10 @@ pub struct DuneConfig {
     cr3: u64,
     status: i64,
     vcpu: u64,
+    /// Area `__dune_retry` saves the VMPL extended state to, 0 for none
+    xsave_area: u64,
+    /// XCR0 features to save
+    xsave_mask: u64,
 }
 
 impl DuneConfig {
@@ -194,6 +205,8 @@ impl DuneConfig {
             cr3: 0,
             status: 0,
             vcpu: 0,
+            xsave_area: 0,
+            xsave_mask: 0,
         }
     }
 
@@ -205,18 +218,39 @@ impl DuneConfig {
         error!("dune: exit due to interrupt {}", self.status);
     }
 
-    pub fn on_dune_syscall(&self) {
-        self.rax = unsafe {
-            libc::syscall(self.status,self.rdi,self.rsi,self.rdx,self.r10,self.r8,self.r9) as u64
-        };
-
+    /// Run the system call `__dune_syscall` exited for and go back. The
+    /// memory-management calls go through `mm_syscall`, so the VMPL view
+    /// of the address space follows them.
+    pub fn on_dune_syscall(&mut self) {
+        let nr = self.status as u64;
+        let args = [self.rdi, self.rsi, self.rdx, self.r10, self.r8, self.r9];
+
+        #[cfg(feature = "mm")]
+        let emulated = mm_syscall(nr, args);
+        #[cfg(not(feature = "mm"))]
+        let emulated = None;
+
+        let ret = emulated.unwrap_or_else(|| {
+            let ret = unsafe {
+                libc::syscall(nr as i64, args[0], args[1], args[2], args[3], args[4], args[5])
+            };
+            // the caller made a raw system call and expects -errno
+            if ret == -1 {
+                -(Error::last_os_error().raw_os_error().unwrap_or(libc::EIO) as i64)
+            } else {
+                ret
+            }
+        });
+        self.rax = ret as u64;
+
+        // the extended state is restored by __dune_go_dune
         unsafe {
-            __dune_go_dune(dune_fd, self);
+            __dune_go_dune(DUNE_FD, self);
         }
     }
 
-    pub fn on_dune_signal(&self) {
-        unsafe { __dune_go_dune(dune_fd, &self) };
+    pub fn on_dune_signal(&mut self) {
+        unsafe { __dune_go_dune(DUNE_FD, self) };
     }
 
     pub fn on_dune_noenter(&self) {
@@ -240,6 +274,8 @@ impl DuneConfig {
     funcs!(rcx, u64);
     funcs!(r8, u64);
     funcs!(r9, u64);
+    funcs!(xsave_area, u64);
+    funcs!(xsave_mask, u64);
 }
 
 pub struct VmplConfig {
@@ -291,6 +327,7 @@ pub struct GdtrEntry {
 }
 
 #[repr(C, packed)]
+#[derive(Clone, Copy, Default)]
 pub struct DuneTrapFrame {
     /* manually saved, arguments */
     rdi: u64,
@@ -325,6 +362,44 @@ pub struct DuneTrapFrame {
     pad3: [u16; 3],
 }
 
+impl DuneTrapFrame {
+    funcs!(rdi, u64);
+    funcs!(rsi, u64);
+    funcs!(rdx, u64);
+    funcs!(rcx, u64);
+    funcs!(r8, u64);
+    funcs!(r9, u64);
+    funcs!(r10, u64);
+    funcs!(r11, u64);
+    funcs!(rbx, u64);
+    funcs!(rbp, u64);
+    funcs!(r12, u64);
+    funcs!(r13, u64);
+    funcs!(r14, u64);
+    funcs!(r15, u64);
+    funcs!(rax, u64);
+    funcs!(err, u32);
+    funcs!(rip, u64);
+    funcs!(cs, u16);
+    funcs!(rflags, u64);
+    funcs!(rsp, u64);
+    funcs!(ss, u16);
+}
+
+impl Display for DuneTrapFrame {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Trap Frame:\n")?;
+        write!(f, "  rax: 0x{:016x} rbx: 0x{:016x} rcx: 0x{:016x}\n", self.rax(), self.rbx(), self.rcx())?;
+        write!(f, "  rdx: 0x{:016x} rsi: 0x{:016x} rdi: 0x{:016x}\n", self.rdx(), self.rsi(), self.rdi())?;
+        write!(f, "  rbp: 0x{:016x} rsp: 0x{:016x} r8:  0x{:016x}\n", self.rbp(), self.rsp(), self.r8())?;
+        write!(f, "  r9:  0x{:016x} r10: 0x{:016x} r11: 0x{:016x}\n", self.r9(), self.r10(), self.r11())?;
+        write!(f, "  r12: 0x{:016x} r13: 0x{:016x} r14: 0x{:016x}\n", self.r12(), self.r13(), self.r14())?;
+        write!(f, "  r15: 0x{:016x}\n", self.r15())?;
+        write!(f, "  rip: 0x{:016x} rflags: 0x{:08x} err: 0x{:x}\n", self.rip(), self.rflags(), self.err())?;
+        write!(f, "  cs: 0x{:x} ss: 0x{:x}\n", self.cs(), self.ss())
+    }
+}
+
 #[repr(C, packed)]
 #[derive(Debug, Default)]
 pub struct GetPagesParams {
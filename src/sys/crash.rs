@@ -0,0 +1,293 @@
+// Crash reporting for fatal exceptions
+//
+// A report contains the exception, the trap frame, a frame-pointer
+// backtrace symbolized against the process ELF, the per-CPU area, the GHCB
+// and the live segment state. It is written to the serial console and to a
+// crash file before the guest is terminated with a VMPL reason code.
+//
+// Backtraces need frame pointers (`-C force-frame-pointers=yes`).
+
+use std::arch::asm;
+use std::fmt::Write as FmtWrite;
+use std::fs::{self, File};
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+use log::info;
+use x86_64::instructions::tables::{sgdt, sidt};
+use x86_64::registers::model_specific::{FsBase, GsBase};
+
+use crate::ghcb::vc::vc_terminate_fatal_exception;
+use crate::prints;
+use crate::sys::core::{DuneTrapFrame, VmplSegs, VmsaSeg};
+use crate::sys::percpu::DunePerCpu;
+
+/// Maximum number of frames to walk
+const MAX_FRAMES: usize = 64;
+/// Frames further than this from the faulting RSP are not trusted
+const MAX_STACK_SPAN: u64 = 8 << 20;
+
+const ELF_MAGIC: &[u8; 4] = b"\x7fELF";
+const ET_DYN: u16 = 3;
+const PT_LOAD: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_DYNSYM: u32 = 11;
+const STT_FUNC: u8 = 2;
+
+static CRASHING: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref CRASH_PATH: Mutex<Option<String>> = Mutex::new(None);
+    static ref SYMBOLS: Option<SymbolTable> = SymbolTable::load();
+}
+
+struct Symbol {
+    addr: u64,
+    size: u64,
+    name: String,
+}
+
+/// Function symbols of the running executable, relocated to runtime addresses
+struct SymbolTable {
+    symbols: Vec<Symbol>,
+}
+
+fn read_u16(data: &[u8], off: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(data.get(off..off + 2)?.try_into().ok()?))
+}
+
+fn read_u32(data: &[u8], off: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(off..off + 4)?.try_into().ok()?))
+}
+
+fn read_u64(data: &[u8], off: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(data.get(off..off + 8)?.try_into().ok()?))
+}
+
+fn read_cstr(data: &[u8], off: usize) -> Option<String> {
+    let bytes = data.get(off..)?;
+    let end = bytes.iter().position(|b| *b == 0)?;
+    Some(String::from_utf8_lossy(&bytes[..end]).into_owned())
+}
+
+impl SymbolTable {
+    fn load() -> Option<SymbolTable> {
+        let exe = fs::read_link("/proc/self/exe").ok()?;
+        let data = fs::read(&exe).ok()?;
+        if data.get(0..4)? != ELF_MAGIC {
+            return None;
+        }
+
+        let bias = if read_u16(&data, 16)? == ET_DYN {
+            Self::load_bias(&data, exe.to_str()?)?
+        } else {
+            0
+        };
+
+        let shoff = read_u64(&data, 0x28)? as usize;
+        let shentsize = read_u16(&data, 0x3a)? as usize;
+        let shnum = read_u16(&data, 0x3c)? as usize;
+        let section = |i: usize| shoff + i * shentsize;
+
+        // Prefer the full symbol table, fall back to the dynamic one
+        let symtab = (0..shnum)
+            .find(|i| read_u32(&data, section(*i) + 4) == Some(SHT_SYMTAB))
+            .or_else(|| (0..shnum).find(|i| read_u32(&data, section(*i) + 4) == Some(SHT_DYNSYM)))?;
+
+        let sym_off = read_u64(&data, section(symtab) + 0x18)? as usize;
+        let sym_size = read_u64(&data, section(symtab) + 0x20)? as usize;
+        let sym_entsize = read_u64(&data, section(symtab) + 0x38)? as usize;
+        let strtab = read_u32(&data, section(symtab) + 0x28)? as usize;
+        let str_off = read_u64(&data, section(strtab) + 0x18)? as usize;
+
+        let mut symbols = Vec::new();
+        for off in (sym_off..sym_off + sym_size).step_by(sym_entsize.max(1)) {
+            let info = *data.get(off + 4)?;
+            let value = read_u64(&data, off + 8)?;
+            if info & 0xf != STT_FUNC || value == 0 {
+                continue;
+            }
+            symbols.push(Symbol {
+                addr: value + bias,
+                size: read_u64(&data, off + 16)?,
+                name: read_cstr(&data, str_off + read_u32(&data, off)? as usize)?,
+            });
+        }
+        symbols.sort_by_key(|s| s.addr);
+
+        info!("crash: loaded {} symbols from {}", symbols.len(), exe.display());
+        Some(SymbolTable { symbols })
+    }
+
+    /// Load bias of a PIE: where the first PT_LOAD segment ended up
+    fn load_bias(data: &[u8], exe: &str) -> Option<u64> {
+        let phoff = read_u64(data, 0x20)? as usize;
+        let phentsize = read_u16(data, 0x36)? as usize;
+        let phnum = read_u16(data, 0x38)? as usize;
+        let first_vaddr = (0..phnum)
+            .map(|i| phoff + i * phentsize)
+            .filter(|ph| read_u32(data, *ph) == Some(PT_LOAD))
+            .filter_map(|ph| read_u64(data, ph + 0x10))
+            .min()?;
+
+        let maps = fs::read_to_string("/proc/self/maps").ok()?;
+        let start = maps.lines().find_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 6 || fields[5] != exe || u64::from_str_radix(fields[2], 16) != Ok(0) {
+                return None;
+            }
+            u64::from_str_radix(fields[0].split('-').next()?, 16).ok()
+        })?;
+
+        Some(start - (first_vaddr & !0xfff))
+    }
+
+    fn lookup(&self, addr: u64) -> Option<(&str, u64)> {
+        let idx = self.symbols.partition_point(|s| s.addr <= addr);
+        let sym = self.symbols.get(idx.checked_sub(1)?)?;
+        if sym.size != 0 && addr >= sym.addr + sym.size {
+            return None;
+        }
+        Some((&sym.name, addr - sym.addr))
+    }
+}
+
+/// Walk the frame-pointer chain starting at `rbp`. Frames must be aligned,
+/// strictly increasing and within `MAX_STACK_SPAN` of `rsp`.
+fn walk_frames(rip: u64, rbp: u64, rsp: u64) -> Vec<u64> {
+    let mut frames = vec![rip];
+    let mut fp = rbp;
+
+    while frames.len() < MAX_FRAMES {
+        if fp == 0 || fp % 8 != 0 || fp < rsp || fp - rsp > MAX_STACK_SPAN {
+            break;
+        }
+
+        let (next, ret) = unsafe { (*(fp as *const u64), *((fp + 8) as *const u64)) };
+        if ret == 0 {
+            break;
+        }
+        frames.push(ret);
+
+        if next <= fp {
+            break;
+        }
+        fp = next;
+    }
+
+    frames
+}
+
+/// Descriptor base/limit/attributes in the VMSA format, read from the GDT
+fn read_system_seg(gdt: u64, selector: u16) -> VmsaSeg {
+    let desc = gdt + (selector & !7) as u64;
+    let (low, high) = unsafe { (*(desc as *const u64), *((desc + 8) as *const u64)) };
+
+    let base = ((low >> 16) & 0xffffff) | (((low >> 56) & 0xff) << 24) | ((high & 0xffffffff) << 32);
+    let limit = ((low & 0xffff) | (((low >> 48) & 0xf) << 16)) as u32;
+    let attrib = (((low >> 40) & 0xff) | (((low >> 52) & 0xf) << 8)) as u16;
+
+    VmsaSeg::tr(selector, base, limit, attrib)
+}
+
+/// Snapshot the live segment state in the layout used by `VMPL_IOCTL_SET_SEGS`
+fn current_segs() -> VmplSegs {
+    let gdt = sgdt();
+    let idt = sidt();
+    let tr: u16;
+
+    unsafe {
+        asm!("str {0:x}", out(reg) tr, options(nomem, nostack, preserves_flags));
+    }
+
+    let gdtr = VmsaSeg::new(0, 0, gdt.limit as u32, gdt.base.as_u64());
+    let idtr = VmsaSeg::new(0, 0, idt.limit as u32, idt.base.as_u64());
+    let tr = if tr != 0 {
+        read_system_seg(gdt.base.as_u64(), tr)
+    } else {
+        VmsaSeg::default()
+    };
+
+    VmplSegs::new(
+        VmsaSeg::fs(FsBase::read().as_u64()),
+        VmsaSeg::gs(GsBase::read().as_u64()),
+        gdtr,
+        idtr,
+        tr,
+    )
+}
+
+fn format_report(name: &str, tf: &DuneTrapFrame) -> String {
+    let mut report = String::new();
+
+    let _ = write!(report, "\n*** VMPL crash: #{} at RIP {:#x} ***\n", name, tf.rip());
+    let _ = write!(report, "{}", tf);
+
+    let _ = write!(report, "Backtrace:\n");
+    for (i, addr) in walk_frames(tf.rip(), tf.rbp(), tf.rsp()).iter().enumerate() {
+        match SYMBOLS.as_ref().and_then(|s| s.lookup(*addr)) {
+            Some((sym, off)) => {
+                let _ = write!(report, "  #{:<2} {:#018x} {}+{:#x}\n", i, addr, sym, off);
+            }
+            None => {
+                let _ = write!(report, "  #{:<2} {:#018x} ??\n", i, addr);
+            }
+        }
+    }
+
+    // In VMPL mode GS points at the per-CPU area
+    let percpu = GsBase::read().as_u64() as *const DunePerCpu;
+    if !percpu.is_null() {
+        let percpu = unsafe { &*percpu };
+        let _ = write!(report, "{}", percpu);
+
+        let ghcb = percpu.get_ghcb();
+        if !ghcb.is_null() {
+            let _ = write!(report, "{}", unsafe { &*ghcb });
+        }
+    }
+
+    let _ = write!(report, "Segments: {}\n", current_segs());
+    report
+}
+
+fn crash_file_path() -> String {
+    CRASH_PATH
+        .lock()
+        .ok()
+        .and_then(|path| path.clone())
+        .unwrap_or_else(|| format!("/tmp/vmpl-crash.{}", std::process::id()))
+}
+
+/// Set where crash reports are written (default `/tmp/vmpl-crash.<pid>`)
+pub fn crash_set_path(path: &str) {
+    *CRASH_PATH.lock().unwrap() = Some(path.to_string());
+}
+
+/// Load the symbol table up front, so that a crash in VMPL mode does not
+/// have to read the executable
+pub fn crash_init() {
+    info!("setup crash reporter");
+    lazy_static::initialize(&SYMBOLS);
+}
+
+/// Report a fatal exception and terminate
+pub fn crash_report(name: &str, tf: &DuneTrapFrame) -> ! {
+    // A fault while reporting must not recurse
+    if CRASHING.swap(true, Ordering::SeqCst) {
+        vc_terminate_fatal_exception();
+    }
+
+    let report = format_report(name, tf);
+    prints!("{}", report);
+
+    let path = crash_file_path();
+    if let Ok(mut file) = File::create(&path) {
+        let _ = file.write_all(report.as_bytes());
+        prints!("crash report written to {}\n", path);
+    }
+
+    vc_terminate_fatal_exception();
+}
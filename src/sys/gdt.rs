@@ -0,0 +1,333 @@
+use std::fmt::Display;
+
+use crate::error::VmplError;
+use crate::globals::{GD_KD, GD_KT, GD_TSS, GD_UD, GD_UT, NR_GDT_ENTRIES};
+use crate::sys::core::VmsaSeg;
+use crate::STATIC_ASSERT;
+
+/// 32-bit compatibility code segments, where Linux puts them
+const GD_KT32: u16 = 0x08;
+const GD_UT32: u16 = 0x20;
+
+/// Access byte bits
+const DESC_ACCESSED: u8 = 1 << 0;
+const DESC_RW: u8 = 1 << 1;
+const DESC_EXEC: u8 = 1 << 3;
+const DESC_CODE_DATA: u8 = 1 << 4;
+const DESC_PRESENT: u8 = 1 << 7;
+const DESC_TSS_AVAIL: u8 = 0x9;
+
+/// Flag nibble bits
+const DESC_LONG: u8 = 1 << 1;
+const DESC_DB: u8 = 1 << 2;
+const DESC_GRANULARITY: u8 = 1 << 3;
+
+// SYSCALL loads SS = CS + 8, SYSRET loads SS = base + 8, CS = base + 16
+STATIC_ASSERT!(GD_KD == GD_KT + 8);
+STATIC_ASSERT!(GD_UD as u16 == GD_UT32 + 8);
+STATIC_ASSERT!(GD_UT == GD_UD + 8);
+// the TSS takes two entries, at the end of the table
+STATIC_ASSERT!((GD_TSS >> 3) as usize + 2 == NR_GDT_ENTRIES);
+
+/// Segment descriptor, encoded in the GDT by `GdtBuilder`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Descriptor {
+    /// Code segment, 64-bit when `long` is set, 32-bit otherwise
+    Code { dpl: u8, long: bool },
+    Data { dpl: u8 },
+    /// 64-bit TSS, takes two GDT entries
+    Tss { base: u64, limit: u32 },
+}
+
+impl Descriptor {
+    pub const KERNEL_CODE32: Descriptor = Descriptor::Code { dpl: 0, long: false };
+    pub const KERNEL_CODE64: Descriptor = Descriptor::Code { dpl: 0, long: true };
+    pub const KERNEL_DATA: Descriptor = Descriptor::Data { dpl: 0 };
+    pub const USER_CODE32: Descriptor = Descriptor::Code { dpl: 3, long: false };
+    pub const USER_CODE64: Descriptor = Descriptor::Code { dpl: 3, long: true };
+    pub const USER_DATA: Descriptor = Descriptor::Data { dpl: 3 };
+
+    pub fn tss(base: u64, limit: u32) -> Descriptor {
+        Descriptor::Tss { base, limit }
+    }
+
+    /// Number of 8-byte GDT entries used
+    pub fn entries(&self) -> usize {
+        match self {
+            Descriptor::Tss { .. } => 2,
+            _ => 1,
+        }
+    }
+
+    fn dpl(&self) -> u8 {
+        match self {
+            Descriptor::Code { dpl, .. } | Descriptor::Data { dpl } => *dpl,
+            Descriptor::Tss { .. } => 0,
+        }
+    }
+
+    fn access(&self) -> u8 {
+        let dpl = (self.dpl() & 3) << 5;
+        match self {
+            Descriptor::Code { .. } => {
+                DESC_PRESENT | dpl | DESC_CODE_DATA | DESC_EXEC | DESC_RW | DESC_ACCESSED
+            }
+            Descriptor::Data { .. } => DESC_PRESENT | dpl | DESC_CODE_DATA | DESC_RW | DESC_ACCESSED,
+            Descriptor::Tss { .. } => DESC_PRESENT | DESC_TSS_AVAIL,
+        }
+    }
+
+    fn flags(&self) -> u8 {
+        match self {
+            Descriptor::Code { long: true, .. } => DESC_GRANULARITY | DESC_LONG,
+            Descriptor::Code { long: false, .. } | Descriptor::Data { .. } => {
+                DESC_GRANULARITY | DESC_DB
+            }
+            Descriptor::Tss { .. } => 0,
+        }
+    }
+
+    fn base(&self) -> u64 {
+        match self {
+            Descriptor::Tss { base, .. } => *base,
+            _ => 0,
+        }
+    }
+
+    fn limit(&self) -> u32 {
+        match self {
+            Descriptor::Tss { limit, .. } => *limit,
+            _ => 0xfffff,
+        }
+    }
+
+    /// Attributes in the VMSA format: access byte, then the flag nibble
+    pub fn attrib(&self) -> u16 {
+        self.access() as u16 | (self.flags() as u16) << 8
+    }
+
+    /// Raw GDT entries (the high half is only used by the TSS)
+    pub fn encode(&self) -> [u64; 2] {
+        let base = self.base();
+        let limit = self.limit() as u64;
+        let low = (limit & 0xffff)
+            | (base & 0xffffff) << 16
+            | (self.access() as u64) << 40
+            | ((limit >> 16) & 0xf) << 48
+            | (self.flags() as u64) << 52
+            | ((base >> 24) & 0xff) << 56;
+        [low, base >> 32]
+    }
+
+    /// Segment register value for `selector`, as loaded into the VMSA
+    pub fn vmsa_seg(&self, selector: u16) -> VmsaSeg {
+        let limit = match self {
+            // expanded by the granularity bit
+            Descriptor::Code { .. } | Descriptor::Data { .. } => u32::MAX,
+            Descriptor::Tss { limit, .. } => *limit,
+        };
+        VmsaSeg::new(selector | self.dpl() as u16, self.attrib(), limit, self.base())
+    }
+}
+
+impl Display for Descriptor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Descriptor::Code { dpl, long } => {
+                write!(f, "code{} dpl={}", if *long { "64" } else { "32" }, dpl)
+            }
+            Descriptor::Data { dpl } => write!(f, "data dpl={}", dpl),
+            Descriptor::Tss { base, limit } => write!(f, "tss base={:#x} limit={:#x}", base, limit),
+        }
+    }
+}
+
+/// Builds a GDT from typed descriptors
+///
+/// `GdtBuilder::linux()` gives the layout Linux uses, so the SYSCALL/SYSRET
+/// MSR values of the host stay valid in VMPL mode.
+#[derive(Debug, Default, Clone)]
+pub struct GdtBuilder {
+    descs: Vec<(u16, Descriptor)>,
+}
+
+impl GdtBuilder {
+    pub fn new() -> GdtBuilder {
+        GdtBuilder { descs: Vec::new() }
+    }
+
+    /// Linux-compatible layout, with the TSS at `GD_TSS`
+    pub fn linux(tss_base: u64, tss_limit: u32) -> GdtBuilder {
+        GdtBuilder::new()
+            .set(GD_KT32, Descriptor::KERNEL_CODE32)
+            .set(GD_KT as u16, Descriptor::KERNEL_CODE64)
+            .set(GD_KD as u16, Descriptor::KERNEL_DATA)
+            .set(GD_UT32, Descriptor::USER_CODE32)
+            .set(GD_UD as u16, Descriptor::USER_DATA)
+            .set(GD_UT as u16, Descriptor::USER_CODE64)
+            .set(GD_TSS as u16, Descriptor::tss(tss_base, tss_limit))
+    }
+
+    pub fn set(mut self, selector: u16, desc: Descriptor) -> GdtBuilder {
+        self.descs.retain(|(sel, _)| *sel != selector);
+        self.descs.push((selector, desc));
+        self
+    }
+
+    /// Check the selectors and encode the table
+    pub fn build(self) -> Result<Gdt, VmplError> {
+        let mut gdt = Gdt {
+            entries: [0; NR_GDT_ENTRIES],
+            descs: [None; NR_GDT_ENTRIES],
+        };
+        // entries taken so far, including the upper half of a TSS
+        let mut used = [false; NR_GDT_ENTRIES];
+
+        for (selector, desc) in self.descs.iter() {
+            // RPL and TI must be clear, and the null descriptor is reserved
+            if selector & 7 != 0 || *selector == 0 {
+                return Err(VmplError::GdtInvalidSelector(*selector));
+            }
+
+            let index = (selector >> 3) as usize;
+            if index + desc.entries() > NR_GDT_ENTRIES {
+                return Err(VmplError::GdtInvalidSelector(*selector));
+            }
+            if used[index..index + desc.entries()].iter().any(|used| *used) {
+                return Err(VmplError::GdtOverlap(*selector));
+            }
+            used[index..index + desc.entries()].fill(true);
+
+            let raw = desc.encode();
+            gdt.entries[index] = raw[0];
+            if desc.entries() == 2 {
+                gdt.entries[index + 1] = raw[1];
+            }
+            gdt.descs[index] = Some(*desc);
+        }
+
+        Ok(gdt)
+    }
+}
+
+/// An encoded GDT and the descriptors it was built from
+#[derive(Debug, Clone, Copy)]
+pub struct Gdt {
+    entries: [u64; NR_GDT_ENTRIES],
+    descs: [Option<Descriptor>; NR_GDT_ENTRIES],
+}
+
+impl Gdt {
+    pub fn entries(&self) -> &[u64; NR_GDT_ENTRIES] {
+        &self.entries
+    }
+
+    pub fn descriptor(&self, selector: u16) -> Option<&Descriptor> {
+        self.descs.get((selector >> 3) as usize)?.as_ref()
+    }
+
+    /// VMSA segment for `selector`, which must hold a descriptor
+    pub fn vmsa_seg(&self, selector: u16) -> Result<VmsaSeg, VmplError> {
+        self.descriptor(selector)
+            .map(|desc| desc.vmsa_seg(selector & !7))
+            .ok_or(VmplError::GdtInvalidSelector(selector))
+    }
+
+    /// GDTR for this table once copied to `base`
+    pub fn gdtr(&self, base: u64) -> VmsaSeg {
+        VmsaSeg::new(0, 0, (NR_GDT_ENTRIES * 8 - 1) as u32, base)
+    }
+
+    /// Check the descriptors SYSCALL/SYSRET and the task register rely on
+    pub fn validate_linux(&self) -> Result<(), VmplError> {
+        let expect = [
+            (GD_KT as u16, Descriptor::KERNEL_CODE64),
+            (GD_KD as u16, Descriptor::KERNEL_DATA),
+            (GD_UT32, Descriptor::USER_CODE32),
+            (GD_UD as u16, Descriptor::USER_DATA),
+            (GD_UT as u16, Descriptor::USER_CODE64),
+        ];
+        for (selector, desc) in expect.iter() {
+            if self.descriptor(*selector) != Some(desc) {
+                return Err(VmplError::GdtInvalidSelector(*selector));
+            }
+        }
+
+        match self.descriptor(GD_TSS as u16) {
+            Some(Descriptor::Tss { .. }) => Ok(()),
+            _ => Err(VmplError::GdtInvalidSelector(GD_TSS as u16)),
+        }
+    }
+}
+
+impl Display for Gdt {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "GDT:")?;
+        for (i, entry) in self.entries.iter().enumerate() {
+            write!(f, "  [{:#04x}] {:#018x}", i * 8, entry)?;
+            if let Some(desc) = &self.descs[i] {
+                write!(f, " {} attrib={:#06x}", desc, desc.attrib())?;
+            }
+            f.write_str("\n")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::globals::GD_TSS2;
+
+    fn linux() -> Gdt {
+        GdtBuilder::linux(0xffff_8880_1234_5000, 0x67).build().unwrap()
+    }
+
+    #[test]
+    fn descriptor_encoding() {
+        assert_eq!(Descriptor::KERNEL_CODE64.encode()[0], 0x00af9b000000ffff);
+        assert_eq!(Descriptor::KERNEL_DATA.encode()[0], 0x00cf93000000ffff);
+        assert_eq!(Descriptor::KERNEL_CODE32.encode()[0], 0x00cf9b000000ffff);
+        assert_eq!(Descriptor::USER_DATA.encode()[0], 0x00cff3000000ffff);
+        assert_eq!(Descriptor::USER_CODE64.encode()[0], 0x00affb000000ffff);
+        assert_eq!(Descriptor::USER_CODE32.encode()[0], 0x00cffb000000ffff);
+        assert_eq!(Descriptor::USER_CODE64.attrib(), 0xafb);
+    }
+
+    #[test]
+    fn tss_takes_two_entries() {
+        let gdt = linux();
+        let index = (GD_TSS >> 3) as usize;
+        assert_eq!(gdt.entries()[index], 0x12008934_50000067);
+        assert_eq!(gdt.entries()[index + 1], 0xffff8880);
+        assert_eq!(GD_TSS2, GD_TSS + 8);
+        assert!(gdt.descriptor(GD_TSS2 as u16).is_none());
+    }
+
+    #[test]
+    fn overlap_rejected() {
+        let builder = GdtBuilder::new().set(0x28, Descriptor::tss(0, 0x67));
+        // the upper half of a TSS with base < 4G is all zero
+        let err = builder.clone().set(0x30, Descriptor::USER_DATA).build();
+        assert!(matches!(err, Err(VmplError::GdtOverlap(_))));
+        let err = builder.clone().set(0x20, Descriptor::tss(0, 0x67)).build();
+        assert!(matches!(err, Err(VmplError::GdtOverlap(_))));
+        assert!(builder.set(0x38, Descriptor::USER_DATA).build().is_ok());
+
+        let err = GdtBuilder::new().set(0x40, Descriptor::tss(0, 0x67)).build();
+        assert!(matches!(err, Err(VmplError::GdtInvalidSelector(0x40))));
+        let err = GdtBuilder::new().set(0x2b, Descriptor::USER_DATA).build();
+        assert!(matches!(err, Err(VmplError::GdtInvalidSelector(0x2b))));
+    }
+
+    #[test]
+    fn selectors_match_dune_s() {
+        // GD_KT, GD_KD, GD_UD and GD_UT in dune.S
+        let gdt = linux();
+        gdt.validate_linux().unwrap();
+        let expect = [(GD_KT, 0x10), (GD_KD, 0x18), (GD_UD, 0x2b), (GD_UT, 0x33)];
+        for (selector, loaded) in expect {
+            assert_eq!(gdt.vmsa_seg(selector as u16).unwrap().selector(), loaded);
+        }
+    }
+}
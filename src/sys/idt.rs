@@ -6,71 +6,282 @@
  *          Tom Lendacky <thomas.lendacky@amd.com>
  */
 
-use lazy_static::lazy_static;
+use std::ptr::addr_of_mut;
+
 use log::info;
+use x86_64::registers::control::Cr2;
 use x86_64::structures::idt::InterruptDescriptorTable;
 use x86_64::structures::idt::InterruptStackFrame;
 use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::VirtAddr;
 
 use crate::error::VmplError;
+use crate::ghcb::vc::__vmpl_vc_entry;
+#[cfg(feature = "mm")]
+use crate::mm::{handle_page_fault, FaultAction};
+use crate::sys::core::DuneTrapFrame;
+use crate::sys::crash::crash_report;
+use crate::sys::user::user_trap_exit;
 
-lazy_static! {
-    static ref IDT: InterruptDescriptorTable = {
-        let mut idt: InterruptDescriptorTable = InterruptDescriptorTable::new();
-
-        idt.breakpoint.set_handler_fn(bp_handler);
-        idt.double_fault.set_handler_fn(df_handler);
-        idt.general_protection_fault.set_handler_fn(gp_handler);
-        idt.page_fault.set_handler_fn(pf_handler);
+/// Generate an exception entry stub that saves the full register state as a
+/// `DuneTrapFrame` (same layout as `__dune_intr` in dune.S) and calls
+/// `$handler(vector, tf)`. Use the `err` form for exceptions that push an
+/// error code, otherwise a zero placeholder is pushed in its place.
+/// Traps from CPL3 switch to the kernel FS base, like `SET_G0_FS_BASE`.
+#[macro_export]
+macro_rules! trap_entry {
+    ($entry: ident, $vector: expr, $handler: path) => {
+        $crate::trap_entry!(@stub $entry, $vector, $handler, "pushq $0");
+    };
+    ($entry: ident, $vector: expr, $handler: path, err) => {
+        $crate::trap_entry!(@stub $entry, $vector, $handler, "");
+    };
+    (@stub $entry: ident, $vector: expr, $handler: path, $push: literal) => {
+        core::arch::global_asm!(
+            concat!(".globl ", stringify!($entry)),
+            concat!(stringify!($entry), ":"),
+            $push,
+            "pushq %rax",
+            "subq $112, %rsp",
+            "movq %rdi, 0(%rsp)",
+            "movq %rsi, 8(%rsp)",
+            "movq %rdx, 16(%rsp)",
+            "movq %rcx, 24(%rsp)",
+            "movq %r8, 32(%rsp)",
+            "movq %r9, 40(%rsp)",
+            "movq %r10, 48(%rsp)",
+            "movq %r11, 56(%rsp)",
+            "movq %rbx, 64(%rsp)",
+            "movq %rbp, 72(%rsp)",
+            "movq %r12, 80(%rsp)",
+            "movq %r13, 88(%rsp)",
+            "movq %r14, 96(%rsp)",
+            "movq %r15, 104(%rsp)",
+            "cld",
+            "testq $3, 136(%rsp)",
+            "jz 1f",
+            "movq $0, %gs:32",
+            "movq %gs:16, %rax",
+            "wrfsbase %rax",
+            "1:",
+            "movq ${vector}, %rdi",
+            "movq %rsp, %rsi",
+            /* the hardware frame leaves us 8 bytes off a 16-byte boundary */
+            "subq $8, %rsp",
+            "call {handler}",
+            "addq $8, %rsp",
+            "testq $3, 136(%rsp)",
+            "jz 2f",
+            "movq $1, %gs:32",
+            "movq %gs:24, %rax",
+            "wrfsbase %rax",
+            "2:",
+            "movq 104(%rsp), %r15",
+            "movq 96(%rsp), %r14",
+            "movq 88(%rsp), %r13",
+            "movq 80(%rsp), %r12",
+            "movq 72(%rsp), %rbp",
+            "movq 64(%rsp), %rbx",
+            "movq 56(%rsp), %r11",
+            "movq 48(%rsp), %r10",
+            "movq 40(%rsp), %r9",
+            "movq 32(%rsp), %r8",
+            "movq 24(%rsp), %rcx",
+            "movq 16(%rsp), %rdx",
+            "movq 8(%rsp), %rsi",
+            "movq 0(%rsp), %rdi",
+            "movq 112(%rsp), %rax",
+            /* skip the registers, %rax and the error code */
+            "addq $128, %rsp",
+            "iretq",
+            vector = const $vector,
+            handler = sym $handler,
+            options(att_syntax)
+        );
 
-        idt
+        extern "C" {
+            pub(crate) fn $entry();
+        }
     };
 }
 
-fn do_panic(stack_frame: InterruptStackFrame, name: &str, error_code: u64) -> ! {
-    let rip: u64 = stack_frame.instruction_pointer.as_u64();
-    let msg: String = format!(
-        "#{} at RIP {:#0x} with error code {:#0x}",
-        name,
-        rip,
-        error_code
-    );
+trap_entry!(__vmpl_de_entry, 0, exception_trap);
+trap_entry!(__vmpl_db_entry, 1, exception_trap);
+trap_entry!(__vmpl_bp_entry, 3, exception_trap);
+trap_entry!(__vmpl_of_entry, 4, exception_trap);
+trap_entry!(__vmpl_br_entry, 5, exception_trap);
+trap_entry!(__vmpl_ud_entry, 6, exception_trap);
+trap_entry!(__vmpl_nm_entry, 7, exception_trap);
+trap_entry!(__vmpl_df_entry, 8, df_trap, err);
+trap_entry!(__vmpl_ts_entry, 10, exception_trap, err);
+trap_entry!(__vmpl_np_entry, 11, exception_trap, err);
+trap_entry!(__vmpl_ss_entry, 12, exception_trap, err);
+trap_entry!(__vmpl_gp_entry, 13, gp_trap, err);
+trap_entry!(__vmpl_pf_entry, 14, pf_trap, err);
+trap_entry!(__vmpl_mf_entry, 16, exception_trap);
+trap_entry!(__vmpl_ac_entry, 17, exception_trap, err);
+trap_entry!(__vmpl_xm_entry, 19, exception_trap);
+trap_entry!(__vmpl_cp_entry, 21, exception_trap, err);
+
+const BP_VECTOR: u64 = 3;
 
-    panic!("{}", msg);
+/// IST slot of the #DF stack, kept apart from the other exceptions' so a
+/// stack overflow still gets reported
+pub const IST_DOUBLE_FAULT: u16 = 0;
+
+static mut IDT: InterruptDescriptorTable = InterruptDescriptorTable::new();
+
+fn idt_setup(idt: &mut InterruptDescriptorTable) {
+    unsafe {
+        idt.divide_error
+            .set_handler_addr(VirtAddr::new(__vmpl_de_entry as usize as u64));
+        idt.debug
+            .set_handler_addr(VirtAddr::new(__vmpl_db_entry as usize as u64));
+        idt.breakpoint
+            .set_handler_addr(VirtAddr::new(__vmpl_bp_entry as usize as u64));
+        idt.overflow
+            .set_handler_addr(VirtAddr::new(__vmpl_of_entry as usize as u64));
+        idt.bound_range_exceeded
+            .set_handler_addr(VirtAddr::new(__vmpl_br_entry as usize as u64));
+        idt.invalid_opcode
+            .set_handler_addr(VirtAddr::new(__vmpl_ud_entry as usize as u64));
+        idt.device_not_available
+            .set_handler_addr(VirtAddr::new(__vmpl_nm_entry as usize as u64));
+        idt.invalid_tss
+            .set_handler_addr(VirtAddr::new(__vmpl_ts_entry as usize as u64));
+        idt.segment_not_present
+            .set_handler_addr(VirtAddr::new(__vmpl_np_entry as usize as u64));
+        idt.stack_segment_fault
+            .set_handler_addr(VirtAddr::new(__vmpl_ss_entry as usize as u64));
+        idt.x87_floating_point
+            .set_handler_addr(VirtAddr::new(__vmpl_mf_entry as usize as u64));
+        idt.alignment_check
+            .set_handler_addr(VirtAddr::new(__vmpl_ac_entry as usize as u64));
+        idt.simd_floating_point
+            .set_handler_addr(VirtAddr::new(__vmpl_xm_entry as usize as u64));
+        idt.cp_protection_exception
+            .set_handler_addr(VirtAddr::new(__vmpl_cp_entry as usize as u64));
+        idt.double_fault
+            .set_handler_addr(VirtAddr::new(__vmpl_df_entry as usize as u64))
+            .set_stack_index(IST_DOUBLE_FAULT);
+        idt.general_protection_fault
+            .set_handler_addr(VirtAddr::new(__vmpl_gp_entry as usize as u64));
+        idt.page_fault
+            .set_handler_addr(VirtAddr::new(__vmpl_pf_entry as usize as u64));
+        idt.vmm_communication_exception
+            .set_handler_addr(VirtAddr::new(__vmpl_vc_entry as usize as u64));
+    }
 }
 
-/// Breakpoint handler
-/// This handler is used for debugging purposes
-/// It will print a message and continue execution
-extern "x86-interrupt" fn bp_handler(stack_frame: InterruptStackFrame) {
-    let rip: u64 = stack_frame.instruction_pointer.as_u64();
-    let msg: String = format!("#BP at RIP {:#0x}", rip);
+fn exception_name(vector: u64) -> &'static str {
+    match vector {
+        0 => "DE",
+        1 => "DB",
+        3 => "BP",
+        4 => "OF",
+        5 => "BR",
+        6 => "UD",
+        7 => "NM",
+        10 => "TS",
+        11 => "NP",
+        12 => "SS",
+        16 => "MF",
+        17 => "AC",
+        19 => "XM",
+        21 => "CP",
+        _ => "TRAP",
+    }
+}
 
-    println!("{}", msg);
+/// Handler of the exceptions without one of their own
+/// From user mode they exit to the `UserContext` owner. In the kernel a
+/// breakpoint prints a message and continues, the rest are fatal.
+extern "C" fn exception_trap(vector: u64, tf: &mut DuneTrapFrame) {
+    user_trap_exit(vector, tf);
+    if vector == BP_VECTOR {
+        println!("#BP at RIP {:#0x}", tf.rip());
+        return;
+    }
+    crash_report(exception_name(vector), tf)
 }
 
 /// Double fault handler
-/// Every interruption except for #PF, #VC and #GP will end up here
-extern "x86-interrupt" fn df_handler(stack_frame: InterruptStackFrame, _error_code: u64) -> ! {
-    do_panic(stack_frame, "DF", 0)
+/// Faults while delivering another exception end up here
+extern "C" fn df_trap(_vector: u64, tf: &mut DuneTrapFrame) {
+    crash_report("DF", tf)
 }
 
 /// General protection fault handler
-extern "x86-interrupt" fn gp_handler(stack_frame: InterruptStackFrame, error_code: u64) {
-    do_panic(stack_frame, "GP", error_code)
+extern "C" fn gp_trap(vector: u64, tf: &mut DuneTrapFrame) {
+    user_trap_exit(vector, tf);
+    crash_report("GP", tf)
 }
 
 /// Page fault handler
-extern "x86-interrupt" fn pf_handler(
-    stack_frame: InterruptStackFrame,
-    error_code: PageFaultErrorCode,
-) {
-    do_panic(stack_frame, "PF", error_code.bits())
+/// Hands the fault to the mm layer, which may fix up the mapping (we then
+/// return and retry the instruction) or ask for a signal to be delivered
+#[cfg(feature = "mm")]
+extern "C" fn pf_trap(vector: u64, tf: &mut DuneTrapFrame) {
+    let addr: u64 = Cr2::read_raw();
+    let error_code = PageFaultErrorCode::from_bits_truncate(tf.err() as u64);
+
+    match handle_page_fault(addr, error_code, tf) {
+        FaultAction::Continue | FaultAction::Retry => {}
+        FaultAction::Signal(sig) => unsafe {
+            // a sandboxed user context gets the fault instead of a signal
+            user_trap_exit(vector, tf);
+            libc::raise(sig);
+        },
+        FaultAction::Abort => {
+            // only the kernel crashes on a fault nobody can fix
+            user_trap_exit(vector, tf);
+            crash_report("PF", tf)
+        }
+    }
+}
+
+#[cfg(not(feature = "mm"))]
+extern "C" fn pf_trap(vector: u64, tf: &mut DuneTrapFrame) {
+    user_trap_exit(vector, tf);
+    crash_report("PF", tf)
 }
 
 /// Load IDT with function handlers for each exception
 pub fn idt_init() -> Result<(), VmplError> {
     info!("Loading IDT");
-    IDT.load();
+    unsafe {
+        let idt = &mut *addr_of_mut!(IDT);
+        idt_setup(idt);
+        idt.load();
+    }
+    Ok(())
+}
+
+/// Install `handler` for the external interrupt `vector`
+/// Vectors below 32 are reserved for exceptions
+pub fn idt_register_irq(
+    vector: u8,
+    handler: extern "x86-interrupt" fn(InterruptStackFrame),
+) -> Result<(), VmplError> {
+    if vector < 32 {
+        return Err(VmplError::Sys(libc::EINVAL));
+    }
+
+    unsafe {
+        (&mut *addr_of_mut!(IDT))[vector].set_handler_fn(handler);
+    }
+    Ok(())
+}
+
+/// Install a `trap_entry!` stub for `vector`, for interrupts whose handler
+/// needs the full trap frame
+pub fn idt_register_trap(vector: u8, entry: unsafe extern "C" fn()) -> Result<(), VmplError> {
+    if vector < 32 {
+        return Err(VmplError::Sys(libc::EINVAL));
+    }
+
+    unsafe {
+        (&mut *addr_of_mut!(IDT))[vector].set_handler_addr(VirtAddr::new(entry as usize as u64));
+    }
     Ok(())
 }
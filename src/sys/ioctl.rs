@@ -53,7 +53,17 @@ pub mod vmpl_ioctl {
         }
 
         pub fn set_user_vmpl(&mut self, gva: u64, page_size: u32, attrs: u32) -> Result<(), Error> {
-            let nr_pages = 1;
+            self.set_user_vmpl_pages(gva, page_size, attrs, 1)
+        }
+
+        /// Set the VMPL permissions of `nr_pages` pages starting at `gva`
+        pub fn set_user_vmpl_pages(
+            &mut self,
+            gva: u64,
+            page_size: u32,
+            attrs: u32,
+            nr_pages: u32,
+        ) -> Result<(), Error> {
             let data = VmplParam::new(gva, page_size, attrs, nr_pages);
             VMPL_IOCTL_SET_DATA.ioctl(&mut self.fd, &data)?;
             Ok(())
@@ -2,10 +2,16 @@
 pub mod x86_64;
 /// APIC (Advanced Programmable Interrupt Controller) module
 pub mod apic;
+/// Emulated x2APIC module
+pub mod apic_emu;
+/// GDT (Global Descriptor Table) module
+pub mod gdt;
 /// IDT (Interrupt Descriptor Table) module
 pub mod idt;
 /// VMPL Core module
 pub mod core;
+/// Crash reporting module
+pub mod crash;
 /// IOCTL module
 pub mod ioctl;
 /// Per-CPU module
@@ -16,8 +22,16 @@ pub mod seimi;
 pub mod serial;
 /// Signal module
 pub mod signal;
+/// Cross-CPU function call module
+pub mod smp;
 /// Syscall module
 pub mod syscall;
+/// x2APIC timer module
+pub mod timer;
+/// Sandboxed user context module
+pub mod user;
+/// Extended state (XSAVE) module
+pub mod xsave;
 
 pub use crate::sys::x86_64::*;
 #[cfg(feature = "apic")]
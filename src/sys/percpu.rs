@@ -7,29 +7,43 @@ use libc::PROT_READ;
 use libc::PROT_WRITE;
 use log::{error, info};
 use std::arch::asm;
+use std::collections::BTreeMap;
 use std::fmt::Pointer;
 use std::mem;
 use std::mem::{offset_of, size_of};
-use std::os::raw::c_char;
 use std::os::raw::c_int;
 use std::ptr::null_mut;
 use std::ptr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use lazy_static::lazy_static;
+use x86_64::registers::segmentation::{Segment64, GS};
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::VirtAddr;
 
 use crate::error::VmplError;
+use crate::funcs;
 use crate::ghcb::vc_init;
 use crate::ghcb::Ghcb;
 use crate::globals::NR_GDT_ENTRIES;
 use crate::mm::PGSIZE;
+use crate::sys::idt::IST_DOUBLE_FAULT;
 use crate::sys::serial_init;
+#[cfg(feature = "apic")]
+use crate::sys::apic::apic::{apic_current_cpu, apic_init_rt_entry};
+#[cfg(feature = "apic")]
+use crate::sys::timer::timer::timer_init;
+#[cfg(feature = "mm")]
+use crate::mm::pkey_init;
+#[cfg(feature = "ghcb")]
+use crate::mm::{pgtable_va_to_pa, PageRef};
+#[cfg(feature = "xsave")]
+use crate::sys::xsave::xsave::{xsave_request_amx, XsaveArea, XsaveInsn};
 
 use super::core::VmplSegs;
 use super::core::VmsaSeg;
 use super::ioctl::vmpl_ioctl::VmplFile;
 
-#[cfg(feature = "xsave")]
-const XSAVE_SIZE: usize = 4096;
 #[cfg(feature = "xsave")]
 const XCR_XFEATURE_ENABLED_MASK: u32 = 0x00000000;
 /// Global variable to indicate whether VMPL has been booted
@@ -41,7 +55,8 @@ type VSyscall = extern "C" fn(u64, u64, u64, u64, u64, u64) -> u64;
 
 #[repr(C)]
 pub struct DunePerCpu {
-    percpu_ptr: Box<DunePerCpu>,
+    /// Points at itself, so `gs:[0]` yields the per-CPU page address
+    percpu_ptr: *mut DunePerCpu,
     tmp: u64,
     kfs_base: u64,
     ufs_base: u64,
@@ -51,53 +66,296 @@ pub struct DunePerCpu {
     ghcb: *mut Ghcb,
     lstar: Box<DuneSyscall>,
     vsyscall: Box<VSyscall>,
-    xsave_area: *mut c_char,
-    xsave_mask: u64,
+    #[cfg(feature = "xsave")]
+    xsave_area: Option<XsaveArea>,
     pkey: c_int,
 }
 
+/// Read a u64 at `offset` in the current per-CPU page
+#[inline]
+unsafe fn percpu_read_u64(offset: usize) -> u64 {
+    let value: u64;
+    asm!(
+        "mov {}, qword ptr gs:[{}]",
+        out(reg) value,
+        in(reg) offset,
+        options(nostack, readonly, preserves_flags),
+    );
+    value
+}
+
+/// Write a u64 at `offset` in the current per-CPU page
+#[inline]
+unsafe fn percpu_write_u64(offset: usize, value: u64) {
+    asm!(
+        "mov qword ptr gs:[{}], {}",
+        in(reg) offset,
+        in(reg) value,
+        options(nostack, preserves_flags),
+    );
+}
+
 pub fn dune_get_user_fs() -> u64 {
-    let ptr: *mut u8;
-    unsafe {
-        asm!(
-            "movq gs:{}, {}",
-            in(reg) offset_of!(DunePerCpu, ufs_base),
-            out(reg) ptr,
-            options(nostack, preserves_flags),
-        );
-    }
-    ptr as u64
+    unsafe { percpu_read_u64(offset_of!(DunePerCpu, ufs_base)) }
 }
 
 pub fn dune_set_user_fs(fs_base: u64) {
-    unsafe {
-        asm!(
-            "movq {}, gs:{}",
-            in(reg) fs_base,
-            in(reg) offset_of!(DunePerCpu, ufs_base),
-            options(nostack, preserves_flags),
-        );
+    unsafe { percpu_write_u64(offset_of!(DunePerCpu, ufs_base), fs_base) }
+}
+
+/// Registry entry of an allocated per-CPU page
+#[derive(Debug, Clone, Copy)]
+pub struct PerCpuEntry {
+    /// CPU found by APIC ID once the thread is in VMPL mode
+    pub cpu: Option<usize>,
+    pub tid: libc::pid_t,
+    percpu: usize,
+}
+
+impl PerCpuEntry {
+    pub fn percpu(&self) -> *mut DunePerCpu {
+        self.percpu as *mut DunePerCpu
+    }
+}
+
+lazy_static! {
+    /// Every live `DunePerCpu`, keyed by the thread that owns it
+    static ref PERCPU_REGISTRY: Mutex<BTreeMap<libc::pid_t, PerCpuEntry>> =
+        Mutex::new(BTreeMap::new());
+}
+
+/// Most per-CPU pages alive at once
+const PERCPU_MAX: usize = 256;
+
+/// Addresses of the registered per-CPU pages, kept next to the registry
+/// so `this_cpu` can check the GS base without a lock: it runs on every
+/// heap allocation and in exception handlers.
+static PERCPU_PAGES: [AtomicUsize; PERCPU_MAX] = [const { AtomicUsize::new(0) }; PERCPU_MAX];
+/// Slots of `PERCPU_PAGES` used so far, lookups stop there
+static PERCPU_PAGES_USED: AtomicUsize = AtomicUsize::new(0);
+
+fn percpu_is_registered(percpu: usize) -> bool {
+    let used = PERCPU_PAGES_USED.load(Ordering::Acquire);
+    PERCPU_PAGES[..used]
+        .iter()
+        .any(|page| page.load(Ordering::Acquire) == percpu)
+}
+
+fn percpu_register(percpu: *mut DunePerCpu) -> Result<(), VmplError> {
+    let tid = unsafe { libc::gettid() };
+    let entry = PerCpuEntry { cpu: None, tid, percpu: percpu as usize };
+
+    let mut registry = PERCPU_REGISTRY.lock().unwrap();
+    // the registry lock serializes writers of PERCPU_PAGES
+    let slot = PERCPU_PAGES
+        .iter()
+        .position(|page| page.load(Ordering::Relaxed) == 0)
+        .ok_or(VmplError::Sys(libc::ENOMEM))?;
+
+    // slots reserved so far start out initialized in the new page too
+    for slot in PERCPU_SLOTS.lock().unwrap().inits.iter() {
+        slot.init(percpu as usize);
+    }
+    registry.insert(tid, entry);
+    PERCPU_PAGES[slot].store(percpu as usize, Ordering::Release);
+    PERCPU_PAGES_USED.fetch_max(slot + 1, Ordering::AcqRel);
+    Ok(())
+}
+
+fn percpu_unregister(percpu: *mut DunePerCpu) {
+    let mut registry = PERCPU_REGISTRY.lock().unwrap();
+    registry.retain(|_, e| e.percpu != percpu as usize);
+    if let Some(page) = PERCPU_PAGES
+        .iter()
+        .find(|page| page.load(Ordering::Relaxed) == percpu as usize)
+    {
+        page.store(0, Ordering::Release);
+    }
+}
+
+/// Record the CPU the per-CPU page `percpu` runs on
+#[cfg(feature = "apic")]
+fn percpu_set_cpu(percpu: *mut DunePerCpu, cpu: usize) {
+    let mut registry = PERCPU_REGISTRY.lock().unwrap();
+    if let Some(entry) = registry.values_mut().find(|e| e.percpu == percpu as usize) {
+        entry.cpu = Some(cpu);
+    }
+}
+
+/// Per-CPU page of the thread `tid`
+pub fn percpu_for_thread(tid: libc::pid_t) -> Option<PerCpuEntry> {
+    PERCPU_REGISTRY.lock().unwrap().get(&tid).copied()
+}
+
+/// Per-CPU pages of threads running on `cpu`
+pub fn percpu_for_cpu(cpu: usize) -> Vec<PerCpuEntry> {
+    PERCPU_REGISTRY
+        .lock()
+        .unwrap()
+        .values()
+        .filter(|e| e.cpu == Some(cpu))
+        .copied()
+        .collect()
+}
+
+/// All registered per-CPU pages
+pub fn percpu_entries() -> Vec<PerCpuEntry> {
+    PERCPU_REGISTRY.lock().unwrap().values().copied().collect()
+}
+
+/// Per-CPU page of the current CPU, found through the GS base
+///
+/// Returns `None` outside VMPL mode, where GS does not point at a
+/// registered `DunePerCpu`. The GS base is checked against the registered
+/// pages before it is dereferenced, without taking a lock, so this is
+/// safe to call from the allocator and from exception handlers. The page
+/// is shared with interrupt handlers on this CPU, so callers dereference
+/// it themselves, for as short as they can.
+pub fn this_cpu() -> Option<*mut DunePerCpu> {
+    let percpu = GS::read_base().as_u64() as usize;
+    if percpu == 0 || !percpu_is_registered(percpu) {
+        return None;
+    }
+    let percpu = percpu as *mut DunePerCpu;
+    if unsafe { (*percpu).percpu_ptr } != percpu {
+        return None;
+    }
+    Some(percpu)
+}
+
+/// Per-CPU page of the calling thread, whether in VMPL mode or not
+pub fn current_percpu() -> Option<*mut DunePerCpu> {
+    if let Some(percpu) = this_cpu() {
+        return Some(percpu);
+    }
+    let entry = percpu_for_thread(unsafe { libc::gettid() })?;
+    Some(entry.percpu())
+}
+
+/// Per-CPU variable slots live in the per-CPU page, after `DunePerCpu`
+const PERCPU_SLOTS_START: usize = (size_of::<DunePerCpu>() + 63) & !63;
+
+struct SlotInit {
+    offset: usize,
+    init: Box<dyn Fn(*mut u8) + Send>,
+}
+
+impl SlotInit {
+    fn init(&self, percpu: usize) {
+        (self.init)((percpu + self.offset) as *mut u8);
+    }
+}
+
+struct PerCpuSlots {
+    next: usize,
+    inits: Vec<SlotInit>,
+}
+
+lazy_static! {
+    static ref PERCPU_SLOTS: Mutex<PerCpuSlots> = Mutex::new(PerCpuSlots {
+        next: PERCPU_SLOTS_START,
+        inits: Vec::new(),
+    });
+}
+
+/// A typed variable with one instance per CPU, stored in the per-CPU page
+///
+/// Declare with `define_percpu!`. The slot is reserved on first use and
+/// initialized in every per-CPU page, existing and future. Slot values are
+/// never dropped.
+pub struct PerCpuVar<T: 'static> {
+    /// Offset in the per-CPU page, 0 until reserved
+    offset: AtomicUsize,
+    init: fn() -> T,
+}
+
+impl<T: Send + 'static> PerCpuVar<T> {
+    pub const fn new(init: fn() -> T) -> PerCpuVar<T> {
+        PerCpuVar {
+            offset: AtomicUsize::new(0),
+            init,
+        }
+    }
+
+    fn reserve(&'static self) -> Result<usize, VmplError> {
+        // the registry lock orders reservation against new pages
+        let registry = PERCPU_REGISTRY.lock().unwrap();
+        let mut slots = PERCPU_SLOTS.lock().unwrap();
+
+        let offset = self.offset.load(Ordering::Acquire);
+        if offset != 0 {
+            return Ok(offset);
+        }
+
+        let align = mem::align_of::<T>().max(8);
+        let offset = (slots.next + align - 1) & !(align - 1);
+        if offset + size_of::<T>() > PGSIZE {
+            return Err(VmplError::PerCpuNoSpace(size_of::<T>()));
+        }
+
+        let slot = SlotInit {
+            offset,
+            init: Box::new(move |ptr: *mut u8| unsafe { ptr::write(ptr as *mut T, (self.init)()) }),
+        };
+        for entry in registry.values() {
+            slot.init(entry.percpu);
+        }
+        slots.inits.push(slot);
+        slots.next = offset + size_of::<T>();
+
+        self.offset.store(offset, Ordering::Release);
+        Ok(offset)
+    }
+
+    /// Address of this CPU's instance
+    pub fn this_cpu_ptr(&'static self) -> Result<*mut T, VmplError> {
+        let offset = match self.offset.load(Ordering::Acquire) {
+            0 => self.reserve()?,
+            offset => offset,
+        };
+        let percpu = this_cpu().ok_or(VmplError::PerCpuNotActive)?;
+        Ok((percpu as usize + offset) as *mut T)
+    }
+
+    /// Run `f` on this CPU's instance
+    pub fn with<R>(&'static self, f: impl FnOnce(&mut T) -> R) -> Result<R, VmplError> {
+        let ptr = self.this_cpu_ptr()?;
+        Ok(f(unsafe { &mut *ptr }))
+    }
+}
+
+impl<T: Copy + Send + 'static> PerCpuVar<T> {
+    pub fn get(&'static self) -> Result<T, VmplError> {
+        self.with(|v| *v)
+    }
+
+    pub fn set(&'static self, value: T) -> Result<(), VmplError> {
+        self.with(|v| *v = value)
     }
 }
 
 use crate::globals::GD_TSS;
+use crate::sys::gdt::{Gdt, GdtBuilder};
 use std::fmt::Display;
 
 impl DunePerCpu {
 
-    fn setup_gdt(&mut self) {
-        GDT.init();
-        let mut gdt = [0; NR_GDT_ENTRIES];
-        let tss = VmsaSeg::new(GD_TSS, 0x0089, mem::size_of_val(&self.tss), &mut self.tss);
-        gdt[GD_TSS as usize] = tss.as_u64();
-        self.gdt = gdt;
+    fn setup_gdt(&mut self) -> Result<Gdt, VmplError> {
+        let tss_base = &self.tss as *const _ as u64;
+        let tss_limit = mem::size_of_val(&self.tss) as u32 - 1;
+        let gdt = GdtBuilder::linux(tss_base, tss_limit).build()?;
+        gdt.validate_linux()?;
+        log::debug!("{}", gdt);
+
+        self.gdt = *gdt.entries();
+        Ok(gdt)
     }
 
-    fn setup_vmsa(&mut self, fd: VmplFile) -> Result<(), VmplError> {
+    fn setup_vmsa(&mut self, fd: VmplFile, gdt: &Gdt) -> Result<(), VmplError> {
         let fs = VmsaSeg::fs(self.kfs_base);
         let gs = VmsaSeg::gs(self as *mut _ as u64);
-        let tr = VmsaSeg::new(GD_TSS, 0x0089,  mem::size_of_val(&self.tss), &mut self.tss);
-        let gdtr = VmsaSeg::new(0, 0, mem::size_of_val(&self.gdt) as usize - 1, &mut self.gdt as *mut _ as u64);
+        let tr = gdt.vmsa_seg(GD_TSS as u16)?;
+        let gdtr = gdt.gdtr(&self.gdt as *const _ as u64);
         let idtr = VmsaSeg::new(0, 0, mem::size_of_val(&IDT) - 1, &IDT as *const _ as u64);
         let segs = VmplSegs::new(fs, gs, gdtr, idtr, tr);
         let mut segs = Box::new(segs);
@@ -111,9 +369,16 @@ impl DunePerCpu {
         self.ghcb
     }
 
-    fn setup_safe_stack(&mut self) -> Result<(), VmplError> {
-        println!("setup safe stack");
-        let safe_stack = unsafe {
+    pub fn set_ghcb(&mut self, ghcb: *mut Ghcb) {
+        self.ghcb = ghcb;
+    }
+
+    /// Protection key of the domain this CPU is in
+    funcs!(pkey, c_int);
+
+    /// Map a one-page stack, returning its top
+    fn map_stack() -> Result<VirtAddr, VmplError> {
+        let stack = unsafe {
             mmap(
                 std::ptr::null_mut(),
                 PGSIZE,
@@ -124,18 +389,26 @@ impl DunePerCpu {
             )
         };
 
-        if safe_stack == MAP_FAILED {
+        if stack == MAP_FAILED {
             return Err(VmplError::Sys(libc::ENOMEM));
         }
 
-        let safe_stack = unsafe { safe_stack.offset(PGSIZE as isize) };
+        Ok(VirtAddr::new(stack as u64 + PGSIZE as u64))
+    }
+
+    fn setup_safe_stack(&mut self) -> Result<(), VmplError> {
+        println!("setup safe stack");
+        let safe_stack = Self::map_stack()?;
         self.tss.iomap_base = size_of::<TaskStateSegment>() as u16;
 
         for i in 0..7 {
-            self.tss.interrupt_stack_table[i] = VirtAddr::new(safe_stack as u64);
+            self.tss.interrupt_stack_table[i] = safe_stack;
         }
 
-        self.tss.privilege_stack_table[0] = VirtAddr::new(safe_stack as u64);
+        // a #DF raised by overflowing the safe stack must not reuse it
+        self.tss.interrupt_stack_table[IST_DOUBLE_FAULT as usize] = Self::map_stack()?;
+
+        self.tss.privilege_stack_table[0] = safe_stack;
 
         Ok(())
     }
@@ -178,6 +451,9 @@ impl DunePerCpu {
         }
 
         unsafe {
+            #[cfg(feature = "xsave")]
+            ptr::write(ptr::addr_of_mut!((*percpu).xsave_area), None);
+            (*percpu).percpu_ptr = percpu;
             (*percpu).kfs_base = fs_base;
             (*percpu).ufs_base = fs_base;
             (*percpu).in_usermode = 1;
@@ -193,69 +469,58 @@ impl DunePerCpu {
             }
         }
 
+        if let Err(e) = percpu_register(percpu) {
+            unsafe { libc::munmap(percpu as *mut _, PGSIZE) };
+            return Err(e);
+        }
         Ok(unsafe { Box::from_raw(percpu) })
     }
 
+    /// Save the Linux extended state before entering VMPL mode. The area
+    /// is saved at CPL3 and restored at CPL0, so XSAVES is never used.
     #[cfg(feature = "xsave")]
     fn xsave_begin(&mut self) -> Result<(), VmplError> {
-        println!("xsave begin");
-        let mut mask: u64 = 0x07;
-        unsafe {
-            asm!(
-                "xgetbv",
-                in("ecx") XCR_XFEATURE_ENABLED_MASK,
-                out("eax") mask,
-            );
+        info!("xsave begin");
+        if let Err(e) = xsave_request_amx() {
+            error!("dune: failed to enable AMX: {}", e);
         }
 
-        println!("xsave mask: {:x}", mask);
-        let xsave_area = vec![0u8; XSAVE_SIZE];
-        if xsave_area.is_empty() {
-            eprintln!("dune: failed to allocate xsave area");
-            return Err(VmplError::Sys(libc::ENOMEM));
-        }
-
-        println!("xsave area at {:?}", xsave_area.as_ptr());
-        unsafe {
-            asm!(
-                ".byte 0x48, 0x0f, 0xae, 0x27",
-                in("rdi") xsave_area.as_ptr(),
-                in("eax") mask,
-                in("edx") 0x00,
-            );
-        }
-
-        self.xsave_mask = mask;
-        self.xsave_area = Some(xsave_area);
+        let mut area = XsaveArea::with_insn(XsaveInsn::Xsave)?;
+        info!("{}", area);
+        area.save();
+        self.xsave_area = Some(area);
 
         Ok(())
     }
 
+    /// Restore XCR0 and the extended state saved by `xsave_begin`
     #[cfg(feature = "xsave")]
     fn xsave_end(&mut self) -> Result<(), VmplError> {
-        let mask = self.xsave_mask;
+        let area = self.xsave_area.as_ref().ok_or(VmplError::Sys(libc::EINVAL))?;
+        let mask = area.mask();
         unsafe {
             asm!(
                 "xsetbv",
                 in("ecx") XCR_XFEATURE_ENABLED_MASK,
-                in("eax") mask,
-                in("edx") (mask >> 32),
-            );
-
-            asm!(
-                ".byte 0x48, 0x0f, 0xae, 0x2f",
-                in("rdi") percpu.xsave_area.as_ref().unwrap().as_ptr(),
-                in("eax") mask,
-                in("edx") 0x00,
+                in("eax") mask as u32,
+                in("edx") (mask >> 32) as u32,
+                options(nomem, nostack),
             );
         }
+        area.restore();
 
-        self.xsave_area = None;
-
-        println!("xsave end");
+        info!("xsave end");
         Ok(())
     }
 
+    /// Area the entry stub keeps the VMPL extended state in while Linux
+    /// runs on behalf of this thread (syscalls, signals). It is in the
+    /// standard format, which the stub saves with plain XSAVE.
+    #[cfg(feature = "xsave")]
+    pub fn xsave_area(&self) -> Option<&XsaveArea> {
+        self.xsave_area.as_ref()
+    }
+
     #[cfg(not(feature = "xsave"))]
     fn xsave_begin(&mut self) -> Result<(), VmplError> {
         Ok(())
@@ -332,9 +597,15 @@ impl DunePerCpu {
     pub fn pre_init(&mut self, fd: VmplFile) -> Result<(), VmplError> {
         info!("vmpl_init_pre");
 
-        self.setup_gdt();
+        let gdt = match self.setup_gdt() {
+            Ok(gdt) => gdt,
+            Err(rc) => {
+                error!("dune: failed to setup gdt");
+                return Err(rc);
+            }
+        };
 
-        if let Err(rc) = self.setup_vmsa(fd) {
+        if let Err(rc) = self.setup_vmsa(fd, &gdt) {
             error!("dune: failed to setup vmsa");
             return Err(rc);
         }
@@ -356,6 +627,15 @@ impl DunePerCpu {
 
         serial_init();
 
+        #[cfg(feature = "apic")]
+        {
+            apic_init_rt_entry()?;
+            percpu_set_cpu(self, apic_current_cpu()?);
+            timer_init()?;
+        }
+        #[cfg(feature = "mm")]
+        pkey_init();
+
         // write fsbase and gsbase use x86_64
         x86_64::instructions::segmentation::FS;
 
@@ -406,19 +686,19 @@ impl Drop for DunePerCpu {
     fn drop(&mut self) {
         log::debug!("vmpl_free_percpu");
 
+        percpu_unregister(self as *mut DunePerCpu);
+
+        // a GHCB from `ghcb_init` keeps the reference to its pool frame
+        #[cfg(feature = "ghcb")]
         if !self.ghcb.is_null() {
-            unsafe {
-                libc::free(self.ghcb as *mut libc::c_void);
-                self.ghcb = ptr::null_mut();
-            }
+            let pa = pgtable_va_to_pa(VirtAddr::new(self.ghcb as u64));
+            drop(unsafe { PageRef::from_raw(pa) });
         }
+        self.ghcb = ptr::null_mut();
 
-        if !self.xsave_area.is_null() {
-            unsafe {
-                libc::free(self.xsave_area as *mut libc::c_void);
-                self.xsave_area = ptr::null_mut();
-            }
-        }
+        // the page is unmapped below, drop the area while it is still there
+        #[cfg(feature = "xsave")]
+        drop(self.xsave_area.take());
 
         unsafe {
             libc::munmap(self as *mut _ as *mut libc::c_void, PGSIZE);
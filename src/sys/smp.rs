@@ -0,0 +1,169 @@
+#[cfg(feature = "apic")]
+pub mod smp {
+    use std::cell::UnsafeCell;
+    use std::collections::VecDeque;
+    use std::ops::Range;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use lazy_static::lazy_static;
+    use log::info;
+    use x86_64::instructions::interrupts;
+    use x86_64::structures::idt::InterruptStackFrame;
+
+    use crate::error::VmplError;
+    use crate::ghcb::globals::PAGE_SIZE;
+    use crate::mm::flush_local;
+    use crate::sys::apic::apic::{apic_current_cpu, apic_eoi, send_ipi_mask, APIC_ROUTING};
+    use crate::sys::idt::idt_register_irq;
+
+    /// Vector reserved for cross-CPU function calls
+    pub const CALL_FUNCTION_VECTOR: u8 = 0xfb;
+
+    type SmpFn = Arc<dyn Fn() + Send + Sync>;
+
+    struct CallRequest {
+        func: SmpFn,
+        /// Number of CPUs that still have to run `func`
+        pending: Arc<AtomicUsize>,
+    }
+
+    /// Queue of calls for one CPU, also taken by the IPI handler
+    ///
+    /// A spin lock held only with interrupts disabled: the handler can't
+    /// interrupt a holder on its own CPU, and a holder on another CPU
+    /// keeps it for a few instructions without ever sleeping.
+    struct CallQueue {
+        locked: AtomicBool,
+        requests: UnsafeCell<VecDeque<CallRequest>>,
+    }
+
+    // `requests` is only reached through `with`, under `locked`
+    unsafe impl Sync for CallQueue {}
+
+    impl CallQueue {
+        fn new() -> CallQueue {
+            CallQueue {
+                locked: AtomicBool::new(false),
+                requests: UnsafeCell::new(VecDeque::new()),
+            }
+        }
+
+        fn with<R>(&self, f: impl FnOnce(&mut VecDeque<CallRequest>) -> R) -> R {
+            interrupts::without_interrupts(|| {
+                while self
+                    .locked
+                    .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+                    .is_err()
+                {
+                    core::hint::spin_loop();
+                }
+                let ret = f(unsafe { &mut *self.requests.get() });
+                self.locked.store(false, Ordering::Release);
+                ret
+            })
+        }
+    }
+
+    lazy_static! {
+        /// Per-CPU queues of functions to run, indexed like `APIC_ROUTING`
+        static ref CALL_QUEUES: Vec<CallQueue> =
+            (0..APIC_ROUTING.len()).map(|_| CallQueue::new()).collect();
+    }
+
+    /// Run everything queued for the current CPU
+    fn smp_process_call_queue() {
+        let cpu = match apic_current_cpu() {
+            Ok(cpu) => cpu,
+            Err(_) => return,
+        };
+
+        loop {
+            // don't hold the queue lock while running the function
+            let req = match CALL_QUEUES[cpu].with(|queue| queue.pop_front()) {
+                Some(req) => req,
+                None => break,
+            };
+            (req.func)();
+            req.pending.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    extern "x86-interrupt" fn call_function_interrupt(_stack_frame: InterruptStackFrame) {
+        smp_process_call_queue();
+        apic_eoi();
+    }
+
+    pub fn smp_init() -> Result<(), VmplError> {
+        info!("setup smp call function");
+        idt_register_irq(CALL_FUNCTION_VECTOR, call_function_interrupt)
+    }
+
+    /// Take back the requests queued with `pending`. A CPU that already
+    /// ran its copy, from another IPI, has counted itself off.
+    fn smp_cancel_call(cpus: &[usize], pending: &Arc<AtomicUsize>) {
+        for cpu in cpus.iter() {
+            CALL_QUEUES[*cpu].with(|queue| queue.retain(|req| !Arc::ptr_eq(&req.pending, pending)));
+        }
+    }
+
+    /// Run `func` on every CPU in `cpus`. The current CPU, if listed, runs
+    /// it directly. With `wait`, return only once every CPU is done.
+    pub fn smp_call_function<F>(cpus: &[usize], func: F, wait: bool) -> Result<(), VmplError>
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        let this_cpu = apic_current_cpu()?;
+        let func: SmpFn = Arc::new(func);
+        let remote: Vec<usize> = cpus.iter().copied().filter(|cpu| *cpu != this_cpu).collect();
+        let pending = Arc::new(AtomicUsize::new(remote.len()));
+
+        if let Some(cpu) = remote.iter().find(|cpu| **cpu >= CALL_QUEUES.len()) {
+            return Err(VmplError::ApicInvalidCpu(*cpu));
+        }
+
+        for cpu in remote.iter() {
+            let req = CallRequest {
+                func: func.clone(),
+                pending: pending.clone(),
+            };
+            CALL_QUEUES[*cpu].with(|queue| queue.push_back(req));
+        }
+        if let Err(e) = send_ipi_mask(remote.iter().copied(), CALL_FUNCTION_VECTOR) {
+            smp_cancel_call(&remote, &pending);
+            return Err(e);
+        }
+
+        if cpus.contains(&this_cpu) {
+            func();
+        }
+
+        if wait {
+            while pending.load(Ordering::SeqCst) != 0 {
+                core::hint::spin_loop();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Flush `range` from the TLB of every CPU running in VMPL mode and wait
+    /// for them to finish. Call after unmapping or downgrading permissions.
+    pub fn tlb_shootdown(range: Range<u64>) -> Result<(), VmplError> {
+        let start = range.start & !(PAGE_SIZE - 1);
+        let end = range.end;
+        if end <= start {
+            return Ok(());
+        }
+
+        flush_local(start, end);
+
+        let this_cpu = apic_current_cpu()?;
+        let cpus: Vec<usize> = APIC_ROUTING.cpus().filter(|cpu| *cpu != this_cpu).collect();
+        if cpus.is_empty() {
+            return Ok(());
+        }
+
+        smp_call_function(&cpus, move || flush_local(start, end), true)
+    }
+}
@@ -0,0 +1,206 @@
+#[cfg(feature = "apic")]
+pub mod timer {
+    use core::arch::x86_64::{__cpuid, _rdtsc};
+    use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+    use std::time::{Duration, Instant};
+
+    use log::{debug, info};
+    use x86_64::structures::idt::InterruptStackFrame;
+
+    use crate::error::VmplError;
+    use crate::sys::apic::apic::{apic_eoi, apic_read, apic_write};
+    use crate::sys::idt::idt_register_irq;
+
+    /// x2APIC timer related MSRs
+    pub const MSR_APIC_LVT_TIMER: u32 = 0x832;
+    pub const MSR_APIC_TMICT: u32 = 0x838;
+    pub const MSR_APIC_TMCCT: u32 = 0x839;
+    pub const MSR_APIC_TDCR: u32 = 0x83E;
+    pub const MSR_TSC_DEADLINE: u32 = 0x6E0;
+
+    pub const APIC_LVT_MASKED: u64 = 1 << 16;
+    pub const APIC_LVT_TIMER_ONESHOT: u64 = 0 << 17;
+    pub const APIC_LVT_TIMER_PERIODIC: u64 = 1 << 17;
+    pub const APIC_LVT_TIMER_TSCDEADLINE: u64 = 2 << 17;
+    pub const APIC_LVT_TIMER_MODE_MASK: u64 = 3 << 17;
+    const APIC_TDCR_DIV_16: u64 = 0x3;
+
+    /// CPUID.01H:ECX.TSC_DEADLINE[bit 24]
+    const CPUID_TSC_DEADLINE: u32 = 1 << 24;
+
+    /// Length of the calibration window
+    const CALIBRATE_MS: u64 = 10;
+
+    /// Frequencies measured by `timer_calibrate`
+    static TSC_HZ: AtomicU64 = AtomicU64::new(0);
+    static APIC_TIMER_HZ: AtomicU64 = AtomicU64::new(0);
+
+    /// Callback run on every timer interrupt
+    static TIMER_CALLBACK: AtomicUsize = AtomicUsize::new(0);
+
+    fn rdtsc() -> u64 {
+        unsafe { _rdtsc() }
+    }
+
+    pub fn tsc_deadline_supported() -> bool {
+        unsafe { __cpuid(1).ecx & CPUID_TSC_DEADLINE != 0 }
+    }
+
+    /// Measure the TSC frequency against the monotonic clock
+    fn calibrate_tsc() -> u64 {
+        let window = Duration::from_millis(CALIBRATE_MS);
+        let start = Instant::now();
+        let tsc_start = rdtsc();
+        while start.elapsed() < window {}
+        let tsc_end = rdtsc();
+
+        (tsc_end - tsc_start) * 1_000_000_000 / start.elapsed().as_nanos() as u64
+    }
+
+    /// Measure the APIC timer frequency (at divide-by-16) against the TSC
+    fn calibrate_apic_timer(tsc_hz: u64) -> u64 {
+        let window = tsc_hz * CALIBRATE_MS / 1000;
+
+        apic_write(MSR_APIC_TDCR, APIC_TDCR_DIV_16);
+        apic_write(MSR_APIC_LVT_TIMER, APIC_LVT_MASKED | APIC_LVT_TIMER_ONESHOT);
+        apic_write(MSR_APIC_TMICT, u32::MAX as u64);
+
+        let tsc_start = rdtsc();
+        while rdtsc() - tsc_start < window {}
+        let remaining = apic_read(MSR_APIC_TMCCT);
+
+        apic_write(MSR_APIC_TMICT, 0);
+
+        (u32::MAX as u64 - remaining) * 1000 / CALIBRATE_MS
+    }
+
+    /// Calibrate the TSC and the APIC timer. Must run before arming the timer.
+    pub fn timer_calibrate() -> Result<(), VmplError> {
+        info!("calibrate apic timer");
+
+        let tsc_hz = calibrate_tsc();
+        if tsc_hz == 0 {
+            return Err(VmplError::Sys(libc::EIO));
+        }
+
+        let apic_hz = calibrate_apic_timer(tsc_hz);
+        if apic_hz == 0 {
+            return Err(VmplError::Sys(libc::EIO));
+        }
+
+        debug!("tsc: {} Hz, apic timer: {} Hz", tsc_hz, apic_hz);
+        TSC_HZ.store(tsc_hz, Ordering::SeqCst);
+        APIC_TIMER_HZ.store(apic_hz, Ordering::SeqCst);
+
+        Ok(())
+    }
+
+    /// Calibrate on the first CPU to enter VMPL mode. The APIC is only
+    /// reachable from there, and the frequencies are the same on all CPUs.
+    pub fn timer_init() -> Result<(), VmplError> {
+        if APIC_TIMER_HZ.load(Ordering::SeqCst) != 0 {
+            return Ok(());
+        }
+        timer_calibrate()
+    }
+
+    /// Use known frequencies instead of calibrating, e.g. from CPUID 0x15
+    pub fn timer_set_frequencies(tsc_hz: u64, apic_timer_hz: u64) {
+        TSC_HZ.store(tsc_hz, Ordering::SeqCst);
+        APIC_TIMER_HZ.store(apic_timer_hz, Ordering::SeqCst);
+    }
+
+    /// Convert a duration into APIC timer ticks
+    fn duration_to_ticks(duration: Duration) -> Result<u32, VmplError> {
+        let hz = APIC_TIMER_HZ.load(Ordering::SeqCst);
+        if hz == 0 {
+            return Err(VmplError::Sys(libc::EAGAIN));
+        }
+
+        let ticks = (duration.as_nanos() * hz as u128 / 1_000_000_000).max(1);
+        u32::try_from(ticks).map_err(|_| VmplError::Sys(libc::ERANGE))
+    }
+
+    /// Register the function run on every timer interrupt
+    pub fn timer_register_callback(cb: fn()) {
+        TIMER_CALLBACK.store(cb as usize, Ordering::SeqCst);
+    }
+
+    extern "x86-interrupt" fn timer_handler(_stack_frame: InterruptStackFrame) {
+        let cb = TIMER_CALLBACK.load(Ordering::SeqCst);
+        if cb != 0 {
+            let cb: fn() = unsafe { std::mem::transmute(cb) };
+            cb();
+        }
+        apic_eoi();
+    }
+
+    fn program(mode: u64, vector: u8) {
+        apic_write(MSR_APIC_TDCR, APIC_TDCR_DIV_16);
+        apic_write(MSR_APIC_LVT_TIMER, mode | vector as u64);
+    }
+
+    fn arm(mode: u64, vector: u8) -> Result<(), VmplError> {
+        idt_register_irq(vector, timer_handler)?;
+        program(mode, vector);
+        Ok(())
+    }
+
+    /// Fire once on `vector` after `duration`
+    pub fn set_oneshot(duration: Duration, vector: u8) -> Result<(), VmplError> {
+        let ticks = duration_to_ticks(duration)?;
+        arm(APIC_LVT_TIMER_ONESHOT, vector)?;
+        apic_write(MSR_APIC_TMICT, ticks as u64);
+        Ok(())
+    }
+
+    /// Like `set_oneshot`, for callers that install their own handler at
+    /// `vector` and do not want the timer callback
+    pub fn set_oneshot_raw(duration: Duration, vector: u8) -> Result<(), VmplError> {
+        let ticks = duration_to_ticks(duration)?;
+        program(APIC_LVT_TIMER_ONESHOT, vector);
+        apic_write(MSR_APIC_TMICT, ticks as u64);
+        Ok(())
+    }
+
+    /// Fire on `vector` every `interval`
+    pub fn set_periodic(interval: Duration, vector: u8) -> Result<(), VmplError> {
+        let ticks = duration_to_ticks(interval)?;
+        arm(APIC_LVT_TIMER_PERIODIC, vector)?;
+        apic_write(MSR_APIC_TMICT, ticks as u64);
+        Ok(())
+    }
+
+    /// Fire once on `vector` when the TSC reaches `tsc`
+    pub fn set_deadline(tsc: u64, vector: u8) -> Result<(), VmplError> {
+        if !tsc_deadline_supported() {
+            return Err(VmplError::Sys(libc::ENOTSUP));
+        }
+
+        arm(APIC_LVT_TIMER_TSCDEADLINE, vector)?;
+        // order the LVT write before the deadline write
+        unsafe { core::arch::asm!("mfence", options(nostack)) };
+        apic_write(MSR_TSC_DEADLINE, tsc);
+        Ok(())
+    }
+
+    /// Fire once on `vector` after `duration`, using the TSC deadline
+    pub fn set_deadline_after(duration: Duration, vector: u8) -> Result<(), VmplError> {
+        let tsc_hz = TSC_HZ.load(Ordering::SeqCst);
+        if tsc_hz == 0 {
+            return Err(VmplError::Sys(libc::EAGAIN));
+        }
+
+        let delta = (duration.as_nanos() * tsc_hz as u128 / 1_000_000_000) as u64;
+        set_deadline(rdtsc() + delta, vector)
+    }
+
+    /// Stop the timer in any mode
+    pub fn timer_stop() {
+        apic_write(MSR_APIC_LVT_TIMER, APIC_LVT_MASKED);
+        apic_write(MSR_APIC_TMICT, 0);
+        if tsc_deadline_supported() {
+            apic_write(MSR_TSC_DEADLINE, 0);
+        }
+    }
+}
@@ -0,0 +1,310 @@
+// Sandboxed user contexts
+//
+// Untrusted code runs at CPL3 inside VMPL mode, in its own address space.
+// `UserContext::run()` enters it through `dune_jump_to_user` and returns
+// when the code makes a system call, takes an exception, or uses up its
+// time slice. Interrupts taken in user mode are handled and the code
+// resumes. The caller inspects the trap frame and calls
+// `run()` again to resume.
+
+use std::ptr;
+use std::time::Duration;
+
+use log::info;
+use x86_64::instructions::interrupts;
+use x86_64::registers::control::{Cr2, Cr3};
+use x86_64::structures::paging::PhysFrame;
+use x86_64::VirtAddr;
+
+use crate::define_percpu;
+use crate::error::VmplError;
+#[cfg(feature = "mm")]
+use crate::mm::mm_syscall;
+use crate::start::dune::{dune_jump_to_user, dune_ret_from_user};
+use crate::sys::core::DuneTrapFrame;
+use crate::sys::crash::crash_report;
+use crate::sys::percpu::this_cpu;
+#[cfg(feature = "apic")]
+use crate::sys::apic::apic::apic_eoi;
+#[cfg(feature = "apic")]
+use crate::sys::idt::idt_register_trap;
+#[cfg(feature = "apic")]
+use crate::sys::timer::timer::{set_oneshot_raw, timer_stop};
+#[cfg(feature = "apic")]
+use crate::trap_entry;
+
+/// Vector of the time slice timer
+pub const USER_PREEMPT_VECTOR: u8 = 0xee;
+
+const PF_VECTOR: u64 = 14;
+/// Vectors below this are exceptions
+const NR_EXCEPTIONS: u64 = 32;
+const RFLAGS_IF: u64 = 1 << 9;
+const RFLAGS_RESERVED: u64 = 1 << 1;
+
+/// Why `UserContext::run()` returned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserExit {
+    /// `syscall` with the number in %rax
+    Syscall(u64),
+    /// Exception, with the faulting address for #PF
+    Exception {
+        vector: u8,
+        error_code: u32,
+        addr: Option<u64>,
+    },
+    /// The time slice expired
+    Preempted,
+}
+
+define_percpu! {
+    /// `UserContext` running on this CPU, 0 if none
+    static CURRENT_USER: usize = 0;
+    /// The time slice expired while the kernel was running for the user
+    /// code; delivered before going back to it
+    static PREEMPT_PENDING: bool = false;
+}
+
+pub struct UserContext {
+    tf: DuneTrapFrame,
+    address_space: PhysFrame,
+    timeslice: Option<Duration>,
+    exit: Option<UserExit>,
+}
+
+impl UserContext {
+    /// Start executing at `entry` on `stack`, with `address_space` as the
+    /// root page table. Only pages mapped with `PERM_U` are reachable.
+    pub fn new(entry: VirtAddr, stack: VirtAddr, address_space: PhysFrame) -> UserContext {
+        let mut tf = DuneTrapFrame::default();
+        tf.set_rip(entry.as_u64());
+        tf.set_rsp(stack.as_u64());
+        tf.set_rflags(RFLAGS_IF | RFLAGS_RESERVED);
+
+        UserContext {
+            tf,
+            address_space,
+            timeslice: None,
+            exit: None,
+        }
+    }
+
+    /// Preempt the user code after `timeslice` (needs the APIC timer)
+    pub fn set_timeslice(&mut self, timeslice: Option<Duration>) {
+        self.timeslice = timeslice;
+    }
+
+    pub fn trap_frame(&self) -> &DuneTrapFrame {
+        &self.tf
+    }
+
+    pub fn trap_frame_mut(&mut self) -> &mut DuneTrapFrame {
+        &mut self.tf
+    }
+
+    /// Reason of the last exit
+    pub fn exit(&self) -> Option<UserExit> {
+        self.exit
+    }
+
+    pub fn address_space(&self) -> PhysFrame {
+        self.address_space
+    }
+
+    /// Set the value `syscall` returns when resumed
+    pub fn set_syscall_return(&mut self, value: u64) {
+        self.tf.set_rax(value);
+    }
+
+    /// Handle the pending system call. Memory-management calls are
+    /// emulated so the VMPL view of the address space follows them, the
+    /// rest go to Linux.
+    pub fn handle_syscall(&mut self) {
+        #[cfg(feature = "mm")]
+        {
+            let tf = &self.tf;
+            let args = [tf.rdi(), tf.rsi(), tf.rdx(), tf.r10(), tf.r8(), tf.r9()];
+            if let Some(ret) = mm_syscall(tf.rax(), args) {
+                self.tf.set_rax(ret as u64);
+                return;
+            }
+        }
+        self.passthrough_syscall();
+    }
+
+    /// Forward the pending system call to Linux and store its result
+    pub fn passthrough_syscall(&mut self) {
+        let tf = &self.tf;
+        let ret = unsafe {
+            libc::syscall(
+                tf.rax() as i64,
+                tf.rdi(),
+                tf.rsi(),
+                tf.rdx(),
+                tf.r10(),
+                tf.r8(),
+                tf.r9(),
+            )
+        };
+        self.tf.set_rax(ret as u64);
+    }
+
+    /// Run the user code until its next exit
+    pub fn run(&mut self) -> Result<UserExit, VmplError> {
+        this_cpu().ok_or(VmplError::PerCpuNotActive)?;
+        if CURRENT_USER.get()? != 0 {
+            return Err(VmplError::Sys(libc::EBUSY));
+        }
+
+        #[cfg(not(feature = "apic"))]
+        if self.timeslice.is_some() {
+            return Err(VmplError::Sys(libc::ENOTSUP));
+        }
+
+        // the exit handlers write through this pointer
+        let this = self as *mut UserContext;
+        CURRENT_USER.set(this as usize)?;
+
+        let (root, flags) = Cr3::read();
+        if root != self.address_space {
+            unsafe { Cr3::write(self.address_space, flags) };
+        }
+
+        // iretq turns interrupts back on, so the time slice cannot expire
+        // between the pending check and reaching user mode
+        let irqs = interrupts::are_enabled();
+        interrupts::disable();
+        let entered = self.arm_timeslice().and_then(|_| self.enter());
+        if irqs {
+            interrupts::enable();
+        }
+
+        if root != self.address_space {
+            unsafe { Cr3::write(root, flags) };
+        }
+
+        #[cfg(feature = "apic")]
+        if self.timeslice.is_some() {
+            timer_stop();
+        }
+
+        CURRENT_USER.set(0)?;
+        entered?;
+        unsafe { ptr::read_volatile(ptr::addr_of!((*this).exit)) }
+            .ok_or(VmplError::Sys(libc::EFAULT))
+    }
+
+    /// Start the time slice, once in the user address space so the switch
+    /// is not charged to it
+    fn arm_timeslice(&self) -> Result<(), VmplError> {
+        #[cfg(feature = "apic")]
+        if let Some(timeslice) = self.timeslice {
+            set_oneshot_raw(timeslice, USER_PREEMPT_VECTOR)?;
+        }
+        Ok(())
+    }
+
+    /// Jump to the user code, or exit right away for a pending preemption
+    fn enter(&mut self) -> Result<(), VmplError> {
+        self.exit = None;
+        let pending = PREEMPT_PENDING.get()?;
+        PREEMPT_PENDING.set(false)?;
+        if pending && self.timeslice.is_some() {
+            self.exit = Some(UserExit::Preempted);
+            return Ok(());
+        }
+
+        let this = self as *mut UserContext;
+        unsafe { dune_jump_to_user(ptr::addr_of_mut!((*this).tf)) };
+        Ok(())
+    }
+}
+
+/// Save `tf` in the running context and return from its `run()`
+fn exit_to_caller(ctx: *mut UserContext, exit: UserExit, tf: &DuneTrapFrame) -> ! {
+    unsafe {
+        (*ctx).tf = *tf;
+        (*ctx).exit = Some(exit);
+        dune_ret_from_user(0)
+    }
+}
+
+fn current_user() -> Option<*mut UserContext> {
+    match CURRENT_USER.get() {
+        Ok(0) | Err(_) => None,
+        Ok(ctx) => Some(ctx as *mut UserContext),
+    }
+}
+
+/// Exit to the `run()` caller if the exception `tf` was taken in user
+/// mode; returns otherwise, so kernel traps keep their normal handling
+pub(crate) fn user_trap_exit(vector: u64, tf: &DuneTrapFrame) {
+    if tf.cs() & 3 != 3 || vector >= NR_EXCEPTIONS {
+        return;
+    }
+    let ctx = match current_user() {
+        Some(ctx) => ctx,
+        None => return,
+    };
+
+    let exit = UserExit::Exception {
+        vector: vector as u8,
+        error_code: tf.err(),
+        addr: (vector == PF_VECTOR).then(Cr2::read_raw),
+    };
+    exit_to_caller(ctx, exit, tf)
+}
+
+/// Called by `__dune_syscall` for a `syscall` at CPL3
+#[no_mangle]
+pub extern "C" fn dune_syscall_handler(tf: &mut DuneTrapFrame) {
+    let ctx = match current_user() {
+        Some(ctx) => ctx,
+        None => crash_report("SYSCALL", tf),
+    };
+
+    // __dune_syscall moved arg3 from %r10 to %rcx; resuming with iretq
+    // must see the registers as `syscall` left them
+    let mut frame = *tf;
+    frame.set_r10(tf.rcx());
+    frame.set_rcx(tf.rip());
+    frame.set_r11(tf.rflags());
+    exit_to_caller(ctx, UserExit::Syscall(tf.rax()), &frame)
+}
+
+/// Called by `__dune_intr` in dune.S. The IDT uses the `trap_entry!`
+/// stubs instead, so this only backs the Dune-compatible entry points.
+#[no_mangle]
+pub extern "C" fn dune_trap_handler(num: u64, tf: &mut DuneTrapFrame) {
+    user_trap_exit(num, tf);
+    if num < 32 {
+        crash_report("TRAP", tf);
+    }
+}
+
+#[cfg(feature = "apic")]
+trap_entry!(__vmpl_user_preempt_entry, USER_PREEMPT_VECTOR, preempt_trap);
+
+#[cfg(feature = "apic")]
+extern "C" fn preempt_trap(_vector: u64, tf: &mut DuneTrapFrame) {
+    apic_eoi();
+    let ctx = match current_user() {
+        Some(ctx) => ctx,
+        None => return,
+    };
+    if tf.cs() & 3 != 3 {
+        // in the kernel on behalf of the user code (a syscall or fault
+        // exit), which must not be cut short
+        let _ = PREEMPT_PENDING.set(true);
+        return;
+    }
+    exit_to_caller(ctx, UserExit::Preempted, tf);
+}
+
+/// Install the time slice interrupt
+pub fn user_init() -> Result<(), VmplError> {
+    info!("setup user contexts");
+    #[cfg(feature = "apic")]
+    idt_register_trap(USER_PREEMPT_VECTOR, __vmpl_user_preempt_entry)?;
+    Ok(())
+}
@@ -0,0 +1,222 @@
+#[cfg(feature = "xsave")]
+pub mod xsave {
+    use core::arch::x86_64::{__cpuid, __cpuid_count};
+    use std::alloc::{alloc_zeroed, dealloc, Layout};
+    use std::arch::asm;
+    use std::fmt::Display;
+
+    use log::{debug, info};
+    use x86_64::registers::model_specific::Msr;
+
+    use crate::error::VmplError;
+
+    const XCR_XFEATURE_ENABLED_MASK: u32 = 0x00000000;
+    const MSR_IA32_XSS: u32 = 0xDA0;
+
+    /// XSAVE areas must be 64-byte aligned
+    const XSAVE_ALIGN: usize = 64;
+    /// Legacy region plus XSAVE header
+    const XSAVE_MIN_SIZE: usize = 512 + 64;
+
+    const CPUID_XSAVE_LEAF: u32 = 0xD;
+    /// CPUID.01H:ECX.XSAVE[bit 26]
+    const CPUID_XSAVE: u32 = 1 << 26;
+    /// CPUID.(EAX=0DH,ECX=1):EAX
+    const CPUID_XSAVEOPT: u32 = 1 << 0;
+    const CPUID_XSAVEC: u32 = 1 << 1;
+    const CPUID_XSAVES: u32 = 1 << 3;
+
+    /// AMX tile data, which Linux only enables on request
+    pub const XFEATURE_XTILEDATA: u64 = 18;
+    const ARCH_REQ_XCOMP_PERM: i32 = 0x1023;
+
+    /// Instruction pair used to save and restore an area
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum XsaveInsn {
+        Xsave,
+        Xsaveopt,
+        Xsavec,
+        /// Supervisor state too, CPL0 only
+        Xsaves,
+    }
+
+    impl XsaveInsn {
+        /// Best instruction available. XSAVES is only picked when every save
+        /// and restore runs at CPL0.
+        pub fn detect(privileged: bool) -> XsaveInsn {
+            let ext = unsafe { __cpuid_count(CPUID_XSAVE_LEAF, 1) }.eax;
+            if privileged && ext & CPUID_XSAVES != 0 {
+                XsaveInsn::Xsaves
+            } else if ext & CPUID_XSAVEOPT != 0 {
+                XsaveInsn::Xsaveopt
+            } else if ext & CPUID_XSAVEC != 0 {
+                XsaveInsn::Xsavec
+            } else {
+                XsaveInsn::Xsave
+            }
+        }
+
+        fn compacted(&self) -> bool {
+            matches!(self, XsaveInsn::Xsavec | XsaveInsn::Xsaves)
+        }
+    }
+
+    pub fn xsave_supported() -> bool {
+        unsafe { __cpuid(1).ecx & CPUID_XSAVE != 0 }
+    }
+
+    /// Features enabled in XCR0
+    pub fn xgetbv() -> u64 {
+        let (eax, edx): (u32, u32);
+        unsafe {
+            asm!(
+                "xgetbv",
+                in("ecx") XCR_XFEATURE_ENABLED_MASK,
+                out("eax") eax,
+                out("edx") edx,
+                options(nomem, nostack, preserves_flags),
+            );
+        }
+        (edx as u64) << 32 | eax as u64
+    }
+
+    /// Size of an area holding every feature in XCR0 (plus IA32_XSS for the
+    /// compacted format), as reported by CPUID leaf 0xD
+    fn xsave_size(insn: XsaveInsn) -> usize {
+        let size = if insn.compacted() {
+            unsafe { __cpuid_count(CPUID_XSAVE_LEAF, 1) }.ebx
+        } else {
+            // ECX covers every supported feature, not just the ones enabled now
+            unsafe { __cpuid_count(CPUID_XSAVE_LEAF, 0) }.ecx
+        };
+        (size as usize).max(XSAVE_MIN_SIZE)
+    }
+
+    /// Ask Linux for permission to use AMX tiles. Without it, tile state is
+    /// left out of XCR0-sized saves and faults on first use.
+    pub fn xsave_request_amx() -> Result<(), VmplError> {
+        let supported = unsafe { __cpuid_count(CPUID_XSAVE_LEAF, 0) }.eax as u64;
+        if supported & (1 << XFEATURE_XTILEDATA) == 0 {
+            return Ok(());
+        }
+
+        let rc = unsafe {
+            libc::syscall(libc::SYS_arch_prctl, ARCH_REQ_XCOMP_PERM, XFEATURE_XTILEDATA)
+        };
+        if rc != 0 {
+            return Err(VmplError::Sys(unsafe { *libc::__errno_location() }));
+        }
+        info!("xsave: AMX tile data enabled");
+        Ok(())
+    }
+
+    /// 64-byte aligned extended state buffer, sized from CPUID
+    pub struct XsaveArea {
+        ptr: *mut u8,
+        layout: Layout,
+        insn: XsaveInsn,
+        mask: u64,
+        valid: bool,
+    }
+
+    unsafe impl Send for XsaveArea {}
+
+    impl XsaveArea {
+        pub fn new(privileged: bool) -> Result<XsaveArea, VmplError> {
+            XsaveArea::with_insn(XsaveInsn::detect(privileged))
+        }
+
+        /// Area saved and restored with `insn`, and sized for its format
+        pub fn with_insn(insn: XsaveInsn) -> Result<XsaveArea, VmplError> {
+            if !xsave_supported() {
+                return Err(VmplError::Sys(libc::ENOTSUP));
+            }
+
+            let mut mask = xgetbv();
+            if insn == XsaveInsn::Xsaves {
+                mask |= unsafe { Msr::new(MSR_IA32_XSS).read() };
+            }
+
+            let size = xsave_size(insn);
+            let layout = Layout::from_size_align(size, XSAVE_ALIGN)
+                .map_err(|_| VmplError::Sys(libc::EINVAL))?;
+            let ptr = unsafe { alloc_zeroed(layout) };
+            if ptr.is_null() {
+                return Err(VmplError::Sys(libc::ENOMEM));
+            }
+
+            debug!("xsave: {:?}, {} bytes, mask {:#x}", insn, size, mask);
+            Ok(XsaveArea {
+                ptr,
+                layout,
+                insn,
+                mask,
+                valid: false,
+            })
+        }
+
+        pub fn as_ptr(&self) -> *const u8 {
+            self.ptr
+        }
+
+        pub fn size(&self) -> usize {
+            self.layout.size()
+        }
+
+        pub fn mask(&self) -> u64 {
+            self.mask
+        }
+
+        pub fn insn(&self) -> XsaveInsn {
+            self.insn
+        }
+
+        /// Save the current extended state
+        pub fn save(&mut self) {
+            let (lo, hi) = (self.mask as u32, (self.mask >> 32) as u32);
+            unsafe {
+                match self.insn {
+                    XsaveInsn::Xsave => asm!("xsave64 [{}]", in(reg) self.ptr, in("eax") lo, in("edx") hi, options(nostack)),
+                    XsaveInsn::Xsaveopt => asm!("xsaveopt64 [{}]", in(reg) self.ptr, in("eax") lo, in("edx") hi, options(nostack)),
+                    XsaveInsn::Xsavec => asm!("xsavec64 [{}]", in(reg) self.ptr, in("eax") lo, in("edx") hi, options(nostack)),
+                    XsaveInsn::Xsaves => asm!("xsaves64 [{}]", in(reg) self.ptr, in("eax") lo, in("edx") hi, options(nostack)),
+                }
+            }
+            self.valid = true;
+        }
+
+        /// Restore the state saved last, if any
+        pub fn restore(&self) {
+            if !self.valid {
+                return;
+            }
+
+            let (lo, hi) = (self.mask as u32, (self.mask >> 32) as u32);
+            unsafe {
+                match self.insn {
+                    XsaveInsn::Xsaves => asm!("xrstors64 [{}]", in(reg) self.ptr, in("eax") lo, in("edx") hi, options(nostack)),
+                    _ => asm!("xrstor64 [{}]", in(reg) self.ptr, in("eax") lo, in("edx") hi, options(nostack)),
+                }
+            }
+        }
+    }
+
+    impl Drop for XsaveArea {
+        fn drop(&mut self) {
+            unsafe { dealloc(self.ptr, self.layout) };
+        }
+    }
+
+    impl Display for XsaveArea {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(
+                f,
+                "XSAVE area: {:p} size: {} insn: {:?} mask: {:#x}\n",
+                self.ptr,
+                self.size(),
+                self.insn,
+                self.mask
+            )
+        }
+    }
+}
@@ -36,6 +36,21 @@ macro_rules! getter_func {
     };
 }
 
+/// Define per-CPU variables stored in the per-CPU page
+///
+/// `define_percpu!(static NAME: Type = init;)` declares a `PerCpuVar`
+/// accessed with `NAME.get()`, `NAME.set()` or `NAME.with()`.
+#[macro_export]
+macro_rules! define_percpu {
+    ($($(#[$attr:meta])* $vis:vis static $name:ident: $T:ty = $init:expr;)+) => {
+        $(
+            $(#[$attr])*
+            $vis static $name: $crate::sys::percpu::PerCpuVar<$T> =
+                $crate::sys::percpu::PerCpuVar::new(|| $init);
+        )+
+    };
+}
+
 /// Statically check for a condition
 #[macro_export]
 macro_rules! STATIC_ASSERT {
@@ -2,6 +2,7 @@ extern crate nix;
 
 use std::fs::OpenOptions;
 use std::mem::transmute;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 #[cfg(feature = "dune")]
 use std::{mem, process, ptr};
@@ -11,28 +12,32 @@ use libc::{signal, SIG_ERR};
 use log::{error, info};
 
 use crate::globals::{DUNE_SIGNAL_INTR_BASE, RUN_VMPL_DEV_NAME};
-use crate::mm::mm_init;
+use crate::mm::{mm_exit, mm_init};
 use crate::start::dune::{__dune_enter, __dune_ret};
 use crate::start::dune_register_intr_handler;
-use crate::sys::apic::{apic_cleanup, apic_setup};
+#[cfg(feature = "apic")]
+use crate::sys::apic::apic::{apic_cleanup, apic_setup};
 use crate::sys::core::DuneConfig;
 
 use crate::error::VmplError;
+use crate::sys::crash::crash_init;
 use crate::sys::idt::idt_init;
+#[cfg(feature = "apic")]
+use crate::sys::smp::smp::smp_init;
 use crate::sys::signal::signal_init;
 use crate::sys::syscall::{setup_syscall, setup_vsyscall};
+use crate::sys::user::user_init;
 use crate::sys::{seimi_init, DunePerCpu};
 
 // declare global variables
 static mut CURRENT_CPU: i32 = 0;
 static mut CPU_COUNT: i32 = 0;
-static mut VMPL_BOOTED: bool = false;
-// define percpu variable for VMPL
-static mut PERCPU: Option<Box<DunePerCpu>> = None;
 
 struct VmplSystem {
     dune_fd: i32,
     percpu: Option<Box<DunePerCpu>>,
+    /// Set once `init` went through, cleared by the first `exit`
+    booted: AtomicBool,
 }
 
 impl VmplSystem {
@@ -40,6 +45,7 @@ impl VmplSystem {
         VmplSystem {
             dune_fd: 0,
             percpu: None,
+            booted: AtomicBool::new(false),
         }
     }
 }
@@ -135,22 +141,37 @@ impl VmplSystem {
             .open(RUN_VMPL_DEV_NAME)?;
 
         #[cfg(feature = "mm")]
-        mm_init(self.dune_fd)?;
+        mm_init(self.dune_fd, map_full)?;
         #[cfg(feature = "seimi")]
         seimi_init(dune_fd)?;
         setup_syscall(dune_fd.as_raw_fd())?;
         setup_vsyscall()?;
         signal_init()?;
         idt_init()?;
-        apic_setup()?;
+        crash_init();
+        #[cfg(feature = "apic")]
+        {
+            apic_setup()?;
+            smp_init()?;
+        }
+        user_init()?;
 
+        self.booted.store(true, Ordering::SeqCst);
         Ok(())
     }
 
-    fn init_exit(&self) {
-        info!("vmpl_init_exit");
-        // mm_exit(&mut vmpl_mm);
+    /// Undo `init` when the system is dropped, reporting the frames still
+    /// referenced. Does nothing unless `init` went through.
+    fn exit(&self) {
+        if !self.booted.swap(false, Ordering::SeqCst) {
+            return;
+        }
+
+        info!("vmpl_exit");
+        #[cfg(feature = "mm")]
+        mm_exit();
         // free_percpu(percpu);
+        #[cfg(feature = "apic")]
         apic_cleanup();
     }
 
@@ -188,7 +209,7 @@ impl VmplSystem {
         self.build_assert();
 
         let mut config = DuneConfig::new(&__dune_ret as *const _ as u64, 0, 0x202);
-        let conf = match Box::new(config) {
+        let mut conf = match Box::new(config) {
             Ok(conf) => conf,
             Err(rc) => {
                 error!("dune: failed to allocate config struct");
@@ -201,6 +222,13 @@ impl VmplSystem {
             return Err(rc);
         }
 
+        // the entry stub saves and restores the VMPL extended state here
+        #[cfg(feature = "xsave")]
+        if let Some(area) = percpu.xsave_area() {
+            conf.set_xsave_area(area.as_ptr() as u64);
+            conf.set_xsave_mask(area.mask());
+        }
+
         // dump_configs(&*percpu);
 
         unsafe {